@@ -0,0 +1,101 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// Redirects from one virtual path to another, consulted before mount resolution so
+// renamed assets stay reachable under their old paths.
+
+use crate::{LaminaFS, LfsError, LfsPath};
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+pub(crate) struct AliasTable {
+	aliases: RwLock<HashMap<String, String>>
+}
+
+// Aliases match whole paths, with or without a trailing slash.
+fn key(path: &str) -> &str {
+	match path.trim_end_matches('/') {
+		"" => "/",
+		trimmed => trimmed
+	}
+}
+
+impl AliasTable {
+	pub(crate) fn new() -> AliasTable {
+		AliasTable {
+			aliases: RwLock::new(HashMap::new())
+		}
+	}
+
+	// The target of `path` if it is aliased. Targets aren't looked up again, so aliases
+	// don't chain and can't loop.
+	pub(crate) fn resolve(&self, path: &str) -> Option<String> {
+		let aliases = self.aliases.read().unwrap();
+		if aliases.is_empty() {
+			return None;
+		}
+		aliases.get(key(path)).cloned()
+	}
+}
+
+impl LaminaFS {
+	// Redirects work on `path` to `target`, replacing any previous alias for `path`.
+	pub fn alias(&self, path: &str, target: &str) -> Result<(), LfsError> {
+		LfsPath::new(path)?;
+		LfsPath::new(target)?;
+		self.aliases.aliases.write().unwrap().insert(key(path).to_string(), target.to_string());
+		Ok(())
+	}
+
+	// Returns whether `path` was aliased.
+	pub fn remove_alias(&self, path: &str) -> bool {
+		self.aliases.aliases.write().unwrap().remove(key(path)).is_some()
+	}
+
+	// The aliased paths and their targets, sorted by path.
+	pub fn aliases(&self) -> Vec<(String, String)> {
+		let mut aliases: Vec<_> = self.aliases.aliases.read().unwrap().iter()
+			.map(|(path, target)| (path.clone(), target.clone()))
+			.collect();
+		aliases.sort();
+		aliases
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn alias_table_test() {
+		let table = AliasTable::new();
+		table.aliases.write().unwrap().insert(key("/textures/old.png/").to_string(), "/textures/new.png".to_string());
+		table.aliases.write().unwrap().insert(key("/textures/new.png").to_string(), "/textures/newer.png".to_string());
+
+		assert!(table.resolve("/textures/old.png") == Some("/textures/new.png".to_string()));
+		assert!(table.resolve("/textures/old.png/") == Some("/textures/new.png".to_string()));
+		assert!(table.resolve("/textures/other.png") == None);
+		assert!(key("/") == "/");
+	}
+}
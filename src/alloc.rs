@@ -0,0 +1,145 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+use crate::laminafs_sys;
+
+use std::ffi::c_void;
+
+// Routes the context's allocations, including read buffers, through a Rust allocator.
+pub trait Allocator: Send + Sync + 'static {
+	fn alloc(&self, size: usize, alignment: usize) -> *mut u8;
+	fn free(&self, ptr: *mut u8);
+}
+
+unsafe extern "C" fn allocator_alloc<A: Allocator>(allocator: *mut c_void, size: usize, alignment: usize) -> *mut c_void {
+	// a panic can't unwind into C, so it fails the allocation
+	std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| (*(allocator as *const A)).alloc(size, alignment))).unwrap_or(0 as *mut u8) as *mut c_void
+}
+
+unsafe extern "C" fn allocator_free<A: Allocator>(allocator: *mut c_void, ptr: *mut c_void) {
+	let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| (*(allocator as *const A)).free(ptr as *mut u8)));
+}
+
+// The allocator a context was created with. Read buffers hold on to it so they can still
+// be freed after their work item, or the context itself, is gone.
+pub(crate) struct ContextAllocator {
+	raw: *mut laminafs_sys::lfs_allocator_t,
+	owned: bool,
+	_allocator: Option<Box<dyn Allocator>>
+}
+
+unsafe impl Send for ContextAllocator {}
+unsafe impl Sync for ContextAllocator {}
+
+impl ContextAllocator {
+	pub(crate) fn default() -> ContextAllocator {
+		ContextAllocator {
+			raw: std::ptr::addr_of_mut!(laminafs_sys::lfs_default_allocator),
+			owned: false,
+			_allocator: None
+		}
+	}
+
+	pub(crate) fn new<A: Allocator>(allocator: A) -> ContextAllocator {
+		let allocator = Box::new(allocator);
+		let raw = Box::new(laminafs_sys::lfs_allocator_t {
+			alloc: Some(allocator_alloc::<A>),
+			free: Some(allocator_free::<A>),
+			allocator: &*allocator as *const A as *mut c_void
+		});
+
+		ContextAllocator {
+			raw: Box::into_raw(raw),
+			owned: true,
+			_allocator: Some(allocator)
+		}
+	}
+
+	pub(crate) fn as_raw(&self) -> *mut laminafs_sys::lfs_allocator_t {
+		self.raw
+	}
+
+	pub(crate) unsafe fn alloc(&self, size: usize, alignment: usize) -> *mut c_void {
+		((*self.raw).alloc.unwrap())((*self.raw).allocator, size, alignment)
+	}
+
+	pub(crate) unsafe fn free(&self, ptr: *mut c_void) {
+		((*self.raw).free.unwrap())((*self.raw).allocator, ptr);
+	}
+}
+
+impl Drop for ContextAllocator {
+	fn drop(&mut self) {
+		if self.owned {
+			unsafe { drop(Box::from_raw(self.raw)); }
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::alloc::Layout;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	struct CountingAllocator {
+		live: AtomicUsize
+	}
+
+	impl Allocator for CountingAllocator {
+		fn alloc(&self, size: usize, alignment: usize) -> *mut u8 {
+			self.live.fetch_add(1, Ordering::SeqCst);
+			// stash the layout ahead of the block so free() can recover it
+			let header = std::cmp::max(alignment, std::mem::size_of::<Layout>());
+			let layout = Layout::from_size_align(size + header, std::cmp::max(alignment, std::mem::align_of::<Layout>())).unwrap();
+			unsafe {
+				let block = std::alloc::alloc(layout);
+				let ptr = block.add(header);
+				(ptr as *mut Layout).sub(1).write_unaligned(layout);
+				ptr
+			}
+		}
+
+		fn free(&self, ptr: *mut u8) {
+			self.live.fetch_sub(1, Ordering::SeqCst);
+			unsafe {
+				let layout = (ptr as *mut Layout).sub(1).read_unaligned();
+				let header = std::cmp::max(layout.align(), std::mem::size_of::<Layout>());
+				std::alloc::dealloc(ptr.sub(header), layout);
+			}
+		}
+	}
+
+	#[test]
+	fn allocator_test() {
+		let allocator = ContextAllocator::new(CountingAllocator { live: AtomicUsize::new(0) });
+		let raw = unsafe { &*allocator.as_raw() };
+
+		let ptr = unsafe { (raw.alloc.unwrap())(raw.allocator, 64, 16) };
+		assert!(ptr as usize % 16 == 0);
+		assert!(unsafe { (*(raw.allocator as *const CountingAllocator)).live.load(Ordering::SeqCst) } == 1);
+
+		unsafe { allocator.free(ptr); }
+		assert!(unsafe { (*(raw.allocator as *const CountingAllocator)).live.load(Ordering::SeqCst) } == 0);
+	}
+}
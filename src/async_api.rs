@@ -0,0 +1,104 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+// Async wrappers over the work item API. The work items are awaited through their
+// completion callbacks, so no runtime thread is parked while the IO is in flight.
+
+use crate::{LaminaFS, LfsError, OperationKind, ResultCode, WorkFuture};
+
+use std::sync::Arc;
+
+impl LaminaFS {
+	pub async fn read_file_async(&self, path: &str) -> Result<Vec<u8>, LfsError> {
+		let buffer = WorkFuture::new(self.read_file(path, false)).await?;
+		Ok(buffer.to_vec())
+	}
+
+	pub async fn read_to_string_async(&self, path: &str) -> Result<String, LfsError> {
+		let buffer = self.read_file_async(path).await?;
+		String::from_utf8(buffer).map_err(|_| LfsError::new(ResultCode::InvalidData, OperationKind::ReadFile, path))
+	}
+
+	pub async fn read_file_segment_async(&self, path: &str, offset: u64, max_bytes: u64) -> Result<Vec<u8>, LfsError> {
+		let buffer = WorkFuture::new(self.read_file_segment(path, offset, max_bytes, false)).await?;
+		Ok(buffer.to_vec())
+	}
+
+	pub async fn write_file_async<B: Into<Arc<[u8]>>>(&self, path: &str, buffer: B) -> Result<(), LfsError> {
+		WorkFuture::new(self.write_file(path, buffer)).await.map(|_| ())
+	}
+
+	pub async fn append_file_async<B: Into<Arc<[u8]>>>(&self, path: &str, buffer: B) -> Result<(), LfsError> {
+		WorkFuture::new(self.append_file(path, buffer)).await.map(|_| ())
+	}
+
+	pub async fn write_string_async(&self, path: &str, contents: &str) -> Result<(), LfsError> {
+		self.write_file_async(path, contents.as_bytes()).await
+	}
+
+	pub async fn append_string_async(&self, path: &str, contents: &str) -> Result<(), LfsError> {
+		self.append_file_async(path, contents.as_bytes()).await
+	}
+
+	pub async fn delete_file_async(&self, path: &str) -> Result<(), LfsError> {
+		WorkFuture::new(self.delete_file(path)).await.map(|_| ())
+	}
+
+	pub async fn create_dir_async(&self, path: &str) -> Result<(), LfsError> {
+		WorkFuture::new(self.create_dir(path)).await.map(|_| ())
+	}
+
+	pub async fn delete_dir_async(&self, path: &str) -> Result<(), LfsError> {
+		WorkFuture::new(self.delete_dir(path)).await.map(|_| ())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::future::block_on;
+	use crate::test_dir::TestDir;
+
+	#[test]
+	fn async_api_test() {
+		let root = TestDir::new("async_api_test");
+		let fs = LaminaFS::new();
+		let _mount = root.mount(&fs);
+
+		block_on(async {
+			fs.create_dir_async("/saves").await.unwrap();
+			fs.write_string_async("/saves/slot0.txt", "level=3").await.unwrap();
+			fs.append_string_async("/saves/slot0.txt", ";gold=10").await.unwrap();
+			assert!(fs.read_to_string_async("/saves/slot0.txt").await.unwrap() == "level=3;gold=10");
+			assert!(fs.read_file_segment_async("/saves/slot0.txt", 6, 1).await.unwrap() == b"3");
+
+			fs.write_file_async("/saves/slot1.bin", &[0xffu8, 0xfe][..]).await.unwrap();
+			assert!(fs.read_to_string_async("/saves/slot1.bin").await.unwrap_err().code() == ResultCode::InvalidData);
+
+			fs.delete_file_async("/saves/slot0.txt").await.unwrap();
+			fs.delete_file_async("/saves/slot1.bin").await.unwrap();
+			assert!(fs.read_file_async("/saves/slot0.txt").await.unwrap_err().code() == ResultCode::NotFound);
+			fs.delete_dir_async("/saves").await.unwrap();
+		});
+		assert!(!root.join("saves").exists());
+	}
+}
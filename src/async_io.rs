@@ -0,0 +1,272 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+use crate::io::DEFAULT_CHUNK_SIZE;
+use crate::task::Task;
+use crate::{LaminaFS, ReadBuffer, WorkFuture};
+
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+// The async counterpart of LfsReader. Segment reads are awaited through their completion
+// callbacks, with the next chunk kept in flight while the current one is consumed.
+pub struct AsyncLfsReader {
+	fs: Arc<LaminaFS>,
+	path: String,
+	chunk_size: u64,
+	position: u64,
+	chunk: Option<(u64, ReadBuffer)>,
+	loading: Option<(u64, WorkFuture)>,
+	read_ahead: Option<(u64, WorkFuture)>,
+	size: Option<u64>,
+	// a seek from the end waits for the file size
+	seek: Option<(i64, Task<u64>)>
+}
+
+impl AsyncLfsReader {
+	pub fn new(fs: Arc<LaminaFS>, path: &str) -> AsyncLfsReader {
+		AsyncLfsReader::with_chunk_size(fs, path, DEFAULT_CHUNK_SIZE)
+	}
+
+	pub fn with_chunk_size(fs: Arc<LaminaFS>, path: &str, chunk_size: u64) -> AsyncLfsReader {
+		AsyncLfsReader {
+			fs: fs,
+			path: path.to_string(),
+			chunk_size: std::cmp::max(chunk_size, 1),
+			position: 0,
+			chunk: None,
+			loading: None,
+			read_ahead: None,
+			size: None,
+			seek: None
+		}
+	}
+
+	pub fn path(&self) -> &str {
+		&self.path
+	}
+
+	fn submit_chunk(&self, offset: u64) -> WorkFuture {
+		WorkFuture::new(self.fs.read_file_segment(&self.path, offset, self.chunk_size, false))
+	}
+
+	fn start_load(&mut self, offset: u64) {
+		let chunk = match self.read_ahead.take() {
+			Some((read_ahead_offset, chunk)) if read_ahead_offset == offset => chunk,
+			Some((_, chunk)) => {
+				chunk.cancel();
+				self.submit_chunk(offset)
+			},
+			None => self.submit_chunk(offset)
+		};
+		self.loading = Some((offset, chunk));
+	}
+
+	fn set_position(&mut self, base: u64, delta: i64) -> io::Result<u64> {
+		let position = if delta < 0 { base.checked_sub(delta.unsigned_abs()) } else { base.checked_add(delta as u64) };
+		match position {
+			Some(position) => {
+				self.position = position;
+				Ok(position)
+			},
+			None => Err(io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position"))
+		}
+	}
+}
+
+impl AsyncRead for AsyncLfsReader {
+	fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf) -> Poll<io::Result<()>> {
+		let reader = &mut *self;
+		loop {
+			let position = reader.position;
+			if buf.remaining() == 0 || reader.size.map_or(false, |size| position >= size) {
+				return Poll::Ready(Ok(()));
+			}
+
+			if let Some((offset, ref chunk)) = reader.chunk {
+				if position >= offset && position < offset + chunk.len() as u64 {
+					let start = (position - offset) as usize;
+					let count = std::cmp::min(chunk.len() - start, buf.remaining());
+					buf.put_slice(&chunk[start..start + count]);
+					reader.position += count as u64;
+					return Poll::Ready(Ok(()));
+				}
+			}
+
+			match reader.loading {
+				Some((offset, _)) if offset == position => {},
+				Some((_, ref chunk)) => {
+					chunk.cancel();
+					reader.loading = None;
+					reader.start_load(position);
+				},
+				None => reader.start_load(position)
+			}
+
+			let (offset, buffer) = match reader.loading {
+				Some((offset, ref mut chunk)) => match Pin::new(chunk).poll(cx) {
+					Poll::Ready(result) => (offset, result),
+					Poll::Pending => return Poll::Pending
+				},
+				None => unreachable!()
+			};
+			reader.loading = None;
+
+			let buffer = buffer?;
+			if buffer.len() as u64 == reader.chunk_size {
+				let next = offset + reader.chunk_size;
+				reader.read_ahead = Some((next, reader.submit_chunk(next)));
+			} else {
+				// a short chunk is the end of the file
+				reader.size = Some(offset + buffer.len() as u64);
+			}
+			reader.chunk = Some((offset, buffer));
+		}
+	}
+}
+
+impl AsyncSeek for AsyncLfsReader {
+	fn start_seek(mut self: Pin<&mut Self>, pos: SeekFrom) -> io::Result<()> {
+		let reader = &mut *self;
+		match pos {
+			SeekFrom::Start(offset) => {
+				reader.position = offset;
+				Ok(())
+			},
+			SeekFrom::Current(delta) => reader.set_position(reader.position, delta).map(|_| ()),
+			SeekFrom::End(delta) => match reader.size {
+				Some(size) => reader.set_position(size, delta).map(|_| ()),
+				None => {
+					reader.seek = Some((delta, reader.fs.file_size(&reader.path)));
+					Ok(())
+				}
+			}
+		}
+	}
+
+	fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<u64>> {
+		let reader = &mut *self;
+		let size = match reader.seek {
+			Some((_, ref mut size)) => match Pin::new(size).poll(cx) {
+				Poll::Ready(size) => size,
+				Poll::Pending => return Poll::Pending
+			},
+			None => return Poll::Ready(Ok(reader.position))
+		};
+
+		let (delta, _) = reader.seek.take().unwrap();
+		let size = size?;
+		reader.size = Some(size);
+		Poll::Ready(reader.set_position(size, delta))
+	}
+}
+
+impl LaminaFS {
+	pub fn open_async_reader(self: &Arc<Self>, path: &str) -> AsyncLfsReader {
+		AsyncLfsReader::new(self.clone(), path)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::future::block_on;
+	use crate::test_dir::TestDir;
+
+	struct Read<'a>(&'a mut AsyncLfsReader, &'a mut [u8]);
+
+	impl<'a> Future for Read<'a> {
+		type Output = io::Result<usize>;
+
+		fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<usize>> {
+			let Read(ref mut reader, ref mut buffer) = *self;
+			let mut buf = ReadBuf::new(buffer);
+			match Pin::new(&mut **reader).poll_read(cx, &mut buf) {
+				Poll::Ready(Ok(())) => Poll::Ready(Ok(buf.filled().len())),
+				Poll::Ready(Err(error)) => Poll::Ready(Err(error)),
+				Poll::Pending => Poll::Pending
+			}
+		}
+	}
+
+	struct Seek<'a>(&'a mut AsyncLfsReader);
+
+	impl<'a> Future for Seek<'a> {
+		type Output = io::Result<u64>;
+
+		fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<u64>> {
+			Pin::new(&mut *self.0).poll_complete(cx)
+		}
+	}
+
+	fn seek(reader: &mut AsyncLfsReader, pos: SeekFrom) -> io::Result<u64> {
+		Pin::new(&mut *reader).start_seek(pos)?;
+		block_on(Seek(reader))
+	}
+
+	// Reads until `len` bytes or the end of the file.
+	fn read(reader: &mut AsyncLfsReader, len: usize) -> io::Result<Vec<u8>> {
+		let mut contents = Vec::new();
+		let mut buffer = [0u8; 4];
+		while contents.len() < len {
+			let wanted = std::cmp::min(buffer.len(), len - contents.len());
+			let count = block_on(Read(reader, &mut buffer[..wanted]))?;
+			if count == 0 {
+				break;
+			}
+			contents.extend_from_slice(&buffer[..count]);
+		}
+		Ok(contents)
+	}
+
+	#[test]
+	fn async_reader_test() {
+		let root = TestDir::new("async_reader_test");
+		std::fs::write(root.join("level.bin"), b"0123456789abcdef").unwrap();
+		let fs = LaminaFS::new();
+		let _mount = root.mount(&fs);
+
+		// chunks smaller than the reads, so reads cross chunk boundaries
+		let mut reader = AsyncLfsReader::with_chunk_size(fs.clone(), "/level.bin", 3);
+		assert!(read(&mut reader, 64).unwrap() == b"0123456789abcdef");
+
+		assert!(seek(&mut reader, SeekFrom::Start(5)).unwrap() == 5);
+		assert!(read(&mut reader, 4).unwrap() == b"5678");
+		assert!(seek(&mut reader, SeekFrom::Current(-6)).unwrap() == 3);
+		assert!(read(&mut reader, 4).unwrap() == b"3456");
+		assert!(seek(&mut reader, SeekFrom::Current(-20)).unwrap_err().kind() == io::ErrorKind::InvalidInput);
+
+		// a seek from the end before the size is known waits for it
+		let mut reader = fs.open_async_reader("/level.bin");
+		assert!(seek(&mut reader, SeekFrom::End(-2)).unwrap() == 14);
+		assert!(read(&mut reader, 64).unwrap() == b"ef");
+
+		let mut missing = fs.open_async_reader("/missing.bin");
+		assert!(read(&mut missing, 4).unwrap_err().kind() == io::ErrorKind::NotFound);
+	}
+}
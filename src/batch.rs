@@ -0,0 +1,128 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+use crate::{LaminaFS, ResultCode, WorkHandle};
+
+use std::sync::Arc;
+
+// A single operation in a batch submission, mirroring the individual LaminaFS calls.
+pub enum Operation<'a> {
+	ReadFile { path: &'a str, null_terminate: bool },
+	ReadFileSegment { path: &'a str, offset: u64, max_bytes: u64, null_terminate: bool },
+	WriteFile { path: &'a str, buffer: Arc<[u8]> },
+	WriteFileSegment { path: &'a str, offset: u64, buffer: Arc<[u8]> },
+	AppendFile { path: &'a str, buffer: Arc<[u8]> },
+	DeleteFile { path: &'a str },
+	CreateDir { path: &'a str },
+	DeleteDir { path: &'a str },
+	FileExists { path: &'a str }
+}
+
+// The work items of a batch submission, in the same order as the operations.
+pub struct Batch {
+	work_items: Vec<WorkHandle>
+}
+
+impl Batch {
+	pub fn len(&self) -> usize {
+		self.work_items.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.work_items.is_empty()
+	}
+
+	pub fn get(&self, index: usize) -> Option<&WorkHandle> {
+		self.work_items.get(index)
+	}
+
+	pub fn work_items(&self) -> &[WorkHandle] {
+		&self.work_items
+	}
+
+	// For taking the read buffers out of the work items.
+	pub fn into_work_items(self) -> Vec<WorkHandle> {
+		self.work_items
+	}
+
+	pub fn wait_all(&self) {
+		for work_item in &self.work_items {
+			work_item.wait();
+		}
+	}
+
+	// Waits for the whole batch and returns the result of every operation.
+	pub fn results(&self) -> Vec<ResultCode> {
+		self.work_items.iter().map(|work_item| work_item.get_result()).collect()
+	}
+}
+
+impl LaminaFS {
+	pub fn submit_batch(&self, operations: &[Operation]) -> Batch {
+		let work_items = operations.iter().map(|operation| match operation {
+			Operation::ReadFile { path, null_terminate } => self.read_file(path, *null_terminate),
+			Operation::ReadFileSegment { path, offset, max_bytes, null_terminate } => self.read_file_segment(path, *offset, *max_bytes, *null_terminate),
+			Operation::WriteFile { path, buffer } => self.write_file(path, buffer.clone()),
+			Operation::WriteFileSegment { path, offset, buffer } => self.write_file_segment(path, *offset, buffer.clone()),
+			Operation::AppendFile { path, buffer } => self.append_file(path, buffer.clone()),
+			Operation::DeleteFile { path } => self.delete_file(path),
+			Operation::CreateDir { path } => self.create_dir(path),
+			Operation::DeleteDir { path } => self.delete_dir(path),
+			Operation::FileExists { path } => self.file_exists(path)
+		}).collect();
+
+		Batch {
+			work_items: work_items
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_dir::TestDir;
+
+	#[test]
+	fn submit_batch_test() {
+		let root = TestDir::new("submit_batch_test");
+		std::fs::write(root.join("level.bin"), b"level data").unwrap();
+		let fs = LaminaFS::new();
+		let _mount = root.mount(&fs);
+
+		let batch = fs.submit_batch(&[
+			Operation::ReadFile { path: "/level.bin", null_terminate: false },
+			Operation::ReadFileSegment { path: "/level.bin", offset: 6, max_bytes: 4, null_terminate: false },
+			Operation::WriteFile { path: "/save.bin", buffer: Arc::from(&b"save"[..]) },
+			Operation::CreateDir { path: "/screenshots" },
+			Operation::DeleteFile { path: "/missing.bin" }
+		]);
+		assert!(batch.len() == 5);
+		assert!(batch.results() == vec![ResultCode::Ok, ResultCode::Ok, ResultCode::Ok, ResultCode::Ok, ResultCode::NotFound]);
+
+		let work_items = batch.into_work_items();
+		assert!(work_items[0].get_buffer() == b"level data");
+		assert!(work_items[1].get_buffer() == b"data");
+		assert!(std::fs::read(root.join("save.bin")).unwrap() == b"save");
+		assert!(root.join("screenshots").is_dir());
+	}
+}
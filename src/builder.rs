@@ -0,0 +1,122 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+use crate::alloc::ContextAllocator;
+use crate::laminafs_sys;
+use crate::task::{self, Spawner};
+use crate::{Allocator, ExecutionMode, LaminaFS};
+
+use std::sync::Arc;
+
+pub(crate) const DEFAULT_THREAD_NAME: &str = "laminafs-task";
+
+// Fluent construction of a LaminaFS, e.g.
+// LaminaFS::builder().capacity(256, 512).task_threads(4).thread_name("io").build()
+// Everything left unset is as for LaminaFS::new.
+pub struct LaminaFSBuilder {
+	pub(crate) allocator: ContextAllocator,
+	pub(crate) capacity: Option<(u64, u64)>,
+	// an existing context to adopt, and whether dropping the LaminaFS destroys it
+	pub(crate) raw_context: Option<(laminafs_sys::lfs_context_t, bool)>,
+	pub(crate) mode: ExecutionMode,
+	pub(crate) task_threads: usize,
+	pub(crate) thread_name: String,
+	pub(crate) spawner: Option<Spawner>
+}
+
+impl LaminaFSBuilder {
+	pub fn allocator<A: Allocator>(mut self, allocator: A) -> LaminaFSBuilder {
+		self.allocator = ContextAllocator::new(allocator);
+		self
+	}
+
+	// Sizes the context's work item queue and pool, see LaminaFS::new_with_capacity.
+	pub fn capacity(mut self, work_item_queue_size: u64, work_item_pool_size: u64) -> LaminaFSBuilder {
+		self.capacity = Some((work_item_queue_size, work_item_pool_size));
+		self
+	}
+
+	/// Adopts a context created by C or C++ code instead of creating one. Takes precedence
+	/// over capacity.
+	///
+	/// # Safety
+	///
+	/// As for LaminaFS::from_raw_context: `context` must be a live context that outlives
+	/// the LaminaFS and every handle taken from it, nothing else may destroy it if `owned`
+	/// is set, and mounts and devices must not be changed from outside while the LaminaFS
+	/// changes them.
+	pub unsafe fn raw_context(mut self, context: laminafs_sys::lfs_context_t, owned: bool) -> LaminaFSBuilder {
+		self.raw_context = Some((context, owned));
+		self
+	}
+
+	pub fn execution_mode(mut self, mode: ExecutionMode) -> LaminaFSBuilder {
+		self.mode = mode;
+		self
+	}
+
+	// The threads running the IO LaminaFS does itself rather than through the context:
+	// copies, moves, truncation, hashing, directory walks and the other operations
+	// returning a Task. Reads and writes are done by the context's own IO thread, which
+	// can't be configured; at most 8 of them are handed to it at a time, fewer if the
+	// queue size passed to capacity is smaller. Ignored in ExecutionMode::SingleThread.
+	pub fn task_threads(mut self, count: usize) -> LaminaFSBuilder {
+		self.task_threads = count;
+		self
+	}
+
+	// Task threads are named `name` followed by their index, e.g. "io-0".
+	pub fn thread_name(mut self, name: &str) -> LaminaFSBuilder {
+		self.thread_name = name.to_string();
+		self
+	}
+
+	// Hands each worker loop to `spawner` along with its thread name instead of starting
+	// a thread for it, so an engine's job system or fiber scheduler can host the workers.
+	// The loop blocks waiting for work and returns once the LaminaFS is dropped, so it
+	// needs a thread or fiber to itself. The context's own IO thread is started by the C
+	// library and can't be hosted this way.
+	pub fn spawner<F>(mut self, spawner: F) -> LaminaFSBuilder
+		where F: Fn(&str, Box<dyn FnOnce() + Send>) + Send + Sync + 'static {
+		self.spawner = Some(Arc::new(spawner));
+		self
+	}
+
+	pub fn build(self) -> Arc<LaminaFS> {
+		LaminaFS::from_builder(self)
+	}
+}
+
+impl LaminaFS {
+	pub fn builder() -> LaminaFSBuilder {
+		LaminaFSBuilder {
+			allocator: ContextAllocator::default(),
+			capacity: None,
+			raw_context: None,
+			mode: ExecutionMode::Threaded,
+			task_threads: task::DEFAULT_TASK_THREADS,
+			thread_name: DEFAULT_THREAD_NAME.to_string(),
+			spawner: None
+		}
+	}
+}
@@ -0,0 +1,343 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// An opt-in, size-bounded LRU cache of read results, so repeated reads of small files
+// such as configs, shaders and localization tables are served from memory. Entries are
+// keyed by the resolved path and the range read. Writes through LaminaFS and changes seen
+// by watchers invalidate a path; any change to the mounts clears the cache, since a path
+// may then resolve to a different mount.
+
+use crate::local::{DeviceOp, LocalResult};
+use crate::queue::CompletionCallback;
+use crate::{LaminaFS, Priority, ResultCode, WorkItemResult};
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+// (path, offset, max_bytes), whole-file reads being (path, 0, u64::max_value())
+pub(crate) type CacheKey = (String, u64, u64);
+
+struct CacheEntry {
+	data: Arc<[u8]>,
+	last_used: u64
+}
+
+struct CacheState {
+	entries: HashMap<CacheKey, CacheEntry>,
+	// last use -> key, oldest first
+	recency: BTreeMap<u64, CacheKey>,
+	capacity: usize,
+	size: usize,
+	tick: u64
+}
+
+pub(crate) struct ReadCache {
+	state: Mutex<CacheState>,
+	// bumped on every invalidation, so reads that raced a change don't get cached
+	generation: AtomicU64
+}
+
+impl ReadCache {
+	pub(crate) fn new() -> ReadCache {
+		ReadCache {
+			state: Mutex::new(CacheState {
+				entries: HashMap::new(),
+				recency: BTreeMap::new(),
+				capacity: 0,
+				size: 0,
+				tick: 0
+			}),
+			generation: AtomicU64::new(0)
+		}
+	}
+
+	pub(crate) fn is_enabled(&self) -> bool {
+		self.state.lock().unwrap().capacity > 0
+	}
+
+	pub(crate) fn capacity(&self) -> usize {
+		self.state.lock().unwrap().capacity
+	}
+
+	pub(crate) fn size(&self) -> usize {
+		self.state.lock().unwrap().size
+	}
+
+	// A capacity of 0 disables the cache.
+	pub(crate) fn set_capacity(&self, capacity: usize) {
+		let mut state = self.state.lock().unwrap();
+		state.capacity = capacity;
+		state.evict(0);
+	}
+
+	pub(crate) fn generation(&self) -> u64 {
+		self.generation.load(Ordering::Acquire)
+	}
+
+	pub(crate) fn get(&self, key: &CacheKey) -> Option<Arc<[u8]>> {
+		let mut state = self.state.lock().unwrap();
+		state.tick += 1;
+		let tick = state.tick;
+
+		let (data, last_used) = {
+			let entry = state.entries.get_mut(key)?;
+			let last_used = entry.last_used;
+			entry.last_used = tick;
+			(entry.data.clone(), last_used)
+		};
+		state.recency.remove(&last_used);
+		state.recency.insert(tick, key.clone());
+		Some(data)
+	}
+
+	// Caches data read at `generation`, unless something was invalidated since.
+	pub(crate) fn insert(&self, key: CacheKey, data: &[u8], generation: u64) {
+		let mut state = self.state.lock().unwrap();
+		if data.len() > state.capacity || self.generation() != generation {
+			return;
+		}
+
+		state.remove(&key);
+		state.evict(data.len());
+		state.tick += 1;
+		let tick = state.tick;
+		state.recency.insert(tick, key.clone());
+		state.size += data.len();
+		state.entries.insert(key, CacheEntry {
+			data: Arc::from(data),
+			last_used: tick
+		});
+	}
+
+	// Drops every range cached for `path`.
+	pub(crate) fn invalidate(&self, path: &str) {
+		let mut state = self.state.lock().unwrap();
+		self.generation.fetch_add(1, Ordering::AcqRel);
+
+		let keys: Vec<_> = state.entries.keys().filter(|key| key.0 == path).cloned().collect();
+		for key in keys {
+			state.remove(&key);
+		}
+	}
+
+	pub(crate) fn clear(&self) {
+		let mut state = self.state.lock().unwrap();
+		self.generation.fetch_add(1, Ordering::AcqRel);
+
+		state.entries.clear();
+		state.recency.clear();
+		state.size = 0;
+	}
+}
+
+// What submit does with a work item once the cache has seen it.
+pub(crate) enum Intercepted {
+	// a read served from the cache, with the callback to complete it with
+	Hit(LocalResult, Option<CompletionCallback>),
+	// anything else, with the callback to submit it with
+	Submit(Option<CompletionCallback>)
+}
+
+impl ReadCache {
+	// Serves reads of `target` from the cache where it can, and otherwise hooks the
+	// callback to fill the cache from reads and invalidate it after writes.
+	pub(crate) fn intercept(self: &Arc<Self>, target: &str, op: &DeviceOp, callback: Option<CompletionCallback>) -> Intercepted {
+		if !self.is_enabled() {
+			return Intercepted::Submit(callback);
+		}
+
+		let (key, null_terminate, allocator) = match *op {
+			DeviceOp::ReadFile { null_terminate, ref allocator } => ((target.to_string(), 0, u64::max_value()), null_terminate, allocator),
+			DeviceOp::ReadFileSegment { offset, max_bytes, null_terminate, ref allocator } => ((target.to_string(), offset, max_bytes), null_terminate, allocator),
+			DeviceOp::WriteFile { .. } | DeviceOp::DeleteFile => {
+				// again on completion, in case a read cached the file while the write ran
+				self.invalidate(target);
+				let cache = self.clone();
+				let target = target.to_string();
+				return Intercepted::Submit(Some(Box::new(move |result: &WorkItemResult| {
+					cache.invalidate(&target);
+					if let Some(callback) = callback {
+						callback(result);
+					}
+				})));
+			},
+			_ => return Intercepted::Submit(callback)
+		};
+
+		if let Some(data) = self.get(&key) {
+			if let Ok(result) = LocalResult::read(&data, null_terminate, allocator) {
+				return Intercepted::Hit(result, callback);
+			}
+		}
+
+		let cache = self.clone();
+		let generation = self.generation();
+		Intercepted::Submit(Some(Box::new(move |result: &WorkItemResult| {
+			if result.get_result() == ResultCode::Ok {
+				cache.insert(key, result.get_buffer(), generation);
+			}
+			if let Some(callback) = callback {
+				callback(result);
+			}
+		})))
+	}
+}
+
+impl LaminaFS {
+	// Caches up to `capacity` bytes of read results, evicting the least recently used
+	// first. 0, the default, turns the cache off and empties it.
+	pub fn set_read_cache_capacity(&self, capacity: usize) {
+		self.cache.set_capacity(capacity);
+	}
+
+	pub fn read_cache_capacity(&self) -> usize {
+		self.cache.capacity()
+	}
+
+	// The bytes currently cached.
+	pub fn read_cache_size(&self) -> usize {
+		self.cache.size()
+	}
+
+	// Drops what is cached for `path`, for files changed behind LaminaFS's back.
+	pub fn invalidate_cached(&self, path: &str) {
+		// invalid paths never had anything cached
+		if let Ok(target) = self.virtual_path(path, false) {
+			self.cache.invalidate(&target);
+		}
+	}
+
+	pub fn clear_read_cache(&self) {
+		self.cache.clear();
+	}
+
+	// Reads each path at background priority purely to fill the cache, e.g. to warm the
+	// assets of the next level while the current one plays. Does nothing while the cache
+	// is disabled, and files larger than its capacity are read but not kept.
+	pub fn prefetch<S: AsRef<str>>(&self, paths: &[S]) {
+		if !self.cache.is_enabled() {
+			return;
+		}
+
+		let mut prefetches = self.prefetches.lock().unwrap();
+		prefetches.retain(|work_item| !work_item.is_finished());
+		for path in paths {
+			prefetches.push(self.read_file_with_priority(path.as_ref(), false, Priority::Background));
+		}
+	}
+
+	// Prefetches that haven't finished yet.
+	pub fn pending_prefetches(&self) -> usize {
+		let mut prefetches = self.prefetches.lock().unwrap();
+		prefetches.retain(|work_item| !work_item.is_finished());
+		prefetches.len()
+	}
+}
+
+impl CacheState {
+	fn remove(&mut self, key: &CacheKey) {
+		if let Some(entry) = self.entries.remove(key) {
+			self.recency.remove(&entry.last_used);
+			self.size -= entry.data.len();
+		}
+	}
+
+	// Evicts least recently used entries until `incoming` more bytes fit.
+	fn evict(&mut self, incoming: usize) {
+		while self.size + incoming > self.capacity {
+			let oldest = match self.recency.keys().next() {
+				Some(&oldest) => oldest,
+				None => return
+			};
+			let key = self.recency.remove(&oldest).unwrap();
+			if let Some(entry) = self.entries.remove(&key) {
+				self.size -= entry.data.len();
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn key(path: &str) -> CacheKey {
+		(path.to_string(), 0, u64::max_value())
+	}
+
+	#[test]
+	fn read_cache_test() {
+		let cache = ReadCache::new();
+		cache.insert(key("/config.ini"), b"abc", cache.generation());
+		assert!(cache.get(&key("/config.ini")).is_none());
+
+		cache.set_capacity(8);
+		cache.insert(key("/a"), b"aaa", cache.generation());
+		cache.insert(key("/b"), b"bbb", cache.generation());
+		assert!(cache.get(&key("/a")).is_some());
+
+		// /b is now the least recently used
+		cache.insert(key("/c"), b"ccc", cache.generation());
+		assert!(cache.get(&key("/b")).is_none());
+		assert!(&*cache.get(&key("/a")).unwrap() == b"aaa");
+		assert!(cache.size() == 6);
+
+		let generation = cache.generation();
+		cache.invalidate("/a");
+		assert!(cache.get(&key("/a")).is_none());
+		cache.insert(key("/a"), b"old", generation);
+		assert!(cache.get(&key("/a")).is_none());
+
+		cache.insert(("/c".to_string(), 4, 16), b"c", cache.generation());
+		cache.invalidate("/c");
+		assert!(cache.size() == 0);
+
+		cache.insert(key("/big"), b"012345678", cache.generation());
+		assert!(cache.get(&key("/big")).is_none());
+	}
+
+	#[test]
+	fn prefetch_test() {
+		let root = crate::test_dir::TestDir::new("prefetch_test");
+		std::fs::write(root.join("level1.bin"), b"level 1").unwrap();
+		std::fs::write(root.join("level2.bin"), b"level 2").unwrap();
+		let fs = LaminaFS::new();
+		let _mount = root.mount(&fs);
+
+		// nothing to warm while the cache is off
+		fs.prefetch(&["/level1.bin"]);
+		assert!(fs.pending_prefetches() == 0);
+
+		fs.set_read_cache_capacity(64);
+		fs.prefetch(&["/level1.bin", "/level2.bin", "/missing.bin"]);
+		while fs.pending_prefetches() != 0 {
+			std::thread::sleep(std::time::Duration::from_millis(1));
+		}
+		assert!(fs.read_cache_size() == 14);
+
+		// served from the cache rather than the changed file
+		std::fs::write(root.join("level1.bin"), b"changed").unwrap();
+		assert!(fs.read_file_sync("/level1.bin").unwrap() == b"level 1");
+	}
+}
@@ -0,0 +1,165 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// Case-insensitive path resolution for mounts whose content was authored on a
+// case-insensitive filesystem. Paths are rewritten to the case found on the device before
+// they reach the context, so the devices themselves stay case-sensitive.
+
+use crate::device::Device;
+use crate::mount::{MountEntry, MountTable};
+use crate::ResultCode;
+
+use std::collections::HashMap;
+
+// Lowercased mount-relative paths and the case they have on the device.
+pub(crate) struct CaseIndex {
+	paths: HashMap<String, String>
+}
+
+impl CaseIndex {
+	// Scans everything under the device's root.
+	pub(crate) fn build(device: &dyn Device) -> Result<CaseIndex, ResultCode> {
+		let mut index = CaseIndex {
+			paths: HashMap::new()
+		};
+
+		let mut dirs = vec![String::new()];
+		while let Some(dir) = dirs.pop() {
+			for entry in device.list_dir(&dir)? {
+				let path = if dir.is_empty() { entry.name } else { format!("{}/{}", dir, entry.name) };
+				if entry.is_dir {
+					dirs.push(path.clone());
+				}
+				index.insert(&path);
+			}
+		}
+		Ok(index)
+	}
+
+	pub(crate) fn insert(&mut self, path: &str) {
+		self.paths.entry(path.to_lowercase()).or_insert_with(|| path.to_string());
+	}
+
+	pub(crate) fn contains(&self, path: &str) -> bool {
+		self.paths.contains_key(&path.to_lowercase())
+	}
+
+	// Rewrites each component the index knows to its case on the device, leaving the
+	// rest as given so new files keep the case they are created with.
+	pub(crate) fn fold(&self, path: &str) -> String {
+		let mut folded = String::with_capacity(path.len());
+		for component in path.split('/').filter(|component| !component.is_empty()) {
+			let prefix_len = folded.len();
+			if !folded.is_empty() {
+				folded.push('/');
+			}
+			folded.push_str(component);
+
+			if let Some(actual) = self.paths.get(&folded.to_lowercase()) {
+				folded.truncate(prefix_len);
+				if !folded.is_empty() {
+					folded.push('/');
+				}
+				folded.push_str(&actual[actual.rfind('/').map(|index| index + 1).unwrap_or(0)..]);
+			}
+		}
+		folded
+	}
+}
+
+impl MountTable {
+	// The path with its case folded for the first case-insensitive mount covering it that
+	// knows the path, or failing that the first covering one. Paths no case-insensitive
+	// mount covers come back unchanged. `created` records the path in that mount's index,
+	// for work that creates it.
+	pub(crate) fn fold_case(&self, path: &str, created: bool) -> String {
+		let mut fallback: Option<(std::sync::Arc<MountEntry>, String)> = None;
+		for (mount, relative_path) in self.resolve(path) {
+			let known = match *mount.case_index.read().unwrap() {
+				Some(ref index) => index.contains(&relative_path),
+				None => continue
+			};
+
+			if known {
+				return mount.fold_case(&relative_path, created);
+			}
+			if fallback.is_none() {
+				fallback = Some((mount, relative_path));
+			}
+		}
+
+		match fallback {
+			Some((mount, relative_path)) => mount.fold_case(&relative_path, created),
+			None => path.to_string()
+		}
+	}
+}
+
+impl MountEntry {
+	fn fold_case(&self, relative_path: &str, created: bool) -> String {
+		let mut case_index = self.case_index.write().unwrap();
+		let index = case_index.as_mut().unwrap();
+		let folded = index.fold(relative_path);
+		if created && !folded.is_empty() {
+			index.insert(&folded);
+		}
+
+		let mount_point = self.mount_point.trim_matches('/');
+		if mount_point.is_empty() {
+			format!("/{}", folded)
+		} else if folded.is_empty() {
+			format!("/{}", mount_point)
+		} else {
+			format!("/{}/{}", mount_point, folded)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::device::{CreatedDevice, MemoryDevice, WriteMode};
+	use crate::{DeviceType, MountPermissions};
+
+	use std::sync::Arc;
+
+	#[test]
+	fn fold_case_test() {
+		let device = MemoryDevice::create("").unwrap();
+		device.create_dir("/Textures").unwrap();
+		device.write_file("/Textures/Crate_Diffuse.PNG", 0, b"x", WriteMode::Overwrite).unwrap();
+
+		let table = MountTable::new();
+		let mount = Arc::new(MountEntry::new("/data", DeviceType::Directory, "", MountPermissions::All, None, None));
+		mount.set_case_index(Some(CaseIndex::build(&device).unwrap()));
+		let device = CreatedDevice::new(Arc::new(device));
+		let other = Arc::new(MountEntry::new("/other", DeviceType::Directory, "", MountPermissions::All, Some(device), None));
+		table.add(&mount);
+		table.add(&other);
+
+		assert!(table.fold_case("/data/textures/crate_diffuse.png", false) == "/data/Textures/Crate_Diffuse.PNG");
+		assert!(table.fold_case("/data/TEXTURES/new.png", true) == "/data/Textures/new.png");
+		assert!(table.fold_case("/data/textures/NEW.PNG", false) == "/data/Textures/new.png");
+		assert!(table.fold_case("/other/textures/a.png", false) == "/other/textures/a.png");
+	}
+}
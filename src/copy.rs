@@ -0,0 +1,172 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// Copies and moves run as tasks, streaming the file between the devices a chunk at a
+// time so only one chunk is ever held in memory. Files backed by real files on both ends
+// are copied by the OS instead, and moved within a mount with an atomic rename.
+
+use crate::device::{self, Device, WriteMode};
+use crate::mount::MountTable;
+use crate::{LaminaFS, MountPermissions, OperationKind, ResultCode, Task, DEFAULT_TRANSFER_CHUNK_SIZE};
+
+use std::sync::Arc;
+
+// Copies `source` to `dest`, returning the bytes copied.
+pub(crate) fn copy(mounts: &MountTable, source: &str, dest: &str, chunk_size: u64) -> Result<u64, ResultCode> {
+	let (source_device, source_path, size) = mounts.readable_file(source)?;
+	let (dest_device, dest_path) = mounts.writable_device(dest, MountPermissions::WriteFile)?;
+
+	if let (Some(from), Some(to)) = (source_device.raw_path(&source_path), dest_device.raw_path(&dest_path)) {
+		return std::fs::copy(from, to).map_err(device::from_io_error);
+	}
+
+	stream(source_device.as_ref(), &source_path, size, dest_device.as_ref(), &dest_path, chunk_size)
+}
+
+// Moves `source` to `dest`, returning the bytes moved. Within one mount backed by real
+// files this is a rename, which replaces `dest` atomically; otherwise the file is copied
+// and the source deleted once the copy succeeded.
+pub(crate) fn move_file(mounts: &MountTable, source: &str, dest: &str, chunk_size: u64) -> Result<u64, ResultCode> {
+	let (source_device, source_path, size) = mounts.readable_file(source)?;
+	let (delete_device, delete_path) = mounts.writable_device(source, MountPermissions::DeleteFile)?;
+	let (dest_device, dest_path) = mounts.writable_device(dest, MountPermissions::WriteFile)?;
+
+	if Arc::ptr_eq(&source_device, &dest_device) && Arc::ptr_eq(&source_device, &delete_device) {
+		if let (Some(from), Some(to)) = (source_device.raw_path(&source_path), dest_device.raw_path(&dest_path)) {
+			return std::fs::rename(from, to).map(|_| size).map_err(device::from_io_error);
+		}
+	}
+
+	let moved = copy(mounts, source, dest, chunk_size)?;
+	delete_device.delete_file(&delete_path)?;
+	Ok(moved)
+}
+
+fn stream(source: &dyn Device, source_path: &str, size: u64, dest: &dyn Device, dest_path: &str, chunk_size: u64) -> Result<u64, ResultCode> {
+	let chunk_size = std::cmp::max(chunk_size, 1);
+	let mut offset = 0;
+	loop {
+		let chunk = source.read_file(source_path, offset, chunk_size)?;
+		// the first chunk replaces whatever was at the destination, even if it's empty
+		let mode = if offset == 0 { WriteMode::Overwrite } else { WriteMode::Append };
+		if offset == 0 || !chunk.is_empty() {
+			dest.write_file(dest_path, 0, &chunk, mode)?;
+		}
+
+		offset += chunk.len() as u64;
+		if chunk.is_empty() || offset >= size {
+			return Ok(offset);
+		}
+	}
+}
+
+impl LaminaFS {
+	// Copies a file between any two mounts the Rust side can reach, without the data
+	// passing through the caller. The result is the number of bytes copied.
+	pub fn copy_file(&self, source: &str, dest: &str) -> Task<u64> {
+		let mounts = self.mounts.clone();
+		let cache = self.cache.clone();
+		let source_path = self.virtual_path(source, false);
+		let dest_path = self.virtual_path(dest, true);
+		self.tasks.spawn(OperationKind::CopyFile, source, move || {
+			let source_path = source_path.map_err(|error| error.code())?;
+			let dest_path = dest_path.map_err(|error| error.code())?;
+			let (_, _, size) = mounts.readable_file(&source_path)?;
+			let result = mounts.charged(&dest_path, MountPermissions::WriteFile, |_| size, || copy(&mounts, &source_path, &dest_path, DEFAULT_TRANSFER_CHUNK_SIZE));
+			cache.invalidate(&dest_path);
+			result
+		})
+	}
+}
+
+impl LaminaFS {
+	// Moves a file, replacing `dest` atomically when both are on the same mount of a
+	// Directory device. Moves across mounts copy the file and delete the source, so a
+	// failure part way can leave both in place. The result is the number of bytes moved.
+	pub fn move_file(&self, source: &str, dest: &str) -> Task<u64> {
+		let mounts = self.mounts.clone();
+		let cache = self.cache.clone();
+		let source_path = self.virtual_path(source, false);
+		let dest_path = self.virtual_path(dest, true);
+		self.tasks.spawn(OperationKind::MoveFile, source, move || {
+			let source_path = source_path.map_err(|error| error.code())?;
+			let dest_path = dest_path.map_err(|error| error.code())?;
+			let (_, _, size) = mounts.readable_file(&source_path)?;
+			let result = mounts.charged(&source_path, MountPermissions::DeleteFile, |_| 0, || {
+				mounts.charged(&dest_path, MountPermissions::WriteFile, |_| size, || move_file(&mounts, &source_path, &dest_path, DEFAULT_TRANSFER_CHUNK_SIZE))
+			});
+			cache.invalidate(&source_path);
+			cache.invalidate(&dest_path);
+			result
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::device::{CreatedDevice, MemoryDevice};
+	use crate::mount::MountEntry;
+	use crate::DeviceType;
+
+	#[test]
+	fn copy_test() {
+		let source = MemoryDevice::create("").unwrap();
+		source.write_file("/level.bin", 0, b"0123456789", WriteMode::Overwrite).unwrap();
+		source.write_file("/empty.bin", 0, b"", WriteMode::Overwrite).unwrap();
+
+		let table = MountTable::new();
+		let data = Arc::new(MountEntry::new("/data", DeviceType::Directory, "", MountPermissions::Read, Some(CreatedDevice::new(Arc::new(source))), None));
+		let saves = Arc::new(MountEntry::new("/saves", DeviceType::Directory, "", MountPermissions::All, Some(CreatedDevice::new(Arc::new(MemoryDevice::create("").unwrap()))), None));
+		table.add(&data);
+		table.add(&saves);
+
+		assert!(copy(&table, "/data/level.bin", "/saves/level.bin", 3) == Ok(10));
+		assert!(table.query("/saves/level.bin", |device, path| device.read_file(path, 0, 100)).unwrap() == b"0123456789");
+		assert!(copy(&table, "/data/empty.bin", "/saves/level.bin", 3) == Ok(0));
+		assert!(table.query("/saves/level.bin", |device, path| device.file_size(path)) == Ok(0));
+
+		assert!(copy(&table, "/data/missing.bin", "/saves/x.bin", 3) == Err(ResultCode::NotFound));
+		assert!(copy(&table, "/saves/level.bin", "/data/x.bin", 3) == Err(ResultCode::PermissionsError));
+	}
+
+	#[test]
+	fn move_test() {
+		let table = MountTable::new();
+		let saves = Arc::new(MountEntry::new("/saves", DeviceType::Directory, "", MountPermissions::All, Some(CreatedDevice::new(Arc::new(MemoryDevice::create("").unwrap()))), None));
+		let backup = Arc::new(MountEntry::new("/backup", DeviceType::Directory, "", MountPermissions::All, Some(CreatedDevice::new(Arc::new(MemoryDevice::create("").unwrap()))), None));
+		table.add(&saves);
+		table.add(&backup);
+
+		let read = |path: &str| table.query(path, |device, path| device.read_file(path, 0, 100));
+		saves.device.as_ref().unwrap().device.write_file("slot0.tmp", 0, b"progress", WriteMode::Overwrite).unwrap();
+
+		assert!(move_file(&table, "/saves/slot0.tmp", "/saves/slot0", 3) == Ok(8));
+		assert!(read("/saves/slot0").unwrap() == b"progress");
+		assert!(read("/saves/slot0.tmp") == Err(ResultCode::NotFound));
+
+		assert!(move_file(&table, "/saves/slot0", "/backup/slot0", 3) == Ok(8));
+		assert!(read("/backup/slot0").unwrap() == b"progress");
+		assert!(read("/saves/slot0") == Err(ResultCode::NotFound));
+	}
+}
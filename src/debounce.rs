@@ -0,0 +1,245 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+// Debounced change notification: the changes of a watch are gathered until the files
+// have been quiet for a while and then delivered together, so re-exporting a texture and
+// its fifty mips is one reload rather than fifty.
+
+use crate::watch::{ChangeEvent, ChangeKind, Watcher};
+use crate::{ExecutionMode, LaminaFS};
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The changes of a burst, at most one per path and sorted by path.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ReloadSet {
+	pub changes: Vec<ChangeEvent>
+}
+
+impl ReloadSet {
+	pub fn paths(&self) -> impl Iterator<Item = &str> {
+		self.changes.iter().map(|change| change.path.as_str())
+	}
+}
+
+// Receives the reload sets of a debounced watch. Dropping it stops watching.
+pub struct DebouncedWatcher {
+	sets: Receiver<ReloadSet>,
+	stop: Arc<AtomicBool>,
+	// the watch and its state for watches polled by the caller
+	manual: Option<Mutex<ManualDebounce>>
+}
+
+struct ManualDebounce {
+	watcher: Watcher,
+	debouncer: Debouncer,
+	sender: Sender<ReloadSet>
+}
+
+impl DebouncedWatcher {
+	pub fn sets(&self) -> &Receiver<ReloadSet> {
+		&self.sets
+	}
+
+	pub fn try_recv(&self) -> Option<ReloadSet> {
+		self.sets.try_recv().ok()
+	}
+
+	// Only watches in ExecutionMode::SingleThread need polling, see Watcher::poll. A set is
+	// delivered by the first poll after the quiet period.
+	pub fn poll(&self) {
+		if let Some(ref manual) = self.manual {
+			let mut manual = manual.lock().unwrap();
+			let manual = &mut *manual;
+			manual.watcher.poll();
+			while let Some(event) = manual.watcher.try_recv() {
+				manual.debouncer.add(event, Instant::now());
+			}
+			if let Some(set) = manual.debouncer.take_ready(Instant::now()) {
+				let _ = manual.sender.send(set);
+			}
+		}
+	}
+}
+
+impl Drop for DebouncedWatcher {
+	fn drop(&mut self) {
+		self.stop.store(true, Ordering::Release);
+	}
+}
+
+struct Debouncer {
+	quiet_period: Duration,
+	changes: HashMap<String, ChangeKind>,
+	// when the last change came in, None if there's nothing waiting
+	last_change: Option<Instant>
+}
+
+impl Debouncer {
+	fn new(quiet_period: Duration) -> Debouncer {
+		Debouncer {
+			quiet_period: quiet_period,
+			changes: HashMap::new(),
+			last_change: None
+		}
+	}
+
+	// Folds the change into what's already waiting for the path: a file created and then
+	// modified was still created, one created and then deleted never needs reloading.
+	fn add(&mut self, event: ChangeEvent, now: Instant) {
+		self.last_change = Some(now);
+		let kind = match (self.changes.get(&event.path), event.kind) {
+			(Some(ChangeKind::Created), ChangeKind::Deleted) => {
+				self.changes.remove(&event.path);
+				return;
+			},
+			(Some(ChangeKind::Created), _) => ChangeKind::Created,
+			(Some(ChangeKind::Deleted), ChangeKind::Created) => ChangeKind::Modified,
+			(_, kind) => kind
+		};
+		self.changes.insert(event.path, kind);
+	}
+
+	// How much longer the current burst has to stay quiet, None without one.
+	fn remaining(&self, now: Instant) -> Option<Duration> {
+		self.last_change.map(|last_change| (last_change + self.quiet_period).saturating_duration_since(now))
+	}
+
+	// The burst's changes once it has been quiet for the quiet period.
+	fn take_ready(&mut self, now: Instant) -> Option<ReloadSet> {
+		if self.remaining(now) != Some(Duration::from_secs(0)) {
+			return None;
+		}
+
+		self.last_change = None;
+		if self.changes.is_empty() {
+			return None;
+		}
+		let mut changes: Vec<ChangeEvent> = self.changes.drain()
+			.map(|(path, kind)| ChangeEvent {
+				path: path,
+				kind: kind
+			})
+			.collect();
+		changes.sort_by(|a, b| a.path.cmp(&b.path));
+		Some(ReloadSet {
+			changes: changes
+		})
+	}
+}
+
+impl LaminaFS {
+	// Watches `pattern` like watch, delivering the changes once nothing has changed for
+	// `quiet_period`.
+	pub fn watch_debounced(&self, pattern: &str, quiet_period: Duration) -> DebouncedWatcher {
+		let watcher = self.watch(pattern);
+		let debouncer = Debouncer::new(quiet_period);
+		let (sender, sets) = channel();
+		let stop = Arc::new(AtomicBool::new(false));
+		if self.execution_mode() == ExecutionMode::SingleThread {
+			return DebouncedWatcher {
+				sets: sets,
+				stop: stop,
+				manual: Some(Mutex::new(ManualDebounce {
+					watcher: watcher,
+					debouncer: debouncer,
+					sender: sender
+				}))
+			};
+		}
+
+		let thread_stop = stop.clone();
+		thread::Builder::new()
+			.name("laminafs-debounce".to_string())
+			.spawn(move || {
+				let mut debouncer = debouncer;
+				loop {
+					// without a burst under way, wake up now and then to see if the watch was dropped
+					let timeout = debouncer.remaining(Instant::now()).unwrap_or(quiet_period);
+					match watcher.events().recv_timeout(timeout) {
+						Ok(event) => debouncer.add(event, Instant::now()),
+						Err(RecvTimeoutError::Timeout) => {},
+						Err(RecvTimeoutError::Disconnected) => return
+					}
+					if thread_stop.load(Ordering::Acquire) {
+						return;
+					}
+
+					if let Some(set) = debouncer.take_ready(Instant::now()) {
+						if sender.send(set).is_err() {
+							return;
+						}
+					}
+				}
+			})
+			.unwrap();
+
+		DebouncedWatcher {
+			sets: sets,
+			stop: stop,
+			manual: None
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn change(path: &str, kind: ChangeKind) -> ChangeEvent {
+		ChangeEvent {
+			path: path.to_string(),
+			kind: kind
+		}
+	}
+
+	#[test]
+	fn debouncer_test() {
+		let quiet_period = Duration::from_millis(100);
+		let mut debouncer = Debouncer::new(quiet_period);
+		let start = Instant::now();
+
+		debouncer.add(change("/textures/rock_mip1.dds", ChangeKind::Modified), start);
+		debouncer.add(change("/textures/rock_mip0.dds", ChangeKind::Modified), start);
+		debouncer.add(change("/textures/rock.tmp", ChangeKind::Created), start);
+		debouncer.add(change("/textures/rock.tmp", ChangeKind::Deleted), start + Duration::from_millis(50));
+		debouncer.add(change("/textures/rock_mip0.dds", ChangeKind::Modified), start + Duration::from_millis(60));
+		assert!(debouncer.take_ready(start + Duration::from_millis(100)).is_none());
+		assert!(debouncer.remaining(start + Duration::from_millis(100)) == Some(Duration::from_millis(60)));
+
+		let set = debouncer.take_ready(start + Duration::from_millis(160)).unwrap();
+		assert!(set.paths().collect::<Vec<_>>() == ["/textures/rock_mip0.dds", "/textures/rock_mip1.dds"]);
+		assert!(debouncer.take_ready(start + Duration::from_secs(1)).is_none());
+		assert!(debouncer.remaining(start + Duration::from_secs(1)).is_none());
+
+		debouncer.add(change("/shaders/lit.hlsl", ChangeKind::Deleted), start);
+		debouncer.add(change("/shaders/lit.hlsl", ChangeKind::Created), start);
+		assert!(debouncer.take_ready(start + quiet_period).unwrap().changes == [change("/shaders/lit.hlsl", ChangeKind::Modified)]);
+	}
+}
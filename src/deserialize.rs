@@ -0,0 +1,103 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// Reads that deserialize the file on a task thread, so config files and asset metadata
+// arrive ready to use. Each format is behind the feature of the same name. Files that
+// don't parse into the requested type fail with InvalidData.
+
+use crate::{LaminaFS, OperationKind, ResultCode, Task};
+
+use serde::de::DeserializeOwned;
+
+impl LaminaFS {
+	#[cfg(feature = "json")]
+	pub fn read_json<T: DeserializeOwned + Send + 'static>(&self, path: &str) -> Task<T> {
+		self.read_deserialized(path, |data| serde_json::from_slice(data).ok())
+	}
+
+	#[cfg(feature = "toml")]
+	pub fn read_toml<T: DeserializeOwned + Send + 'static>(&self, path: &str) -> Task<T> {
+		self.read_deserialized(path, |data| std::str::from_utf8(data).ok().and_then(|text| toml_rs::from_str(text).ok()))
+	}
+
+	#[cfg(feature = "ron")]
+	pub fn read_ron<T: DeserializeOwned + Send + 'static>(&self, path: &str) -> Task<T> {
+		self.read_deserialized(path, |data| ron_rs::de::from_bytes(data).ok())
+	}
+
+	fn read_deserialized<T, F>(&self, path: &str, deserialize: F) -> Task<T>
+		where T: Send + 'static, F: FnOnce(&[u8]) -> Option<T> + Send + 'static {
+		let mounts = self.mounts.clone();
+		let path_owned = self.virtual_path(path, false);
+		self.tasks.spawn(OperationKind::ReadFile, path, move || {
+			let path_owned = path_owned.map_err(|error| error.code())?;
+			let (device, relative_path, size) = mounts.readable_file(&path_owned)?;
+			let data = device.read_file(&relative_path, 0, size)?;
+			deserialize(&data).ok_or(ResultCode::InvalidData)
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_dir::TestDir;
+
+	use serde::Deserialize;
+
+	#[derive(Deserialize, PartialEq, Debug)]
+	struct Config {
+		name: String,
+		level: u32
+	}
+
+	fn read_config<F: Fn(&LaminaFS, &str) -> Task<Config>>(name: &str, contents: &[u8], read: F) {
+		let root = TestDir::new(name);
+		std::fs::write(root.join("config"), contents).unwrap();
+		std::fs::write(root.join("broken"), b"{ name = ").unwrap();
+		let fs = LaminaFS::new();
+		let _mount = root.mount(&fs);
+
+		assert!(read(&fs, "/config").get_result().unwrap() == Config { name: "caves".to_string(), level: 3 });
+		assert!(read(&fs, "/broken").get_result().unwrap_err().code() == ResultCode::InvalidData);
+		assert!(read(&fs, "/missing").get_result().unwrap_err().code() == ResultCode::NotFound);
+	}
+
+	#[cfg(feature = "json")]
+	#[test]
+	fn read_json_test() {
+		read_config("read_json_test", br#"{ "name": "caves", "level": 3 }"#, |fs, path| fs.read_json(path));
+	}
+
+	#[cfg(feature = "toml")]
+	#[test]
+	fn read_toml_test() {
+		read_config("read_toml_test", b"name = \"caves\"\nlevel = 3\n", |fs, path| fs.read_toml(path));
+	}
+
+	#[cfg(feature = "ron")]
+	#[test]
+	fn read_ron_test() {
+		read_config("read_ron_test", b"(name: \"caves\", level: 3)", |fs, path| fs.read_ron(path));
+	}
+}
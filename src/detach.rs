@@ -0,0 +1,171 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+// Dropped work handles normally wait for their work. Detached ones, and ones whose work
+// timed out while the context still has it, are parked here instead and released by a
+// reaper thread once the context is done with them, so their work items go back to the
+// pool even if nothing else is ever submitted. Whatever is left when the last handle and
+// the LaminaFS are gone is waited for then.
+
+use crate::alloc::ContextAllocator;
+use crate::queue::WorkState;
+use crate::Context;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Wake, Waker};
+use std::thread;
+
+// What dropping an unfinished WorkHandle does, see LaminaFS::set_drop_policy.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum DropPolicy {
+	// block until the work completes
+	#[default]
+	Wait,
+	// return straight away, leaving the work to complete in the background
+	Detach,
+	// cancel the work if it's still queued, detaching it if it's already in flight
+	Cancel
+}
+
+// What a dropped handle still holds on to until its work completes.
+pub(crate) struct DetachedWork {
+	pub(crate) work: Arc<WorkState>,
+	pub(crate) context: Arc<Context>,
+	// the context reads writes straight out of it
+	pub(crate) write_buffer: Option<Arc<[u8]>>,
+	pub(crate) owns_buffer: bool,
+	pub(crate) allocator: Arc<ContextAllocator>
+}
+
+impl Drop for DetachedWork {
+	fn drop(&mut self) {
+		crate::release_handle(&self.work, &self.context, self.write_buffer.take(), self.owns_buffer, &self.allocator);
+	}
+}
+
+// Shared with the reaper thread.
+struct DetachedState {
+	work: Mutex<Vec<DetachedWork>>,
+	// signalled when detached work completes, or to stop the reaper
+	changed: Condvar,
+	closed: AtomicBool
+}
+
+impl DetachedState {
+	fn notify(&self) {
+		// taken so the reaper can't miss a completion between checking and waiting
+		let _work = self.work.lock().unwrap();
+		self.changed.notify_all();
+	}
+}
+
+// Woken by WorkState once parked work is idle.
+struct ReaperWake(Arc<DetachedState>);
+
+impl Wake for ReaperWake {
+	fn wake(self: Arc<Self>) {
+		self.0.notify();
+	}
+}
+
+pub(crate) struct Detached {
+	policy: Mutex<DropPolicy>,
+	state: Arc<DetachedState>,
+	reaper_started: AtomicBool
+}
+
+impl Detached {
+	pub(crate) fn new() -> Detached {
+		Detached {
+			policy: Mutex::new(DropPolicy::Wait),
+			state: Arc::new(DetachedState {
+				work: Mutex::new(Vec::new()),
+				changed: Condvar::new(),
+				closed: AtomicBool::new(false)
+			}),
+			reaper_started: AtomicBool::new(false)
+		}
+	}
+
+	pub(crate) fn policy(&self) -> DropPolicy {
+		*self.policy.lock().unwrap()
+	}
+
+	pub(crate) fn set_policy(&self, policy: DropPolicy) {
+		*self.policy.lock().unwrap() = policy;
+	}
+
+	pub(crate) fn push(&self, work: DetachedWork) {
+		if !self.reaper_started.swap(true, Ordering::AcqRel) {
+			let state = self.state.clone();
+			let spawned = thread::Builder::new()
+				.name("laminafs-reaper".to_string())
+				.spawn(move || reap(&state));
+			// this runs in drop, so rather than panic release the work here and try again
+			// next time
+			if spawned.is_err() {
+				self.reaper_started.store(false, Ordering::Release);
+				drop(work);
+				return;
+			}
+		}
+
+		// registered before the work is parked, as the work takes its own lock to wake it
+		let waker = Waker::from(Arc::new(ReaperWake(self.state.clone())));
+		work.work.poll_idle(&waker);
+		self.state.work.lock().unwrap().push(work);
+		self.state.notify();
+	}
+}
+
+impl Drop for Detached {
+	fn drop(&mut self) {
+		self.state.closed.store(true, Ordering::Release);
+		self.state.notify();
+		let remaining: Vec<DetachedWork> = self.state.work.lock().unwrap().drain(..).collect();
+		drop(remaining);
+	}
+}
+
+// Releases detached work once it's idle, until the Detached is dropped.
+fn reap(state: &DetachedState) {
+	loop {
+		let completed: Vec<DetachedWork> = {
+			let mut detached = state.work.lock().unwrap();
+			while !state.closed.load(Ordering::Acquire) && detached.iter().all(|detached| !detached.work.is_idle()) {
+				detached = state.changed.wait(detached).unwrap();
+			}
+			if state.closed.load(Ordering::Acquire) {
+				return;
+			}
+			let (completed, running) = detached.drain(..).partition(|detached| detached.work.is_idle());
+			*detached = running;
+			completed
+		};
+
+		// released outside the lock, as that may wait for the context to let go of the items
+		drop(completed);
+	}
+}
@@ -0,0 +1,275 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use super::{normalize, DirEntry, Device, FileStat, SyncMode, WriteMode};
+use crate::ResultCode;
+
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+const COMPRESSED_MAGIC: &[u8; 4] = b"LFSZ";
+const COMPRESSED_HEADER_SIZE: usize = 13;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CompressionAlgorithm {
+	Deflate,
+	#[cfg(feature = "zstd")]
+	Zstd,
+	#[cfg(feature = "lz4")]
+	Lz4
+}
+
+impl CompressionAlgorithm {
+	fn from_name(name: &str) -> Option<CompressionAlgorithm> {
+		match name {
+			"deflate" => Some(CompressionAlgorithm::Deflate),
+			#[cfg(feature = "zstd")]
+			"zstd" => Some(CompressionAlgorithm::Zstd),
+			#[cfg(feature = "lz4")]
+			"lz4" => Some(CompressionAlgorithm::Lz4),
+			_ => None
+		}
+	}
+
+	fn to_raw(self) -> u8 {
+		match self {
+			CompressionAlgorithm::Deflate => 1,
+			#[cfg(feature = "zstd")]
+			CompressionAlgorithm::Zstd => 2,
+			#[cfg(feature = "lz4")]
+			CompressionAlgorithm::Lz4 => 3
+		}
+	}
+
+	fn from_raw(raw: u8) -> Result<CompressionAlgorithm, ResultCode> {
+		match raw {
+			1 => Ok(CompressionAlgorithm::Deflate),
+			#[cfg(feature = "zstd")]
+			2 => Ok(CompressionAlgorithm::Zstd),
+			#[cfg(feature = "lz4")]
+			3 => Ok(CompressionAlgorithm::Lz4),
+			_ => Err(ResultCode::Unsupported)
+		}
+	}
+
+	fn compress(self, data: &[u8]) -> Result<Vec<u8>, ResultCode> {
+		match self {
+			CompressionAlgorithm::Deflate => Ok(miniz_oxide::deflate::compress_to_vec(data, 6)),
+			#[cfg(feature = "zstd")]
+			CompressionAlgorithm::Zstd => zstd::encode_all(data, 0).map_err(|_| ResultCode::GenericError),
+			#[cfg(feature = "lz4")]
+			CompressionAlgorithm::Lz4 => Ok(lz4_flex::compress(data))
+		}
+	}
+
+	fn decompress(self, data: &[u8], size: usize) -> Result<Vec<u8>, ResultCode> {
+		let decompressed = match self {
+			CompressionAlgorithm::Deflate => miniz_oxide::inflate::decompress_to_vec(data).map_err(|_| ResultCode::GenericError),
+			#[cfg(feature = "zstd")]
+			CompressionAlgorithm::Zstd => zstd::decode_all(data).map_err(|_| ResultCode::GenericError),
+			#[cfg(feature = "lz4")]
+			CompressionAlgorithm::Lz4 => lz4_flex::decompress(data, size).map_err(|_| ResultCode::GenericError)
+		}?;
+
+		if decompressed.len() == size {
+			Ok(decompressed)
+		} else {
+			Err(ResultCode::GenericError)
+		}
+	}
+}
+
+// Wraps another device, compressing whole files on write and decompressing them on read.
+// The device path names the algorithm followed by the inner device's path, e.g.
+// "zstd:./saves". Files written by the wrapper carry a small header ("LFSZ", algorithm,
+// decompressed size); files without it are passed through untouched, so a mount can mix
+// compressed and plain content.
+pub struct CompressedDevice<D: Device> {
+	inner: D,
+	algorithm: CompressionAlgorithm,
+	write_lock: Mutex<()>
+}
+
+impl<D: Device> CompressedDevice<D> {
+	pub fn algorithm(&self) -> CompressionAlgorithm {
+		self.algorithm
+	}
+
+	fn read_header(&self, path: &str) -> Result<Option<(CompressionAlgorithm, u64)>, ResultCode> {
+		let header = self.inner.read_file(path, 0, COMPRESSED_HEADER_SIZE as u64)?;
+		if header.len() < COMPRESSED_HEADER_SIZE || &header[0..4] != COMPRESSED_MAGIC {
+			return Ok(None);
+		}
+
+		let mut size = [0u8; 8];
+		size.copy_from_slice(&header[5..13]);
+		Ok(Some((CompressionAlgorithm::from_raw(header[4])?, u64::from_le_bytes(size))))
+	}
+
+	fn read_decompressed(&self, path: &str) -> Result<Vec<u8>, ResultCode> {
+		let stored = self.inner.read_file(path, 0, u64::max_value())?;
+		if stored.len() < COMPRESSED_HEADER_SIZE || &stored[0..4] != COMPRESSED_MAGIC {
+			return Ok(stored);
+		}
+
+		let mut size = [0u8; 8];
+		size.copy_from_slice(&stored[5..13]);
+		CompressionAlgorithm::from_raw(stored[4])?.decompress(&stored[COMPRESSED_HEADER_SIZE..], u64::from_le_bytes(size) as usize)
+	}
+
+	// `mode` is either Overwrite or Atomic
+	fn write_compressed(&self, path: &str, data: &[u8], mode: WriteMode) -> Result<(), ResultCode> {
+		let mut stored = Vec::with_capacity(COMPRESSED_HEADER_SIZE + data.len() / 2);
+		stored.extend_from_slice(COMPRESSED_MAGIC);
+		stored.push(self.algorithm.to_raw());
+		stored.extend_from_slice(&(data.len() as u64).to_le_bytes());
+		stored.extend_from_slice(&self.algorithm.compress(data)?);
+
+		self.inner.write_file(path, 0, &stored, mode).map(|_| ())
+	}
+}
+
+impl<D: Device> Device for CompressedDevice<D> {
+	fn create(device_path: &str) -> Result<CompressedDevice<D>, ResultCode> {
+		let mut parts = device_path.splitn(2, ':');
+		let algorithm = parts.next().and_then(CompressionAlgorithm::from_name).ok_or(ResultCode::InvalidDevice)?;
+		let inner = D::create(parts.next().unwrap_or(""))?;
+
+		Ok(CompressedDevice {
+			inner: inner,
+			algorithm: algorithm,
+			write_lock: Mutex::new(())
+		})
+	}
+
+	fn file_exists(&self, path: &str) -> bool {
+		self.inner.file_exists(path)
+	}
+
+	fn file_size(&self, path: &str) -> Result<u64, ResultCode> {
+		match self.read_header(path)? {
+			Some((_, size)) => Ok(size),
+			None => self.inner.file_size(path)
+		}
+	}
+
+	fn read_file(&self, path: &str, offset: u64, max_bytes: u64) -> Result<Vec<u8>, ResultCode> {
+		if self.read_header(path)?.is_none() {
+			return self.inner.read_file(path, offset, max_bytes);
+		}
+
+		let data = self.read_decompressed(path)?;
+		let start = std::cmp::min(offset, data.len() as u64) as usize;
+		let end = std::cmp::min(offset.saturating_add(max_bytes), data.len() as u64) as usize;
+		Ok(data[start..end].to_vec())
+	}
+
+	fn write_file(&self, path: &str, offset: u64, buffer: &[u8], mode: WriteMode) -> Result<u64, ResultCode> {
+		let _lock = self.write_lock.lock().unwrap();
+
+		// compressed streams can't be patched in place, so partial writes rewrite the file
+		let data = match mode {
+			WriteMode::Overwrite | WriteMode::Atomic => buffer.to_vec(),
+			WriteMode::Append | WriteMode::Segment => {
+				let mut data = match self.read_decompressed(path) {
+					Ok(data) => data,
+					Err(ResultCode::NotFound) => Vec::new(),
+					Err(code) => return Err(code)
+				};
+
+				let start = if mode == WriteMode::Append { data.len() } else { offset as usize };
+				let end = start + buffer.len();
+				if data.len() < end {
+					data.resize(end, 0);
+				}
+				data[start..end].copy_from_slice(buffer);
+				data
+			}
+		};
+
+		let mode = if mode == WriteMode::Atomic { WriteMode::Atomic } else { WriteMode::Overwrite };
+		self.write_compressed(path, &data, mode)?;
+		Ok(buffer.len() as u64)
+	}
+
+	fn set_len(&self, path: &str, len: u64) -> Result<(), ResultCode> {
+		let _lock = self.write_lock.lock().unwrap();
+		let mut data = self.read_decompressed(path)?;
+		data.resize(len as usize, 0);
+		self.write_compressed(path, &data, WriteMode::Overwrite)
+	}
+
+	fn delete_file(&self, path: &str) -> Result<(), ResultCode> {
+		self.inner.delete_file(path)
+	}
+
+	fn sync(&self, path: &str, mode: SyncMode) -> Result<(), ResultCode> {
+		self.inner.sync(path, mode)
+	}
+
+	fn create_dir(&self, path: &str) -> Result<(), ResultCode> {
+		self.inner.create_dir(path)
+	}
+
+	fn delete_dir(&self, path: &str) -> Result<(), ResultCode> {
+		self.inner.delete_dir(path)
+	}
+
+	fn list_dir(&self, path: &str) -> Result<Vec<DirEntry>, ResultCode> {
+		let mut entries = self.inner.list_dir(path)?;
+		for entry in entries.iter_mut().filter(|entry| !entry.is_dir) {
+			let file_path = format!("{}/{}", normalize(path), entry.name);
+			entry.size = self.file_size(&file_path).unwrap_or(entry.size);
+		}
+		Ok(entries)
+	}
+
+	fn stat(&self, path: &str) -> Result<FileStat, ResultCode> {
+		let mut stat = self.inner.stat(path)?;
+		if !stat.is_dir {
+			stat.size = self.file_size(path)?;
+		}
+		Ok(stat)
+	}
+
+	fn backing_path(&self, path: &str) -> Option<PathBuf> {
+		self.inner.backing_path(path)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::device::MemoryDevice;
+
+	#[test]
+	fn compressed_device_test() {
+		let device = CompressedDevice::<MemoryDevice>::create("deflate:").unwrap();
+		let text = b"the quick brown fox jumps over the lazy dog, the quick brown fox jumps over the lazy dog";
+
+		assert!(device.write_file("/log.txt", 0, text, WriteMode::Overwrite) == Ok(text.len() as u64));
+		assert!(device.write_file("/log.txt", 0, b"!", WriteMode::Append).is_ok());
+		assert!(device.file_size("/log.txt") == Ok(text.len() as u64 + 1));
+		assert!(device.read_file("/log.txt", 4, 5).unwrap() == b"quick");
+		assert!(device.inner.file_size("/log.txt").unwrap() < text.len() as u64);
+	}
+}
@@ -0,0 +1,412 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use super::{normalize, DirEntry, Device, FileStat, SyncMode, WriteMode};
+use crate::ResultCode;
+
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static TEMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// Windows paths at least this long need the \\?\ prefix to get past MAX_PATH.
+#[cfg(windows)]
+const MAX_PATH: usize = 260;
+
+pub(crate) fn from_io_error(error: std::io::Error) -> ResultCode {
+	match error.kind() {
+		ErrorKind::NotFound => ResultCode::NotFound,
+		ErrorKind::PermissionDenied => ResultCode::PermissionsError,
+		ErrorKind::AlreadyExists => ResultCode::AlreadyExists,
+		_ if is_out_of_space(&error) => ResultCode::OutOfSpace,
+		_ => ResultCode::GenericError
+	}
+}
+
+#[cfg(unix)]
+fn is_out_of_space(error: &std::io::Error) -> bool {
+	error.raw_os_error() == Some(libc::ENOSPC)
+}
+
+// ERROR_HANDLE_DISK_FULL and ERROR_DISK_FULL
+#[cfg(windows)]
+fn is_out_of_space(error: &std::io::Error) -> bool {
+	error.raw_os_error() == Some(39) || error.raw_os_error() == Some(112)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn is_out_of_space(_error: &std::io::Error) -> bool {
+	false
+}
+
+// Reserves the file's blocks up front where the platform can. Elsewhere, extending the
+// file is the closest match; on Windows that's SetEndOfFile, which allocates the space.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn allocate_file(file: &File, len: u64) -> std::io::Result<()> {
+	use std::os::unix::io::AsRawFd;
+
+	if len == 0 {
+		return Ok(());
+	}
+	match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, len as libc::off_t) } {
+		0 => Ok(()),
+		error => Err(std::io::Error::from_raw_os_error(error))
+	}
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn allocate_file(file: &File, len: u64) -> std::io::Result<()> {
+	if file.metadata()?.len() < len {
+		file.set_len(len)?;
+	}
+	Ok(())
+}
+
+// Writes a temporary sibling of `path` and renames it over the original once the data
+// has reached the disk. The sibling is removed again if anything fails.
+fn write_atomic(path: &Path, buffer: &[u8]) -> std::io::Result<()> {
+	let file_name = path.file_name().ok_or(ErrorKind::InvalidInput)?.to_string_lossy();
+	let temp_name = format!(".{}.{}-{}.tmp", file_name, std::process::id(), TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed));
+	let temp_path = path.with_file_name(temp_name);
+
+	let result = File::create(&temp_path)
+		.and_then(|mut file| file.write_all(buffer).and_then(|_| file.sync_all()))
+		.and_then(|_| std::fs::rename(&temp_path, path));
+	if result.is_err() {
+		let _ = std::fs::remove_file(&temp_path);
+	}
+	result
+}
+
+// Makes a file's directory entry durable. Windows has no way to sync a directory, but
+// commits directory changes along with the file's own metadata.
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> std::io::Result<()> {
+	match path.parent() {
+		Some(parent) => File::open(parent)?.sync_all(),
+		None => Ok(())
+	}
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> std::io::Result<()> {
+	Ok(())
+}
+
+// Turns an absolute Windows path into its verbatim \\?\ form. The Windows APIs take
+// verbatim paths as is, so separators become backslashes and . and .. are resolved here.
+#[cfg(any(windows, test))]
+fn verbatim_path(path: &str) -> String {
+	if path.starts_with(r"\\?\") || path.starts_with(r"\\.\") {
+		return path.to_string();
+	}
+
+	let path = path.replace('/', "\\");
+	// .. never climbs above the drive, or above the server and share of a UNC path
+	let (prefix, rest, root_len) = if path.starts_with(r"\\") {
+		(r"\\?\UNC\", &path[2..], 2)
+	} else {
+		(r"\\?\", &path[..], 1)
+	};
+
+	let mut components: Vec<&str> = Vec::new();
+	for component in rest.split('\\') {
+		match component {
+			"" | "." => {},
+			".." => if components.len() > root_len {
+				components.pop();
+			},
+			_ => components.push(component)
+		}
+	}
+	format!("{}{}", prefix, components.join("\\"))
+}
+
+// Prefixes paths too long for the plain Windows APIs, including UNC paths, so asset trees
+// deeper than MAX_PATH stay reachable. Shorter paths and other platforms are unchanged.
+#[cfg(windows)]
+pub(crate) fn long_path(path: PathBuf) -> PathBuf {
+	let absolute = if path.is_absolute() {
+		path.clone()
+	} else {
+		match std::env::current_dir() {
+			Ok(dir) => dir.join(&path),
+			Err(_) => return path
+		}
+	};
+
+	match absolute.to_str() {
+		Some(absolute) if absolute.len() >= MAX_PATH => PathBuf::from(verbatim_path(absolute)),
+		_ => path
+	}
+}
+
+#[cfg(not(windows))]
+pub(crate) fn long_path(path: PathBuf) -> PathBuf {
+	path
+}
+
+// std::fs backed counterpart of the built-in Directory device, used as a building
+// block by the devices that layer on top of real directories.
+pub(crate) struct DiskDevice {
+	root: PathBuf
+}
+
+impl DiskDevice {
+	// Fails with InvalidPath for anything but plain names below the root, so nothing that
+	// slipped past path validation, like a drive prefix or a ".." the platform splits off
+	// at a backslash, can reach outside it.
+	pub(crate) fn resolve(&self, path: &str) -> Result<PathBuf, ResultCode> {
+		let relative = Path::new(normalize(path));
+		if !relative.components().all(|component| matches!(component, Component::Normal(_))) {
+			return Err(ResultCode::InvalidPath);
+		}
+		Ok(long_path(self.root.join(relative)))
+	}
+}
+
+impl Device for DiskDevice {
+	fn create(device_path: &str) -> Result<DiskDevice, ResultCode> {
+		let root = PathBuf::from(device_path);
+		if !long_path(root.clone()).is_dir() {
+			return Err(ResultCode::NotFound);
+		}
+
+		Ok(DiskDevice {
+			root: root
+		})
+	}
+
+	fn file_exists(&self, path: &str) -> bool {
+		self.resolve(path).map_or(false, |path| path.exists())
+	}
+
+	fn file_size(&self, path: &str) -> Result<u64, ResultCode> {
+		let metadata = std::fs::metadata(self.resolve(path)?).map_err(from_io_error)?;
+		if metadata.is_file() {
+			Ok(metadata.len())
+		} else {
+			Err(ResultCode::NotFound)
+		}
+	}
+
+	fn read_file(&self, path: &str, offset: u64, max_bytes: u64) -> Result<Vec<u8>, ResultCode> {
+		let mut file = File::open(self.resolve(path)?).map_err(from_io_error)?;
+		file.seek(SeekFrom::Start(offset)).map_err(from_io_error)?;
+
+		let mut data = Vec::new();
+		file.take(max_bytes).read_to_end(&mut data).map_err(from_io_error)?;
+		Ok(data)
+	}
+
+	fn read_ranges(&self, path: &str, ranges: &[(u64, u64)]) -> Result<Vec<Vec<u8>>, ResultCode> {
+		let mut file = File::open(self.resolve(path)?).map_err(from_io_error)?;
+		ranges.iter()
+			.map(|&(offset, max_bytes)| {
+				file.seek(SeekFrom::Start(offset)).map_err(from_io_error)?;
+				let mut data = Vec::new();
+				(&mut file).take(max_bytes).read_to_end(&mut data).map_err(from_io_error)?;
+				Ok(data)
+			})
+			.collect()
+	}
+
+	fn write_file(&self, path: &str, offset: u64, buffer: &[u8], mode: WriteMode) -> Result<u64, ResultCode> {
+		let path = self.resolve(path)?;
+		if mode == WriteMode::Atomic {
+			return write_atomic(&path, buffer).map(|_| buffer.len() as u64).map_err(from_io_error);
+		}
+
+		let mut file = match mode {
+			WriteMode::Overwrite | WriteMode::Atomic => File::create(path),
+			WriteMode::Append => OpenOptions::new().append(true).create(true).open(path),
+			WriteMode::Segment => OpenOptions::new().write(true).create(true).truncate(false).open(path)
+		}.map_err(from_io_error)?;
+
+		if mode == WriteMode::Segment {
+			file.seek(SeekFrom::Start(offset)).map_err(from_io_error)?;
+		}
+
+		file.write_all(buffer).map_err(from_io_error)?;
+		Ok(buffer.len() as u64)
+	}
+
+	fn write_gather(&self, path: &str, buffers: &[&[u8]]) -> Result<u64, ResultCode> {
+		let mut file = File::create(self.resolve(path)?).map_err(from_io_error)?;
+		for buffer in buffers {
+			file.write_all(buffer).map_err(from_io_error)?;
+		}
+		Ok(buffers.iter().map(|buffer| buffer.len() as u64).sum())
+	}
+
+	fn delete_file(&self, path: &str) -> Result<(), ResultCode> {
+		std::fs::remove_file(self.resolve(path)?).map_err(from_io_error)
+	}
+
+	fn allocate(&self, path: &str, len: u64) -> Result<(), ResultCode> {
+		let file = OpenOptions::new().write(true).create(true).truncate(false).open(self.resolve(path)?).map_err(from_io_error)?;
+		allocate_file(&file, len).map_err(from_io_error)
+	}
+
+	fn set_len(&self, path: &str, len: u64) -> Result<(), ResultCode> {
+		let file = OpenOptions::new().write(true).open(self.resolve(path)?).map_err(from_io_error)?;
+		file.set_len(len).map_err(from_io_error)
+	}
+
+	fn sync(&self, path: &str, mode: SyncMode) -> Result<(), ResultCode> {
+		let path = self.resolve(path)?;
+		// Windows only flushes handles opened for writing
+		let file = OpenOptions::new().write(true).open(&path).map_err(from_io_error)?;
+		match mode {
+			SyncMode::None => Ok(()),
+			SyncMode::Data => file.sync_data().map_err(from_io_error),
+			SyncMode::Full => {
+				file.sync_all().map_err(from_io_error)?;
+				sync_parent_dir(&path).map_err(from_io_error)
+			}
+		}
+	}
+
+	fn create_dir(&self, path: &str) -> Result<(), ResultCode> {
+		std::fs::create_dir(self.resolve(path)?).map_err(from_io_error)
+	}
+
+	fn delete_dir(&self, path: &str) -> Result<(), ResultCode> {
+		std::fs::remove_dir(self.resolve(path)?).map_err(from_io_error)
+	}
+
+	fn list_dir(&self, path: &str) -> Result<Vec<DirEntry>, ResultCode> {
+		let mut entries = Vec::new();
+		for entry in std::fs::read_dir(self.resolve(path)?).map_err(from_io_error)? {
+			let entry = entry.map_err(from_io_error)?;
+			let metadata = entry.metadata().map_err(from_io_error)?;
+			entries.push(DirEntry {
+				name: entry.file_name().to_string_lossy().into_owned(),
+				is_dir: metadata.is_dir(),
+				size: if metadata.is_dir() { 0 } else { metadata.len() }
+			});
+		}
+
+		entries.sort_by(|a, b| a.name.cmp(&b.name));
+		Ok(entries)
+	}
+
+	fn backing_path(&self, path: &str) -> Option<PathBuf> {
+		self.resolve(path).ok()
+	}
+
+	fn raw_path(&self, path: &str) -> Option<PathBuf> {
+		self.resolve(path).ok()
+	}
+
+	fn stat(&self, path: &str) -> Result<FileStat, ResultCode> {
+		let metadata = std::fs::metadata(self.resolve(path)?).map_err(from_io_error)?;
+		Ok(FileStat {
+			size: if metadata.is_dir() { 0 } else { metadata.len() },
+			modified: metadata.modified().ok(),
+			is_dir: metadata.is_dir()
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_dir::TestDir;
+
+	#[test]
+	fn verbatim_path_test() {
+		assert!(verbatim_path(r"C:\assets\.\characters\..\props/crate.fbx") == r"\\?\C:\assets\props\crate.fbx");
+		assert!(verbatim_path("C:/assets/../../props") == r"\\?\C:\props");
+		assert!(verbatim_path(r"\\server\share\assets\crate.fbx") == r"\\?\UNC\server\share\assets\crate.fbx");
+		assert!(verbatim_path(r"\\server\share\..\..\crate.fbx") == r"\\?\UNC\server\share\crate.fbx");
+		assert!(verbatim_path(r"\\?\C:\assets\..") == r"\\?\C:\assets\..");
+	}
+
+	#[test]
+	fn atomic_write_test() {
+		let root = TestDir::new("atomic_write_test");
+		std::fs::write(root.join("save.dat"), b"old save").unwrap();
+
+		let device = DiskDevice::create(root.to_str()).unwrap();
+		assert!(device.write_file("/save.dat", 0, b"new", WriteMode::Atomic) == Ok(3));
+		assert!(std::fs::read(root.join("save.dat")).unwrap() == b"new");
+		assert!(device.list_dir("/").unwrap().len() == 1);
+		assert!(device.write_file("/missing/save.dat", 0, b"new", WriteMode::Atomic) == Err(ResultCode::NotFound));
+	}
+
+	#[test]
+	fn resolve_test() {
+		let device = DiskDevice::create(".").unwrap();
+		assert!(device.resolve("/src/lib.rs") == Ok(PathBuf::from("./src/lib.rs")));
+		assert!(device.resolve("") == Ok(PathBuf::from(".")));
+		assert!(device.resolve("/src/../../secret") == Err(ResultCode::InvalidPath));
+		#[cfg(windows)]
+		{
+			assert!(device.resolve("/mods/..\\..\\secret") == Err(ResultCode::InvalidPath));
+			assert!(device.resolve("/C:/Windows/win.ini") == Err(ResultCode::InvalidPath));
+			assert!(device.resolve("/\\Windows\\win.ini") == Err(ResultCode::InvalidPath));
+		}
+	}
+
+	#[test]
+	fn read_ranges_test() {
+		let root = TestDir::new("read_ranges_test");
+		std::fs::write(root.join("textures.pak"), b"0123456789abcdef").unwrap();
+
+		let device = DiskDevice::create(root.to_str()).unwrap();
+		let ranges = device.read_ranges("/textures.pak", &[(10, 3), (0, 2), (14, 10), (20, 4)]).unwrap();
+		assert!(ranges == [&b"abc"[..], b"01", b"ef", b""]);
+		assert!(device.read_ranges("/missing.pak", &[(0, 1)]) == Err(ResultCode::NotFound));
+	}
+
+	#[test]
+	fn write_gather_test() {
+		let root = TestDir::new("write_gather_test");
+		std::fs::write(root.join("save.dat"), b"an older and longer save").unwrap();
+
+		let device = DiskDevice::create(root.to_str()).unwrap();
+		assert!(device.write_gather("/save.dat", &[b"HDR", b"", b"payload", b"END"]) == Ok(13));
+		assert!(std::fs::read(root.join("save.dat")).unwrap() == b"HDRpayloadEND");
+	}
+
+	#[test]
+	fn allocate_test() {
+		let root = TestDir::new("allocate_test");
+		std::fs::write(root.join("recording.bin"), b"frames").unwrap();
+
+		let device = DiskDevice::create(root.to_str()).unwrap();
+		assert!(device.allocate("/download.bin", 4096) == Ok(()));
+		assert!(device.file_size("/download.bin") == Ok(4096));
+
+		// existing data is kept and the file is never shrunk
+		assert!(device.allocate("/recording.bin", 2) == Ok(()));
+		assert!(device.allocate("/recording.bin", 64) == Ok(()));
+		assert!(device.file_size("/recording.bin") == Ok(64));
+		assert!(device.read_file("/recording.bin", 0, 6) == Ok(b"frames".to_vec()));
+
+		#[cfg(unix)]
+		assert!(from_io_error(std::io::Error::from_raw_os_error(libc::ENOSPC)) == ResultCode::OutOfSpace);
+	}
+}
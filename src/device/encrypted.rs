@@ -0,0 +1,240 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use super::{normalize, DirEntry, Device, FileStat, SyncMode, WriteMode};
+use crate::ResultCode;
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+const ENCRYPTED_MAGIC: &[u8; 4] = b"LFSE";
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+const ENCRYPTED_HEADER_SIZE: usize = 4 + NONCE_SIZE;
+
+pub type EncryptionKey = [u8; 32];
+
+// Supplies the keys for an `EncryptedDevice`. The provider is created from the key id in
+// the device path and asked for a key for every file, so it can hand out a single mount
+// key or derive per-file keys.
+pub trait KeyProvider: Send + Sync + 'static {
+	fn create(key_id: &str) -> Result<Self, ResultCode> where Self: Sized;
+	fn key(&self, path: &str) -> Result<EncryptionKey, ResultCode>;
+}
+
+// Wraps another device, encrypting file contents with ChaCha20-Poly1305 on write and
+// decrypting on read. The device path is the key id followed by the inner device's
+// path, e.g. "savegame:./saves". Each file is stored as "LFSE", a random nonce and the
+// sealed contents, with the file's path as associated data so files can't be swapped.
+// Files that fail authentication are reported as `PermissionsError`.
+pub struct EncryptedDevice<D: Device, K: KeyProvider> {
+	inner: D,
+	keys: K,
+	write_lock: Mutex<()>
+}
+
+impl<D: Device, K: KeyProvider> EncryptedDevice<D, K> {
+	fn cipher(&self, path: &str) -> Result<ChaCha20Poly1305, ResultCode> {
+		let key = self.keys.key(normalize(path))?;
+		Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+	}
+
+	fn read_decrypted(&self, path: &str) -> Result<Vec<u8>, ResultCode> {
+		let stored = self.inner.read_file(path, 0, u64::max_value())?;
+		if stored.len() < ENCRYPTED_HEADER_SIZE + TAG_SIZE || &stored[0..4] != ENCRYPTED_MAGIC {
+			return Err(ResultCode::PermissionsError);
+		}
+
+		let payload = Payload {
+			msg: &stored[ENCRYPTED_HEADER_SIZE..],
+			aad: normalize(path).as_bytes()
+		};
+		self.cipher(path)?
+			.decrypt(Nonce::from_slice(&stored[4..ENCRYPTED_HEADER_SIZE]), payload)
+			.map_err(|_| ResultCode::PermissionsError)
+	}
+
+	// `mode` is either Overwrite or Atomic
+	fn write_encrypted(&self, path: &str, data: &[u8], mode: WriteMode) -> Result<(), ResultCode> {
+		let mut nonce = [0u8; NONCE_SIZE];
+		getrandom::getrandom(&mut nonce).map_err(|_| ResultCode::GenericError)?;
+
+		let payload = Payload {
+			msg: data,
+			aad: normalize(path).as_bytes()
+		};
+		let sealed = self.cipher(path)?
+			.encrypt(Nonce::from_slice(&nonce), payload)
+			.map_err(|_| ResultCode::GenericError)?;
+
+		let mut stored = Vec::with_capacity(ENCRYPTED_HEADER_SIZE + sealed.len());
+		stored.extend_from_slice(ENCRYPTED_MAGIC);
+		stored.extend_from_slice(&nonce);
+		stored.extend_from_slice(&sealed);
+		self.inner.write_file(path, 0, &stored, mode).map(|_| ())
+	}
+}
+
+impl<D: Device, K: KeyProvider> Device for EncryptedDevice<D, K> {
+	fn create(device_path: &str) -> Result<EncryptedDevice<D, K>, ResultCode> {
+		let mut parts = device_path.splitn(2, ':');
+		let keys = K::create(parts.next().unwrap_or(""))?;
+		let inner = D::create(parts.next().ok_or(ResultCode::InvalidDevice)?)?;
+
+		Ok(EncryptedDevice {
+			inner: inner,
+			keys: keys,
+			write_lock: Mutex::new(())
+		})
+	}
+
+	fn file_exists(&self, path: &str) -> bool {
+		self.inner.file_exists(path)
+	}
+
+	fn file_size(&self, path: &str) -> Result<u64, ResultCode> {
+		let stored_size = self.inner.file_size(path)?;
+		stored_size.checked_sub((ENCRYPTED_HEADER_SIZE + TAG_SIZE) as u64).ok_or(ResultCode::PermissionsError)
+	}
+
+	fn read_file(&self, path: &str, offset: u64, max_bytes: u64) -> Result<Vec<u8>, ResultCode> {
+		let data = self.read_decrypted(path)?;
+		let start = std::cmp::min(offset, data.len() as u64) as usize;
+		let end = std::cmp::min(offset.saturating_add(max_bytes), data.len() as u64) as usize;
+		Ok(data[start..end].to_vec())
+	}
+
+	fn write_file(&self, path: &str, offset: u64, buffer: &[u8], mode: WriteMode) -> Result<u64, ResultCode> {
+		let _lock = self.write_lock.lock().unwrap();
+
+		// sealed files can't be patched in place, so partial writes rewrite the file
+		let data = match mode {
+			WriteMode::Overwrite | WriteMode::Atomic => buffer.to_vec(),
+			WriteMode::Append | WriteMode::Segment => {
+				let mut data = match self.read_decrypted(path) {
+					Ok(data) => data,
+					Err(ResultCode::NotFound) => Vec::new(),
+					Err(code) => return Err(code)
+				};
+
+				let start = if mode == WriteMode::Append { data.len() } else { offset as usize };
+				let end = start + buffer.len();
+				if data.len() < end {
+					data.resize(end, 0);
+				}
+				data[start..end].copy_from_slice(buffer);
+				data
+			}
+		};
+
+		let mode = if mode == WriteMode::Atomic { WriteMode::Atomic } else { WriteMode::Overwrite };
+		self.write_encrypted(path, &data, mode)?;
+		Ok(buffer.len() as u64)
+	}
+
+	fn set_len(&self, path: &str, len: u64) -> Result<(), ResultCode> {
+		let _lock = self.write_lock.lock().unwrap();
+		let mut data = self.read_decrypted(path)?;
+		data.resize(len as usize, 0);
+		self.write_encrypted(path, &data, WriteMode::Overwrite)
+	}
+
+	fn delete_file(&self, path: &str) -> Result<(), ResultCode> {
+		self.inner.delete_file(path)
+	}
+
+	fn sync(&self, path: &str, mode: SyncMode) -> Result<(), ResultCode> {
+		self.inner.sync(path, mode)
+	}
+
+	fn create_dir(&self, path: &str) -> Result<(), ResultCode> {
+		self.inner.create_dir(path)
+	}
+
+	fn delete_dir(&self, path: &str) -> Result<(), ResultCode> {
+		self.inner.delete_dir(path)
+	}
+
+	fn list_dir(&self, path: &str) -> Result<Vec<DirEntry>, ResultCode> {
+		let mut entries = self.inner.list_dir(path)?;
+		for entry in entries.iter_mut().filter(|entry| !entry.is_dir) {
+			let file_path = format!("{}/{}", normalize(path), entry.name);
+			entry.size = self.file_size(&file_path).unwrap_or(entry.size);
+		}
+		Ok(entries)
+	}
+
+	fn stat(&self, path: &str) -> Result<FileStat, ResultCode> {
+		let mut stat = self.inner.stat(path)?;
+		if !stat.is_dir {
+			stat.size = self.file_size(path)?;
+		}
+		Ok(stat)
+	}
+
+	fn backing_path(&self, path: &str) -> Option<PathBuf> {
+		self.inner.backing_path(path)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::device::MemoryDevice;
+
+	struct TestKeys;
+
+	impl KeyProvider for TestKeys {
+		fn create(key_id: &str) -> Result<TestKeys, ResultCode> {
+			if key_id == "savegame" { Ok(TestKeys) } else { Err(ResultCode::InvalidDevice) }
+		}
+
+		fn key(&self, _path: &str) -> Result<EncryptionKey, ResultCode> {
+			Ok([7u8; 32])
+		}
+	}
+
+	#[test]
+	fn encrypted_device_test() {
+		assert!(EncryptedDevice::<MemoryDevice, TestKeys>::create("other:").err() == Some(ResultCode::InvalidDevice));
+		let device = EncryptedDevice::<MemoryDevice, TestKeys>::create("savegame:").unwrap();
+
+		assert!(device.write_file("/slot0.sav", 0, b"level=3", WriteMode::Overwrite) == Ok(7));
+		assert!(device.write_file("/slot0.sav", 0, b";gold=10", WriteMode::Append) == Ok(8));
+		assert!(device.read_file("/slot0.sav", 0, u64::max_value()).unwrap() == b"level=3;gold=10");
+		assert!(device.read_file("/slot0.sav", 6, 1).unwrap() == b"3");
+		assert!(device.file_size("/slot0.sav") == Ok(15));
+
+		let stored = device.inner.read_file("/slot0.sav", 0, u64::max_value()).unwrap();
+		assert!(&stored[0..4] == ENCRYPTED_MAGIC);
+		assert!(stored.len() == ENCRYPTED_HEADER_SIZE + 15 + TAG_SIZE);
+
+		// the path is bound into the seal, so a file copied elsewhere doesn't authenticate
+		device.inner.write_file("/slot1.sav", 0, &stored, WriteMode::Overwrite).unwrap();
+		assert!(device.read_file("/slot1.sav", 0, u64::max_value()) == Err(ResultCode::PermissionsError));
+		device.inner.write_file("/plain.txt", 0, b"not sealed at all", WriteMode::Overwrite).unwrap();
+		assert!(device.read_file("/plain.txt", 0, u64::max_value()) == Err(ResultCode::PermissionsError));
+	}
+}
@@ -0,0 +1,260 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// Serves gzip'd content inflated, the way web servers hand out precompressed files: a
+// path missing from the inner device is looked up with GZIP_EXTENSION appended, and that
+// file is inflated on read and reported at its inflated size. Paths the inner device has
+// as they are pass straight through, as does anything written. The device path is the
+// inner device's path.
+
+use super::{normalize, DirEntry, Device, FileStat, SyncMode, WriteMode};
+use crate::hash::crc32;
+use crate::ResultCode;
+
+use std::path::PathBuf;
+
+pub const GZIP_EXTENSION: &str = ".gz";
+
+const GZIP_MAGIC: &[u8; 3] = b"\x1f\x8b\x08";
+const GZIP_HEADER_SIZE: usize = 10;
+const GZIP_TRAILER_SIZE: usize = 8;
+
+const FLAG_HCRC: u8 = 0x02;
+const FLAG_EXTRA: u8 = 0x04;
+const FLAG_NAME: u8 = 0x08;
+const FLAG_COMMENT: u8 = 0x10;
+
+fn read_u32(buffer: &[u8]) -> u32 {
+	let mut bytes = [0u8; 4];
+	bytes.copy_from_slice(&buffer[..4]);
+	u32::from_le_bytes(bytes)
+}
+
+// Inflates the first member of a gzip stream, checking it against the trailer's CRC and
+// size.
+fn inflate(data: &[u8]) -> Result<Vec<u8>, ResultCode> {
+	if data.len() < GZIP_HEADER_SIZE + GZIP_TRAILER_SIZE || &data[0..3] != GZIP_MAGIC {
+		return Err(ResultCode::InvalidData);
+	}
+
+	let flags = data[3];
+	let mut start = GZIP_HEADER_SIZE;
+	if flags & FLAG_EXTRA != 0 {
+		let extra = data.get(start..start + 2).ok_or(ResultCode::InvalidData)?;
+		start += 2 + u16::from_le_bytes([extra[0], extra[1]]) as usize;
+	}
+	for flag in &[FLAG_NAME, FLAG_COMMENT] {
+		if flags & flag != 0 {
+			let end = data.get(start..).and_then(|rest| rest.iter().position(|&byte| byte == 0)).ok_or(ResultCode::InvalidData)?;
+			start += end + 1;
+		}
+	}
+	if flags & FLAG_HCRC != 0 {
+		start += 2;
+	}
+
+	let end = data.len() - GZIP_TRAILER_SIZE;
+	let compressed = data.get(start..end).ok_or(ResultCode::InvalidData)?;
+	let inflated = miniz_oxide::inflate::decompress_to_vec(compressed).map_err(|_| ResultCode::InvalidData)?;
+	if crc32(&inflated) != read_u32(&data[end..]) || inflated.len() as u32 != read_u32(&data[end + 4..]) {
+		return Err(ResultCode::InvalidData);
+	}
+	Ok(inflated)
+}
+
+pub struct GzipDevice<D: Device> {
+	inner: D
+}
+
+impl<D: Device> GzipDevice<D> {
+	pub fn new(inner: D) -> GzipDevice<D> {
+		GzipDevice {
+			inner: inner
+		}
+	}
+
+	pub fn inner(&self) -> &D {
+		&self.inner
+	}
+
+	// The gzip'd file serving `path`, None if the inner device has the path itself.
+	fn gzip_path(&self, path: &str) -> Option<String> {
+		if self.inner.file_exists(path) {
+			return None;
+		}
+		let gzip_path = format!("{}{}", path.trim_end_matches('/'), GZIP_EXTENSION);
+		if self.inner.file_exists(&gzip_path) { Some(gzip_path) } else { None }
+	}
+
+	// The size the trailer records, which is only the inflated size modulo 4GB.
+	fn inflated_size(&self, gzip_path: &str) -> Result<u64, ResultCode> {
+		let size = self.inner.file_size(gzip_path)?;
+		if size < (GZIP_HEADER_SIZE + GZIP_TRAILER_SIZE) as u64 {
+			return Err(ResultCode::InvalidData);
+		}
+		let trailer = self.inner.read_file(gzip_path, size - 4, 4)?;
+		if trailer.len() < 4 {
+			return Err(ResultCode::InvalidData);
+		}
+		Ok(read_u32(&trailer) as u64)
+	}
+}
+
+impl<D: Device> Device for GzipDevice<D> {
+	fn create(device_path: &str) -> Result<GzipDevice<D>, ResultCode> {
+		Ok(GzipDevice::new(D::create(device_path)?))
+	}
+
+	fn file_exists(&self, path: &str) -> bool {
+		self.inner.file_exists(path) || self.gzip_path(path).is_some()
+	}
+
+	fn file_size(&self, path: &str) -> Result<u64, ResultCode> {
+		match self.gzip_path(path) {
+			Some(gzip_path) => self.inflated_size(&gzip_path),
+			None => self.inner.file_size(path)
+		}
+	}
+
+	fn read_file(&self, path: &str, offset: u64, max_bytes: u64) -> Result<Vec<u8>, ResultCode> {
+		let gzip_path = match self.gzip_path(path) {
+			Some(gzip_path) => gzip_path,
+			None => return self.inner.read_file(path, offset, max_bytes)
+		};
+
+		let data = inflate(&self.inner.read_file(&gzip_path, 0, u64::max_value())?)?;
+		let start = std::cmp::min(offset, data.len() as u64) as usize;
+		let end = std::cmp::min(offset.saturating_add(max_bytes), data.len() as u64) as usize;
+		Ok(data[start..end].to_vec())
+	}
+
+	fn write_file(&self, path: &str, offset: u64, buffer: &[u8], mode: WriteMode) -> Result<u64, ResultCode> {
+		self.inner.write_file(path, offset, buffer, mode)
+	}
+
+	fn set_len(&self, path: &str, len: u64) -> Result<(), ResultCode> {
+		self.inner.set_len(path, len)
+	}
+
+	fn delete_file(&self, path: &str) -> Result<(), ResultCode> {
+		match self.gzip_path(path) {
+			Some(gzip_path) => self.inner.delete_file(&gzip_path),
+			None => self.inner.delete_file(path)
+		}
+	}
+
+	fn sync(&self, path: &str, mode: SyncMode) -> Result<(), ResultCode> {
+		self.inner.sync(path, mode)
+	}
+
+	fn create_dir(&self, path: &str) -> Result<(), ResultCode> {
+		self.inner.create_dir(path)
+	}
+
+	fn delete_dir(&self, path: &str) -> Result<(), ResultCode> {
+		self.inner.delete_dir(path)
+	}
+
+	// gzip'd files are listed under the name they're served as, unless the inner device
+	// has a file by that name too
+	fn list_dir(&self, path: &str) -> Result<Vec<DirEntry>, ResultCode> {
+		let mut entries = self.inner.list_dir(path)?;
+		let names: std::collections::HashSet<String> = entries.iter().map(|entry| entry.name.clone()).collect();
+		for entry in entries.iter_mut().filter(|entry| !entry.is_dir && entry.name.ends_with(GZIP_EXTENSION)) {
+			let name = entry.name[..entry.name.len() - GZIP_EXTENSION.len()].to_string();
+			if names.contains(&name) {
+				continue;
+			}
+			let gzip_path = format!("{}/{}", normalize(path), entry.name);
+			entry.size = self.inflated_size(&gzip_path).unwrap_or(entry.size);
+			entry.name = name;
+		}
+		entries.sort_by(|a, b| a.name.cmp(&b.name));
+		Ok(entries)
+	}
+
+	fn stat(&self, path: &str) -> Result<FileStat, ResultCode> {
+		let gzip_path = match self.gzip_path(path) {
+			Some(gzip_path) => gzip_path,
+			None => return self.inner.stat(path)
+		};
+
+		let mut stat = self.inner.stat(&gzip_path)?;
+		stat.size = self.inflated_size(&gzip_path)?;
+		Ok(stat)
+	}
+
+	fn backing_path(&self, path: &str) -> Option<PathBuf> {
+		match self.gzip_path(path) {
+			Some(gzip_path) => self.inner.backing_path(&gzip_path),
+			None => self.inner.backing_path(path)
+		}
+	}
+
+	// the gzip'd file on disk doesn't hold the bytes served
+	fn raw_path(&self, path: &str) -> Option<PathBuf> {
+		match self.gzip_path(path) {
+			Some(_) => None,
+			None => self.inner.raw_path(path)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::device::MemoryDevice;
+
+	fn gzip(data: &[u8]) -> Vec<u8> {
+		let mut gzip = vec![0x1f, 0x8b, 0x08, FLAG_NAME, 0, 0, 0, 0, 0, 0xff];
+		gzip.extend_from_slice(b"index.html\0");
+		gzip.extend_from_slice(&miniz_oxide::deflate::compress_to_vec(data, 6));
+		gzip.extend_from_slice(&crc32(data).to_le_bytes());
+		gzip.extend_from_slice(&(data.len() as u32).to_le_bytes());
+		gzip
+	}
+
+	#[test]
+	fn gzip_device_test() {
+		let device = GzipDevice::<MemoryDevice>::create("").unwrap();
+		let page = b"<html><body>hello, hello, hello, hello</body></html>";
+		device.inner().write_file("/index.html.gz", 0, &gzip(page), WriteMode::Overwrite).unwrap();
+		device.inner().write_file("/style.css", 0, b"body {}", WriteMode::Overwrite).unwrap();
+
+		assert!(device.file_exists("/index.html"));
+		assert!(device.file_size("/index.html") == Ok(page.len() as u64));
+		assert!(device.read_file("/index.html", 0, u64::max_value()).unwrap() == &page[..]);
+		assert!(device.read_file("/index.html", 6, 6).unwrap() == b"<body>");
+		assert!(device.read_file("/index.html.gz", 0, u64::max_value()).unwrap() == gzip(page));
+		assert!(device.read_file("/style.css", 0, u64::max_value()).unwrap() == b"body {}");
+
+		let listing = device.list_dir("/").unwrap();
+		assert!(listing.iter().map(|entry| (entry.name.as_str(), entry.size)).collect::<Vec<_>>() == [("index.html", page.len() as u64), ("style.css", 7)]);
+
+		let mut corrupt = gzip(page);
+		let last = corrupt.len() - 9;
+		corrupt[last] ^= 0xff;
+		device.inner().write_file("/broken.html.gz", 0, &corrupt, WriteMode::Overwrite).unwrap();
+		assert!(device.read_file("/broken.html", 0, u64::max_value()) == Err(ResultCode::InvalidData));
+	}
+}
@@ -0,0 +1,170 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use super::{normalize, Device};
+use crate::ResultCode;
+
+use std::io::Read;
+
+// Read-only device mapping mount-relative paths onto URLs below the device path,
+// e.g. mounting "https://cdn.example.com/content" serves "/a/b.png" from
+// "https://cdn.example.com/content/a/b.png". Requests block the work item thread.
+pub struct HttpDevice {
+	base_url: String
+}
+
+fn status_to_result_code(status: u16) -> ResultCode {
+	match status {
+		401 | 403 => ResultCode::PermissionsError,
+		404 | 410 => ResultCode::NotFound,
+		_ => ResultCode::GenericError
+	}
+}
+
+fn request_error(error: ureq::Error) -> ResultCode {
+	match error {
+		ureq::Error::Status(status, _) => status_to_result_code(status),
+		ureq::Error::Transport(_) => ResultCode::GenericError
+	}
+}
+
+fn encode_path(path: &str) -> String {
+	let mut encoded = String::with_capacity(path.len());
+	for b in path.bytes() {
+		match b {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => encoded.push(b as char),
+			_ => encoded.push_str(&format!("%{:02X}", b))
+		}
+	}
+	encoded
+}
+
+impl HttpDevice {
+	fn url(&self, path: &str) -> String {
+		format!("{}/{}", self.base_url, encode_path(normalize(path)))
+	}
+}
+
+impl Device for HttpDevice {
+	fn create(device_path: &str) -> Result<HttpDevice, ResultCode> {
+		if !device_path.starts_with("http://") && !device_path.starts_with("https://") {
+			return Err(ResultCode::InvalidDevice);
+		}
+
+		Ok(HttpDevice {
+			base_url: device_path.trim_end_matches('/').to_string()
+		})
+	}
+
+	fn file_exists(&self, path: &str) -> bool {
+		ureq::head(&self.url(path)).call().is_ok()
+	}
+
+	fn file_size(&self, path: &str) -> Result<u64, ResultCode> {
+		let response = ureq::head(&self.url(path)).call().map_err(request_error)?;
+		response.header("Content-Length")
+			.and_then(|length| length.parse().ok())
+			.ok_or(ResultCode::Unsupported)
+	}
+
+	fn read_file(&self, path: &str, offset: u64, max_bytes: u64) -> Result<Vec<u8>, ResultCode> {
+		if max_bytes == 0 {
+			return Ok(Vec::new());
+		}
+
+		let mut request = ureq::get(&self.url(path));
+		let ranged = offset > 0 || max_bytes != u64::max_value();
+		if ranged {
+			let last = offset.saturating_add(max_bytes - 1);
+			request = request.set("Range", &format!("bytes={}-{}", offset, last));
+		}
+
+		let response = match request.call() {
+			Ok(response) => response,
+			// reading past the end of the resource yields no data, like the other devices
+			Err(ureq::Error::Status(416, _)) => return Ok(Vec::new()),
+			Err(error) => return Err(request_error(error))
+		};
+
+		// servers that ignore Range send the whole body, so trim it down here
+		let skip = if ranged && response.status() != 206 { offset } else { 0 };
+
+		let mut reader = response.into_reader();
+		std::io::copy(&mut reader.by_ref().take(skip), &mut std::io::sink()).map_err(|_| ResultCode::GenericError)?;
+
+		let mut data = Vec::new();
+		reader.take(max_bytes).read_to_end(&mut data).map_err(|_| ResultCode::GenericError)?;
+		Ok(data)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::Write;
+	use std::net::TcpListener;
+
+	// Serves `body` at /content/level.bin and 404s anything else for the next `requests`
+	// connections, ignoring Range like some servers do. Returns the base URL.
+	fn serve(body: &'static [u8], requests: usize) -> String {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let address = listener.local_addr().unwrap();
+		std::thread::spawn(move || {
+			for stream in listener.incoming().take(requests) {
+				let mut stream = stream.unwrap();
+				let mut request = Vec::new();
+				let mut byte = [0u8; 1];
+				while !request.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap() == 1 {
+					request.push(byte[0]);
+				}
+
+				let request = String::from_utf8_lossy(&request);
+				let found = request.contains(" /content/level.bin ");
+				let response = if found {
+					format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len())
+				} else {
+					"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+				};
+				stream.write_all(response.as_bytes()).unwrap();
+				if found && !request.starts_with("HEAD") {
+					stream.write_all(body).unwrap();
+				}
+			}
+		});
+		format!("http://{}/content/", address)
+	}
+
+	#[test]
+	fn http_device_test() {
+		assert!(HttpDevice::create("ftp://example.com/content").err() == Some(ResultCode::InvalidDevice));
+		let device = HttpDevice::create("https://example.com/content/").unwrap();
+		assert!(device.url("/maps/level 1.bin") == "https://example.com/content/maps/level%201.bin");
+
+		let device = HttpDevice::create(&serve(b"0123456789", 5)).unwrap();
+		assert!(device.file_size("/level.bin") == Ok(10));
+		assert!(device.read_file("/level.bin", 0, u64::max_value()).unwrap() == b"0123456789");
+		// the server ignores Range, so the device trims the body itself
+		assert!(device.read_file("/level.bin", 3, 4).unwrap() == b"3456");
+		assert!(!device.file_exists("/missing.bin"));
+		assert!(device.read_file("/missing.bin", 0, 1) == Err(ResultCode::NotFound));
+	}
+}
@@ -0,0 +1,244 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+use super::{normalize, DirEntry, Device, FileStat, SyncMode, WriteMode};
+use crate::ResultCode;
+
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+// How slow the simulated media is.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MediaProfile {
+	// paid by every operation
+	pub latency: Duration,
+	// paid when an operation doesn't pick up where the previous read or write left off
+	pub seek: Duration,
+	// 0 for no limit
+	pub bytes_per_sec: u64
+}
+
+impl MediaProfile {
+	// Roughly a Blu-ray drive at its slowest.
+	pub const OPTICAL: MediaProfile = MediaProfile {
+		latency: Duration::from_millis(5),
+		seek: Duration::from_millis(120),
+		bytes_per_sec: 9_000_000
+	};
+
+	// A laptop hard drive.
+	pub const HDD: MediaProfile = MediaProfile {
+		latency: Duration::from_millis(1),
+		seek: Duration::from_millis(15),
+		bytes_per_sec: 60_000_000
+	};
+
+	// Low-end mobile flash storage.
+	pub const FLASH: MediaProfile = MediaProfile {
+		latency: Duration::from_millis(2),
+		seek: Duration::from_millis(0),
+		bytes_per_sec: 40_000_000
+	};
+
+	// "optical", "hdd", "flash", or "latency_ms/seek_ms/bytes_per_sec" like "5/120/9000000".
+	fn from_name(name: &str) -> Option<MediaProfile> {
+		match name {
+			"optical" => Some(MediaProfile::OPTICAL),
+			"hdd" => Some(MediaProfile::HDD),
+			"flash" => Some(MediaProfile::FLASH),
+			_ => {
+				let values: Vec<u64> = name.split('/').map(|value| value.parse().ok()).collect::<Option<_>>()?;
+				match values[..] {
+					[latency, seek, bytes_per_sec] => Some(MediaProfile {
+						latency: Duration::from_millis(latency),
+						seek: Duration::from_millis(seek),
+						bytes_per_sec: bytes_per_sec
+					}),
+					_ => None
+				}
+			}
+		}
+	}
+
+	fn transfer_time(&self, bytes: u64) -> Duration {
+		if self.bytes_per_sec == 0 {
+			Duration::from_secs(0)
+		} else {
+			Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64)
+		}
+	}
+}
+
+// Wraps another device, delaying and throttling it to behave like slow media so streaming
+// can be tried against worst-case seek times and bandwidth. The device path names the
+// profile followed by the inner device's path, e.g. "optical:./data", see MediaProfile.
+// Like the media it stands in for, it serves one operation at a time.
+pub struct LatencyDevice<D: Device> {
+	inner: D,
+	profile: Mutex<MediaProfile>,
+	// held while an operation is served; the file and offset the last one left off at
+	head: Mutex<Option<(String, u64)>>
+}
+
+impl<D: Device> LatencyDevice<D> {
+	pub fn new(inner: D, profile: MediaProfile) -> LatencyDevice<D> {
+		LatencyDevice {
+			inner: inner,
+			profile: Mutex::new(profile),
+			head: Mutex::new(None)
+		}
+	}
+
+	pub fn profile(&self) -> MediaProfile {
+		*self.profile.lock().unwrap()
+	}
+
+	pub fn set_profile(&self, profile: MediaProfile) {
+		*self.profile.lock().unwrap() = profile;
+	}
+
+	// Serves an operation starting at `position` in `path` and returning what it produced
+	// along with the bytes it moved. Operations without a position always seek and leave
+	// the head somewhere unknown.
+	fn serve<T, F>(&self, path: &str, position: Option<u64>, operation: F) -> Result<T, ResultCode>
+		where F: FnOnce() -> Result<(T, u64), ResultCode> {
+		let profile = self.profile();
+		let mut head = self.head.lock().unwrap();
+		let path = normalize(path);
+
+		let result = operation();
+		let bytes = result.as_ref().map_or(0, |&(_, bytes)| bytes);
+		let sequential = match (head.as_ref(), position) {
+			(Some((head_path, head_offset)), Some(offset)) => head_path == path && *head_offset == offset,
+			_ => false
+		};
+
+		let mut delay = profile.latency + profile.transfer_time(bytes);
+		if !sequential {
+			delay += profile.seek;
+		}
+		thread::sleep(delay);
+
+		*head = position.map(|offset| (path.to_string(), offset + bytes));
+		result.map(|(value, _)| value)
+	}
+
+	fn metadata<T, F: FnOnce() -> Result<T, ResultCode>>(&self, path: &str, operation: F) -> Result<T, ResultCode> {
+		self.serve(path, None, || operation().map(|value| (value, 0)))
+	}
+}
+
+impl<D: Device> Device for LatencyDevice<D> {
+	fn create(device_path: &str) -> Result<LatencyDevice<D>, ResultCode> {
+		let mut parts = device_path.splitn(2, ':');
+		let profile = parts.next().and_then(MediaProfile::from_name).ok_or(ResultCode::InvalidDevice)?;
+		Ok(LatencyDevice::new(D::create(parts.next().unwrap_or(""))?, profile))
+	}
+
+	fn file_exists(&self, path: &str) -> bool {
+		self.metadata(path, || Ok(self.inner.file_exists(path))).unwrap_or(false)
+	}
+
+	fn file_size(&self, path: &str) -> Result<u64, ResultCode> {
+		self.metadata(path, || self.inner.file_size(path))
+	}
+
+	fn read_file(&self, path: &str, offset: u64, max_bytes: u64) -> Result<Vec<u8>, ResultCode> {
+		self.serve(path, Some(offset), || self.inner.read_file(path, offset, max_bytes).map(|data| {
+			let bytes = data.len() as u64;
+			(data, bytes)
+		}))
+	}
+
+	fn write_file(&self, path: &str, offset: u64, buffer: &[u8], mode: WriteMode) -> Result<u64, ResultCode> {
+		// appends pick up at the end of the file, which only a previous append knows
+		let position = if mode == WriteMode::Segment { offset } else { 0 };
+		self.serve(path, Some(position), || self.inner.write_file(path, offset, buffer, mode).map(|written| (written, written)))
+	}
+
+	fn delete_file(&self, path: &str) -> Result<(), ResultCode> {
+		self.metadata(path, || self.inner.delete_file(path))
+	}
+
+	fn set_len(&self, path: &str, len: u64) -> Result<(), ResultCode> {
+		self.metadata(path, || self.inner.set_len(path, len))
+	}
+
+	fn sync(&self, path: &str, mode: SyncMode) -> Result<(), ResultCode> {
+		self.metadata(path, || self.inner.sync(path, mode))
+	}
+
+	fn create_dir(&self, path: &str) -> Result<(), ResultCode> {
+		self.metadata(path, || self.inner.create_dir(path))
+	}
+
+	fn delete_dir(&self, path: &str) -> Result<(), ResultCode> {
+		self.metadata(path, || self.inner.delete_dir(path))
+	}
+
+	fn list_dir(&self, path: &str) -> Result<Vec<DirEntry>, ResultCode> {
+		self.metadata(path, || self.inner.list_dir(path))
+	}
+
+	fn stat(&self, path: &str) -> Result<FileStat, ResultCode> {
+		self.metadata(path, || self.inner.stat(path))
+	}
+
+	fn backing_path(&self, path: &str) -> Option<PathBuf> {
+		self.inner.backing_path(path)
+	}
+
+	fn raw_path(&self, path: &str) -> Option<PathBuf> {
+		self.inner.raw_path(path)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::device::MemoryDevice;
+	use std::time::Instant;
+
+	#[test]
+	fn latency_device_test() {
+		assert!(LatencyDevice::<MemoryDevice>::create("floppy:").is_err());
+		let device = LatencyDevice::<MemoryDevice>::create("0/50/100000:").unwrap();
+		assert!(device.profile().bytes_per_sec == 100000);
+		device.inner.write_file("/level.bin", 0, &[0u8; 10000], WriteMode::Overwrite).unwrap();
+
+		// the first read seeks, the one following on from it doesn't
+		let started = Instant::now();
+		assert!(device.read_file("/level.bin", 0, 5000).unwrap().len() == 5000);
+		assert!(started.elapsed() >= Duration::from_millis(100));
+		let started = Instant::now();
+		assert!(device.read_file("/level.bin", 5000, 5000).unwrap().len() == 5000);
+		let elapsed = started.elapsed();
+		assert!(elapsed >= Duration::from_millis(50) && elapsed < Duration::from_millis(100));
+
+		device.set_profile(MediaProfile::FLASH);
+		assert!(device.profile() == MediaProfile::FLASH);
+	}
+}
@@ -0,0 +1,238 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use super::{child_entries, normalize, parent, DirEntry, Device, WriteMode};
+use crate::ResultCode;
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::sync::RwLock;
+
+// RAM-backed device. Every mount gets its own empty tree; the device path is ignored.
+pub struct MemoryDevice {
+	tree: RwLock<MemoryTree>
+}
+
+#[derive(Default)]
+struct MemoryTree {
+	files: HashMap<String, Vec<u8>>,
+	dirs: HashSet<String>
+}
+
+impl MemoryTree {
+	fn dir_exists(&self, path: &str) -> bool {
+		path.is_empty() || self.dirs.contains(path)
+	}
+
+	fn dir_is_empty(&self, path: &str) -> bool {
+		let prefix = format!("{}/", path);
+		!self.files.keys().chain(self.dirs.iter()).any(|p| p.starts_with(&prefix))
+	}
+}
+
+// Grows or shrinks `data` to `len`, failing with OutOfSpace rather than aborting if the
+// memory isn't there.
+fn resize(data: &mut Vec<u8>, len: usize) -> Result<(), ResultCode> {
+	if len > data.len() {
+		data.try_reserve(len - data.len()).map_err(|_| ResultCode::OutOfSpace)?;
+	}
+	data.resize(len, 0);
+	Ok(())
+}
+
+fn write_data(data: &mut Vec<u8>, offset: u64, buffer: &[u8], mode: WriteMode) -> Result<u64, ResultCode> {
+	match mode {
+		WriteMode::Overwrite | WriteMode::Atomic => {
+			data.clear();
+			resize(data, buffer.len())?;
+			data.copy_from_slice(buffer);
+		},
+		WriteMode::Append => {
+			let start = data.len();
+			resize(data, start.checked_add(buffer.len()).ok_or(ResultCode::OutOfSpace)?)?;
+			data[start..].copy_from_slice(buffer);
+		},
+		WriteMode::Segment => {
+			let start = usize::try_from(offset).map_err(|_| ResultCode::GenericError)?;
+			let end = start.checked_add(buffer.len()).ok_or(ResultCode::GenericError)?;
+			if data.len() < end {
+				resize(data, end)?;
+			}
+			data[start..end].copy_from_slice(buffer);
+		}
+	}
+
+	Ok(buffer.len() as u64)
+}
+
+impl Device for MemoryDevice {
+	fn create(_device_path: &str) -> Result<MemoryDevice, ResultCode> {
+		Ok(MemoryDevice {
+			tree: RwLock::new(MemoryTree::default())
+		})
+	}
+
+	fn file_exists(&self, path: &str) -> bool {
+		let tree = self.tree.read().unwrap();
+		let path = normalize(path);
+		tree.files.contains_key(path) || tree.dir_exists(path)
+	}
+
+	fn file_size(&self, path: &str) -> Result<u64, ResultCode> {
+		let tree = self.tree.read().unwrap();
+		match tree.files.get(normalize(path)) {
+			Some(data) => Ok(data.len() as u64),
+			None => Err(ResultCode::NotFound)
+		}
+	}
+
+	fn read_file(&self, path: &str, offset: u64, max_bytes: u64) -> Result<Vec<u8>, ResultCode> {
+		let tree = self.tree.read().unwrap();
+		let data = tree.files.get(normalize(path)).ok_or(ResultCode::NotFound)?;
+
+		let start = std::cmp::min(offset, data.len() as u64) as usize;
+		let end = std::cmp::min(offset.saturating_add(max_bytes), data.len() as u64) as usize;
+		Ok(data[start..end].to_vec())
+	}
+
+	fn write_file(&self, path: &str, offset: u64, buffer: &[u8], mode: WriteMode) -> Result<u64, ResultCode> {
+		let mut tree = self.tree.write().unwrap();
+		let path = normalize(path);
+
+		if path.is_empty() || tree.dirs.contains(path) {
+			return Err(ResultCode::GenericError);
+		}
+		if !tree.dir_exists(parent(path)) {
+			return Err(ResultCode::NotFound);
+		}
+
+		// a failed write doesn't leave an empty file behind
+		let existed = tree.files.contains_key(path);
+		let result = write_data(tree.files.entry(path.to_string()).or_default(), offset, buffer, mode);
+		if result.is_err() && !existed {
+			tree.files.remove(path);
+		}
+		result
+	}
+
+	fn delete_file(&self, path: &str) -> Result<(), ResultCode> {
+		let mut tree = self.tree.write().unwrap();
+		match tree.files.remove(normalize(path)) {
+			Some(_) => Ok(()),
+			None => Err(ResultCode::NotFound)
+		}
+	}
+
+	fn set_len(&self, path: &str, len: u64) -> Result<(), ResultCode> {
+		let mut tree = self.tree.write().unwrap();
+		match tree.files.get_mut(normalize(path)) {
+			Some(data) => resize(data, usize::try_from(len).map_err(|_| ResultCode::GenericError)?),
+			None => Err(ResultCode::NotFound)
+		}
+	}
+
+	fn create_dir(&self, path: &str) -> Result<(), ResultCode> {
+		let mut tree = self.tree.write().unwrap();
+		let path = normalize(path);
+
+		if tree.dir_exists(path) || tree.files.contains_key(path) {
+			return Err(ResultCode::AlreadyExists);
+		}
+		if !tree.dir_exists(parent(path)) {
+			return Err(ResultCode::NotFound);
+		}
+
+		tree.dirs.insert(path.to_string());
+		Ok(())
+	}
+
+	fn delete_dir(&self, path: &str) -> Result<(), ResultCode> {
+		let mut tree = self.tree.write().unwrap();
+		let path = normalize(path);
+
+		if path.is_empty() {
+			return Err(ResultCode::PermissionsError);
+		}
+		if !tree.dirs.contains(path) {
+			return Err(ResultCode::NotFound);
+		}
+		if !tree.dir_is_empty(path) {
+			return Err(ResultCode::GenericError);
+		}
+
+		tree.dirs.remove(path);
+		Ok(())
+	}
+
+	fn list_dir(&self, path: &str) -> Result<Vec<DirEntry>, ResultCode> {
+		let tree = self.tree.read().unwrap();
+		let path = normalize(path);
+		if !tree.dir_exists(path) {
+			return Err(ResultCode::NotFound);
+		}
+
+		let files = tree.files.iter().map(|(name, data)| (name.as_str(), data.len() as u64));
+		Ok(child_entries(path, files, tree.dirs.iter().map(|dir| dir.as_str())))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn memory_device_test() {
+		let device = MemoryDevice::create("").unwrap();
+		assert!(device.create_dir("/saves").is_ok());
+		assert!(device.write_file("/saves/slot0", 0, b"hello", WriteMode::Overwrite).is_ok());
+		assert!(device.write_file("/saves/slot0", 0, b" world", WriteMode::Append).is_ok());
+		assert!(device.write_file("/saves/slot0", 0, b"J", WriteMode::Segment).is_ok());
+		assert!(device.write_file("/missing/file", 0, b"x", WriteMode::Overwrite) == Err(ResultCode::NotFound));
+		assert!(device.write_file("/saves/slot1", u64::max_value(), b"x", WriteMode::Segment) == Err(ResultCode::GenericError));
+		assert!(!device.file_exists("/saves/slot1"));
+		assert!(device.write_file("/saves/slot1", 1 << 50, b"x", WriteMode::Segment).is_err());
+		assert!(!device.file_exists("/saves/slot1"));
+
+		let entries = device.list_dir("/").unwrap();
+		assert!(entries.len() == 1 && entries[0].name == "saves" && entries[0].is_dir);
+		assert!(device.list_dir("/saves").unwrap()[0].name == "slot0");
+		assert!(device.list_dir("/missing") == Err(ResultCode::NotFound));
+		assert!(device.stat("/saves").unwrap().is_dir);
+
+		assert!(device.file_exists("/saves/slot0"));
+		assert!(device.file_size("/saves/slot0") == Ok(11));
+		assert!(device.read_file("/saves/slot0", 0, u64::max_value()).unwrap() == b"Jello world");
+		assert!(device.read_file("/saves/slot0", 6, 3).unwrap() == b"wor");
+
+		assert!(device.delete_dir("/saves") == Err(ResultCode::GenericError));
+		assert!(device.set_len("/saves/slot0", 3).is_ok());
+		assert!(device.read_file("/saves/slot0", 0, 100).unwrap() == b"Jel");
+		assert!(device.set_len("/saves/slot0", 5).is_ok());
+		assert!(device.read_file("/saves/slot0", 0, 100).unwrap() == b"Jel\0\0");
+		assert!(device.set_len("/saves/slot0", 1 << 50).is_err());
+		assert!(device.file_size("/saves/slot0") == Ok(5));
+		assert!(device.set_len("/saves/missing", 0) == Err(ResultCode::NotFound));
+		assert!(device.delete_file("/saves/slot0").is_ok());
+		assert!(device.delete_dir("/saves").is_ok());
+		assert!(!device.file_exists("/saves"));
+	}
+}
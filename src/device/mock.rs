@@ -0,0 +1,222 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+use super::{normalize, DirEntry, Device, FileStat, MemoryDevice, SyncMode, WriteMode};
+use crate::ResultCode;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+// A RAM-backed device for tests, which can be scripted to fail operations on given paths
+// and to respond slowly. Reach the instance behind a mount with Mount::device, e.g.
+// mount.device::<MockDevice>().unwrap().fail("/saves/slot0", ResultCode::OutOfSpace)
+pub struct MockDevice {
+	files: MemoryDevice,
+	faults: Mutex<HashMap<String, Fault>>,
+	latency: Mutex<Duration>,
+	operations: AtomicUsize
+}
+
+struct Fault {
+	code: ResultCode,
+	// None to keep failing
+	remaining: Option<usize>
+}
+
+impl MockDevice {
+	pub fn new() -> MockDevice {
+		MockDevice {
+			files: MemoryDevice::create("").unwrap(),
+			faults: Mutex::new(HashMap::new()),
+			latency: Mutex::new(Duration::from_secs(0)),
+			operations: AtomicUsize::new(0)
+		}
+	}
+
+	// Adds or replaces a file, creating the directories leading up to it.
+	pub fn set_file(&self, path: &str, data: &[u8]) {
+		let path = normalize(path);
+		let mut dir = String::new();
+		for component in path.split('/').rev().skip(1).collect::<Vec<_>>().into_iter().rev() {
+			dir.push('/');
+			dir.push_str(component);
+			let _ = self.files.create_dir(&dir);
+		}
+		self.files.write_file(path, 0, data, WriteMode::Overwrite).unwrap();
+	}
+
+	// Fails every operation on `path` with `code` until cleared.
+	pub fn fail(&self, path: &str, code: ResultCode) {
+		self.add_fault(path, code, None);
+	}
+
+	// Fails the next `count` operations on `path` with `code`.
+	pub fn fail_times(&self, path: &str, code: ResultCode, count: usize) {
+		self.add_fault(path, code, Some(count));
+	}
+
+	fn add_fault(&self, path: &str, code: ResultCode, remaining: Option<usize>) {
+		self.faults.lock().unwrap().insert(normalize(path).to_string(), Fault {
+			code: code,
+			remaining: remaining
+		});
+	}
+
+	pub fn clear_faults(&self) {
+		self.faults.lock().unwrap().clear();
+	}
+
+	// Delays every operation by `latency`.
+	pub fn set_latency(&self, latency: Duration) {
+		*self.latency.lock().unwrap() = latency;
+	}
+
+	// How many operations reached the device, failed ones included.
+	pub fn operation_count(&self) -> usize {
+		self.operations.load(Ordering::SeqCst)
+	}
+
+	// Runs an operation on `path` after the scripted latency, unless a fault fails it.
+	fn scripted<T, F: FnOnce() -> Result<T, ResultCode>>(&self, path: &str, operation: F) -> Result<T, ResultCode> {
+		self.operations.fetch_add(1, Ordering::SeqCst);
+
+		let latency = *self.latency.lock().unwrap();
+		if latency > Duration::from_secs(0) {
+			thread::sleep(latency);
+		}
+
+		let failure = {
+			let mut faults = self.faults.lock().unwrap();
+			let path = normalize(path);
+			let (code, exhausted) = match faults.get_mut(path) {
+				Some(fault) => {
+					if let Some(ref mut remaining) = fault.remaining {
+						*remaining -= 1;
+					}
+					(Some(fault.code), fault.remaining == Some(0))
+				},
+				None => (None, false)
+			};
+			if exhausted {
+				faults.remove(path);
+			}
+			code
+		};
+
+		match failure {
+			Some(code) => Err(code),
+			None => operation()
+		}
+	}
+}
+
+impl Default for MockDevice {
+	fn default() -> MockDevice {
+		MockDevice::new()
+	}
+}
+
+impl Device for MockDevice {
+	// Every mount gets its own empty device; the device path is ignored.
+	fn create(_device_path: &str) -> Result<MockDevice, ResultCode> {
+		Ok(MockDevice::new())
+	}
+
+	fn file_exists(&self, path: &str) -> bool {
+		self.scripted(path, || Ok(self.files.file_exists(path))).unwrap_or(false)
+	}
+
+	fn file_size(&self, path: &str) -> Result<u64, ResultCode> {
+		self.scripted(path, || self.files.file_size(path))
+	}
+
+	fn read_file(&self, path: &str, offset: u64, max_bytes: u64) -> Result<Vec<u8>, ResultCode> {
+		self.scripted(path, || self.files.read_file(path, offset, max_bytes))
+	}
+
+	fn write_file(&self, path: &str, offset: u64, buffer: &[u8], mode: WriteMode) -> Result<u64, ResultCode> {
+		self.scripted(path, || self.files.write_file(path, offset, buffer, mode))
+	}
+
+	fn delete_file(&self, path: &str) -> Result<(), ResultCode> {
+		self.scripted(path, || self.files.delete_file(path))
+	}
+
+	fn set_len(&self, path: &str, len: u64) -> Result<(), ResultCode> {
+		self.scripted(path, || self.files.set_len(path, len))
+	}
+
+	fn sync(&self, path: &str, mode: SyncMode) -> Result<(), ResultCode> {
+		self.scripted(path, || self.files.sync(path, mode))
+	}
+
+	fn create_dir(&self, path: &str) -> Result<(), ResultCode> {
+		self.scripted(path, || self.files.create_dir(path))
+	}
+
+	fn delete_dir(&self, path: &str) -> Result<(), ResultCode> {
+		self.scripted(path, || self.files.delete_dir(path))
+	}
+
+	fn list_dir(&self, path: &str) -> Result<Vec<DirEntry>, ResultCode> {
+		self.scripted(path, || self.files.list_dir(path))
+	}
+
+	fn stat(&self, path: &str) -> Result<FileStat, ResultCode> {
+		self.scripted(path, || self.files.stat(path))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn mock_device_test() {
+		let device = MockDevice::new();
+		device.set_file("/saves/slot0", b"save");
+		assert!(device.read_file("/saves/slot0", 0, u64::max_value()).unwrap() == b"save");
+		assert!(device.list_dir("/saves").unwrap()[0].name == "slot0");
+
+		device.fail("/saves/slot0", ResultCode::PermissionsError);
+		assert!(device.read_file("/saves/slot0", 0, 4) == Err(ResultCode::PermissionsError));
+		assert!(device.write_file("/saves/slot0", 0, b"x", WriteMode::Overwrite) == Err(ResultCode::PermissionsError));
+		assert!(device.file_size("/saves/slot1") == Err(ResultCode::NotFound));
+		device.clear_faults();
+
+		device.fail_times("/saves/slot1", ResultCode::OutOfSpace, 2);
+		assert!(device.write_file("/saves/slot1", 0, b"x", WriteMode::Overwrite) == Err(ResultCode::OutOfSpace));
+		assert!(device.write_file("/saves/slot1", 0, b"x", WriteMode::Overwrite) == Err(ResultCode::OutOfSpace));
+		assert!(device.write_file("/saves/slot1", 0, b"x", WriteMode::Overwrite) == Ok(1));
+
+		device.set_latency(Duration::from_millis(20));
+		let started = std::time::Instant::now();
+		assert!(device.file_exists("/saves/slot1"));
+		assert!(started.elapsed() >= Duration::from_millis(20));
+		assert!(device.operation_count() == 9);
+	}
+}
@@ -0,0 +1,459 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+mod compressed;
+mod disk;
+mod gzip;
+#[cfg(feature = "encryption")]
+mod encrypted;
+#[cfg(feature = "http")]
+mod http;
+mod latency;
+mod memory;
+#[cfg(feature = "testing")]
+mod mock;
+mod overlay;
+mod pack;
+mod patch;
+mod replay;
+#[cfg(feature = "signing")]
+mod signed;
+mod tar;
+mod zip;
+
+pub use self::compressed::{CompressedDevice, CompressionAlgorithm};
+pub(crate) use self::disk::{from_io_error, long_path, DiskDevice};
+#[cfg(feature = "encryption")]
+pub use self::encrypted::{EncryptedDevice, EncryptionKey, KeyProvider};
+pub use self::gzip::{GzipDevice, GZIP_EXTENSION};
+#[cfg(feature = "http")]
+pub use self::http::HttpDevice;
+pub use self::latency::{LatencyDevice, MediaProfile};
+pub use self::memory::MemoryDevice;
+#[cfg(feature = "testing")]
+pub use self::mock::MockDevice;
+pub use self::overlay::{OverlayDevice, OVERLAY_LAYER_SEPARATOR, OVERLAY_WHITEOUT_PREFIX};
+pub use self::pack::{PackBuilder, PackCompression, PackDevice};
+pub use self::patch::{PatchDevice, PATCH_EXTENSION};
+pub use self::replay::ReplayDevice;
+#[cfg(feature = "signing")]
+pub use self::signed::{PublicKey, SignedDevice, TrustedKeys, SIGNATURE_EXTENSION, SIGNATURE_MANIFEST};
+pub use self::tar::TarDevice;
+pub use self::zip::ZipDevice;
+
+use crate::laminafs_sys;
+use crate::retry::{RetryingDevice, RetrySlot};
+use crate::ResultCode;
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_void};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WriteMode {
+	Overwrite,
+	Append,
+	Segment,
+	// Replaces the whole file like Overwrite, but so that the file is left with either its
+	// old or its new contents should the write fail or the process die partway through.
+	// Devices that can't guarantee that treat it as Overwrite.
+	Atomic
+}
+
+// How far a write is pushed towards stable storage before it's reported as done.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum SyncMode {
+	// left to the OS to write back whenever it likes
+	#[default]
+	None,
+	// the file's data, and the metadata needed to read it back, like fdatasync
+	Data,
+	// the data, all of the file's metadata and the directory entry naming it, like fsync
+	// on the file and its directory
+	Full
+}
+
+impl WriteMode {
+	fn from_lamina(mode: laminafs_sys::lfs_write_mode_t) -> WriteMode {
+		match mode {
+			laminafs_sys::lfs_write_mode_t_LFS_WRITE_MODE_APPEND => WriteMode::Append,
+			laminafs_sys::lfs_write_mode_t_LFS_WRITE_MODE_SEGMENT => WriteMode::Segment,
+			_ => WriteMode::Overwrite
+		}
+	}
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DirEntry {
+	pub name: String,
+	pub is_dir: bool,
+	pub size: u64
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FileStat {
+	pub size: u64,
+	pub modified: Option<SystemTime>,
+	pub is_dir: bool
+}
+
+// Devices implemented in Rust. Paths passed to a device are relative to the mount point.
+// The work item threads call into the device concurrently, so implementations need
+// to handle their own synchronization.
+pub trait Device: Send + Sync + 'static {
+	fn create(device_path: &str) -> Result<Self, ResultCode> where Self: Sized;
+
+	fn file_exists(&self, path: &str) -> bool;
+	fn file_size(&self, path: &str) -> Result<u64, ResultCode>;
+	fn read_file(&self, path: &str, offset: u64, max_bytes: u64) -> Result<Vec<u8>, ResultCode>;
+
+	// Reads each (offset, max_bytes) range like read_file. Devices that can serve them all
+	// from one open file should.
+	fn read_ranges(&self, path: &str, ranges: &[(u64, u64)]) -> Result<Vec<Vec<u8>>, ResultCode> {
+		ranges.iter().map(|&(offset, max_bytes)| self.read_file(path, offset, max_bytes)).collect()
+	}
+
+	fn write_file(&self, _path: &str, _offset: u64, _buffer: &[u8], _mode: WriteMode) -> Result<u64, ResultCode> {
+		Err(ResultCode::Unsupported)
+	}
+
+	// Overwrites the file with the buffers back to back. The default joins them first;
+	// devices that can write each buffer in turn should, to skip the copy.
+	fn write_gather(&self, path: &str, buffers: &[&[u8]]) -> Result<u64, ResultCode> {
+		self.write_file(path, 0, &buffers.concat(), WriteMode::Overwrite)
+	}
+
+	fn delete_file(&self, _path: &str) -> Result<(), ResultCode> {
+		Err(ResultCode::Unsupported)
+	}
+
+	// Reserves storage for at least len bytes of the file, creating it if needed, so a
+	// lack of space shows up as OutOfSpace now rather than partway through later writes.
+	// Never shrinks the file.
+	fn allocate(&self, _path: &str, _len: u64) -> Result<(), ResultCode> {
+		Err(ResultCode::Unsupported)
+	}
+
+	// Shortens or zero-extends an existing file to len bytes.
+	fn set_len(&self, _path: &str, _len: u64) -> Result<(), ResultCode> {
+		Err(ResultCode::Unsupported)
+	}
+
+	// Forces the file's written data to stable storage. Devices without storage of their
+	// own to flush can rely on the default.
+	fn sync(&self, _path: &str, _mode: SyncMode) -> Result<(), ResultCode> {
+		Ok(())
+	}
+
+	fn create_dir(&self, _path: &str) -> Result<(), ResultCode> {
+		Err(ResultCode::Unsupported)
+	}
+
+	fn delete_dir(&self, _path: &str) -> Result<(), ResultCode> {
+		Err(ResultCode::Unsupported)
+	}
+
+	fn list_dir(&self, _path: &str) -> Result<Vec<DirEntry>, ResultCode> {
+		Err(ResultCode::Unsupported)
+	}
+
+	// Devices that don't track modification times can rely on the default.
+	fn stat(&self, path: &str) -> Result<FileStat, ResultCode> {
+		match self.file_size(path) {
+			Ok(size) => Ok(FileStat {
+				size: size,
+				modified: None,
+				is_dir: false
+			}),
+			Err(ResultCode::NotFound) if self.file_exists(path) => Ok(FileStat {
+				size: 0,
+				modified: None,
+				is_dir: true
+			}),
+			Err(code) => Err(code)
+		}
+	}
+
+	// Where the file lives on disk, if anywhere: the file itself for disk-backed devices
+	// or the containing archive for archive devices.
+	fn backing_path(&self, _path: &str) -> Option<PathBuf> {
+		None
+	}
+
+	// The file on disk holding exactly the file's bytes, for devices that store files as
+	// they are. Unlike backing_path, never an archive or a transformed copy.
+	fn raw_path(&self, _path: &str) -> Option<PathBuf> {
+		None
+	}
+}
+
+pub(crate) fn normalize(path: &str) -> &str {
+	path.trim_matches('/')
+}
+
+pub(crate) fn parent(path: &str) -> &str {
+	match path.rfind('/') {
+		Some(index) => &path[..index],
+		None => ""
+	}
+}
+
+// Records every ancestor directory of an archive member so directory paths resolve.
+pub(crate) fn add_parent_dirs(dirs: &mut HashSet<String>, path: &str) {
+	let mut dir = parent(path);
+	while !dir.is_empty() && dirs.insert(dir.to_string()) {
+		dir = parent(dir);
+	}
+}
+
+// Collects the direct children of `dir` out of a device's file and directory index.
+pub(crate) fn child_entries<'a, F, D>(dir: &str, files: F, dirs: D) -> Vec<DirEntry>
+	where F: Iterator<Item = (&'a str, u64)>, D: Iterator<Item = &'a str> {
+	let is_child = |path: &str| !path.is_empty() && parent(path) == dir;
+	let name = |path: &str| path.rsplit('/').next().unwrap_or(path).to_string();
+
+	let mut entries: Vec<DirEntry> = dirs.filter(|path| is_child(path)).map(|path| DirEntry {
+		name: name(path),
+		is_dir: true,
+		size: 0
+	}).chain(files.filter(|&(path, _)| is_child(path)).map(|(path, size)| DirEntry {
+		name: name(path),
+		is_dir: false,
+		size: size
+	})).collect();
+
+	entries.sort_by(|a, b| a.name.cmp(&b.name));
+	entries
+}
+
+// Merges listings of the same directory from several layers, highest priority first.
+pub(crate) fn merge_listings<I: IntoIterator<Item = Vec<DirEntry>>>(listings: I) -> Vec<DirEntry> {
+	let mut names = HashSet::new();
+	let mut entries: Vec<DirEntry> = listings.into_iter()
+		.flat_map(|listing| listing.into_iter())
+		.filter(|entry| names.insert(entry.name.clone()))
+		.collect();
+
+	entries.sort_by(|a, b| a.name.cmp(&b.name));
+	entries
+}
+
+// A device instance created for a mount, both as a device and as its concrete type so
+// the same instance can be handed back when the mount has to be recreated.
+#[derive(Clone)]
+pub(crate) struct CreatedDevice {
+	pub(crate) device: Arc<dyn Device>,
+	instance: Arc<dyn Any + Send + Sync>,
+	pub(crate) retry: RetrySlot
+}
+
+thread_local! {
+	// The last device created on this thread. Mounts are created synchronously, so
+	// create_mount picks the device up from here to reach it from the Rust side.
+	static CREATED_DEVICE: RefCell<Option<CreatedDevice>> = RefCell::new(None);
+
+	// An existing device for the next create on this thread to use instead of a new one.
+	static REUSED_DEVICE: RefCell<Option<CreatedDevice>> = RefCell::new(None);
+}
+
+impl CreatedDevice {
+	pub(crate) fn new<D: Device>(device: Arc<D>) -> CreatedDevice {
+		CreatedDevice::with_retry(Arc::new(RetryingDevice::new(device, Arc::new(Mutex::new(None)))))
+	}
+
+	// The device as its concrete type, None if it's some other type.
+	pub(crate) fn instance<D: Device>(&self) -> Option<Arc<D>> {
+		self.instance.clone().downcast::<D>().ok()
+	}
+
+	fn with_retry<D: Device>(device: Arc<RetryingDevice<D>>) -> CreatedDevice {
+		CreatedDevice {
+			device: device.clone(),
+			instance: device.device.clone(),
+			retry: device.policy()
+		}
+	}
+}
+
+pub(crate) fn take_created_device() -> Option<CreatedDevice> {
+	CREATED_DEVICE.with(|device| device.borrow_mut().take())
+}
+
+pub(crate) fn reuse_device(device: Option<&CreatedDevice>) {
+	REUSED_DEVICE.with(|reused| *reused.borrow_mut() = device.cloned());
+}
+
+pub(crate) fn device_interface<D: Device>() -> laminafs_sys::lfs_device_interface_t {
+	laminafs_sys::lfs_device_interface_t {
+		_create: Some(create_device::<D>),
+		_destroy: Some(destroy_device::<D>),
+		_fileExists: Some(device_file_exists::<D>),
+		_fileSize: Some(device_file_size::<D>),
+		_readFile: Some(device_read_file::<D>),
+		_writeFile: Some(device_write_file::<D>),
+		_deleteFile: Some(device_delete_file::<D>),
+		_createDir: Some(device_create_dir::<D>),
+		_deleteDir: Some(device_delete_dir::<D>)
+	}
+}
+
+fn to_result_code(result: Result<(), ResultCode>) -> laminafs_sys::lfs_error_code_t {
+	match result {
+		Ok(()) => laminafs_sys::lfs_error_code_t_LFS_OK,
+		Err(code) => code.to_lamina()
+	}
+}
+
+// A panicking device must not unwind into C, so the operation fails instead.
+fn contained<T, F: FnOnce() -> T>(failed: T, f: F) -> T {
+	std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or(failed)
+}
+
+// The context's handle on a device goes through its retry policy.
+unsafe fn device_ref<'a, D: Device>(device: *mut c_void) -> &'a RetryingDevice<D> {
+	&**(device as *const Arc<RetryingDevice<D>>)
+}
+
+unsafe fn path_str<'a>(path: *const c_char) -> Result<&'a str, ResultCode> {
+	CStr::from_ptr(path).to_str().map_err(|_| ResultCode::NotFound)
+}
+
+unsafe extern "C" fn create_device<D: Device>(
+	_allocator: *mut laminafs_sys::lfs_allocator_t,
+	device_path: *const c_char,
+	device: *mut *mut c_void) -> laminafs_sys::lfs_error_code_t {
+	// a recreated mount keeps its retry policy along with the device
+	let reused = REUSED_DEVICE.with(|reused| reused.borrow_mut().take())
+		.and_then(|CreatedDevice { instance, retry, .. }| instance.downcast::<D>().ok().map(|d| (d, retry)));
+	let result = contained(Err(ResultCode::GenericError), || match reused {
+		Some((d, retry)) => Ok(Arc::new(RetryingDevice::new(d, retry))),
+		None => path_str(device_path).and_then(D::create).map(|d| Arc::new(RetryingDevice::new(Arc::new(d), Arc::new(Mutex::new(None)))))
+	});
+
+	match result {
+		Ok(d) => {
+			CREATED_DEVICE.with(|created| *created.borrow_mut() = Some(CreatedDevice::with_retry(d.clone())));
+			*device = Box::into_raw(Box::new(d)) as *mut c_void;
+			laminafs_sys::lfs_error_code_t_LFS_OK
+		},
+		Err(code) => code.to_lamina()
+	}
+}
+
+unsafe extern "C" fn destroy_device<D: Device>(device: *mut c_void) {
+	contained((), || drop(Box::from_raw(device as *mut Arc<RetryingDevice<D>>)));
+}
+
+unsafe extern "C" fn device_file_exists<D: Device>(device: *mut c_void, path: *const c_char) -> bool {
+	contained(false, || match path_str(path) {
+		Ok(path) => device_ref::<D>(device).file_exists(path),
+		Err(_) => false
+	})
+}
+
+unsafe extern "C" fn device_file_size<D: Device>(
+	device: *mut c_void,
+	path: *const c_char,
+	result_code: *mut laminafs_sys::lfs_error_code_t) -> u64 {
+	match contained(Err(ResultCode::GenericError), || path_str(path).and_then(|path| device_ref::<D>(device).file_size(path))) {
+		Ok(size) => {
+			*result_code = laminafs_sys::lfs_error_code_t_LFS_OK;
+			size
+		},
+		Err(code) => {
+			*result_code = code.to_lamina();
+			0
+		}
+	}
+}
+
+unsafe extern "C" fn device_read_file<D: Device>(
+	device: *mut c_void,
+	path: *const c_char,
+	offset: u64,
+	max_bytes: u64,
+	allocator: *mut laminafs_sys::lfs_allocator_t,
+	null_terminate: bool,
+	buffer: *mut *mut c_void,
+	bytes_read: *mut u64) -> laminafs_sys::lfs_error_code_t {
+	let data = match contained(Err(ResultCode::GenericError), || path_str(path).and_then(|path| device_ref::<D>(device).read_file(path, offset, max_bytes))) {
+		Ok(data) => data,
+		Err(code) => return code.to_lamina()
+	};
+
+	// at least a byte, as allocators may return null for an empty file's zero-sized buffer
+	let alloc_size = std::cmp::max(data.len() + if null_terminate { 1 } else { 0 }, 1);
+	let out = ((*allocator).alloc.unwrap())((*allocator).allocator, alloc_size, 1) as *mut u8;
+	if out.is_null() {
+		return laminafs_sys::lfs_error_code_t_LFS_OUT_OF_SPACE;
+	}
+
+	std::ptr::copy_nonoverlapping(data.as_ptr(), out, data.len());
+	if null_terminate {
+		*out.add(data.len()) = 0;
+	}
+
+	*buffer = out as *mut c_void;
+	*bytes_read = data.len() as u64;
+	laminafs_sys::lfs_error_code_t_LFS_OK
+}
+
+unsafe extern "C" fn device_write_file<D: Device>(
+	device: *mut c_void,
+	path: *const c_char,
+	offset: u64,
+	buffer: *const c_void,
+	buffer_bytes: u64,
+	write_mode: laminafs_sys::lfs_write_mode_t,
+	bytes_written: *mut u64) -> laminafs_sys::lfs_error_code_t {
+	let data = if buffer_bytes > 0 {
+		std::slice::from_raw_parts(buffer as *const u8, buffer_bytes as usize)
+	} else {
+		&[]
+	};
+
+	let mode = WriteMode::from_lamina(write_mode);
+	match contained(Err(ResultCode::GenericError), || path_str(path).and_then(|path| device_ref::<D>(device).write_file(path, offset, data, mode))) {
+		Ok(written) => {
+			*bytes_written = written;
+			laminafs_sys::lfs_error_code_t_LFS_OK
+		},
+		Err(code) => code.to_lamina()
+	}
+}
+
+unsafe extern "C" fn device_delete_file<D: Device>(device: *mut c_void, path: *const c_char) -> laminafs_sys::lfs_error_code_t {
+	to_result_code(contained(Err(ResultCode::GenericError), || path_str(path).and_then(|path| device_ref::<D>(device).delete_file(path))))
+}
+
+unsafe extern "C" fn device_create_dir<D: Device>(device: *mut c_void, path: *const c_char) -> laminafs_sys::lfs_error_code_t {
+	to_result_code(contained(Err(ResultCode::GenericError), || path_str(path).and_then(|path| device_ref::<D>(device).create_dir(path))))
+}
+
+unsafe extern "C" fn device_delete_dir<D: Device>(device: *mut c_void, path: *const c_char) -> laminafs_sys::lfs_error_code_t {
+	to_result_code(contained(Err(ResultCode::GenericError), || path_str(path).and_then(|path| device_ref::<D>(device).delete_dir(path))))
+}
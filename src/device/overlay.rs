@@ -0,0 +1,376 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use super::disk::DiskDevice;
+use super::{merge_listings, normalize, parent, DirEntry, Device, FileStat, PackDevice, SyncMode, TarDevice, WriteMode, ZipDevice};
+use crate::ResultCode;
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+pub const OVERLAY_LAYER_SEPARATOR: char = '|';
+pub const OVERLAY_WHITEOUT_PREFIX: &str = ".wh.";
+
+// Copy-on-write device. The device path lists the writable upper directory followed by
+// one or more read-only lower layers, highest priority first:
+//
+//   "saves/mods|data/patch.zip|data/base.lfp|data/loose"
+//
+// Lower layers are picked by extension (.zip, .tar, .lfp), anything else is treated
+// as a directory. Reads fall through the layers, writes always land in the upper layer.
+//
+// Deleting a file a lower layer has leaves a whiteout in the upper layer, an empty
+// ".wh.<name>" file in the same directory, which hides the lower file from then on.
+// Writing the file again removes the whiteout. Names starting with the prefix are
+// reserved, they're never visible through the device and can't be written.
+pub struct OverlayDevice {
+	upper: DiskDevice,
+	lower: Vec<Box<dyn Device>>,
+	copy_up_lock: Mutex<()>
+}
+
+pub(super) fn create_layer(path: &str) -> Result<Box<dyn Device>, ResultCode> {
+	let extension = std::path::Path::new(path).extension().and_then(|e| e.to_str()).unwrap_or("");
+	Ok(match extension {
+		"zip" => Box::new(ZipDevice::create(path)?),
+		"tar" => Box::new(TarDevice::create(path)?),
+		"lfp" => Box::new(PackDevice::create(path)?),
+		_ => Box::new(DiskDevice::create(path)?)
+	})
+}
+
+// The upper layer path of the whiteout hiding `path`.
+fn whiteout_path(path: &str) -> String {
+	let path = normalize(path);
+	let dir = parent(path);
+	if dir.is_empty() {
+		format!("{}{}", OVERLAY_WHITEOUT_PREFIX, path)
+	} else {
+		format!("{}/{}{}", dir, OVERLAY_WHITEOUT_PREFIX, &path[dir.len() + 1..])
+	}
+}
+
+// Whether the last component of `path` is a whiteout name.
+fn is_whiteout(path: &str) -> bool {
+	let path = normalize(path);
+	path[parent(path).len()..].trim_start_matches('/').starts_with(OVERLAY_WHITEOUT_PREFIX)
+}
+
+fn check_writable(path: &str) -> Result<(), ResultCode> {
+	if is_whiteout(path) {
+		Err(ResultCode::InvalidPath)
+	} else {
+		Ok(())
+	}
+}
+
+impl OverlayDevice {
+	fn is_whited_out(&self, path: &str) -> bool {
+		self.upper.file_exists(&whiteout_path(path))
+	}
+
+	fn clear_whiteout(&self, path: &str) -> Result<(), ResultCode> {
+		match self.upper.delete_file(&whiteout_path(path)) {
+			Ok(()) | Err(ResultCode::NotFound) => Ok(()),
+			Err(code) => Err(code)
+		}
+	}
+
+	fn lower_layer(&self, path: &str) -> Option<&dyn Device> {
+		if self.is_whited_out(path) {
+			return None;
+		}
+		self.lower.iter().map(|layer| layer.as_ref()).find(|layer| layer.file_exists(path))
+	}
+
+	fn layer(&self, path: &str) -> Option<&dyn Device> {
+		if is_whiteout(path) {
+			None
+		} else if self.upper.file_exists(path) {
+			Some(&self.upper)
+		} else {
+			self.lower_layer(path)
+		}
+	}
+
+	// Recreates the directory chain of `dir` in the upper layer if it only exists below.
+	fn copy_up_dirs(&self, dir: &str) -> Result<(), ResultCode> {
+		let dir = normalize(dir);
+		if dir.is_empty() || self.upper.file_exists(dir) {
+			return Ok(());
+		}
+		if self.lower_layer(dir).is_none() {
+			return Err(ResultCode::NotFound);
+		}
+
+		self.copy_up_dirs(parent(dir))?;
+		match self.upper.create_dir(dir) {
+			Ok(()) | Err(ResultCode::AlreadyExists) => Ok(()),
+			Err(code) => Err(code)
+		}
+	}
+
+	fn copy_up_file(&self, path: &str) -> Result<(), ResultCode> {
+		if self.upper.file_exists(path) {
+			return Ok(());
+		}
+
+		if let Some(layer) = self.lower_layer(path) {
+			let data = layer.read_file(path, 0, u64::max_value())?;
+			self.upper.write_file(path, 0, &data, WriteMode::Overwrite)?;
+		}
+		Ok(())
+	}
+}
+
+impl Device for OverlayDevice {
+	fn create(device_path: &str) -> Result<OverlayDevice, ResultCode> {
+		let mut layers = device_path.split(OVERLAY_LAYER_SEPARATOR);
+		let upper = DiskDevice::create(layers.next().unwrap_or(""))?;
+		let lower = layers.map(create_layer).collect::<Result<Vec<_>, _>>()?;
+		if lower.is_empty() {
+			return Err(ResultCode::InvalidDevice);
+		}
+
+		Ok(OverlayDevice {
+			upper: upper,
+			lower: lower,
+			copy_up_lock: Mutex::new(())
+		})
+	}
+
+	fn file_exists(&self, path: &str) -> bool {
+		self.layer(path).is_some()
+	}
+
+	fn file_size(&self, path: &str) -> Result<u64, ResultCode> {
+		self.layer(path).ok_or(ResultCode::NotFound)?.file_size(path)
+	}
+
+	fn read_file(&self, path: &str, offset: u64, max_bytes: u64) -> Result<Vec<u8>, ResultCode> {
+		self.layer(path).ok_or(ResultCode::NotFound)?.read_file(path, offset, max_bytes)
+	}
+
+	fn write_file(&self, path: &str, offset: u64, buffer: &[u8], mode: WriteMode) -> Result<u64, ResultCode> {
+		check_writable(path)?;
+		{
+			let _lock = self.copy_up_lock.lock().unwrap();
+			self.copy_up_dirs(parent(normalize(path)))?;
+			if mode == WriteMode::Append || mode == WriteMode::Segment {
+				self.copy_up_file(path)?;
+			}
+		}
+
+		let written = self.upper.write_file(path, offset, buffer, mode)?;
+		self.clear_whiteout(path)?;
+		Ok(written)
+	}
+
+	fn set_len(&self, path: &str, len: u64) -> Result<(), ResultCode> {
+		check_writable(path)?;
+		{
+			let _lock = self.copy_up_lock.lock().unwrap();
+			self.copy_up_dirs(parent(normalize(path)))?;
+			self.copy_up_file(path)?;
+		}
+
+		self.upper.set_len(path, len)
+	}
+
+	// lower layers are never written, so only the upper layer has anything to flush
+	fn sync(&self, path: &str, mode: SyncMode) -> Result<(), ResultCode> {
+		if !is_whiteout(path) && self.upper.file_exists(path) {
+			self.upper.sync(path, mode)
+		} else {
+			Ok(())
+		}
+	}
+
+	fn delete_file(&self, path: &str) -> Result<(), ResultCode> {
+		check_writable(path)?;
+		let _lock = self.copy_up_lock.lock().unwrap();
+		let in_upper = self.upper.file_exists(path);
+		let in_lower = self.lower_layer(path).is_some();
+		if !in_upper && !in_lower {
+			return Err(ResultCode::NotFound);
+		}
+
+		if in_upper {
+			self.upper.delete_file(path)?;
+		}
+		if in_lower {
+			self.copy_up_dirs(parent(normalize(path)))?;
+			self.upper.write_file(&whiteout_path(path), 0, &[], WriteMode::Overwrite)?;
+		}
+		Ok(())
+	}
+
+	fn create_dir(&self, path: &str) -> Result<(), ResultCode> {
+		check_writable(path)?;
+		if self.file_exists(path) {
+			return Err(ResultCode::AlreadyExists);
+		}
+
+		let _lock = self.copy_up_lock.lock().unwrap();
+		self.copy_up_dirs(parent(normalize(path)))?;
+		self.upper.create_dir(path)?;
+		self.clear_whiteout(path)
+	}
+
+	fn delete_dir(&self, path: &str) -> Result<(), ResultCode> {
+		check_writable(path)?;
+		if self.lower_layer(path).is_some() {
+			Err(ResultCode::PermissionsError)
+		} else {
+			self.upper.delete_dir(path)
+		}
+	}
+
+	fn list_dir(&self, path: &str) -> Result<Vec<DirEntry>, ResultCode> {
+		let mut listings = Vec::new();
+		let mut whiteouts = HashSet::new();
+		match self.upper.list_dir(path) {
+			Ok(entries) => {
+				let (markers, entries): (Vec<DirEntry>, Vec<DirEntry>) = entries.into_iter().partition(|entry| entry.name.starts_with(OVERLAY_WHITEOUT_PREFIX));
+				whiteouts.extend(markers.into_iter().map(|marker| marker.name[OVERLAY_WHITEOUT_PREFIX.len()..].to_string()));
+				listings.push(entries);
+			},
+			Err(ResultCode::NotFound) => {},
+			Err(code) => return Err(code)
+		}
+
+		for layer in self.lower.iter() {
+			match layer.list_dir(path) {
+				Ok(entries) => listings.push(entries.into_iter().filter(|entry| !whiteouts.contains(&entry.name)).collect()),
+				Err(ResultCode::NotFound) => {},
+				Err(code) => return Err(code)
+			}
+		}
+
+		if listings.is_empty() {
+			Err(ResultCode::NotFound)
+		} else {
+			Ok(merge_listings(listings))
+		}
+	}
+
+	fn stat(&self, path: &str) -> Result<FileStat, ResultCode> {
+		self.layer(path).ok_or(ResultCode::NotFound)?.stat(path)
+	}
+
+	fn backing_path(&self, path: &str) -> Option<PathBuf> {
+		self.layer(path)?.backing_path(path)
+	}
+
+	fn raw_path(&self, path: &str) -> Option<PathBuf> {
+		self.layer(path)?.raw_path(path)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_dir::TestDir;
+
+	#[test]
+	fn overlay_test() {
+		let root = TestDir::new("overlay_test");
+		std::fs::create_dir_all(root.join("base/config")).unwrap();
+		std::fs::create_dir_all(root.join("upper")).unwrap();
+		std::fs::write(root.join("base/config/game.ini"), b"volume=5").unwrap();
+
+		let device_path = format!("{}|{}", root.join("upper").to_str().unwrap(), root.join("base").to_str().unwrap());
+		let device = OverlayDevice::create(&device_path).unwrap();
+
+		assert!(device.read_file("/config/game.ini", 0, u64::max_value()).unwrap() == b"volume=5");
+		assert!(device.write_file("/config/game.ini", 0, b"\nmusic=3", WriteMode::Append).is_ok());
+		assert!(device.read_file("/config/game.ini", 0, u64::max_value()).unwrap() == b"volume=5\nmusic=3");
+		assert!(std::fs::read(root.join("base/config/game.ini")).unwrap() == b"volume=5");
+		assert!(device.list_dir("/config").unwrap().len() == 1);
+
+		assert!(device.delete_file("/config/game.ini").is_ok());
+		assert!(!device.file_exists("/config/game.ini"));
+		assert!(device.read_file("/config/game.ini", 0, u64::max_value()) == Err(ResultCode::NotFound));
+		assert!(device.list_dir("/config").unwrap().is_empty());
+		assert!(device.delete_file("/config/game.ini") == Err(ResultCode::NotFound));
+		assert!(std::fs::read(root.join("base/config/game.ini")).unwrap() == b"volume=5");
+
+		assert!(device.write_file("/config/game.ini", 0, b"volume=9", WriteMode::Overwrite).is_ok());
+		assert!(device.read_file("/config/game.ini", 0, u64::max_value()).unwrap() == b"volume=9");
+		assert!(!root.join("upper/config/.wh.game.ini").exists());
+
+		assert!(device.delete_file("/config/game.ini").is_ok());
+		assert!(root.join("upper/config/.wh.game.ini").exists());
+		assert!(!device.file_exists("/config/.wh.game.ini"));
+		assert!(device.read_file("/config/.wh.game.ini", 0, u64::max_value()) == Err(ResultCode::NotFound));
+		assert!(device.file_size("/config/.wh.game.ini") == Err(ResultCode::NotFound));
+		assert!(device.stat("/config/.wh.game.ini").is_err());
+		assert!(device.write_file("/config/.wh.game.ini", 0, b"", WriteMode::Overwrite) == Err(ResultCode::InvalidPath));
+		assert!(device.delete_file("/config/.wh.game.ini") == Err(ResultCode::InvalidPath));
+		assert!(device.create_dir("/.wh.config") == Err(ResultCode::InvalidPath));
+		assert!(!device.file_exists("/config/game.ini"));
+	}
+
+	#[test]
+	fn whiteout_test() {
+		let root = TestDir::new("whiteout_test");
+		for layer in &["upper", "mods", "base"] {
+			std::fs::create_dir_all(root.join(layer)).unwrap();
+		}
+		std::fs::create_dir_all(root.join("base/textures")).unwrap();
+		std::fs::create_dir_all(root.join("mods/textures")).unwrap();
+		std::fs::write(root.join("base/readme.txt"), b"base").unwrap();
+		std::fs::write(root.join("base/textures/rock.png"), b"base rock").unwrap();
+		std::fs::write(root.join("base/textures/tree.png"), b"base tree").unwrap();
+		std::fs::write(root.join("mods/textures/rock.png"), b"mod rock").unwrap();
+
+		let layers: Vec<_> = ["upper", "mods", "base"].iter().map(|layer| root.join(layer).to_str().unwrap().to_string()).collect();
+		let device = OverlayDevice::create(&layers.join(&OVERLAY_LAYER_SEPARATOR.to_string())).unwrap();
+		assert!(device.read_file("/textures/rock.png", 0, u64::max_value()).unwrap() == b"mod rock");
+
+		// one whiteout hides the file in every layer below, in a directory the upper layer
+		// didn't have yet
+		assert!(device.delete_file("/textures/rock.png").is_ok());
+		assert!(root.join("upper/textures/.wh.rock.png").exists());
+		assert!(!device.file_exists("/textures/rock.png"));
+		assert!(device.read_file("/textures/rock.png", 0, u64::max_value()) == Err(ResultCode::NotFound));
+		let names: Vec<_> = device.list_dir("/textures").unwrap().into_iter().map(|entry| entry.name).collect();
+		assert!(names == vec!["tree.png".to_string()]);
+
+		// and at the root
+		assert!(device.delete_file("/readme.txt").is_ok());
+		assert!(!device.file_exists("/readme.txt"));
+		assert!(device.list_dir("/").unwrap().iter().all(|entry| entry.name == "textures"));
+
+		// the markers themselves stay out of reach
+		assert!(!device.file_exists("/textures/.wh.rock.png"));
+		assert!(device.write_file("/textures/.wh.rock.png", 0, b"", WriteMode::Overwrite) == Err(ResultCode::InvalidPath));
+		assert!(device.write_file("/.wh.readme.txt", 0, b"", WriteMode::Overwrite) == Err(ResultCode::InvalidPath));
+		assert!(device.delete_file("/.wh.readme.txt") == Err(ResultCode::InvalidPath));
+		assert!(root.join("upper/.wh.readme.txt").exists());
+
+		// the lower layers are untouched
+		assert!(std::fs::read(root.join("base/textures/rock.png")).unwrap() == b"base rock");
+		assert!(std::fs::read(root.join("mods/textures/rock.png")).unwrap() == b"mod rock");
+	}
+}
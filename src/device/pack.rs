@@ -0,0 +1,425 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+// laminaFS pack format, all integers little-endian:
+//
+//   header     magic "LFSPACK\0", version u32, entry count u32, slot count u32,
+//              alignment u32, index offset u64
+//   data       entry payloads, each starting on an `alignment` boundary
+//   index      slot count x u32 (entry index + 1, 0 = empty), an open-addressed hash
+//              table keyed by FNV-1a of the normalized path,
+//              entry count x { hash u64, name offset u32, name length u32, data offset u64,
+//              stored size u64, size u64, compression u32, reserved u32 },
+//              followed by the packed entry names
+
+use super::{add_parent_dirs, child_entries, normalize, DirEntry, Device};
+use crate::ResultCode;
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+const PACK_MAGIC: &[u8; 8] = b"LFSPACK\0";
+const PACK_VERSION: u32 = 1;
+const HEADER_SIZE: usize = 32;
+const ENTRY_SIZE: usize = 48;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PackCompression {
+	None,
+	Deflate
+}
+
+impl PackCompression {
+	fn to_raw(self) -> u32 {
+		match self {
+			PackCompression::None => 0,
+			PackCompression::Deflate => 1
+		}
+	}
+
+	fn from_raw(raw: u32) -> Result<PackCompression, ResultCode> {
+		match raw {
+			0 => Ok(PackCompression::None),
+			1 => Ok(PackCompression::Deflate),
+			_ => Err(ResultCode::Unsupported)
+		}
+	}
+}
+
+fn hash_path(path: &str) -> u64 {
+	path.bytes().fold(0xcbf29ce484222325, |hash, b| (hash ^ u64::from(b)).wrapping_mul(0x100000001b3))
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+	(value + alignment - 1) / alignment * alignment
+}
+
+fn read_u32(buffer: &[u8], offset: usize) -> u32 {
+	let mut bytes = [0u8; 4];
+	bytes.copy_from_slice(&buffer[offset..offset + 4]);
+	u32::from_le_bytes(bytes)
+}
+
+fn read_u64(buffer: &[u8], offset: usize) -> u64 {
+	let mut bytes = [0u8; 8];
+	bytes.copy_from_slice(&buffer[offset..offset + 8]);
+	u64::from_le_bytes(bytes)
+}
+
+fn io_error(_: std::io::Error) -> ResultCode {
+	ResultCode::GenericError
+}
+
+struct PackBuilderEntry {
+	path: String,
+	data: Vec<u8>,
+	size: u64,
+	compression: PackCompression
+}
+
+pub struct PackBuilder {
+	alignment: u32,
+	entries: Vec<PackBuilderEntry>
+}
+
+impl PackBuilder {
+	pub fn new() -> PackBuilder {
+		PackBuilder {
+			alignment: 16,
+			entries: Vec::new()
+		}
+	}
+
+	// Alignments that aren't a power of two are rounded up to the next one.
+	pub fn alignment(mut self, alignment: u32) -> PackBuilder {
+		self.alignment = alignment.checked_next_power_of_two().unwrap_or(1 << 31);
+		self
+	}
+
+	// Fails with AlreadyExists if a file with the same path was added already.
+	pub fn add_file(&mut self, path: &str, data: &[u8], compression: PackCompression) -> Result<(), ResultCode> {
+		let path = normalize(path);
+		if self.entries.iter().any(|entry| entry.path == path) {
+			return Err(ResultCode::AlreadyExists);
+		}
+
+		let stored = match compression {
+			PackCompression::None => data.to_vec(),
+			PackCompression::Deflate => miniz_oxide::deflate::compress_to_vec(data, 6)
+		};
+
+		self.entries.push(PackBuilderEntry {
+			path: path.to_string(),
+			data: stored,
+			size: data.len() as u64,
+			compression: compression
+		});
+		Ok(())
+	}
+
+	pub fn write<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+		let alignment = u64::from(self.alignment);
+		let slot_count = std::cmp::max(self.entries.len() * 2, 1).next_power_of_two();
+
+		// lay out the data section
+		let mut data_offsets = Vec::with_capacity(self.entries.len());
+		let mut offset = HEADER_SIZE as u64;
+		for entry in &self.entries {
+			offset = align_up(offset, alignment);
+			data_offsets.push(offset);
+			offset += entry.data.len() as u64;
+		}
+		let index_offset = offset;
+
+		let mut slots = vec![0u32; slot_count];
+		for (i, entry) in self.entries.iter().enumerate() {
+			let mut slot = hash_path(&entry.path) as usize & (slot_count - 1);
+			while slots[slot] != 0 {
+				slot = (slot + 1) & (slot_count - 1);
+			}
+			slots[slot] = i as u32 + 1;
+		}
+
+		writer.write_all(PACK_MAGIC)?;
+		writer.write_all(&PACK_VERSION.to_le_bytes())?;
+		writer.write_all(&(self.entries.len() as u32).to_le_bytes())?;
+		writer.write_all(&(slot_count as u32).to_le_bytes())?;
+		writer.write_all(&self.alignment.to_le_bytes())?;
+		writer.write_all(&index_offset.to_le_bytes())?;
+
+		let mut position = HEADER_SIZE as u64;
+		for (entry, &data_offset) in self.entries.iter().zip(data_offsets.iter()) {
+			writer.write_all(&vec![0u8; (data_offset - position) as usize])?;
+			writer.write_all(&entry.data)?;
+			position = data_offset + entry.data.len() as u64;
+		}
+
+		for slot in &slots {
+			writer.write_all(&slot.to_le_bytes())?;
+		}
+
+		let mut name_offset = 0u32;
+		for (entry, &data_offset) in self.entries.iter().zip(data_offsets.iter()) {
+			writer.write_all(&hash_path(&entry.path).to_le_bytes())?;
+			writer.write_all(&name_offset.to_le_bytes())?;
+			writer.write_all(&(entry.path.len() as u32).to_le_bytes())?;
+			writer.write_all(&data_offset.to_le_bytes())?;
+			writer.write_all(&(entry.data.len() as u64).to_le_bytes())?;
+			writer.write_all(&entry.size.to_le_bytes())?;
+			writer.write_all(&entry.compression.to_raw().to_le_bytes())?;
+			writer.write_all(&0u32.to_le_bytes())?;
+			name_offset += entry.path.len() as u32;
+		}
+
+		for entry in &self.entries {
+			writer.write_all(entry.path.as_bytes())?;
+		}
+
+		Ok(())
+	}
+
+	pub fn write_to_file(&self, path: &str) -> std::io::Result<()> {
+		let mut file = std::io::BufWriter::new(File::create(path)?);
+		self.write(&mut file)?;
+		file.flush()
+	}
+}
+
+impl Default for PackBuilder {
+	fn default() -> PackBuilder {
+		PackBuilder::new()
+	}
+}
+
+struct PackEntry {
+	hash: u64,
+	name: String,
+	data_offset: u64,
+	stored_size: u64,
+	size: u64,
+	compression: PackCompression
+}
+
+// Read-only device serving a pack built with `PackBuilder`.
+pub struct PackDevice {
+	pack_path: PathBuf,
+	slots: Vec<u32>,
+	entries: Vec<PackEntry>,
+	dirs: HashSet<String>
+}
+
+impl PackDevice {
+	fn find(&self, path: &str) -> Option<&PackEntry> {
+		let path = normalize(path);
+		let hash = hash_path(path);
+		let mask = self.slots.len() - 1;
+
+		// bounded, as a corrupt index may have no empty slot to stop at
+		let mut slot = hash as usize & mask;
+		for _ in 0..self.slots.len() {
+			match self.slots[slot] {
+				0 => return None,
+				index => {
+					let entry = &self.entries[index as usize - 1];
+					if entry.hash == hash && entry.name == path {
+						return Some(entry);
+					}
+				}
+			}
+			slot = (slot + 1) & mask;
+		}
+		None
+	}
+}
+
+impl Device for PackDevice {
+	fn create(device_path: &str) -> Result<PackDevice, ResultCode> {
+		let mut file = File::open(device_path).map_err(|_| ResultCode::NotFound)?;
+
+		let mut header = [0u8; HEADER_SIZE];
+		file.read_exact(&mut header).map_err(io_error)?;
+		if &header[0..8] != PACK_MAGIC || read_u32(&header, 8) != PACK_VERSION {
+			return Err(ResultCode::GenericError);
+		}
+
+		let entry_count = read_u32(&header, 12) as usize;
+		let slot_count = read_u32(&header, 16) as usize;
+		let index_offset = read_u64(&header, 24);
+		if !slot_count.is_power_of_two() || slot_count <= entry_count {
+			return Err(ResultCode::GenericError);
+		}
+
+		let mut index = Vec::new();
+		file.seek(SeekFrom::Start(index_offset)).map_err(io_error)?;
+		file.read_to_end(&mut index).map_err(io_error)?;
+
+		let names_offset = slot_count * 4 + entry_count * ENTRY_SIZE;
+		if index.len() < names_offset {
+			return Err(ResultCode::GenericError);
+		}
+
+		let slots: Vec<u32> = (0..slot_count).map(|i| read_u32(&index, i * 4)).collect();
+		if slots.iter().any(|&slot| slot as usize > entry_count) {
+			return Err(ResultCode::GenericError);
+		}
+
+		let mut entries = Vec::with_capacity(entry_count);
+		let mut dirs = HashSet::new();
+		for i in 0..entry_count {
+			let base = slot_count * 4 + i * ENTRY_SIZE;
+			let name_start = names_offset + read_u32(&index, base + 8) as usize;
+			let name_end = name_start + read_u32(&index, base + 12) as usize;
+			if name_end > index.len() {
+				return Err(ResultCode::GenericError);
+			}
+
+			let name = String::from_utf8(index[name_start..name_end].to_vec()).map_err(|_| ResultCode::GenericError)?;
+			add_parent_dirs(&mut dirs, &name);
+
+			entries.push(PackEntry {
+				hash: read_u64(&index, base),
+				name: name,
+				data_offset: read_u64(&index, base + 16),
+				stored_size: read_u64(&index, base + 24),
+				size: read_u64(&index, base + 32),
+				compression: PackCompression::from_raw(read_u32(&index, base + 40))?
+			});
+		}
+
+		Ok(PackDevice {
+			pack_path: PathBuf::from(device_path),
+			slots: slots,
+			entries: entries,
+			dirs: dirs
+		})
+	}
+
+	fn file_exists(&self, path: &str) -> bool {
+		let normalized = normalize(path);
+		normalized.is_empty() || self.dirs.contains(normalized) || self.find(path).is_some()
+	}
+
+	fn file_size(&self, path: &str) -> Result<u64, ResultCode> {
+		self.find(path).map(|entry| entry.size).ok_or(ResultCode::NotFound)
+	}
+
+	fn read_file(&self, path: &str, offset: u64, max_bytes: u64) -> Result<Vec<u8>, ResultCode> {
+		let entry = self.find(path).ok_or(ResultCode::NotFound)?;
+		let start = std::cmp::min(offset, entry.size);
+		let end = std::cmp::min(offset.saturating_add(max_bytes), entry.size);
+
+		let mut file = File::open(&self.pack_path).map_err(io_error)?;
+		match entry.compression {
+			PackCompression::None => {
+				let mut data = vec![0u8; (end - start) as usize];
+				file.seek(SeekFrom::Start(entry.data_offset + start)).map_err(io_error)?;
+				file.read_exact(&mut data).map_err(io_error)?;
+				Ok(data)
+			},
+			PackCompression::Deflate => {
+				let mut stored = vec![0u8; entry.stored_size as usize];
+				file.seek(SeekFrom::Start(entry.data_offset)).map_err(io_error)?;
+				file.read_exact(&mut stored).map_err(io_error)?;
+
+				let data = miniz_oxide::inflate::decompress_to_vec(&stored).map_err(|_| ResultCode::GenericError)?;
+				if data.len() as u64 != entry.size {
+					return Err(ResultCode::GenericError);
+				}
+				Ok(data[start as usize..end as usize].to_vec())
+			}
+		}
+	}
+
+	fn list_dir(&self, path: &str) -> Result<Vec<DirEntry>, ResultCode> {
+		let path = normalize(path);
+		if !path.is_empty() && !self.dirs.contains(path) {
+			return Err(ResultCode::NotFound);
+		}
+
+		let files = self.entries.iter().map(|entry| (entry.name.as_str(), entry.size));
+		Ok(child_entries(path, files, self.dirs.iter().map(|dir| dir.as_str())))
+	}
+
+	fn backing_path(&self, _path: &str) -> Option<PathBuf> {
+		Some(self.pack_path.clone())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_dir::TestDir;
+
+	#[test]
+	fn pack_round_trip_test() {
+		let root = TestDir::new("pack_round_trip_test");
+		let pack_path = root.join("assets.lfp");
+		let pack_path = pack_path.to_str().unwrap();
+
+		let mut builder = PackBuilder::new().alignment(4000);
+		builder.add_file("/shaders/basic.vert", b"void main() {}", PackCompression::None).unwrap();
+		builder.add_file("/levels/0/chunk.bin", &[7u8; 1000], PackCompression::None).unwrap();
+		builder.add_file("/levels/0/script.lua", b"print('level 0')", PackCompression::Deflate).unwrap();
+		assert!(builder.add_file("levels/0/chunk.bin", b"", PackCompression::None) == Err(ResultCode::AlreadyExists));
+		builder.write_to_file(pack_path).unwrap();
+
+		let device = PackDevice::create(pack_path).unwrap();
+		assert!(device.file_exists("/shaders"));
+		assert!(device.file_exists("/levels/0/chunk.bin"));
+		assert!(!device.file_exists("/levels/1/chunk.bin"));
+		assert!(device.file_size("/levels/0/chunk.bin") == Ok(1000));
+		assert!(device.read_file("/shaders/basic.vert", 5, 4).unwrap() == b"main");
+		assert!(device.entries.iter().all(|entry| entry.data_offset % 4096 == 0));
+		assert!(device.file_size("/levels/0/script.lua") == Ok(16));
+		assert!(device.read_file("/levels/0/script.lua", 0, u64::max_value()).unwrap() == b"print('level 0')");
+		assert!(device.read_file("/levels/0/script.lua", 7, 7).unwrap() == b"level 0");
+	}
+
+	#[test]
+	fn corrupt_index_test() {
+		let root = TestDir::new("corrupt_index_test");
+		let mut builder = PackBuilder::new();
+		builder.add_file("/config.ini", b"volume=5", PackCompression::None).unwrap();
+		let mut pack = Vec::new();
+		builder.write(&mut pack).unwrap();
+		let slots_start = read_u64(&pack, 24) as usize;
+		let slot_count = read_u32(&pack, 16) as usize;
+
+		// a slot pointing past the entries
+		let mut corrupt = pack.clone();
+		corrupt[slots_start..slots_start + 4].copy_from_slice(&2u32.to_le_bytes());
+		std::fs::write(root.join("past_end.lfp"), &corrupt).unwrap();
+		assert!(PackDevice::create(root.join("past_end.lfp").to_str().unwrap()).err() == Some(ResultCode::GenericError));
+
+		// no empty slot to end a lookup
+		let mut corrupt = pack.clone();
+		for slot in 0..slot_count {
+			corrupt[slots_start + slot * 4..slots_start + slot * 4 + 4].copy_from_slice(&1u32.to_le_bytes());
+		}
+		std::fs::write(root.join("full.lfp"), &corrupt).unwrap();
+		let device = PackDevice::create(root.join("full.lfp").to_str().unwrap()).unwrap();
+		assert!(device.read_file("/config.ini", 0, u64::max_value()).unwrap() == b"volume=5");
+		assert!(!device.file_exists("/missing.ini"));
+	}
+}
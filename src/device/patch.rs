@@ -0,0 +1,312 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// Applies binary patches on top of another device, so a small downloadable update can
+// change files in a large shipped pack without a patched copy ever being written out.
+// The device path names the patch layer followed by the base device's path, e.g.
+// "update1.zip|data/base.lfp". The patch layer is picked by extension like overlay
+// layers. For each file it holds either a patch, stored as the file's path plus
+// PATCH_EXTENSION, or a complete replacement under the file's own path.
+//
+// Patches use the raw bsdiff format, a sequence of records, all integers 8 byte
+// sign-magnitude little-endian:
+//
+//   control    add length, copy length, seek length
+//   diff       add length bytes, added bytewise to the old file at the current position
+//   extra      copy length bytes, copied as they are
+//
+// after which the old file position advances by the add length plus the seek length.
+// With the zstd feature, patches may also be stored zstd-compressed.
+
+use super::overlay::create_layer;
+use super::{merge_listings, normalize, DirEntry, Device, FileStat};
+use crate::ResultCode;
+
+use std::path::PathBuf;
+
+pub const PATCH_EXTENSION: &str = ".bsdiff";
+const PATCH_LAYER_SEPARATOR: char = '|';
+const CONTROL_SIZE: usize = 24;
+
+#[cfg(feature = "zstd")]
+const ZSTD_MAGIC: &[u8; 4] = b"\x28\xb5\x2f\xfd";
+
+fn read_offset(buffer: &[u8]) -> i64 {
+	let mut bytes = [0u8; 8];
+	bytes.copy_from_slice(&buffer[..8]);
+	let magnitude = (u64::from_le_bytes(bytes) & !(1 << 63)) as i64;
+	if bytes[7] & 0x80 != 0 { -magnitude } else { magnitude }
+}
+
+struct Control {
+	add: usize,
+	copy: usize,
+	seek: i64
+}
+
+// Walks the patch's records, handing each control along with its diff and extra bytes.
+fn for_each_record<F>(patch: &[u8], mut f: F) -> Result<(), ResultCode>
+	where F: FnMut(&Control, &[u8], &[u8]) -> Result<(), ResultCode> {
+	let mut position = 0;
+	while position < patch.len() {
+		let control = patch.get(position..position + CONTROL_SIZE).ok_or(ResultCode::GenericError)?;
+		let (add, copy) = (read_offset(&control[0..8]), read_offset(&control[8..16]));
+		if add < 0 || copy < 0 {
+			return Err(ResultCode::GenericError);
+		}
+		let control = Control {
+			add: add as usize,
+			copy: copy as usize,
+			seek: read_offset(&control[16..24])
+		};
+		position += CONTROL_SIZE;
+
+		let diff = patch.get(position..position + control.add).ok_or(ResultCode::GenericError)?;
+		position += control.add;
+		let extra = patch.get(position..position + control.copy).ok_or(ResultCode::GenericError)?;
+		position += control.copy;
+
+		f(&control, diff, extra)?;
+	}
+	Ok(())
+}
+
+fn patched_size(patch: &[u8]) -> Result<u64, ResultCode> {
+	let mut size = 0;
+	for_each_record(patch, |control, _, _| {
+		size += (control.add + control.copy) as u64;
+		Ok(())
+	})?;
+	Ok(size)
+}
+
+fn apply_patch(old: &[u8], patch: &[u8]) -> Result<Vec<u8>, ResultCode> {
+	let mut new = Vec::new();
+	let mut old_position: i64 = 0;
+	for_each_record(patch, |control, diff, extra| {
+		let start = old_position as usize;
+		let old = old_position.checked_add(control.add as i64)
+			.and_then(|end| old.get(start..end as usize))
+			.filter(|_| old_position >= 0)
+			.ok_or(ResultCode::GenericError)?;
+		new.extend(old.iter().zip(diff).map(|(&old, &diff)| old.wrapping_add(diff)));
+		new.extend_from_slice(extra);
+		old_position += control.add as i64 + control.seek;
+		Ok(())
+	})?;
+	Ok(new)
+}
+
+pub struct PatchDevice<D: Device> {
+	base: D,
+	patches: Box<dyn Device>
+}
+
+impl<D: Device> PatchDevice<D> {
+	fn patch_path(path: &str) -> String {
+		format!("{}{}", normalize(path), PATCH_EXTENSION)
+	}
+
+	fn is_replaced(&self, path: &str) -> bool {
+		match self.patches.stat(path) {
+			Ok(stat) => !stat.is_dir,
+			Err(_) => false
+		}
+	}
+
+	fn read_patch(&self, path: &str) -> Result<Option<Vec<u8>>, ResultCode> {
+		let patch = match self.patches.read_file(&PatchDevice::<D>::patch_path(path), 0, u64::max_value()) {
+			Ok(patch) => patch,
+			Err(ResultCode::NotFound) => return Ok(None),
+			Err(code) => return Err(code)
+		};
+
+		#[cfg(feature = "zstd")]
+		{
+			if patch.starts_with(ZSTD_MAGIC) {
+				return zstd::decode_all(&patch[..]).map(Some).map_err(|_| ResultCode::GenericError);
+			}
+		}
+		Ok(Some(patch))
+	}
+
+	// A patch against a file missing from the base adds the file.
+	fn read_patched(&self, path: &str, patch: &[u8]) -> Result<Vec<u8>, ResultCode> {
+		let old = match self.base.read_file(path, 0, u64::max_value()) {
+			Ok(old) => old,
+			Err(ResultCode::NotFound) => Vec::new(),
+			Err(code) => return Err(code)
+		};
+		apply_patch(&old, patch)
+	}
+}
+
+impl<D: Device> Device for PatchDevice<D> {
+	fn create(device_path: &str) -> Result<PatchDevice<D>, ResultCode> {
+		let mut parts = device_path.splitn(2, PATCH_LAYER_SEPARATOR);
+		let patches = create_layer(parts.next().unwrap_or(""))?;
+		let base = D::create(parts.next().ok_or(ResultCode::InvalidDevice)?)?;
+
+		Ok(PatchDevice {
+			base: base,
+			patches: patches
+		})
+	}
+
+	fn file_exists(&self, path: &str) -> bool {
+		self.base.file_exists(path) || self.patches.file_exists(path) || self.patches.file_exists(&PatchDevice::<D>::patch_path(path))
+	}
+
+	fn file_size(&self, path: &str) -> Result<u64, ResultCode> {
+		if self.is_replaced(path) {
+			return self.patches.file_size(path);
+		}
+		match self.read_patch(path)? {
+			Some(patch) => patched_size(&patch),
+			None => self.base.file_size(path)
+		}
+	}
+
+	fn read_file(&self, path: &str, offset: u64, max_bytes: u64) -> Result<Vec<u8>, ResultCode> {
+		if self.is_replaced(path) {
+			return self.patches.read_file(path, offset, max_bytes);
+		}
+
+		let patch = match self.read_patch(path)? {
+			Some(patch) => patch,
+			None => return self.base.read_file(path, offset, max_bytes)
+		};
+
+		let data = self.read_patched(path, &patch)?;
+		let start = std::cmp::min(offset, data.len() as u64) as usize;
+		let end = std::cmp::min(offset.saturating_add(max_bytes), data.len() as u64) as usize;
+		Ok(data[start..end].to_vec())
+	}
+
+	fn list_dir(&self, path: &str) -> Result<Vec<DirEntry>, ResultCode> {
+		let base = match self.base.list_dir(path) {
+			Ok(entries) => entries,
+			Err(ResultCode::NotFound) => Vec::new(),
+			Err(code) => return Err(code)
+		};
+		let patches = match self.patches.list_dir(path) {
+			Ok(entries) => entries,
+			Err(ResultCode::NotFound) if !base.is_empty() || self.base.file_exists(path) => Vec::new(),
+			Err(code) => return Err(code)
+		};
+
+		// patches show up under the name of the file they patch
+		let patches = patches.into_iter().map(|mut entry| {
+			if !entry.is_dir && entry.name.ends_with(PATCH_EXTENSION) {
+				entry.name.truncate(entry.name.len() - PATCH_EXTENSION.len());
+				let file_path = format!("{}/{}", normalize(path), entry.name);
+				entry.size = self.file_size(&file_path).unwrap_or(entry.size);
+			}
+			entry
+		}).collect();
+		Ok(merge_listings(vec![patches, base]))
+	}
+
+	fn stat(&self, path: &str) -> Result<FileStat, ResultCode> {
+		if self.is_replaced(path) {
+			return self.patches.stat(path);
+		}
+		match self.read_patch(path)? {
+			Some(patch) => Ok(FileStat {
+				size: patched_size(&patch)?,
+				modified: self.patches.stat(&PatchDevice::<D>::patch_path(path))?.modified,
+				is_dir: false
+			}),
+			None => self.base.stat(path)
+		}
+	}
+
+	fn backing_path(&self, path: &str) -> Option<PathBuf> {
+		if self.is_replaced(path) {
+			self.patches.backing_path(path)
+		} else if self.patches.file_exists(&PatchDevice::<D>::patch_path(path)) {
+			self.patches.backing_path(&PatchDevice::<D>::patch_path(path))
+		} else {
+			self.base.backing_path(path)
+		}
+	}
+
+	fn raw_path(&self, path: &str) -> Option<PathBuf> {
+		if self.is_replaced(path) {
+			self.patches.raw_path(path)
+		} else if self.patches.file_exists(&PatchDevice::<D>::patch_path(path)) {
+			None
+		} else {
+			self.base.raw_path(path)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::device::{MemoryDevice, WriteMode};
+
+	fn control(add: i64, copy: i64, seek: i64) -> Vec<u8> {
+		[add, copy, seek].iter().flat_map(|&value| {
+			let mut bytes = value.abs().to_le_bytes();
+			if value < 0 {
+				bytes[7] |= 0x80;
+			}
+			bytes.to_vec()
+		}).collect()
+	}
+
+	#[test]
+	fn patch_device_test() {
+		let device = PatchDevice {
+			base: MemoryDevice::create("").unwrap(),
+			patches: Box::new(MemoryDevice::create("").unwrap())
+		};
+		assert!(device.base.create_dir("/levels").is_ok());
+		assert!(device.patches.create_dir("/levels").is_ok());
+		assert!(device.base.write_file("/levels/one.txt", 0, b"hello world", WriteMode::Overwrite).is_ok());
+		assert!(device.base.write_file("/levels/two.txt", 0, b"untouched", WriteMode::Overwrite).is_ok());
+
+		// keep "hello", append "!", then jump back over " world" to repeat "hello"
+		let mut patch = control(5, 1, -5);
+		patch.extend_from_slice(&[0; 5]);
+		patch.push(b'!');
+		patch.extend(control(5, 0, 0));
+		patch.extend_from_slice(&[0, 0, 0, 0, 1]);
+		assert!(device.patches.write_file("/levels/one.txt.bsdiff", 0, &patch, WriteMode::Overwrite).is_ok());
+		assert!(device.patches.write_file("/levels/three.txt", 0, b"new", WriteMode::Overwrite).is_ok());
+
+		assert!(device.read_file("/levels/one.txt", 0, u64::max_value()).unwrap() == b"hello!hellp");
+		assert!(device.read_file("/levels/one.txt", 6, 3).unwrap() == b"hel");
+		assert!(device.file_size("/levels/one.txt") == Ok(11));
+		assert!(device.read_file("/levels/two.txt", 0, u64::max_value()).unwrap() == b"untouched");
+		assert!(device.read_file("/levels/three.txt", 0, u64::max_value()).unwrap() == b"new");
+
+		let names: Vec<_> = device.list_dir("/levels").unwrap().into_iter().map(|entry| (entry.name, entry.size)).collect();
+		assert!(names == vec![("one.txt".to_string(), 11), ("three.txt".to_string(), 3), ("two.txt".to_string(), 9)]);
+
+		assert!(device.patches.write_file("/levels/two.txt.bsdiff", 0, &control(64, 0, 0), WriteMode::Overwrite).is_ok());
+		assert!(device.read_file("/levels/two.txt", 0, u64::max_value()) == Err(ResultCode::GenericError));
+	}
+}
@@ -0,0 +1,154 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+use super::{normalize, Device};
+use crate::{OperationKind, RecordedOp, Recording, ResultCode};
+
+use std::collections::HashMap;
+use std::thread;
+
+// Serves reads from a recording made with LaminaFS::start_recording(true), taking as long
+// as they took when recorded, so loads can be benchmarked or reproduced without the
+// original files. The device path is the recording's file. Recorded paths are the paths
+// work was submitted with, so the device is meant to be mounted at "/". Anything the
+// recording didn't see reading is NotFound, and the device is read-only.
+pub struct ReplayDevice {
+	// the recorded reads and existence checks, by path
+	ops: HashMap<String, Vec<RecordedOp>>
+}
+
+impl ReplayDevice {
+	pub fn new(recording: Recording) -> ReplayDevice {
+		let mut ops: HashMap<String, Vec<RecordedOp>> = HashMap::new();
+		for op in recording.ops {
+			match op.operation {
+				OperationKind::ReadFile | OperationKind::ReadFileSegment | OperationKind::FileExists => {
+					ops.entry(normalize(&op.path).to_string()).or_default().push(op);
+				},
+				_ => {}
+			}
+		}
+
+		ReplayDevice {
+			ops: ops
+		}
+	}
+
+	fn recorded(&self, path: &str) -> &[RecordedOp] {
+		self.ops.get(normalize(path)).map_or(&[], |ops| &ops[..])
+	}
+
+	// The recorded whole-file read of `path` that returned data.
+	fn whole_file(&self, path: &str) -> Option<&RecordedOp> {
+		self.recorded(path).iter().find(|op| op.operation == OperationKind::ReadFile && op.data.is_some())
+	}
+}
+
+impl Device for ReplayDevice {
+	fn create(device_path: &str) -> Result<ReplayDevice, ResultCode> {
+		Ok(ReplayDevice::new(Recording::read_from_file(device_path)?))
+	}
+
+	fn file_exists(&self, path: &str) -> bool {
+		self.recorded(path).iter().any(|op| op.result == ResultCode::Ok)
+	}
+
+	fn file_size(&self, path: &str) -> Result<u64, ResultCode> {
+		match self.whole_file(path) {
+			Some(op) => Ok(op.data.as_ref().unwrap().len() as u64),
+			None => Err(ResultCode::NotFound)
+		}
+	}
+
+	fn read_file(&self, path: &str, offset: u64, max_bytes: u64) -> Result<Vec<u8>, ResultCode> {
+		let recorded = self.recorded(path);
+		let segment = recorded.iter().find(|op| op.operation == OperationKind::ReadFileSegment && op.offset == offset && op.data.is_some());
+		let (op, data) = match (self.whole_file(path), segment) {
+			(Some(op), _) => {
+				let data = op.data.as_ref().unwrap();
+				let start = std::cmp::min(offset, data.len() as u64) as usize;
+				let end = std::cmp::min(offset.saturating_add(max_bytes), data.len() as u64) as usize;
+				(op, data[start..end].to_vec())
+			},
+			(None, Some(op)) => {
+				let data = op.data.as_ref().unwrap();
+				(op, data[..std::cmp::min(max_bytes, data.len() as u64) as usize].to_vec())
+			},
+			(None, None) => {
+				// reads that failed when recorded fail the same way
+				let failed = recorded.iter().find(|op| op.operation != OperationKind::FileExists && op.result != ResultCode::Ok);
+				return match failed {
+					Some(op) => {
+						thread::sleep(op.duration);
+						Err(op.result)
+					},
+					None => Err(ResultCode::NotFound)
+				};
+			}
+		};
+
+		thread::sleep(op.duration);
+		Ok(data)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::Duration;
+
+	fn read(operation: OperationKind, path: &str, offset: u64, result: ResultCode, data: Option<&[u8]>) -> RecordedOp {
+		RecordedOp {
+			operation: operation,
+			path: path.to_string(),
+			offset: offset,
+			length: 0,
+			submitted: Duration::from_secs(0),
+			duration: Duration::from_millis(1),
+			result: result,
+			bytes: data.map_or(0, |data| data.len() as u64),
+			data: data.map(|data| data.to_vec())
+		}
+	}
+
+	#[test]
+	fn replay_device_test() {
+		let device = ReplayDevice::new(Recording {
+			ops: vec![
+				read(OperationKind::ReadFile, "/config.ini", 0, ResultCode::Ok, Some(b"[video]")),
+				read(OperationKind::ReadFileSegment, "/levels/1.bin", 1024, ResultCode::Ok, Some(b"tiles")),
+				read(OperationKind::ReadFile, "/save.dat", 0, ResultCode::PermissionsError, None)
+			]
+		});
+
+		assert!(device.file_exists("/config.ini"));
+		assert!(device.file_size("/config.ini") == Ok(7));
+		assert!(device.read_file("/config.ini", 1, 5).unwrap() == b"video");
+		assert!(device.read_file("/levels/1.bin", 1024, 4).unwrap() == b"tile");
+		assert!(device.read_file("/levels/1.bin", 0, 4) == Err(ResultCode::NotFound));
+		assert!(device.read_file("/save.dat", 0, 4) == Err(ResultCode::PermissionsError));
+		assert!(!device.file_exists("/save.dat"));
+		assert!(device.write_file("/save.dat", 0, b"x", crate::device::WriteMode::Overwrite) == Err(ResultCode::Unsupported));
+	}
+}
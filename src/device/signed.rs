@@ -0,0 +1,215 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// Wraps another device, checking every read against an ed25519 signature of the whole
+// file before handing out any of it. The device path is the key id followed by the inner
+// device's path, e.g. "release:./data". Signatures come from SIGNATURE_MANIFEST at the
+// inner device's root if it lists the file, with one "<signature in hex> <path>" line
+// per file, or otherwise from a sidecar holding the raw 64 bytes, stored as the file's
+// path plus SIGNATURE_EXTENSION. Files with a missing or mismatching signature are
+// reported as `PermissionsError`. The device is read-only.
+
+use super::{normalize, DirEntry, Device, FileStat};
+use crate::ResultCode;
+
+use ed25519_dalek::{Signature, VerifyingKey};
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+pub const SIGNATURE_MANIFEST: &str = "signatures.txt";
+pub const SIGNATURE_EXTENSION: &str = ".sig";
+const SIGNATURE_SIZE: usize = 64;
+
+pub type PublicKey = [u8; 32];
+
+// Supplies the public keys for a `SignedDevice`. The provider is created from the key id
+// in the device path and asked for the key of every file it verifies.
+pub trait TrustedKeys: Send + Sync + 'static {
+	fn create(key_id: &str) -> Result<Self, ResultCode> where Self: Sized;
+	fn public_key(&self, path: &str) -> Result<PublicKey, ResultCode>;
+}
+
+fn parse_signature(hex: &str) -> Option<[u8; SIGNATURE_SIZE]> {
+	if hex.len() != SIGNATURE_SIZE * 2 || !hex.is_ascii() {
+		return None;
+	}
+	let mut signature = [0u8; SIGNATURE_SIZE];
+	for (i, byte) in signature.iter_mut().enumerate() {
+		*byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+	}
+	Some(signature)
+}
+
+fn is_signature(name: &str) -> bool {
+	name.ends_with(SIGNATURE_EXTENSION) || name == SIGNATURE_MANIFEST
+}
+
+fn parse_manifest(manifest: &[u8]) -> Result<HashMap<String, [u8; SIGNATURE_SIZE]>, ResultCode> {
+	let manifest = std::str::from_utf8(manifest).map_err(|_| ResultCode::InvalidData)?;
+	let mut signatures = HashMap::new();
+	for line in manifest.lines().map(|line| line.trim()).filter(|line| !line.is_empty()) {
+		let mut parts = line.splitn(2, char::is_whitespace);
+		let signature = parts.next().and_then(parse_signature).ok_or(ResultCode::InvalidData)?;
+		let path = parts.next().map(|path| normalize(path.trim())).ok_or(ResultCode::InvalidData)?;
+		signatures.insert(path.to_string(), signature);
+	}
+	Ok(signatures)
+}
+
+pub struct SignedDevice<D: Device, K: TrustedKeys> {
+	inner: D,
+	keys: K,
+	// from SIGNATURE_MANIFEST, by normalized path
+	manifest: HashMap<String, [u8; SIGNATURE_SIZE]>
+}
+
+impl<D: Device, K: TrustedKeys> SignedDevice<D, K> {
+	fn signature(&self, path: &str) -> Result<[u8; SIGNATURE_SIZE], ResultCode> {
+		if let Some(signature) = self.manifest.get(normalize(path)) {
+			return Ok(*signature);
+		}
+
+		let sidecar = format!("{}{}", path.trim_end_matches('/'), SIGNATURE_EXTENSION);
+		match self.inner.read_file(&sidecar, 0, SIGNATURE_SIZE as u64 + 1) {
+			Ok(ref signature) if signature.len() == SIGNATURE_SIZE => {
+				let mut bytes = [0u8; SIGNATURE_SIZE];
+				bytes.copy_from_slice(signature);
+				Ok(bytes)
+			},
+			Ok(_) | Err(ResultCode::NotFound) => Err(ResultCode::PermissionsError),
+			Err(code) => Err(code)
+		}
+	}
+
+	fn read_verified(&self, path: &str) -> Result<Vec<u8>, ResultCode> {
+		let data = self.inner.read_file(path, 0, u64::max_value())?;
+		let signature = Signature::from_bytes(&self.signature(path)?);
+		let key = VerifyingKey::from_bytes(&self.keys.public_key(normalize(path))?).map_err(|_| ResultCode::PermissionsError)?;
+		key.verify_strict(&data, &signature).map_err(|_| ResultCode::PermissionsError)?;
+		Ok(data)
+	}
+}
+
+impl<D: Device, K: TrustedKeys> Device for SignedDevice<D, K> {
+	fn create(device_path: &str) -> Result<SignedDevice<D, K>, ResultCode> {
+		let mut parts = device_path.splitn(2, ':');
+		let keys = K::create(parts.next().unwrap_or(""))?;
+		let inner = D::create(parts.next().ok_or(ResultCode::InvalidDevice)?)?;
+		let manifest = match inner.read_file(SIGNATURE_MANIFEST, 0, u64::max_value()) {
+			Ok(manifest) => parse_manifest(&manifest)?,
+			Err(ResultCode::NotFound) => HashMap::new(),
+			Err(code) => return Err(code)
+		};
+
+		Ok(SignedDevice {
+			inner: inner,
+			keys: keys,
+			manifest: manifest
+		})
+	}
+
+	fn file_exists(&self, path: &str) -> bool {
+		self.inner.file_exists(path)
+	}
+
+	fn file_size(&self, path: &str) -> Result<u64, ResultCode> {
+		self.inner.file_size(path)
+	}
+
+	fn read_file(&self, path: &str, offset: u64, max_bytes: u64) -> Result<Vec<u8>, ResultCode> {
+		let data = self.read_verified(path)?;
+		let start = std::cmp::min(offset, data.len() as u64) as usize;
+		let end = std::cmp::min(offset.saturating_add(max_bytes), data.len() as u64) as usize;
+		Ok(data[start..end].to_vec())
+	}
+
+	// signatures aren't content of their own
+	fn list_dir(&self, path: &str) -> Result<Vec<DirEntry>, ResultCode> {
+		let mut entries = self.inner.list_dir(path)?;
+		entries.retain(|entry| entry.is_dir || !is_signature(&entry.name));
+		Ok(entries)
+	}
+
+	fn stat(&self, path: &str) -> Result<FileStat, ResultCode> {
+		self.inner.stat(path)
+	}
+
+	fn backing_path(&self, path: &str) -> Option<PathBuf> {
+		self.inner.backing_path(path)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::device::{MemoryDevice, WriteMode};
+
+	// RFC 8032's second test key
+	const PUBLIC_KEY: &str = "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c";
+	const SIGNATURE_VOLUME_5: &str = "5c0853c8f1a2e4008c04935ae3a6c540a9ca1213ab5b906b9dc830448a0077c43058a22971f72e33b1179a8ecc9aae7ba55a43cd73dfb8e88c31cf3a3ecd8d08";
+	const SIGNATURE_VOLUME_9: &str = "8d74e28ba1a060f26a60f8a22bc7c087c8346735b4a75265041c892cc947171014322d4016ab9b7af56805c44fc03bd1970dad6121e8097afd91b25dc5dc3309";
+
+	struct TestKeys;
+
+	impl TrustedKeys for TestKeys {
+		fn create(_key_id: &str) -> Result<TestKeys, ResultCode> {
+			Ok(TestKeys)
+		}
+
+		fn public_key(&self, _path: &str) -> Result<PublicKey, ResultCode> {
+			let mut key = [0u8; 32];
+			for (i, byte) in key.iter_mut().enumerate() {
+				*byte = u8::from_str_radix(&PUBLIC_KEY[i * 2..i * 2 + 2], 16).unwrap();
+			}
+			Ok(key)
+		}
+	}
+
+	#[test]
+	fn signed_device_test() {
+		let inner = MemoryDevice::create("").unwrap();
+		inner.write_file("config.ini", 0, b"volume=5\n", WriteMode::Overwrite).unwrap();
+		inner.write_file("config.ini.sig", 0, &parse_signature(SIGNATURE_VOLUME_5).unwrap(), WriteMode::Overwrite).unwrap();
+		inner.write_file("tampered.ini", 0, b"volume=11\n", WriteMode::Overwrite).unwrap();
+		inner.write_file("tampered.ini.sig", 0, &parse_signature(SIGNATURE_VOLUME_9).unwrap(), WriteMode::Overwrite).unwrap();
+		inner.write_file("audio.ini", 0, b"volume=9\n", WriteMode::Overwrite).unwrap();
+		inner.write_file("unsigned.ini", 0, b"volume=5\n", WriteMode::Overwrite).unwrap();
+		inner.write_file(SIGNATURE_MANIFEST, 0, format!("{} audio.ini\n", SIGNATURE_VOLUME_9).as_bytes(), WriteMode::Overwrite).unwrap();
+
+		let device = SignedDevice::<MemoryDevice, TestKeys> {
+			manifest: parse_manifest(&inner.read_file(SIGNATURE_MANIFEST, 0, u64::max_value()).unwrap()).unwrap(),
+			inner: inner,
+			keys: TestKeys
+		};
+
+		assert!(device.read_file("/config.ini", 0, u64::max_value()).unwrap() == b"volume=5\n");
+		assert!(device.read_file("/config.ini", 7, 1).unwrap() == b"5");
+		assert!(device.read_file("/audio.ini", 0, u64::max_value()).unwrap() == b"volume=9\n");
+		assert!(device.read_file("/tampered.ini", 0, u64::max_value()) == Err(ResultCode::PermissionsError));
+		assert!(device.read_file("/unsigned.ini", 0, u64::max_value()) == Err(ResultCode::PermissionsError));
+
+		let names: Vec<String> = device.list_dir("/").unwrap().into_iter().map(|entry| entry.name).collect();
+		assert!(names == ["audio.ini", "config.ini", "tampered.ini", "unsigned.ini"]);
+	}
+}
@@ -0,0 +1,305 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use super::{add_parent_dirs, child_entries, normalize, DirEntry, Device};
+use crate::ResultCode;
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+
+const BLOCK_SIZE: u64 = 512;
+
+const TYPE_FILE: u8 = b'0';
+const TYPE_FILE_OLD: u8 = 0;
+const TYPE_DIRECTORY: u8 = b'5';
+const TYPE_GNU_LONG_NAME: u8 = b'L';
+const TYPE_PAX_HEADER: u8 = b'x';
+
+struct TarEntry {
+	data_offset: u64,
+	size: u64
+}
+
+// Read-only device serving the members of a tar/ustar archive. Members are stored
+// uncompressed, so segment reads seek directly into the archive.
+pub struct TarDevice {
+	archive_path: PathBuf,
+	entries: HashMap<String, TarEntry>,
+	dirs: HashSet<String>
+}
+
+fn io_error(_: std::io::Error) -> ResultCode {
+	ResultCode::GenericError
+}
+
+fn parse_string(field: &[u8]) -> String {
+	let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+	String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+fn parse_number(field: &[u8]) -> Result<u64, ResultCode> {
+	// GNU base-256 encoding for values that don't fit in octal
+	if field[0] & 0x80 != 0 {
+		return field[1..].iter()
+			.try_fold(u64::from(field[0] & 0x7f), |acc, &b| acc.checked_mul(256).map(|acc| acc | u64::from(b)))
+			.ok_or(ResultCode::InvalidData);
+	}
+
+	let digits = parse_string(field);
+	let digits = digits.trim_matches(|c: char| c == ' ' || c == '\0');
+	if digits.is_empty() {
+		Ok(0)
+	} else {
+		u64::from_str_radix(digits, 8).map_err(|_| ResultCode::GenericError)
+	}
+}
+
+// The `path` record of a PAX extended header, whose records each read
+// "<length> <key>=<value>\n" with the length counting the whole record.
+fn parse_pax_path(data: &[u8]) -> Result<Option<String>, ResultCode> {
+	let mut path = None;
+	let mut pos = 0;
+	while pos < data.len() && data[pos] != 0 {
+		let space = data[pos..].iter().position(|&b| b == b' ').ok_or(ResultCode::InvalidData)?;
+		let length = std::str::from_utf8(&data[pos..pos + space]).ok()
+			.and_then(|length| length.parse::<usize>().ok())
+			.ok_or(ResultCode::InvalidData)?;
+		let record = pos.checked_add(length).and_then(|end| data.get(pos + space + 1..end)).ok_or(ResultCode::InvalidData)?;
+		let record = record.strip_suffix(b"\n").ok_or(ResultCode::InvalidData)?;
+		if let Some(value) = record.strip_prefix(b"path=") {
+			path = Some(String::from_utf8_lossy(value).into_owned());
+		}
+		pos += length;
+	}
+	Ok(path)
+}
+
+impl TarDevice {
+	fn read_index(file: &mut File) -> Result<(HashMap<String, TarEntry>, HashSet<String>), ResultCode> {
+		let mut entries = HashMap::new();
+		let mut dirs = HashSet::new();
+		let mut long_name: Option<String> = None;
+		let mut offset = 0;
+		let mut header = [0u8; BLOCK_SIZE as usize];
+
+		loop {
+			file.seek(SeekFrom::Start(offset)).map_err(io_error)?;
+			if file.read_exact(&mut header).is_err() || header.iter().all(|&b| b == 0) {
+				break;
+			}
+
+			// a size running past u64 can only come from a corrupt header
+			let size = parse_number(&header[124..136])?;
+			let data_offset = offset + BLOCK_SIZE;
+			offset = size.checked_add(BLOCK_SIZE - 1)
+				.map(|padded| padded / BLOCK_SIZE * BLOCK_SIZE)
+				.and_then(|padded| data_offset.checked_add(padded))
+				.ok_or(ResultCode::InvalidData)?;
+
+			// GNU long names and PAX path records name the member that follows them
+			let type_flag = header[156];
+			if type_flag == TYPE_GNU_LONG_NAME || type_flag == TYPE_PAX_HEADER {
+				let mut data = Vec::new();
+				(&mut *file).take(size).read_to_end(&mut data).map_err(io_error)?;
+				if data.len() as u64 != size {
+					return Err(ResultCode::InvalidData);
+				}
+
+				if type_flag == TYPE_GNU_LONG_NAME {
+					long_name = Some(parse_string(&data));
+				} else if let Some(path) = parse_pax_path(&data)? {
+					long_name = Some(path);
+				}
+				continue;
+			}
+
+			let name = match long_name.take() {
+				Some(name) => name,
+				None => {
+					let name = parse_string(&header[0..100]);
+					let prefix = if &header[257..262] == b"ustar" { parse_string(&header[345..500]) } else { String::new() };
+					if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) }
+				}
+			};
+			let name = normalize(&name).to_string();
+			if name.is_empty() {
+				continue;
+			}
+
+			add_parent_dirs(&mut dirs, &name);
+			match type_flag {
+				TYPE_FILE | TYPE_FILE_OLD => {
+					entries.insert(name, TarEntry {
+						data_offset: data_offset,
+						size: size
+					});
+				},
+				TYPE_DIRECTORY => {
+					dirs.insert(name);
+				},
+				_ => {}
+			}
+		}
+
+		Ok((entries, dirs))
+	}
+}
+
+impl Device for TarDevice {
+	fn create(device_path: &str) -> Result<TarDevice, ResultCode> {
+		let mut file = File::open(device_path).map_err(|_| ResultCode::NotFound)?;
+		let (entries, dirs) = TarDevice::read_index(&mut file)?;
+
+		Ok(TarDevice {
+			archive_path: PathBuf::from(device_path),
+			entries: entries,
+			dirs: dirs
+		})
+	}
+
+	fn file_exists(&self, path: &str) -> bool {
+		let path = normalize(path);
+		path.is_empty() || self.entries.contains_key(path) || self.dirs.contains(path)
+	}
+
+	fn file_size(&self, path: &str) -> Result<u64, ResultCode> {
+		match self.entries.get(normalize(path)) {
+			Some(entry) => Ok(entry.size),
+			None => Err(ResultCode::NotFound)
+		}
+	}
+
+	fn read_file(&self, path: &str, offset: u64, max_bytes: u64) -> Result<Vec<u8>, ResultCode> {
+		let entry = self.entries.get(normalize(path)).ok_or(ResultCode::NotFound)?;
+
+		let start = std::cmp::min(offset, entry.size);
+		let end = std::cmp::min(offset.saturating_add(max_bytes), entry.size);
+		let mut data = vec![0u8; (end - start) as usize];
+
+		let mut file = File::open(&self.archive_path).map_err(io_error)?;
+		file.seek(SeekFrom::Start(entry.data_offset + start)).map_err(io_error)?;
+		file.read_exact(&mut data).map_err(io_error)?;
+		Ok(data)
+	}
+
+	fn list_dir(&self, path: &str) -> Result<Vec<DirEntry>, ResultCode> {
+		let path = normalize(path);
+		if !path.is_empty() && !self.dirs.contains(path) {
+			return Err(ResultCode::NotFound);
+		}
+
+		let files = self.entries.iter().map(|(name, entry)| (name.as_str(), entry.size));
+		Ok(child_entries(path, files, self.dirs.iter().map(|dir| dir.as_str())))
+	}
+
+	fn backing_path(&self, _path: &str) -> Option<PathBuf> {
+		Some(self.archive_path.clone())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_dir::TestDir;
+
+	// Appends a ustar member, its data padded out to whole blocks.
+	fn push_member(out: &mut Vec<u8>, name: &str, type_flag: u8, data: &[u8]) {
+		let mut header = [0u8; BLOCK_SIZE as usize];
+		let name = name.as_bytes();
+		header[..std::cmp::min(name.len(), 100)].copy_from_slice(&name[..std::cmp::min(name.len(), 100)]);
+		header[124..135].copy_from_slice(format!("{:011o}", data.len()).as_bytes());
+		header[156] = type_flag;
+		header[257..263].copy_from_slice(b"ustar\0");
+		out.extend_from_slice(&header);
+		out.extend_from_slice(data);
+		out.resize((out.len() + BLOCK_SIZE as usize - 1) / BLOCK_SIZE as usize * BLOCK_SIZE as usize, 0);
+	}
+
+	fn pax_record(key: &str, value: &str) -> String {
+		// the length counts its own digits, which the loop settles on
+		let body = format!(" {}={}\n", key, value);
+		let mut length = body.len();
+		while length != body.len() + length.to_string().len() {
+			length = body.len() + length.to_string().len();
+		}
+		format!("{}{}", length, body)
+	}
+
+	#[test]
+	fn tar_read_test() {
+		let long_name = format!("{}/deep.txt", "nested".repeat(20));
+		let pax_name = format!("{}/pax.txt", "pax".repeat(40));
+		let level: Vec<u8> = (0..2000u32).map(|i| i as u8).collect();
+
+		let mut contents = Vec::new();
+		push_member(&mut contents, "data/", TYPE_DIRECTORY, b"");
+		push_member(&mut contents, "data/level.bin", TYPE_FILE, &level);
+		push_member(&mut contents, "././@LongLink", TYPE_GNU_LONG_NAME, format!("{}\0", long_name).as_bytes());
+		push_member(&mut contents, "short_name", TYPE_FILE, b"long");
+		let pax = format!("{}{}", pax_record("mtime", "1500000000.5"), pax_record("path", &pax_name));
+		push_member(&mut contents, "PaxHeaders/pax", TYPE_PAX_HEADER, pax.as_bytes());
+		push_member(&mut contents, "short_name", TYPE_FILE, b"pax");
+		contents.extend_from_slice(&[0u8; 2 * BLOCK_SIZE as usize]);
+
+		let root = TestDir::new("tar_read");
+		let archive = root.join("archive.tar");
+		std::fs::write(&archive, &contents).unwrap();
+		let tar = TarDevice::create(archive.to_str().unwrap()).unwrap();
+
+		assert!(tar.file_exists("data") && tar.file_exists("data/level.bin"));
+		assert!(tar.file_size("data/level.bin").unwrap() == level.len() as u64);
+		assert!(tar.read_file("data/level.bin", 0, u64::max_value()).unwrap() == level);
+		assert!(tar.read_file("data/level.bin", 600, 100).unwrap() == level[600..700].to_vec());
+		assert!(tar.read_file("data/level.bin", 1990, 100).unwrap() == level[1990..].to_vec());
+
+		assert!(tar.read_file(&long_name, 0, u64::max_value()).unwrap() == b"long".to_vec());
+		assert!(tar.read_file(&pax_name, 0, u64::max_value()).unwrap() == b"pax".to_vec());
+		assert!(!tar.file_exists("short_name"));
+
+		let entries = tar.list_dir("data").unwrap();
+		assert!(entries.len() == 1 && entries[0].name == "level.bin" && entries[0].size == level.len() as u64);
+	}
+
+	#[test]
+	fn oversized_member_test() {
+		let mut contents = Vec::new();
+		push_member(&mut contents, "huge.bin", TYPE_FILE, b"");
+		// a base-256 size right at the top of u64 overflows the block rounding
+		contents[124] = 0x80;
+		for byte in &mut contents[128..136] {
+			*byte = 0xff;
+		}
+		contents.extend_from_slice(&[0u8; 2 * BLOCK_SIZE as usize]);
+
+		let root = TestDir::new("tar_oversized");
+		let archive = root.join("archive.tar");
+		std::fs::write(&archive, &contents).unwrap();
+		assert!(TarDevice::create(archive.to_str().unwrap()).err() == Some(ResultCode::InvalidData));
+
+		// and one that doesn't fit u64 at all
+		contents[127] = 1;
+		std::fs::write(&archive, &contents).unwrap();
+		assert!(TarDevice::create(archive.to_str().unwrap()).err() == Some(ResultCode::InvalidData));
+	}
+}
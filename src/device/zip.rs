@@ -0,0 +1,500 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use super::{add_parent_dirs, child_entries, normalize, DirEntry, Device};
+use crate::ResultCode;
+
+use miniz_oxide::inflate::stream::{inflate, InflateState};
+use miniz_oxide::{DataFormat, MZError, MZFlush, MZStatus};
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+
+const END_OF_CENTRAL_DIR_SIGNATURE: u32 = 0x06054b50;
+const ZIP64_END_OF_CENTRAL_DIR_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const CENTRAL_DIR_SIGNATURE: u32 = 0x02014b50;
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+
+const END_OF_CENTRAL_DIR_SIZE: usize = 22;
+const ZIP64_END_OF_CENTRAL_DIR_SIZE: usize = 56;
+const ZIP64_LOCATOR_SIZE: usize = 20;
+const CENTRAL_DIR_HEADER_SIZE: usize = 46;
+const LOCAL_HEADER_SIZE: usize = 30;
+
+// the extra field holding the 64-bit values of a zip64 entry
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+// what a 32-bit field reads when the real value lives in a zip64 record
+const ZIP64_MARKER: u32 = 0xffffffff;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+
+const INFLATE_CHUNK_SIZE: usize = 64 * 1024;
+
+struct ZipEntry {
+	method: u16,
+	compressed_size: u64,
+	uncompressed_size: u64,
+	header_offset: u64
+}
+
+// Read-only device serving the members of a .zip archive. The central directory is
+// indexed on mount, entries are decompressed on demand by the reading work item.
+pub struct ZipDevice {
+	archive_path: PathBuf,
+	entries: HashMap<String, ZipEntry>,
+	dirs: HashSet<String>
+}
+
+fn read_u16(buffer: &[u8], offset: usize) -> u16 {
+	u16::from(buffer[offset]) | (u16::from(buffer[offset + 1]) << 8)
+}
+
+fn read_u32(buffer: &[u8], offset: usize) -> u32 {
+	u32::from(read_u16(buffer, offset)) | (u32::from(read_u16(buffer, offset + 2)) << 16)
+}
+
+fn read_u64(buffer: &[u8], offset: usize) -> u64 {
+	u64::from(read_u32(buffer, offset)) | (u64::from(read_u32(buffer, offset + 4)) << 32)
+}
+
+fn io_error(_: std::io::Error) -> ResultCode {
+	ResultCode::GenericError
+}
+
+// Replaces the sizes and offset of a central directory entry that are ZIP64_MARKER with
+// the values from its zip64 extra field, which only holds the ones that overflowed, in
+// this order.
+fn apply_zip64_extra(extra: &[u8], entry: &mut ZipEntry) -> Result<(), ResultCode> {
+	let mut pos = 0;
+	while pos + 4 <= extra.len() {
+		let id = read_u16(extra, pos);
+		let size = read_u16(extra, pos + 2) as usize;
+		let data = extra.get(pos + 4..pos + 4 + size).ok_or(ResultCode::InvalidData)?;
+		if id == ZIP64_EXTRA_ID {
+			let mut values = data.chunks_exact(8).map(|value| read_u64(value, 0));
+			for field in &mut [&mut entry.uncompressed_size, &mut entry.compressed_size, &mut entry.header_offset] {
+				if **field == u64::from(ZIP64_MARKER) {
+					**field = values.next().ok_or(ResultCode::InvalidData)?;
+				}
+			}
+			return Ok(());
+		}
+		pos += 4 + size;
+	}
+	Ok(())
+}
+
+// Inflates the raw deflate stream read from `compressed`, keeping only the bytes in
+// [start, end) and stopping as soon as it has them.
+fn inflate_range<R: Read>(mut compressed: R, start: u64, end: u64) -> Result<Vec<u8>, ResultCode> {
+	let mut state = InflateState::new_boxed(DataFormat::Raw);
+	let mut input = vec![0u8; INFLATE_CHUNK_SIZE];
+	let mut output = vec![0u8; INFLATE_CHUNK_SIZE];
+	let mut data = Vec::with_capacity((end - start) as usize);
+	let (mut input_start, mut input_end) = (0, 0);
+	let mut eof = false;
+	let mut position = 0u64;
+
+	while position < end {
+		if input_start == input_end && !eof {
+			input_start = 0;
+			input_end = compressed.read(&mut input).map_err(io_error)?;
+			eof = input_end == 0;
+		}
+
+		let flush = if eof { MZFlush::Finish } else { MZFlush::None };
+		let result = inflate(&mut state, &input[input_start..input_end], &mut output, flush);
+		input_start += result.bytes_consumed;
+
+		let chunk_end = position + result.bytes_written as u64;
+		if chunk_end > start {
+			let from = start.saturating_sub(position) as usize;
+			let to = (std::cmp::min(chunk_end, end) - position) as usize;
+			data.extend_from_slice(&output[from..to]);
+		}
+		position = chunk_end;
+
+		match result.status {
+			Ok(MZStatus::StreamEnd) => break,
+			Ok(_) | Err(MZError::Buf) if !eof || result.bytes_written > 0 => {},
+			_ => return Err(ResultCode::InvalidData)
+		}
+	}
+
+	// a stream ending before `end` doesn't match the size in the central directory
+	if data.len() as u64 != end - start {
+		return Err(ResultCode::InvalidData);
+	}
+	Ok(data)
+}
+
+impl ZipDevice {
+	fn read_central_directory(file: &mut File) -> Result<(HashMap<String, ZipEntry>, HashSet<String>), ResultCode> {
+		let file_size = file.seek(SeekFrom::End(0)).map_err(io_error)?;
+
+		// the end of central directory record sits behind an optional comment of up to 64k,
+		// with room in front of it for the zip64 locator
+		let tail_size = std::cmp::min(file_size, (ZIP64_LOCATOR_SIZE + END_OF_CENTRAL_DIR_SIZE + 0xffff) as u64);
+		let mut tail = vec![0u8; tail_size as usize];
+		file.seek(SeekFrom::Start(file_size - tail_size)).map_err(io_error)?;
+		file.read_exact(&mut tail).map_err(io_error)?;
+
+		if tail.len() < END_OF_CENTRAL_DIR_SIZE {
+			return Err(ResultCode::GenericError);
+		}
+
+		let eocd = (0..=tail.len() - END_OF_CENTRAL_DIR_SIZE).rev()
+			.find(|&i| read_u32(&tail, i) == END_OF_CENTRAL_DIR_SIGNATURE)
+			.ok_or(ResultCode::GenericError)?;
+
+		let mut entry_count = u64::from(read_u16(&tail, eocd + 10));
+		let mut dir_size = u64::from(read_u32(&tail, eocd + 12));
+		let mut dir_offset = u64::from(read_u32(&tail, eocd + 16));
+
+		// zip64 archives keep the real values in a record the locator in front of the end
+		// of central directory points at
+		let locator = eocd.checked_sub(ZIP64_LOCATOR_SIZE).filter(|&i| read_u32(&tail, i) == ZIP64_LOCATOR_SIGNATURE);
+		if let Some(locator) = locator {
+			let mut record = [0u8; ZIP64_END_OF_CENTRAL_DIR_SIZE];
+			file.seek(SeekFrom::Start(read_u64(&tail, locator + 8))).map_err(io_error)?;
+			file.read_exact(&mut record).map_err(io_error)?;
+			if read_u32(&record, 0) != ZIP64_END_OF_CENTRAL_DIR_SIGNATURE {
+				return Err(ResultCode::InvalidData);
+			}
+
+			entry_count = read_u64(&record, 32);
+			dir_size = read_u64(&record, 40);
+			dir_offset = read_u64(&record, 48);
+		}
+
+		if dir_offset.checked_add(dir_size).map_or(true, |dir_end| dir_end > file_size) {
+			return Err(ResultCode::InvalidData);
+		}
+
+		let mut dir = vec![0u8; usize::try_from(dir_size).map_err(|_| ResultCode::OutOfSpace)?];
+		file.seek(SeekFrom::Start(dir_offset)).map_err(io_error)?;
+		file.read_exact(&mut dir).map_err(io_error)?;
+
+		let mut entries = HashMap::new();
+		let mut dirs = HashSet::new();
+		let mut pos = 0;
+		for _ in 0..entry_count {
+			if pos + CENTRAL_DIR_HEADER_SIZE > dir.len() || read_u32(&dir, pos) != CENTRAL_DIR_SIGNATURE {
+				return Err(ResultCode::GenericError);
+			}
+
+			let name_len = read_u16(&dir, pos + 28) as usize;
+			let extra_len = read_u16(&dir, pos + 30) as usize;
+			let comment_len = read_u16(&dir, pos + 32) as usize;
+			let name_start = pos + CENTRAL_DIR_HEADER_SIZE;
+			let extra_start = name_start + name_len;
+			if name_len == 0 || extra_start + extra_len > dir.len() {
+				return Err(ResultCode::GenericError);
+			}
+
+			let name = String::from_utf8_lossy(&dir[name_start..extra_start]);
+			let name = normalize(&name).to_string();
+			add_parent_dirs(&mut dirs, &name);
+
+			if dir[extra_start - 1] == b'/' {
+				dirs.insert(name);
+			} else {
+				let mut entry = ZipEntry {
+					method: read_u16(&dir, pos + 10),
+					compressed_size: u64::from(read_u32(&dir, pos + 20)),
+					uncompressed_size: u64::from(read_u32(&dir, pos + 24)),
+					header_offset: u64::from(read_u32(&dir, pos + 42))
+				};
+				apply_zip64_extra(&dir[extra_start..extra_start + extra_len], &mut entry)?;
+				entries.insert(name, entry);
+			}
+
+			pos = extra_start + extra_len + comment_len;
+		}
+
+		Ok((entries, dirs))
+	}
+
+	fn read_entry_data(&self, entry: &ZipEntry, offset: u64, max_bytes: u64) -> Result<Vec<u8>, ResultCode> {
+		let mut file = File::open(&self.archive_path).map_err(io_error)?;
+
+		let mut header = [0u8; LOCAL_HEADER_SIZE];
+		file.seek(SeekFrom::Start(entry.header_offset)).map_err(io_error)?;
+		file.read_exact(&mut header).map_err(io_error)?;
+		if read_u32(&header, 0) != LOCAL_HEADER_SIGNATURE {
+			return Err(ResultCode::GenericError);
+		}
+
+		let data_offset = entry.header_offset
+			+ LOCAL_HEADER_SIZE as u64
+			+ u64::from(read_u16(&header, 26))
+			+ u64::from(read_u16(&header, 28));
+
+		let start = std::cmp::min(offset, entry.uncompressed_size);
+		let end = std::cmp::min(offset.saturating_add(max_bytes), entry.uncompressed_size);
+
+		match entry.method {
+			METHOD_STORED => {
+				// stored entries can seek straight to the requested segment
+				let mut data = vec![0u8; (end - start) as usize];
+				file.seek(SeekFrom::Start(data_offset + start)).map_err(io_error)?;
+				file.read_exact(&mut data).map_err(io_error)?;
+				Ok(data)
+			},
+			METHOD_DEFLATE => {
+				// deflate can't seek, but only has to run up to the end of the segment
+				file.seek(SeekFrom::Start(data_offset)).map_err(io_error)?;
+				inflate_range(file.take(entry.compressed_size), start, end)
+			},
+			_ => Err(ResultCode::Unsupported)
+		}
+	}
+}
+
+impl Device for ZipDevice {
+	fn create(device_path: &str) -> Result<ZipDevice, ResultCode> {
+		let mut file = File::open(device_path).map_err(|_| ResultCode::NotFound)?;
+		let (entries, dirs) = ZipDevice::read_central_directory(&mut file)?;
+
+		Ok(ZipDevice {
+			archive_path: PathBuf::from(device_path),
+			entries: entries,
+			dirs: dirs
+		})
+	}
+
+	fn file_exists(&self, path: &str) -> bool {
+		let path = normalize(path);
+		path.is_empty() || self.entries.contains_key(path) || self.dirs.contains(path)
+	}
+
+	fn file_size(&self, path: &str) -> Result<u64, ResultCode> {
+		match self.entries.get(normalize(path)) {
+			Some(entry) => Ok(entry.uncompressed_size),
+			None => Err(ResultCode::NotFound)
+		}
+	}
+
+	fn read_file(&self, path: &str, offset: u64, max_bytes: u64) -> Result<Vec<u8>, ResultCode> {
+		match self.entries.get(normalize(path)) {
+			Some(entry) => self.read_entry_data(entry, offset, max_bytes),
+			None => Err(ResultCode::NotFound)
+		}
+	}
+
+	fn list_dir(&self, path: &str) -> Result<Vec<DirEntry>, ResultCode> {
+		let path = normalize(path);
+		if !path.is_empty() && !self.dirs.contains(path) {
+			return Err(ResultCode::NotFound);
+		}
+
+		let files = self.entries.iter().map(|(name, entry)| (name.as_str(), entry.uncompressed_size));
+		Ok(child_entries(path, files, self.dirs.iter().map(|dir| dir.as_str())))
+	}
+
+	fn backing_path(&self, _path: &str) -> Option<PathBuf> {
+		Some(self.archive_path.clone())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_dir::TestDir;
+	use crate::{DeviceType, LaminaFS};
+
+	fn push_u16(out: &mut Vec<u8>, value: u16) {
+		out.extend_from_slice(&value.to_le_bytes());
+	}
+
+	fn push_u32(out: &mut Vec<u8>, value: u32) {
+		out.extend_from_slice(&value.to_le_bytes());
+	}
+
+	fn push_u64(out: &mut Vec<u8>, value: u64) {
+		out.extend_from_slice(&value.to_le_bytes());
+	}
+
+	// Builds an archive of (name, method, contents), writing every size and offset through
+	// the zip64 records if `zip64` is set. Names ending in '/' are directories.
+	fn build_zip(files: &[(&str, u16, &[u8])], zip64: bool) -> Vec<u8> {
+		let mut out = Vec::new();
+		let mut dir = Vec::new();
+		for &(name, method, contents) in files {
+			let payload = match method {
+				METHOD_DEFLATE => miniz_oxide::deflate::compress_to_vec(contents, 6),
+				_ => contents.to_vec()
+			};
+			let offset = out.len() as u64;
+
+			push_u32(&mut out, LOCAL_HEADER_SIGNATURE);
+			out.extend_from_slice(&[20, 0, 0, 0]);
+			push_u16(&mut out, method);
+			out.extend_from_slice(&[0; 8]);
+			push_u32(&mut out, payload.len() as u32);
+			push_u32(&mut out, contents.len() as u32);
+			push_u16(&mut out, name.len() as u16);
+			push_u16(&mut out, 0);
+			out.extend_from_slice(name.as_bytes());
+			out.extend_from_slice(&payload);
+
+			let (sizes, extra) = if zip64 {
+				let mut extra = Vec::new();
+				push_u16(&mut extra, ZIP64_EXTRA_ID);
+				push_u16(&mut extra, 24);
+				push_u64(&mut extra, contents.len() as u64);
+				push_u64(&mut extra, payload.len() as u64);
+				push_u64(&mut extra, offset);
+				([ZIP64_MARKER; 3], extra)
+			} else {
+				([payload.len() as u32, contents.len() as u32, offset as u32], Vec::new())
+			};
+
+			push_u32(&mut dir, CENTRAL_DIR_SIGNATURE);
+			dir.extend_from_slice(&[20, 0, 20, 0, 0, 0]);
+			push_u16(&mut dir, method);
+			dir.extend_from_slice(&[0; 8]);
+			push_u32(&mut dir, sizes[0]);
+			push_u32(&mut dir, sizes[1]);
+			push_u16(&mut dir, name.len() as u16);
+			push_u16(&mut dir, extra.len() as u16);
+			dir.extend_from_slice(&[0; 10]);
+			push_u32(&mut dir, sizes[2]);
+			dir.extend_from_slice(name.as_bytes());
+			dir.extend_from_slice(&extra);
+		}
+
+		let dir_offset = out.len() as u64;
+		out.extend_from_slice(&dir);
+
+		if zip64 {
+			let record_offset = out.len() as u64;
+			push_u32(&mut out, ZIP64_END_OF_CENTRAL_DIR_SIGNATURE);
+			push_u64(&mut out, (ZIP64_END_OF_CENTRAL_DIR_SIZE - 12) as u64);
+			out.extend_from_slice(&[45, 0, 45, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+			push_u64(&mut out, files.len() as u64);
+			push_u64(&mut out, files.len() as u64);
+			push_u64(&mut out, dir.len() as u64);
+			push_u64(&mut out, dir_offset);
+
+			push_u32(&mut out, ZIP64_LOCATOR_SIGNATURE);
+			push_u32(&mut out, 0);
+			push_u64(&mut out, record_offset);
+			push_u32(&mut out, 1);
+		}
+
+		let (count, dir_size, dir_offset) = if zip64 {
+			(0xffff, ZIP64_MARKER, ZIP64_MARKER)
+		} else {
+			(files.len() as u16, dir.len() as u32, dir_offset as u32)
+		};
+		push_u32(&mut out, END_OF_CENTRAL_DIR_SIGNATURE);
+		out.extend_from_slice(&[0; 4]);
+		push_u16(&mut out, count);
+		push_u16(&mut out, count);
+		push_u32(&mut out, dir_size);
+		push_u32(&mut out, dir_offset);
+		push_u16(&mut out, 0);
+		out
+	}
+
+	fn check_archive(name: &str, zip64: bool) {
+		let readme = b"stored as is";
+		let level: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+		let root = TestDir::new(name);
+		let archive = root.join("assets.zip");
+		std::fs::write(&archive, build_zip(&[
+			("readme.txt", METHOD_STORED, readme),
+			("data/", METHOD_STORED, b""),
+			("data/level.bin", METHOD_DEFLATE, &level)
+		], zip64)).unwrap();
+
+		let zip = ZipDevice::create(archive.to_str().unwrap()).unwrap();
+		assert!(zip.file_exists("readme.txt") && zip.file_exists("data") && zip.file_exists("data/level.bin"));
+		assert!(!zip.file_exists("missing.txt"));
+		assert!(zip.file_size("data/level.bin").unwrap() == level.len() as u64);
+
+		assert!(zip.read_file("readme.txt", 0, u64::max_value()).unwrap() == readme.to_vec());
+		assert!(zip.read_file("readme.txt", 7, 2).unwrap() == b"as".to_vec());
+		assert!(zip.read_file("data/level.bin", 0, u64::max_value()).unwrap() == level);
+
+		// segments straddling the inflate chunks come out of the middle of the stream
+		assert!(zip.read_file("data/level.bin", 70_000, 1000).unwrap() == level[70_000..71_000].to_vec());
+		assert!(zip.read_file("data/level.bin", 99_990, 100).unwrap() == level[99_990..].to_vec());
+		assert!(zip.read_file("data/level.bin", 200_000, 10).unwrap().is_empty());
+		assert!(zip.read_file("missing.txt", 0, 1).unwrap_err() == ResultCode::NotFound);
+
+		let entries = zip.list_dir("data").unwrap();
+		assert!(entries.len() == 1 && entries[0].name == "level.bin");
+	}
+
+	#[test]
+	fn zip_read_test() {
+		check_archive("zip_read", false);
+	}
+
+	#[test]
+	fn zip64_read_test() {
+		check_archive("zip64_read", true);
+	}
+
+	#[test]
+	fn truncated_deflate_test() {
+		let level = vec![7u8; 10_000];
+		let mut contents = build_zip(&[("level.bin", METHOD_DEFLATE, &level)], false);
+		// claim more inflated bytes than the stream holds
+		let signature = CENTRAL_DIR_SIGNATURE.to_le_bytes();
+		let dir = (0..contents.len()).find(|&i| contents[i..].starts_with(&signature)).unwrap();
+		contents[dir + 24..dir + 28].copy_from_slice(&20_000u32.to_le_bytes());
+
+		let root = TestDir::new("zip_truncated");
+		let archive = root.join("broken.zip");
+		std::fs::write(&archive, contents).unwrap();
+
+		let zip = ZipDevice::create(archive.to_str().unwrap()).unwrap();
+		assert!(zip.read_file("level.bin", 0, u64::max_value()).unwrap_err() == ResultCode::InvalidData);
+		assert!(zip.read_file("level.bin", 5000, 100).unwrap() == vec![7u8; 100]);
+	}
+
+	#[test]
+	fn zip_mount_test() {
+		let level = vec![3u8; 5000];
+		let root = TestDir::new("zip_mount");
+		let archive = root.join("assets.zip");
+		std::fs::write(&archive, build_zip(&[("maps/level.bin", METHOD_DEFLATE, &level)], false)).unwrap();
+
+		let fs = LaminaFS::new();
+		let mount = fs.create_mount(DeviceType::Zip, "/assets", archive.to_str().unwrap()).unwrap();
+		assert!(mount.device_type() == DeviceType::Zip);
+		assert!(fs.file_exists_sync("/assets/maps/level.bin").unwrap());
+		assert!(fs.read_file_sync("/assets/maps/level.bin").unwrap() == level);
+
+		// further archives reuse the device the first mount registered
+		let _more = fs.create_mount(DeviceType::Zip, "/more", archive.to_str().unwrap()).unwrap();
+		assert!(fs.read_file_sync("/more/maps/level.bin").unwrap() == level);
+	}
+}
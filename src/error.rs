@@ -0,0 +1,255 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+use crate::{MountPermissions, ResultCode};
+
+use std::fmt;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OperationKind {
+	CreateMount,
+	Unmount,
+	ReadFile,
+	ReadFileSegment,
+	ReadFileRanges,
+	WriteFile,
+	WriteFileSegment,
+	AppendFile,
+	DeleteFile,
+	CreateDir,
+	DeleteDir,
+	FileExists,
+	FileSize,
+	Stat,
+	ListDir,
+	Resolve,
+	CopyFile,
+	MoveFile,
+	TruncateFile,
+	AllocateFile,
+	LockFile,
+	Open,
+	MapFile,
+	Walk,
+	Find,
+	LoadManifest,
+	ReadAsset,
+	Flush,
+	SetRetryPolicy
+}
+
+impl OperationKind {
+	pub(crate) fn name(self) -> &'static str {
+		match self {
+			OperationKind::CreateMount => "create_mount",
+			OperationKind::Unmount => "unmount",
+			OperationKind::ReadFile => "read_file",
+			OperationKind::ReadFileSegment => "read_file_segment",
+			OperationKind::ReadFileRanges => "read_file_ranges",
+			OperationKind::WriteFile => "write_file",
+			OperationKind::WriteFileSegment => "write_file_segment",
+			OperationKind::AppendFile => "append_file",
+			OperationKind::DeleteFile => "delete_file",
+			OperationKind::CreateDir => "create_dir",
+			OperationKind::DeleteDir => "delete_dir",
+			OperationKind::FileExists => "file_exists",
+			OperationKind::FileSize => "file_size",
+			OperationKind::Stat => "stat",
+			OperationKind::ListDir => "list_dir",
+			OperationKind::Resolve => "resolve",
+			OperationKind::CopyFile => "copy_file",
+			OperationKind::MoveFile => "move_file",
+			OperationKind::TruncateFile => "truncate_file",
+			OperationKind::AllocateFile => "allocate_file",
+			OperationKind::LockFile => "lock_file",
+			OperationKind::Open => "open",
+			OperationKind::MapFile => "map_file",
+			OperationKind::Walk => "walk",
+			OperationKind::Find => "find",
+			OperationKind::LoadManifest => "load_manifest",
+			OperationKind::ReadAsset => "read_asset",
+			OperationKind::Flush => "flush",
+			OperationKind::SetRetryPolicy => "set_retry_policy"
+		}
+	}
+}
+
+impl OperationKind {
+	pub(crate) fn from_name(name: &str) -> Option<OperationKind> {
+		const OPERATIONS: [OperationKind; 29] = [OperationKind::CreateMount, OperationKind::Unmount, OperationKind::ReadFile, OperationKind::ReadFileSegment, OperationKind::ReadFileRanges,
+			OperationKind::WriteFile, OperationKind::WriteFileSegment, OperationKind::AppendFile, OperationKind::DeleteFile, OperationKind::CreateDir,
+			OperationKind::DeleteDir, OperationKind::FileExists, OperationKind::FileSize, OperationKind::Stat, OperationKind::ListDir, OperationKind::Resolve,
+			OperationKind::CopyFile, OperationKind::MoveFile, OperationKind::TruncateFile, OperationKind::AllocateFile, OperationKind::LockFile, OperationKind::Open, OperationKind::MapFile, OperationKind::Walk,
+			OperationKind::Find, OperationKind::LoadManifest, OperationKind::ReadAsset, OperationKind::Flush,
+			OperationKind::SetRetryPolicy];
+		OPERATIONS.iter().cloned().find(|operation| operation.name() == name)
+	}
+
+	// The mount permission the operation needs on its path. Copies and moves are checked
+	// against their source; their destination needs WriteFile. Mounting, unmounting and
+	// configuring a mount don't depend on any mount's permissions.
+	pub(crate) fn permission(self) -> Option<MountPermissions> {
+		match self {
+			OperationKind::CreateMount | OperationKind::Unmount | OperationKind::SetRetryPolicy => None,
+			OperationKind::WriteFile | OperationKind::WriteFileSegment | OperationKind::AppendFile | OperationKind::TruncateFile | OperationKind::AllocateFile
+				| OperationKind::Flush => Some(MountPermissions::WriteFile),
+			OperationKind::DeleteFile | OperationKind::MoveFile => Some(MountPermissions::DeleteFile),
+			OperationKind::CreateDir => Some(MountPermissions::CreateDir),
+			OperationKind::DeleteDir => Some(MountPermissions::DeleteDir),
+			_ => Some(MountPermissions::Read)
+		}
+	}
+}
+
+impl fmt::Display for OperationKind {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(self.name())
+	}
+}
+
+impl fmt::Display for ResultCode {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(match self {
+			ResultCode::Ok => "ok",
+			ResultCode::NotFound => "not found",
+			ResultCode::InvalidDevice => "invalid device",
+			ResultCode::AlreadyExists => "already exists",
+			ResultCode::OutOfSpace => "out of space",
+			ResultCode::PermissionsError => "permission denied",
+			ResultCode::Unsupported => "unsupported",
+			ResultCode::GenericError => "generic error",
+			ResultCode::Cancelled => "cancelled",
+			ResultCode::InvalidPath => "invalid path",
+			ResultCode::TimedOut => "timed out",
+			ResultCode::QueueFull => "queue full",
+			ResultCode::InvalidData => "invalid data"
+		})
+	}
+}
+
+// A failed operation: the result code along with which operation failed and on what
+// path, when known. Errors converted from a bare ResultCode carry neither.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct LfsError {
+	code: ResultCode,
+	operation: Option<OperationKind>,
+	path: Option<String>
+}
+
+impl LfsError {
+	pub fn new(code: ResultCode, operation: OperationKind, path: &str) -> LfsError {
+		LfsError {
+			code: code,
+			operation: Some(operation),
+			path: Some(path.to_string())
+		}
+	}
+
+	pub(crate) fn invalid_path(path: &str) -> LfsError {
+		LfsError {
+			code: ResultCode::InvalidPath,
+			operation: None,
+			path: Some(path.to_string())
+		}
+	}
+
+	pub fn code(&self) -> ResultCode {
+		self.code
+	}
+
+	pub fn operation(&self) -> Option<OperationKind> {
+		self.operation
+	}
+
+	pub fn path(&self) -> Option<&str> {
+		self.path.as_ref().map(|path| path.as_str())
+	}
+}
+
+impl From<ResultCode> for LfsError {
+	fn from(code: ResultCode) -> LfsError {
+		LfsError {
+			code: code,
+			operation: None,
+			path: None
+		}
+	}
+}
+
+impl fmt::Display for LfsError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match (self.operation, &self.path) {
+			(Some(operation), Some(path)) => write!(f, "{} \"{}\": {}", operation, path, self.code),
+			(Some(operation), None) => write!(f, "{}: {}", operation, self.code),
+			_ => write!(f, "{}", self.code)
+		}
+	}
+}
+
+impl std::error::Error for LfsError {}
+
+impl From<LfsError> for std::io::Error {
+	fn from(error: LfsError) -> std::io::Error {
+		let kind = match error.code {
+			ResultCode::NotFound => std::io::ErrorKind::NotFound,
+			ResultCode::AlreadyExists => std::io::ErrorKind::AlreadyExists,
+			ResultCode::PermissionsError => std::io::ErrorKind::PermissionDenied,
+			ResultCode::InvalidPath => std::io::ErrorKind::InvalidInput,
+			ResultCode::TimedOut => std::io::ErrorKind::TimedOut,
+			ResultCode::QueueFull => std::io::ErrorKind::WouldBlock,
+			ResultCode::InvalidData => std::io::ErrorKind::InvalidData,
+			// not Interrupted, which read_to_end and friends would retry forever; Cancelled
+			// stays reachable through the wrapped LfsError
+			_ => std::io::ErrorKind::Other
+		};
+		std::io::Error::new(kind, error)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn display_test() {
+		let error = LfsError::new(ResultCode::NotFound, OperationKind::ReadFile, "/data/level1.bin");
+		assert!(error.to_string() == "read_file \"/data/level1.bin\": not found");
+		assert!(LfsError::from(ResultCode::OutOfSpace).to_string() == "out of space");
+
+		let error = LfsError::new(ResultCode::Unsupported, OperationKind::SetRetryPolicy, "/");
+		assert!(error.to_string() == "set_retry_policy \"/\": unsupported");
+		assert!(OperationKind::from_name("set_retry_policy") == Some(OperationKind::SetRetryPolicy));
+		assert!(OperationKind::SetRetryPolicy.permission().is_none());
+	}
+
+	#[test]
+	fn io_error_test() {
+		let error = std::io::Error::from(LfsError::new(ResultCode::NotFound, OperationKind::ReadFile, "/data/level1.bin"));
+		assert!(error.kind() == std::io::ErrorKind::NotFound);
+
+		let error = std::io::Error::from(LfsError::new(ResultCode::Cancelled, OperationKind::ReadFile, "/data/level1.bin"));
+		assert!(error.kind() == std::io::ErrorKind::Other);
+		let inner = error.get_ref().and_then(|inner| inner.downcast_ref::<LfsError>()).unwrap();
+		assert!(inner.code() == ResultCode::Cancelled);
+	}
+}
@@ -0,0 +1,109 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+// Callbacks run as mounts are created and released, for subsystems keeping something per
+// mount, such as a shader cache or the localization tables of a mod.
+
+use crate::MountInfo;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum MountEvent {
+	// the mount is in place and paths resolve against it
+	Created(MountInfo),
+	// the mount is gone, through Mount::unmount or by being dropped
+	Released(MountInfo)
+}
+
+// Identifies a callback added with LaminaFS::on_mount_event, to remove it again.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct MountEventHook(u64);
+
+type MountEventCallback = Arc<dyn Fn(&MountEvent) + Send + Sync>;
+
+pub(crate) struct MountEvents {
+	callbacks: Mutex<Vec<(MountEventHook, MountEventCallback)>>,
+	next_hook: AtomicU64
+}
+
+impl MountEvents {
+	pub(crate) fn new() -> MountEvents {
+		MountEvents {
+			callbacks: Mutex::new(Vec::new()),
+			next_hook: AtomicU64::new(0)
+		}
+	}
+
+	pub(crate) fn add(&self, callback: MountEventCallback) -> MountEventHook {
+		let hook = MountEventHook(self.next_hook.fetch_add(1, Ordering::Relaxed));
+		self.callbacks.lock().unwrap().push((hook, callback));
+		hook
+	}
+
+	pub(crate) fn remove(&self, hook: MountEventHook) -> bool {
+		let mut callbacks = self.callbacks.lock().unwrap();
+		let count = callbacks.len();
+		callbacks.retain(|(added, _)| *added != hook);
+		callbacks.len() != count
+	}
+
+	// Callbacks run on the thread creating or releasing the mount, without the list locked
+	// so they can add or remove callbacks, or mounts, themselves.
+	pub(crate) fn fire(&self, event: MountEvent) {
+		let callbacks: Vec<MountEventCallback> = self.callbacks.lock().unwrap().iter().map(|(_, callback)| callback.clone()).collect();
+		for callback in callbacks {
+			callback(&event);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{DeviceType, MountPermissions};
+
+	#[test]
+	fn mount_events_test() {
+		let events = MountEvents::new();
+		let seen = Arc::new(Mutex::new(Vec::new()));
+		let callback_seen = seen.clone();
+		let hook = events.add(Arc::new(move |event: &MountEvent| callback_seen.lock().unwrap().push(event.clone())));
+
+		let info = MountInfo {
+			mount_point: "/mods/".to_string(),
+			device_type: DeviceType::Directory,
+			device_path: "./mods".to_string(),
+			permissions: MountPermissions::Read,
+			priority: 0
+		};
+		events.fire(MountEvent::Created(info.clone()));
+		assert!(events.remove(hook));
+		assert!(!events.remove(hook));
+		events.fire(MountEvent::Released(info.clone()));
+
+		assert!(*seen.lock().unwrap() == [MountEvent::Created(info)]);
+	}
+}
@@ -0,0 +1,188 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// Files resolved once and kept open, for streaming many segments out of one file. The
+// mount is resolved when the file is opened, and files backed by real files keep their
+// OS handle open, so segment reads skip both the mount lookup and the open.
+
+use crate::device::{self, Device, WriteMode};
+use crate::{LaminaFS, LfsError, MountPermissions, OperationKind, ResultCode, Task};
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+
+struct OpenFile {
+	device: Arc<dyn Device>,
+	relative_path: String,
+	// the OS handle, for files backed by one
+	file: Option<Mutex<File>>,
+	writable: bool
+}
+
+impl OpenFile {
+	fn read_segment(&self, offset: u64, max_bytes: u64) -> Result<Vec<u8>, ResultCode> {
+		match self.file {
+			Some(ref file) => {
+				let mut file = file.lock().unwrap();
+				file.seek(SeekFrom::Start(offset)).map_err(device::from_io_error)?;
+
+				let mut data = Vec::new();
+				(&mut *file).take(max_bytes).read_to_end(&mut data).map_err(device::from_io_error)?;
+				Ok(data)
+			},
+			None => self.device.read_file(&self.relative_path, offset, max_bytes)
+		}
+	}
+
+	fn write_segment(&self, offset: u64, buffer: &[u8]) -> Result<u64, ResultCode> {
+		if !self.writable {
+			return Err(ResultCode::PermissionsError);
+		}
+
+		match self.file {
+			Some(ref file) => {
+				let mut file = file.lock().unwrap();
+				file.seek(SeekFrom::Start(offset)).map_err(device::from_io_error)?;
+				file.write_all(buffer).map_err(device::from_io_error)?;
+				Ok(buffer.len() as u64)
+			},
+			None => self.device.write_file(&self.relative_path, offset, buffer, WriteMode::Segment)
+		}
+	}
+
+	fn size(&self) -> Result<u64, ResultCode> {
+		match self.file {
+			Some(ref file) => file.lock().unwrap().metadata().map(|metadata| metadata.len()).map_err(device::from_io_error),
+			None => self.device.file_size(&self.relative_path)
+		}
+	}
+}
+
+// An open file. Segment reads and writes run as tasks like the other operations the
+// Rust side implements, and any number of them can be outstanding at once.
+pub struct FileHandle {
+	fs: Arc<LaminaFS>,
+	path: String,
+	// the path after aliasing and case folding
+	target: String,
+	file: Arc<OpenFile>
+}
+
+impl FileHandle {
+	pub fn path(&self) -> &str {
+		&self.path
+	}
+
+	// Whether the file's mount allows writing it.
+	pub fn is_writable(&self) -> bool {
+		self.file.writable
+	}
+
+	pub fn read_segment(&self, offset: u64, max_bytes: u64) -> Task<Vec<u8>> {
+		let file = self.file.clone();
+		self.fs.tasks.spawn(OperationKind::ReadFileSegment, &self.path, move || file.read_segment(offset, max_bytes))
+	}
+
+	// Buffers can be passed as Arc<[u8]>, Vec<u8>, Box<[u8]> or &[u8], the latter copied.
+	pub fn write_segment<B: Into<Arc<[u8]>>>(&self, offset: u64, buffer: B) -> Task<u64> {
+		let file = self.file.clone();
+		let buffer: Arc<[u8]> = buffer.into();
+		let mounts = self.fs.mounts.clone();
+		let cache = self.fs.cache.clone();
+		let target = self.target.clone();
+		self.fs.tasks.spawn(OperationKind::WriteFileSegment, &self.path, move || {
+			let end = offset.checked_add(buffer.len() as u64).ok_or(ResultCode::GenericError)?;
+			let result = mounts.charged(&target, MountPermissions::WriteFile, |size| std::cmp::max(size, end), || file.write_segment(offset, &buffer));
+			cache.invalidate(&target);
+			result
+		})
+	}
+
+	pub fn size(&self) -> Task<u64> {
+		let file = self.file.clone();
+		self.fs.tasks.spawn(OperationKind::FileSize, &self.path, move || file.size())
+	}
+}
+
+impl LaminaFS {
+	// Opens an existing file on a mount the Rust side can reach. The file stays on the
+	// mount it was opened on, even if the mounts change while it is open.
+	pub fn open(self: &Arc<Self>, path: &str) -> Result<FileHandle, LfsError> {
+		let error = |code| LfsError::new(code, OperationKind::Open, path);
+		let target = self.virtual_path(path, false).map_err(|e| error(e.code()))?;
+		let (device, relative_path, _) = self.mounts.readable_file(&target).map_err(error)?;
+		let writable = match self.mounts.writable_device(&target, MountPermissions::WriteFile) {
+			Ok((writable_device, _)) => Arc::ptr_eq(&device, &writable_device),
+			Err(_) => false
+		};
+
+		let file = match device.raw_path(&relative_path) {
+			Some(raw_path) => Some(Mutex::new(
+				OpenOptions::new().read(true).write(writable).open(raw_path).map_err(|io_error| error(device::from_io_error(io_error)))?
+			)),
+			None => None
+		};
+
+		Ok(FileHandle {
+			fs: self.clone(),
+			path: path.to_string(),
+			target: target,
+			file: Arc::new(OpenFile {
+				device: device,
+				relative_path: relative_path,
+				file: file,
+				writable: writable
+			})
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::device::MemoryDevice;
+
+	#[test]
+	fn open_file_test() {
+		let device = MemoryDevice::create("").unwrap();
+		device.write_file("/movie.bik", 0, b"0123456789", WriteMode::Overwrite).unwrap();
+
+		let file = OpenFile {
+			device: Arc::new(device),
+			relative_path: "movie.bik".to_string(),
+			file: None,
+			writable: false
+		};
+		assert!(file.read_segment(2, 3).unwrap() == b"234");
+		assert!(file.size() == Ok(10));
+		assert!(file.write_segment(0, b"x") == Err(ResultCode::PermissionsError));
+
+		let file = OpenFile {
+			writable: true,
+			..file
+		};
+		assert!(file.write_segment(8, b"abcd") == Ok(4));
+		assert!(file.read_segment(6, 100).unwrap() == b"67abcd");
+	}
+}
@@ -0,0 +1,177 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// Durability control: writes that are synced to stable storage before they complete,
+// and flushing a file written earlier, for data such as saves that must survive a crash
+// or power loss right after it was written. Writes can also be read back and checked,
+// for storage such as SD cards that may corrupt data without reporting an error.
+
+use crate::device::SyncMode;
+use crate::hash::crc32;
+use crate::mount::MountTable;
+use crate::queue::CompletionCallback;
+use crate::{LaminaFS, MountPermissions, OperationKind, ResultCode, Task, WorkHandle, WorkItemResult};
+
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct WriteOptions {
+	pub sync: SyncMode,
+	// read the data back once written, failing the write with GenericError if it differs
+	pub verify: bool
+}
+
+impl WriteOptions {
+	pub fn new() -> WriteOptions {
+		WriteOptions::default()
+	}
+
+	pub fn sync(mut self, sync: SyncMode) -> WriteOptions {
+		self.sync = sync;
+		self
+	}
+
+	pub fn verify(mut self, verify: bool) -> WriteOptions {
+		self.verify = verify;
+		self
+	}
+}
+
+// Mounts the Rust side has no device for are left to the context, which has no way of
+// flushing a file.
+fn sync_file(mounts: &MountTable, path: &str, mode: SyncMode) -> Result<(), ResultCode> {
+	match mounts.writable_device(path, MountPermissions::WriteFile) {
+		Ok((device, relative_path)) => device.sync(&relative_path, mode),
+		Err(ResultCode::NotFound) => Ok(()),
+		Err(code) => Err(code)
+	}
+}
+
+// Compares the checksum of what the device now holds against the buffer written, the
+// whole file after an overwrite or its tail after an append. Like syncing, this is left
+// out for mounts the Rust side has no device for.
+fn verify_file(mounts: &MountTable, path: &str, buffer: &[u8], append: bool) -> Result<(), ResultCode> {
+	let (device, relative_path) = match mounts.writable_device(path, MountPermissions::WriteFile) {
+		Ok(writable) => writable,
+		Err(ResultCode::NotFound) => return Ok(()),
+		Err(code) => return Err(code)
+	};
+
+	let offset = if append {
+		device.file_size(&relative_path)?.checked_sub(buffer.len() as u64).ok_or(ResultCode::GenericError)?
+	} else {
+		0
+	};
+	let written = device.read_file(&relative_path, offset, u64::max_value())?;
+	if written.len() == buffer.len() && crc32(&written) == crc32(buffer) {
+		Ok(())
+	} else {
+		Err(ResultCode::GenericError)
+	}
+}
+
+impl LaminaFS {
+	pub fn write_file_with_options<B: Into<Arc<[u8]>>>(&self, path: &str, buffer: B, options: WriteOptions) -> WorkHandle {
+		let buffer = buffer.into();
+		let callback = self.write_options_callback(path, options, &buffer, false, None);
+		self.submit_write_file(path, buffer, callback)
+	}
+
+	pub fn append_file_with_options<B: Into<Arc<[u8]>>>(&self, path: &str, buffer: B, options: WriteOptions) -> WorkHandle {
+		let buffer = buffer.into();
+		let callback = self.write_options_callback(path, options, &buffer, true, None);
+		self.submit_append_file(path, buffer, callback)
+	}
+
+	// Syncs the file once the write succeeds, then reads it back if asked to, before the
+	// work item completes. Either failing fails the work item. The read back goes through
+	// the OS, which may answer it from its cache, so it's only as good as the device's
+	// own view of the file.
+	fn write_options_callback(&self, path: &str, options: WriteOptions, buffer: &Arc<[u8]>, append: bool, callback: Option<CompletionCallback>) -> Option<CompletionCallback> {
+		if options.sync == SyncMode::None && !options.verify {
+			return callback;
+		}
+
+		// writes to invalid paths fail before anything lands that could need syncing
+		let target = match self.virtual_path(path, true) {
+			Ok(target) => target,
+			Err(_) => return callback
+		};
+		let mounts = self.mounts.clone();
+		let buffer = buffer.clone();
+		Some(Box::new(move |result: &WorkItemResult| {
+			if result.get_result() == ResultCode::Ok {
+				let checked = match options.sync {
+					SyncMode::None => Ok(()),
+					mode => sync_file(&mounts, &target, mode)
+				};
+				let checked = match checked {
+					Ok(()) if options.verify => verify_file(&mounts, &target, &buffer, append),
+					checked => checked
+				};
+				if let Err(code) = checked {
+					result.fail(code);
+				}
+			}
+			if let Some(callback) = callback {
+				callback(result);
+			}
+		}))
+	}
+
+	// Forces everything written to the file so far to stable storage, including the
+	// directory entry naming it.
+	pub fn flush(&self, path: &str) -> Task<()> {
+		let mounts = self.mounts.clone();
+		let path_owned = self.virtual_path(path, false);
+		self.tasks.spawn(OperationKind::Flush, path, move || {
+			let path_owned = path_owned.map_err(|error| error.code())?;
+			sync_file(&mounts, &path_owned, SyncMode::Full)
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::device::{CreatedDevice, Device, MemoryDevice, WriteMode};
+	use crate::mount::{DeviceType, MountEntry};
+
+	#[test]
+	fn verify_file_test() {
+		let device = Arc::new(MemoryDevice::create("").unwrap());
+		let mounts = MountTable::new();
+		let saves = Arc::new(MountEntry::new("/saves", DeviceType::Directory, "", MountPermissions::All, Some(CreatedDevice::new(device.clone())), None));
+		mounts.add(&saves);
+
+		device.write_file("slot1", 0, b"level=3", WriteMode::Overwrite).unwrap();
+		assert!(verify_file(&mounts, "/saves/slot1", b"level=3", false).is_ok());
+		assert!(verify_file(&mounts, "/saves/slot1", b"level=4", false) == Err(ResultCode::GenericError));
+		assert!(verify_file(&mounts, "/saves/slot1", b"level", false) == Err(ResultCode::GenericError));
+
+		device.write_file("slot1", 0, b";gold=20", WriteMode::Append).unwrap();
+		assert!(verify_file(&mounts, "/saves/slot1", b";gold=20", true).is_ok());
+		assert!(verify_file(&mounts, "/saves/slot1", b";gold=99", true) == Err(ResultCode::GenericError));
+		assert!(verify_file(&mounts, "/other/slot1", b"", false).is_ok());
+	}
+}
@@ -0,0 +1,117 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::queue::WorkState;
+use crate::{LfsError, ReadBuffer, ResultCode, WorkHandle};
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+// Resolves once the work item's completion callback fires, yielding the read buffer
+// (empty for operations that don't read) or the failing result code.
+pub struct WorkFuture {
+	work_item: WorkHandle,
+	work: Arc<WorkState>
+}
+
+impl WorkFuture {
+	pub fn new(work_item: WorkHandle) -> WorkFuture {
+		let work = work_item.work_state().clone();
+		WorkFuture {
+			work_item: work_item,
+			work: work
+		}
+	}
+
+	pub fn cancel(&self) -> bool {
+		self.work_item.cancel()
+	}
+}
+
+impl From<WorkHandle> for WorkFuture {
+	fn from(work_item: WorkHandle) -> WorkFuture {
+		WorkFuture::new(work_item)
+	}
+}
+
+impl Future for WorkFuture {
+	type Output = Result<ReadBuffer, LfsError>;
+
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+		if !self.work.poll_finished(cx.waker()) {
+			return Poll::Pending;
+		}
+
+		let work_item = &mut self.work_item;
+		match work_item.get_result() {
+			ResultCode::Ok => Poll::Ready(Ok(work_item.take_buffer())),
+			code => Poll::Ready(Err(work_item.error(code)))
+		}
+	}
+}
+
+// Polls a future to completion on the current thread, for tests that have no runtime.
+#[cfg(test)]
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+	struct ThreadWake(std::thread::Thread);
+
+	impl std::task::Wake for ThreadWake {
+		fn wake(self: Arc<Self>) {
+			self.0.unpark();
+		}
+	}
+
+	let waker = Arc::new(ThreadWake(std::thread::current())).into();
+	let mut cx = Context::from_waker(&waker);
+	let mut future = Box::pin(future);
+	loop {
+		match future.as_mut().poll(&mut cx) {
+			Poll::Ready(output) => return output,
+			Poll::Pending => std::thread::park()
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_dir::TestDir;
+	use crate::LaminaFS;
+
+	#[test]
+	fn work_future_test() {
+		let root = TestDir::new("work_future_test");
+		std::fs::write(root.join("level.bin"), b"level data").unwrap();
+		let fs = LaminaFS::new();
+		let _mount = root.mount(&fs);
+
+		let buffer = block_on(WorkFuture::new(fs.read_file("/level.bin", false))).unwrap();
+		assert!(&buffer[..] == b"level data");
+
+		match block_on(WorkFuture::from(fs.read_file("/missing.bin", false))) {
+			Err(error) => assert!(error.code() == ResultCode::NotFound && error.path() == Some("/missing.bin")),
+			Ok(_) => panic!("read a missing file")
+		}
+	}
+}
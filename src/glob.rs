@@ -0,0 +1,83 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// Minimal glob matching over '/' separated paths: `*` and `?` match within a path
+// component, `**` matches any number of whole components.
+
+fn match_component(pattern: &[u8], name: &[u8]) -> bool {
+	match pattern.split_first() {
+		None => name.is_empty(),
+		Some((b'*', rest)) => (0..=name.len()).any(|skip| match_component(rest, &name[skip..])),
+		Some((b'?', rest)) => !name.is_empty() && match_component(rest, &name[1..]),
+		Some((c, rest)) => name.first() == Some(c) && match_component(rest, &name[1..])
+	}
+}
+
+fn match_components(pattern: &[&str], path: &[&str]) -> bool {
+	match pattern.split_first() {
+		None => path.is_empty(),
+		Some((&"**", rest)) => (0..=path.len()).any(|skip| match_components(rest, &path[skip..])),
+		Some((component, rest)) => match path.split_first() {
+			Some((name, path_rest)) => match_component(component.as_bytes(), name.as_bytes()) && match_components(rest, path_rest),
+			None => false
+		}
+	}
+}
+
+fn components(path: &str) -> Vec<&str> {
+	path.split('/').filter(|component| !component.is_empty()).collect()
+}
+
+pub(crate) fn is_glob(pattern: &str) -> bool {
+	pattern.contains(|c| c == '*' || c == '?')
+}
+
+pub(crate) fn glob_match(pattern: &str, path: &str) -> bool {
+	match_components(&components(pattern), &components(path))
+}
+
+// The directory below which every match of `pattern` lives, and how many levels below
+// it matches can be (None for patterns containing `**`).
+pub(crate) fn glob_base(pattern: &str) -> (String, Option<usize>) {
+	let pattern = components(pattern);
+	let literal = pattern.iter().take_while(|component| !is_glob(component)).count();
+	let base = std::cmp::min(literal, pattern.len().saturating_sub(1));
+
+	let depth = if pattern.contains(&"**") { None } else { Some(pattern.len() - base) };
+	(format!("/{}", pattern[..base].join("/")), depth)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn glob_test() {
+		assert!(glob_match("/assets/**", "/assets/textures/stone.png"));
+		assert!(glob_match("/assets/**/*.png", "/assets/stone.png"));
+		assert!(!glob_match("/assets/*.png", "/assets/textures/stone.png"));
+		assert!(glob_match("/levels/level?.bin", "/levels/level3.bin"));
+		assert!(glob_base("/assets/**") == ("/assets".to_string(), None));
+		assert!(glob_base("/config/game.ini") == ("/config".to_string(), Some(1)));
+	}
+}
@@ -0,0 +1,115 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// Reads that hash their data in the completion callback, on the worker thread that read
+// it, so checking downloaded content doesn't take a second pass over it.
+
+use crate::{LaminaFS, Priority, ResultCode, WorkHandle, WorkItemResult};
+
+#[cfg(feature = "sha256")]
+use sha2::{Digest, Sha256};
+
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HashKind {
+	Crc32,
+	#[cfg(feature = "sha256")]
+	Sha256
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FileHash {
+	Crc32(u32),
+	#[cfg(feature = "sha256")]
+	Sha256([u8; 32])
+}
+
+// CRC-32 as used by zip and PNG (reflected, polynomial 0xEDB88320).
+const fn crc32_table() -> [u32; 256] {
+	let mut table = [0u32; 256];
+	let mut i = 0;
+	while i < 256 {
+		let mut crc = i as u32;
+		let mut bit = 0;
+		while bit < 8 {
+			crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+			bit += 1;
+		}
+		table[i] = crc;
+		i += 1;
+	}
+	table
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+	!data.iter().fold(!0u32, |crc, &byte| CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8))
+}
+
+impl HashKind {
+	pub fn hash(self, data: &[u8]) -> FileHash {
+		match self {
+			HashKind::Crc32 => FileHash::Crc32(crc32(data)),
+			#[cfg(feature = "sha256")]
+			HashKind::Sha256 => {
+				let mut hasher = Sha256::new();
+				hasher.update(data);
+				FileHash::Sha256(hasher.finalize().into())
+			}
+		}
+	}
+}
+
+pub(crate) type HashSlot = Arc<Mutex<Option<FileHash>>>;
+
+impl LaminaFS {
+	// Reads the file and hashes it before the work item completes. The hash is available
+	// from WorkHandle::get_hash once the read has succeeded.
+	pub fn read_file_hashed(&self, path: &str, kind: HashKind) -> WorkHandle {
+		let slot: HashSlot = Arc::new(Mutex::new(None));
+		let callback_slot = slot.clone();
+		let callback = move |result: &WorkItemResult| {
+			if result.get_result() == ResultCode::Ok {
+				*callback_slot.lock().unwrap() = Some(kind.hash(result.get_buffer()));
+			}
+		};
+
+		let mut work_item = self.submit_read_file(path, false, Priority::Normal, Some(Box::new(callback)));
+		work_item.set_hash_slot(slot);
+		work_item
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn crc32_test() {
+		assert!(HashKind::Crc32.hash(b"") == FileHash::Crc32(0));
+		assert!(HashKind::Crc32.hash(b"123456789") == FileHash::Crc32(0xCBF4_3926));
+		assert!(HashKind::Crc32.hash(b"The quick brown fox jumps over the lazy dog") == FileHash::Crc32(0x414F_A339));
+	}
+}
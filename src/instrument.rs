@@ -0,0 +1,170 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// Metrics for the work submitted through LaminaFS, emitted through the `metrics` facade
+// so they reach whatever recorder the application installed:
+//
+//   laminafs.operations          counter, per operation, mount and result
+//   laminafs.bytes_read          counter, bytes read per operation and mount
+//   laminafs.bytes_written       counter, bytes written per operation and mount
+//   laminafs.operation.duration  histogram, seconds from submission to completion
+//   laminafs.queue.wait          histogram, seconds work spent queued before reaching
+//                                the context
+//
+// The mount label is the mount point of the first mount covering the path, "" if none.
+
+use crate::local::DeviceOp;
+use crate::queue::CompletionCallback;
+use crate::{OperationKind, WorkItemResult};
+
+use std::time::{Duration, Instant};
+
+// Records the work once it completes.
+pub(crate) fn on_completion(operation: OperationKind, mount: String, op: &DeviceOp, callback: Option<CompletionCallback>) -> Option<CompletionCallback> {
+	let submitted = Instant::now();
+	let (reads, write_len) = match *op {
+		DeviceOp::ReadFile { .. } | DeviceOp::ReadFileSegment { .. } => (true, None),
+		DeviceOp::WriteFile { ref buffer, .. } => (false, Some(buffer.len())),
+		_ => (false, None)
+	};
+
+	Some(Box::new(move |result: &WorkItemResult| {
+		let name = operation.name();
+		metrics::increment_counter!("laminafs.operations", "operation" => name, "mount" => mount.clone(), "result" => result.get_result().to_string());
+		metrics::histogram!("laminafs.operation.duration", submitted.elapsed().as_secs_f64(), "operation" => name, "mount" => mount.clone());
+		if reads {
+			metrics::counter!("laminafs.bytes_read", result.get_bytes() as u64, "operation" => name, "mount" => mount);
+		} else if let Some(len) = write_len {
+			metrics::counter!("laminafs.bytes_written", len as u64, "operation" => name, "mount" => mount);
+		}
+
+		if let Some(callback) = callback {
+			callback(result);
+		}
+	}))
+}
+
+pub(crate) fn queue_wait(wait: Duration) {
+	metrics::histogram!("laminafs.queue.wait", wait.as_secs_f64());
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::test_dir::TestDir;
+	use crate::{LaminaFS, ResultCode};
+
+	use metrics::{Counter, CounterFn, Gauge, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder, SharedString, Unit};
+
+	use std::collections::HashMap;
+	use std::sync::{Arc, Mutex};
+
+	// "name result" -> total, for metrics labelled with the test's mount
+	type Totals = Arc<Mutex<HashMap<String, f64>>>;
+
+	struct Total {
+		name: String,
+		totals: Totals
+	}
+
+	impl CounterFn for Total {
+		fn increment(&self, value: u64) {
+			*self.totals.lock().unwrap().entry(self.name.clone()).or_insert(0.0) += value as f64;
+		}
+
+		fn absolute(&self, value: u64) {
+			self.totals.lock().unwrap().insert(self.name.clone(), value as f64);
+		}
+	}
+
+	impl HistogramFn for Total {
+		// counts the samples
+		fn record(&self, _value: f64) {
+			*self.totals.lock().unwrap().entry(self.name.clone()).or_insert(0.0) += 1.0;
+		}
+	}
+
+	struct TestRecorder {
+		mount: &'static str,
+		totals: Totals
+	}
+
+	impl TestRecorder {
+		fn total(&self, key: &Key) -> Option<Arc<Total>> {
+			let label = |name: &str| key.labels().find(|label| label.key() == name).map(|label| label.value().to_string());
+			if label("mount").as_deref() != Some(self.mount) {
+				return None;
+			}
+
+			let name = match label("result") {
+				Some(result) => format!("{} {}", key.name(), result),
+				None => key.name().to_string()
+			};
+			Some(Arc::new(Total {
+				name: name,
+				totals: self.totals.clone()
+			}))
+		}
+	}
+
+	impl Recorder for TestRecorder {
+		fn describe_counter(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+		fn describe_gauge(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+		fn describe_histogram(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+		fn register_counter(&self, key: &Key, _metadata: &Metadata<'_>) -> Counter {
+			self.total(key).map_or_else(Counter::noop, Counter::from_arc)
+		}
+
+		fn register_gauge(&self, _key: &Key, _metadata: &Metadata<'_>) -> Gauge {
+			Gauge::noop()
+		}
+
+		fn register_histogram(&self, key: &Key, _metadata: &Metadata<'_>) -> Histogram {
+			self.total(key).map_or_else(Histogram::noop, Histogram::from_arc)
+		}
+	}
+
+	#[test]
+	fn metrics_test() {
+		let root = TestDir::new("metrics_test");
+		let totals = Totals::default();
+		metrics::set_boxed_recorder(Box::new(TestRecorder {
+			mount: "/metrics_test",
+			totals: totals.clone()
+		})).unwrap();
+
+		let fs = LaminaFS::new();
+		let _mount = fs.create_mount_with_permissions(crate::DeviceType::Directory, "/metrics_test", root.to_str(), crate::MountPermissions::All).unwrap();
+		fs.write_file_sync("/metrics_test/save.bin", b"save").unwrap();
+		assert!(fs.read_file_sync("/metrics_test/save.bin").unwrap() == b"save");
+		assert!(fs.read_file("/metrics_test/missing.bin", false).get_result() == ResultCode::NotFound);
+
+		let totals = totals.lock().unwrap();
+		let total = |name: &str| totals.get(name).cloned().unwrap_or(0.0);
+		assert!(total(&format!("laminafs.operations {}", ResultCode::Ok)) == 2.0);
+		assert!(total(&format!("laminafs.operations {}", ResultCode::NotFound)) == 1.0);
+		assert!(total("laminafs.bytes_written") == 4.0);
+		assert!(total("laminafs.bytes_read") == 4.0);
+		assert!(total("laminafs.operation.duration") == 3.0);
+	}
+}
@@ -0,0 +1,511 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+use crate::{LaminaFS, LfsError, ReadBuffer, ResultCode, WorkHandle};
+
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+
+pub const DEFAULT_CHUNK_SIZE: u64 = 256 * 1024;
+const MAX_PENDING_WRITES: usize = 4;
+const STREAM_READ_AHEAD: usize = 2;
+
+fn wait_for_chunk(mut work_item: WorkHandle) -> Result<ReadBuffer, LfsError> {
+	match work_item.get_result() {
+		ResultCode::Ok => Ok(work_item.take_buffer()),
+		code => Err(work_item.error(code))
+	}
+}
+
+// Reads a file through segment reads of `chunk_size` bytes, keeping the next chunk in
+// flight while the current one is consumed.
+pub struct LfsReader<'a> {
+	fs: &'a LaminaFS,
+	path: String,
+	chunk_size: u64,
+	position: u64,
+	chunk: Option<(u64, ReadBuffer)>,
+	read_ahead: Option<(u64, WorkHandle)>,
+	size: Option<u64>
+}
+
+impl<'a> LfsReader<'a> {
+	pub fn new(fs: &'a LaminaFS, path: &str) -> LfsReader<'a> {
+		LfsReader::with_chunk_size(fs, path, DEFAULT_CHUNK_SIZE)
+	}
+
+	pub fn with_chunk_size(fs: &'a LaminaFS, path: &str, chunk_size: u64) -> LfsReader<'a> {
+		LfsReader {
+			fs: fs,
+			path: path.to_string(),
+			chunk_size: std::cmp::max(chunk_size, 1),
+			position: 0,
+			chunk: None,
+			read_ahead: None,
+			size: None
+		}
+	}
+
+	pub fn path(&self) -> &str {
+		&self.path
+	}
+
+	pub fn chunk_size(&self) -> u64 {
+		self.chunk_size
+	}
+
+	fn submit_chunk(&self, offset: u64) -> WorkHandle {
+		self.fs.read_file_segment(&self.path, offset, self.chunk_size, false)
+	}
+
+	fn load_chunk(&mut self, offset: u64) -> Result<(), LfsError> {
+		let work_item = match self.read_ahead.take() {
+			Some((read_ahead_offset, work_item)) if read_ahead_offset == offset => work_item,
+			Some((_, work_item)) => {
+				work_item.cancel();
+				self.submit_chunk(offset)
+			},
+			None => self.submit_chunk(offset)
+		};
+
+		let buffer = wait_for_chunk(work_item)?;
+		if buffer.len() as u64 == self.chunk_size {
+			let next = offset + self.chunk_size;
+			self.read_ahead = Some((next, self.submit_chunk(next)));
+		} else {
+			// a short chunk is the end of the file
+			self.size = Some(offset + buffer.len() as u64);
+		}
+
+		self.chunk = Some((offset, buffer));
+		Ok(())
+	}
+
+	fn size(&mut self) -> Result<u64, LfsError> {
+		if let Some(size) = self.size {
+			return Ok(size);
+		}
+
+		let size = self.fs.file_size(&self.path).get_result()?;
+		self.size = Some(size);
+		Ok(size)
+	}
+
+	// The unread part of the chunk holding the current position, empty at the end of the file.
+	fn current_chunk(&mut self) -> Result<&[u8], LfsError> {
+		if self.size.map_or(false, |size| self.position >= size) {
+			return Ok(&[]);
+		}
+
+		let position = self.position;
+		let loaded = match self.chunk {
+			Some((offset, ref chunk)) => position >= offset && position < offset + chunk.len() as u64,
+			None => false
+		};
+		if !loaded {
+			self.load_chunk(position)?;
+		}
+
+		let (offset, chunk) = self.chunk.as_ref().unwrap();
+		let start = std::cmp::min((position - offset) as usize, chunk.len());
+		Ok(&chunk[start..])
+	}
+}
+
+impl<'a> Read for LfsReader<'a> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		if buf.is_empty() {
+			return Ok(0);
+		}
+
+		let chunk = self.current_chunk()?;
+		let count = std::cmp::min(chunk.len(), buf.len());
+		buf[..count].copy_from_slice(&chunk[..count]);
+		self.position += count as u64;
+		Ok(count)
+	}
+}
+
+impl<'a> Seek for LfsReader<'a> {
+	fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+		let position = match pos {
+			SeekFrom::Start(offset) => Some(offset),
+			SeekFrom::Current(delta) => offset_by(self.position, delta),
+			SeekFrom::End(delta) => offset_by(self.size()?, delta)
+		};
+
+		match position {
+			Some(position) => {
+				self.position = position;
+				Ok(position)
+			},
+			None => Err(io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position"))
+		}
+	}
+}
+
+fn offset_by(base: u64, delta: i64) -> Option<u64> {
+	if delta < 0 {
+		base.checked_sub(delta.unsigned_abs())
+	} else {
+		base.checked_add(delta as u64)
+	}
+}
+
+// Buffered reads straight out of LfsReader's chunks, so lines and records can be consumed
+// incrementally without copying through a second buffer.
+pub struct LfsBufReader<'a> {
+	reader: LfsReader<'a>
+}
+
+impl<'a> LfsBufReader<'a> {
+	pub fn new(reader: LfsReader<'a>) -> LfsBufReader<'a> {
+		LfsBufReader {
+			reader: reader
+		}
+	}
+
+	pub fn get_ref(&self) -> &LfsReader<'a> {
+		&self.reader
+	}
+
+	pub fn into_inner(self) -> LfsReader<'a> {
+		self.reader
+	}
+}
+
+impl<'a> Read for LfsBufReader<'a> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		self.reader.read(buf)
+	}
+}
+
+impl<'a> BufRead for LfsBufReader<'a> {
+	fn fill_buf(&mut self) -> io::Result<&[u8]> {
+		Ok(self.reader.current_chunk()?)
+	}
+
+	fn consume(&mut self, amount: usize) {
+		self.reader.position += amount as u64;
+	}
+}
+
+impl<'a> Seek for LfsBufReader<'a> {
+	fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+		self.reader.seek(pos)
+	}
+}
+
+// Yields a file as consecutive chunks, each a separate segment read, with a couple of
+// chunks kept in flight. Iteration ends at the first short chunk or after an error.
+pub struct StreamedRead<'a> {
+	fs: &'a LaminaFS,
+	path: String,
+	chunk_size: u64,
+	next_offset: u64,
+	in_flight: VecDeque<WorkHandle>,
+	finished: bool
+}
+
+impl<'a> StreamedRead<'a> {
+	pub fn new(fs: &'a LaminaFS, path: &str, chunk_size: u64) -> StreamedRead<'a> {
+		StreamedRead {
+			fs: fs,
+			path: path.to_string(),
+			chunk_size: std::cmp::max(chunk_size, 1),
+			next_offset: 0,
+			in_flight: VecDeque::new(),
+			finished: false
+		}
+	}
+
+	fn finish(&mut self) {
+		self.finished = true;
+		for work_item in self.in_flight.drain(..) {
+			work_item.cancel();
+		}
+	}
+}
+
+impl<'a> Iterator for StreamedRead<'a> {
+	type Item = Result<ReadBuffer, LfsError>;
+
+	fn next(&mut self) -> Option<Result<ReadBuffer, LfsError>> {
+		while !self.finished && self.in_flight.len() < STREAM_READ_AHEAD {
+			self.in_flight.push_back(self.fs.read_file_segment(&self.path, self.next_offset, self.chunk_size, false));
+			self.next_offset += self.chunk_size;
+		}
+
+		let chunk = match self.in_flight.pop_front() {
+			Some(work_item) => wait_for_chunk(work_item),
+			None => return None
+		};
+
+		match chunk {
+			Ok(ref buffer) if (buffer.len() as u64) < self.chunk_size => {
+				self.finish();
+				if buffer.is_empty() {
+					return None;
+				}
+			},
+			Ok(_) => {},
+			Err(_) => self.finish()
+		}
+		Some(chunk)
+	}
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum WriterMode {
+	Create,
+	Append
+}
+
+// Buffers writes into chunks of `chunk_size` bytes. In create mode the first chunk
+// replaces the file and the rest go out as segment writes that can complete in any
+// order; in append mode each append waits for the previous one to keep them ordered.
+// Dropping the writer flushes it, but only flush() reports errors.
+pub struct LfsWriter<'a> {
+	fs: &'a LaminaFS,
+	path: String,
+	mode: WriterMode,
+	chunk_size: usize,
+	buffer: Vec<u8>,
+	offset: u64,
+	started: bool,
+	pending: Vec<WorkHandle>
+}
+
+impl<'a> LfsWriter<'a> {
+	pub fn create(fs: &'a LaminaFS, path: &str) -> LfsWriter<'a> {
+		LfsWriter::new(fs, path, WriterMode::Create, DEFAULT_CHUNK_SIZE as usize)
+	}
+
+	pub fn append(fs: &'a LaminaFS, path: &str) -> LfsWriter<'a> {
+		LfsWriter::new(fs, path, WriterMode::Append, DEFAULT_CHUNK_SIZE as usize)
+	}
+
+	pub fn with_chunk_size(mut self, chunk_size: usize) -> LfsWriter<'a> {
+		self.chunk_size = std::cmp::max(chunk_size, 1);
+		self
+	}
+
+	fn new(fs: &'a LaminaFS, path: &str, mode: WriterMode, chunk_size: usize) -> LfsWriter<'a> {
+		LfsWriter {
+			fs: fs,
+			path: path.to_string(),
+			mode: mode,
+			chunk_size: chunk_size,
+			buffer: Vec::new(),
+			offset: 0,
+			started: false,
+			pending: Vec::new()
+		}
+	}
+
+	pub fn path(&self) -> &str {
+		&self.path
+	}
+
+	fn wait_pending(&mut self, count: usize) -> Result<(), LfsError> {
+		let mut first_error = None;
+		for work_item in self.pending.drain(..count) {
+			match work_item.get_result() {
+				ResultCode::Ok => {},
+				code => {
+					first_error.get_or_insert_with(|| work_item.error(code));
+				}
+			}
+		}
+
+		match first_error {
+			Some(error) => Err(error),
+			None => Ok(())
+		}
+	}
+
+	fn write_chunk(&mut self) -> Result<(), LfsError> {
+		if self.buffer.is_empty() && self.started {
+			return Ok(());
+		}
+
+		let data: Arc<[u8]> = Arc::from(std::mem::replace(&mut self.buffer, Vec::new()));
+		let len = data.len() as u64;
+		match self.mode {
+			WriterMode::Create if !self.started => {
+				// everything after this is a segment write, so the file has to exist first
+				self.pending.push(self.fs.write_file(&self.path, data));
+				let count = self.pending.len();
+				self.wait_pending(count)?;
+			},
+			WriterMode::Create => {
+				if self.pending.len() >= MAX_PENDING_WRITES {
+					self.wait_pending(1)?;
+				}
+				self.pending.push(self.fs.write_file_segment(&self.path, self.offset, data));
+			},
+			WriterMode::Append => {
+				let count = self.pending.len();
+				self.wait_pending(count)?;
+				self.pending.push(self.fs.append_file(&self.path, data));
+			}
+		}
+
+		self.started = true;
+		self.offset += len;
+		Ok(())
+	}
+}
+
+impl<'a> Write for LfsWriter<'a> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let count = std::cmp::min(self.chunk_size - self.buffer.len(), buf.len());
+		self.buffer.extend_from_slice(&buf[..count]);
+		if self.buffer.len() == self.chunk_size {
+			self.write_chunk()?;
+		}
+		Ok(count)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.write_chunk()?;
+		let count = self.pending.len();
+		self.wait_pending(count)?;
+		Ok(())
+	}
+}
+
+impl<'a> Drop for LfsWriter<'a> {
+	fn drop(&mut self) {
+		let _ = self.flush();
+	}
+}
+
+impl LaminaFS {
+	pub fn read_file_streamed(&self, path: &str, chunk_size: u64) -> StreamedRead<'_> {
+		StreamedRead::new(self, path, chunk_size)
+	}
+
+	pub fn open_reader(&self, path: &str) -> LfsReader<'_> {
+		LfsReader::new(self, path)
+	}
+
+	pub fn open_buf_reader(&self, path: &str) -> LfsBufReader<'_> {
+		LfsBufReader::new(LfsReader::new(self, path))
+	}
+
+	// Iterates over the lines of a text file, reading it a chunk at a time.
+	pub fn read_lines(&self, path: &str) -> io::Lines<LfsBufReader<'_>> {
+		self.open_buf_reader(path).lines()
+	}
+
+	pub fn create_writer(&self, path: &str) -> LfsWriter<'_> {
+		LfsWriter::create(self, path)
+	}
+
+	pub fn append_writer(&self, path: &str) -> LfsWriter<'_> {
+		LfsWriter::append(self, path)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{device, MountPermissions};
+
+	#[test]
+	fn reader_test() {
+		let fs = LaminaFS::new();
+		let memory = fs.register_device::<device::MemoryDevice>();
+		let _mount = fs.create_mount_with_permissions(memory, "/", "", MountPermissions::All).unwrap();
+		fs.write_file_sync("/level.bin", b"0123456789abcdef").unwrap();
+
+		// chunks smaller than the reads, so reads cross chunk boundaries
+		let mut reader = LfsReader::with_chunk_size(&fs, "/level.bin", 3);
+		let mut contents = Vec::new();
+		reader.read_to_end(&mut contents).unwrap();
+		assert!(contents == b"0123456789abcdef");
+
+		let mut buffer = [0u8; 4];
+		assert!(reader.seek(SeekFrom::Start(5)).unwrap() == 5);
+		reader.read_exact(&mut buffer).unwrap();
+		assert!(&buffer == b"5678");
+		assert!(reader.seek(SeekFrom::Current(-6)).unwrap() == 3);
+		reader.read_exact(&mut buffer).unwrap();
+		assert!(&buffer == b"3456");
+		assert!(reader.seek(SeekFrom::End(-2)).unwrap() == 14);
+		assert!(reader.read(&mut buffer).unwrap() == 2 && &buffer[..2] == b"ef");
+		assert!(reader.read(&mut buffer).unwrap() == 0);
+		assert!(reader.seek(SeekFrom::Current(-20)).unwrap_err().kind() == io::ErrorKind::InvalidInput);
+
+		let mut missing = LfsReader::new(&fs, "/missing.bin");
+		assert!(missing.read(&mut buffer).unwrap_err().kind() == io::ErrorKind::NotFound);
+	}
+
+	#[test]
+	fn writer_test() {
+		let fs = LaminaFS::new();
+		let memory = fs.register_device::<device::MemoryDevice>();
+		let _mount = fs.create_mount_with_permissions(memory, "/", "", MountPermissions::All).unwrap();
+		fs.write_file_sync("/save.bin", b"stale contents from an older save").unwrap();
+
+		// the first chunk replaces the file, the rest are segment writes
+		let mut writer = LfsWriter::create(&fs, "/save.bin").with_chunk_size(4);
+		writer.write_all(b"level 2, gold 30").unwrap();
+		writer.flush().unwrap();
+		drop(writer);
+		assert!(fs.read_file_sync("/save.bin").unwrap() == b"level 2, gold 30");
+
+		let mut writer = LfsWriter::append(&fs, "/save.bin").with_chunk_size(3);
+		writer.write_all(b", hp 12").unwrap();
+		drop(writer);
+		assert!(fs.read_file_sync("/save.bin").unwrap() == b"level 2, gold 30, hp 12");
+
+		let mut writer = LfsWriter::create(&fs, "/missing/save.bin").with_chunk_size(4);
+		assert!(writer.write_all(b"save").unwrap_err().kind() == io::ErrorKind::NotFound);
+	}
+
+	#[test]
+	fn streamed_read_test() {
+		let fs = LaminaFS::new();
+		let memory = fs.register_device::<device::MemoryDevice>();
+		let _mount = fs.create_mount_with_permissions(memory, "/", "", MountPermissions::All).unwrap();
+		fs.write_file_sync("/level.bin", b"0123456789").unwrap();
+		fs.write_file_sync("/save.bin", b"01234567").unwrap();
+
+		let chunks: Vec<Vec<u8>> = fs.read_file_streamed("/level.bin", 4).map(|chunk| chunk.unwrap().to_vec()).collect();
+		assert!(chunks == vec![b"0123".to_vec(), b"4567".to_vec(), b"89".to_vec()]);
+
+		// a file that ends on a chunk boundary has no trailing empty chunk
+		let chunks: Vec<Vec<u8>> = fs.read_file_streamed("/save.bin", 4).map(|chunk| chunk.unwrap().to_vec()).collect();
+		assert!(chunks == vec![b"0123".to_vec(), b"4567".to_vec()]);
+
+		let mut missing = fs.read_file_streamed("/missing.bin", 4);
+		match missing.next() {
+			Some(Err(error)) => assert!(error.code() == ResultCode::NotFound),
+			_ => panic!("expected the first chunk to fail")
+		}
+		assert!(missing.next().is_none());
+	}
+}
@@ -24,13 +24,126 @@ SOFTWARE.
 extern crate bitflags;
 
 mod laminafs_sys;
+mod alias;
+mod alloc;
+#[cfg(feature = "tokio")]
+mod async_api;
+#[cfg(feature = "tokio")]
+mod async_io;
+mod batch;
+mod builder;
+mod cache;
+mod case_index;
+mod copy;
+mod debounce;
+#[cfg(any(feature = "json", feature = "toml", feature = "ron"))]
+mod deserialize;
+mod detach;
+pub mod device;
+mod error;
+mod events;
+mod file;
+mod flush;
+mod future;
+mod glob;
+mod hash;
+#[cfg(feature = "metrics")]
+mod instrument;
+mod io;
+mod local;
+mod lock;
+#[cfg(any(feature = "json", feature = "toml"))]
+mod manifest;
+#[cfg(feature = "mmap")]
+mod mapped;
+mod mount;
+#[cfg(feature = "notify")]
+mod native_watch;
+mod notify;
+mod path;
+mod pending;
+mod pool;
+mod queue;
+mod quota;
+mod raw;
+mod record;
+mod retry;
+#[cfg(feature = "tracing")]
+mod spans;
+#[cfg(feature = "stream")]
+mod stream;
+mod sync_api;
+mod task;
+#[cfg(test)]
+mod test_dir;
+mod throttle;
+mod timeout;
+mod transfer;
+mod walk;
+mod watch;
+
+use alias::AliasTable;
+use alloc::ContextAllocator;
+use cache::{Intercepted, ReadCache};
+use case_index::CaseIndex;
+use hash::HashSlot;
+use detach::{Detached, DetachedWork};
+use device::{Device, WriteMode};
+use device::CreatedDevice;
+use local::DeviceOp;
+use mount::{MountEntry, MountOptions, MountPtr, MountTable};
+use events::MountEvents;
+use queue::{CompletionCallback, SubmitFn, WorkQueue, WorkState};
+use pending::PendingWork;
+use quota::Quota;
+use record::Recorder;
+use task::TaskPool;
+use throttle::Throttle;
 
+pub use alloc::Allocator;
+#[cfg(feature = "tokio")]
+pub use async_io::AsyncLfsReader;
+pub use batch::{Batch, Operation};
+pub use builder::LaminaFSBuilder;
+pub use debounce::{DebouncedWatcher, ReloadSet};
+pub use detach::DropPolicy;
+pub use device::{DirEntry, FileStat, SyncMode};
+pub use error::{LfsError, OperationKind};
+pub use events::{MountEvent, MountEventHook};
+pub use file::FileHandle;
+pub use flush::WriteOptions;
+pub use future::WorkFuture;
+pub use hash::{FileHash, HashKind};
+pub use io::{LfsBufReader, LfsReader, LfsWriter, StreamedRead};
+pub use local::ExecutionMode;
+pub use lock::{FileLock, LockMode};
+#[cfg(any(feature = "json", feature = "toml"))]
+pub use manifest::{Manifest, ManifestEntry};
+#[cfg(feature = "mmap")]
+pub use mapped::MappedBuffer;
+pub use mount::{DeviceType, MountBuilder, MountInfo, RegisteredDevice, ResolvedPath, UnmountMode};
+pub use notify::{CompletedWork, NotifySender};
+pub use path::{LfsPath, LfsPathBuf};
+pub use pool::{BufferPool, PooledBuffer, DEFAULT_BUFFERS_PER_CLASS};
+pub use queue::{Backpressure, Priority, QueueStats};
+pub use raw::sys;
+pub use record::{RecordedOp, Recording};
+pub use retry::RetryPolicy;
+#[cfg(feature = "stream")]
+pub use stream::DirStream;
+pub use task::Task;
+pub use transfer::{Transfer, DEFAULT_TRANSFER_CHUNK_SIZE};
+pub use walk::WalkEntry;
+pub use watch::{ChangeEvent, ChangeKind, Watcher, DEFAULT_POLL_INTERVAL};
+
+use std::cell::Cell;
+use std::collections::HashMap;
 use std::ffi::CString;
 use std::ptr::NonNull;
 use std::sync::Arc;
 use std::sync::Mutex;
 
-#[derive(PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum ResultCode {
 	Ok,
 	NotFound,
@@ -39,7 +152,13 @@ pub enum ResultCode {
 	OutOfSpace,
 	PermissionsError,
 	Unsupported,
-	GenericError
+	GenericError,
+	Cancelled,
+	InvalidPath,
+	TimedOut,
+	QueueFull,
+	// the file's contents aren't what the caller asked for, e.g. not UTF-8
+	InvalidData
 }
 
 impl ResultCode {
@@ -52,7 +171,7 @@ impl ResultCode {
 			ResultCode::OutOfSpace => laminafs_sys::lfs_error_code_t_LFS_OUT_OF_SPACE ,
 			ResultCode::PermissionsError => laminafs_sys::lfs_error_code_t_LFS_PERMISSIONS_ERROR,
 			ResultCode::Unsupported => laminafs_sys::lfs_error_code_t_LFS_UNSUPPORTED,
-			ResultCode::GenericError => laminafs_sys::lfs_error_code_t_LFS_GENERIC_ERROR
+			ResultCode::GenericError | ResultCode::Cancelled | ResultCode::InvalidPath | ResultCode::TimedOut | ResultCode::QueueFull | ResultCode::InvalidData => laminafs_sys::lfs_error_code_t_LFS_GENERIC_ERROR
 		}
 	}
 
@@ -69,6 +188,17 @@ impl ResultCode {
 			_ => panic!("Unexpected error code from Lamina {}", error)
 		}
 	}
+
+	fn to_index(self) -> u8 {
+		self as u8
+	}
+
+	fn from_index(index: u8) -> ResultCode {
+		const CODES: [ResultCode; 13] = [ResultCode::Ok, ResultCode::NotFound, ResultCode::InvalidDevice, ResultCode::AlreadyExists, ResultCode::OutOfSpace,
+			ResultCode::PermissionsError, ResultCode::Unsupported, ResultCode::GenericError, ResultCode::Cancelled, ResultCode::InvalidPath, ResultCode::TimedOut,
+			ResultCode::QueueFull, ResultCode::InvalidData];
+		CODES[index as usize]
+	}
 }
 
 bitflags! {
@@ -84,235 +214,983 @@ bitflags! {
 	}
 }
 
+// Owns the C context, which is shared with the work items so it is only destroyed once
+// none of them has anything left to release.
+struct Context {
+	raw: laminafs_sys::lfs_context_t,
+	// false for a context adopted from C or C++ code that destroys it itself
+	owned: bool,
+	// held while the context's device and mount lists change
+	mount_lock: Mutex<()>,
+	// both dropped after the context is destroyed
+	device_interfaces: Mutex<Vec<Box<laminafs_sys::lfs_device_interface_t>>>,
+	allocator: Arc<ContextAllocator>
+}
+
+// Work submission and the work item calls are safe from any thread: the context hands
+// work to its own worker threads through an internally synchronized queue. Registering
+// devices and creating or releasing mounts changes lists the C side doesn't lock, so
+// those go through mount_lock. Everything else reachable from a LaminaFS, Mount or
+// WorkHandle is plain Rust and gets its Send and Sync from the compiler.
+unsafe impl Send for Context {}
+unsafe impl Sync for Context {}
+
+impl Context {
+	fn release_mount(&self, mount: &MountPtr) -> bool {
+		let _lock = self.mount_lock.lock().unwrap();
+		unsafe { laminafs_sys::lfs_release_mount(self.raw, mount.ptr) }
+	}
+}
+
+impl Drop for Context {
+	fn drop(&mut self) {
+		if self.owned {
+			unsafe {
+				laminafs_sys::lfs_context_destroy(self.raw);
+			}
+		}
+	}
+}
+
 pub struct LaminaFS {
-	context: laminafs_sys::lfs_context_t
+	context: Arc<Context>,
+	queue: Arc<WorkQueue>,
+	mounts: Arc<MountTable>,
+	aliases: AliasTable,
+	cache: Arc<ReadCache>,
+	// prefetch reads, kept until they finish
+	prefetches: Mutex<Vec<WorkHandle>>,
+	pending: Arc<PendingWork>,
+	detached: Arc<Detached>,
+	recorder: Recorder,
+	mount_events: MountEvents,
+	tasks: TaskPool,
+	buffer_pool: Mutex<Option<Arc<BufferPool>>>,
+	retry_policy: Mutex<Option<RetryPolicy>>,
+	// the built-in Zip device, once a mount has needed it
+	zip_device: Mutex<Option<RegisteredDevice>>,
+	mode: ExecutionMode
 }
 
 impl LaminaFS {
 	pub fn new() -> Arc<LaminaFS> {
-		Arc::new(LaminaFS {
-			context: unsafe { laminafs_sys::lfs_context_create(&mut laminafs_sys::lfs_default_allocator) }
-		})
+		LaminaFS::builder().build()
+	}
+
+	// Work runs on the submitting thread against the mounts' Rust devices and is finished
+	// by the time the submission returns. Only Directory mounts and registered devices are
+	// reachable this way; mounts are still created through the context.
+	pub fn new_single_threaded() -> Arc<LaminaFS> {
+		LaminaFS::builder().execution_mode(ExecutionMode::SingleThread).build()
 	}
 
 	pub fn new_with_capacity(work_item_queue_size: u64, work_item_pool_size: u64) -> Arc<LaminaFS> {
+		LaminaFS::builder().capacity(work_item_queue_size, work_item_pool_size).build()
+	}
+
+	pub fn new_with_allocator<A: Allocator>(allocator: A) -> Arc<LaminaFS> {
+		LaminaFS::builder().allocator(allocator).build()
+	}
+
+	pub fn new_with_capacity_and_allocator<A: Allocator>(work_item_queue_size: u64, work_item_pool_size: u64, allocator: A) -> Arc<LaminaFS> {
+		LaminaFS::builder().capacity(work_item_queue_size, work_item_pool_size).allocator(allocator).build()
+	}
+
+	fn from_builder(builder: LaminaFSBuilder) -> Arc<LaminaFS> {
+		let LaminaFSBuilder { allocator, capacity, raw_context, mode, task_threads, thread_name, spawner } = builder;
+		let (context, max_in_flight, pool_size) = match (raw_context, capacity) {
+			(Some((context, _)), _) => (context, queue::DEFAULT_MAX_IN_FLIGHT, None),
+			(None, Some((work_item_queue_size, work_item_pool_size))) => (
+				unsafe { laminafs_sys::lfs_context_create_capacity(allocator.as_raw(), work_item_queue_size, work_item_pool_size) },
+				std::cmp::min(queue::DEFAULT_MAX_IN_FLIGHT as u64, work_item_queue_size) as usize,
+				Some(work_item_pool_size as usize)
+			),
+			(None, None) => (
+				unsafe { laminafs_sys::lfs_context_create(allocator.as_raw()) },
+				queue::DEFAULT_MAX_IN_FLIGHT,
+				None
+			)
+		};
+
 		Arc::new(LaminaFS {
-			context: unsafe { laminafs_sys::lfs_context_create_capacity(
-				&mut laminafs_sys::lfs_default_allocator,
-				work_item_queue_size,
-				work_item_pool_size) }
+			context: Arc::new(Context {
+				raw: context,
+				owned: raw_context.map_or(true, |(_, owned)| owned),
+				mount_lock: Mutex::new(()),
+				device_interfaces: Mutex::new(Vec::new()),
+				allocator: Arc::new(allocator)
+			}),
+			queue: WorkQueue::with_capacity(max_in_flight, pool_size),
+			mounts: Arc::new(MountTable::new()),
+			aliases: AliasTable::new(),
+			cache: Arc::new(ReadCache::new()),
+			prefetches: Mutex::new(Vec::new()),
+			pending: Arc::new(PendingWork::new()),
+			detached: Arc::new(Detached::new()),
+			recorder: Recorder::new(),
+			mount_events: MountEvents::new(),
+			tasks: match mode {
+				ExecutionMode::Threaded => TaskPool::new(task_threads, &thread_name, spawner),
+				ExecutionMode::SingleThread => TaskPool::inline()
+			},
+			buffer_pool: Mutex::new(None),
+			retry_policy: Mutex::new(None),
+			zip_device: Mutex::new(None),
+			mode: mode
 		})
 	}
 
-	pub fn create_mount_with_permissions(&self, device_type: u32, mount_point: &str, device_path: &str, permissions: MountPermissions) -> Result<Mount, ResultCode> {
+	pub fn register_device<D: Device>(&self) -> RegisteredDevice {
+		let mut interface = Box::new(device::device_interface::<D>());
+		let device_type = {
+			let _lock = self.context.mount_lock.lock().unwrap();
+			unsafe { laminafs_sys::lfs_register_device_interface(self.context.raw, &mut *interface) }
+		};
+
+		// keep the interface alive for as long as the context might call into it
+		self.context.device_interfaces.lock().unwrap().push(interface);
+		RegisteredDevice::new(device_type)
+	}
+
+	// The context's id for `device_type`, registering the Zip device on first use.
+	fn device_id(&self, device_type: DeviceType) -> u32 {
+		match device_type.id() {
+			Some(id) => id,
+			None => self.zip_device.lock().unwrap().get_or_insert_with(|| self.register_device::<device::ZipDevice>()).id()
+		}
+	}
+
+	// Creates the context's side of a mount, handing it `reused` as its device if set.
+	fn create_lamina_mount(&self, device_type: DeviceType, mount_point: &str, device_path: &str, permissions: MountPermissions, reused: Option<&CreatedDevice>) -> Result<(MountPtr, Option<CreatedDevice>), ResultCode> {
 		let mut result_code: laminafs_sys::lfs_error_code_t = 0;
-		let mount_point = CString::new(mount_point).unwrap();
-		let device_path = CString::new(device_path).unwrap();
+		let mount_point = LfsPath::new(mount_point).map_err(|error| error.code())?.to_c_string();
+		// the built-in Directory device hands its path straight to the OS
+		let device_path = match device_type {
+			DeviceType::Directory => device::long_path(std::path::PathBuf::from(device_path)).to_string_lossy().into_owned(),
+			_ => device_path.to_string()
+		};
+		let device_path = CString::new(device_path).map_err(|_| ResultCode::InvalidPath)?;
+		let device_id = self.device_id(device_type);
 
+		let _lock = self.context.mount_lock.lock().unwrap();
+		device::take_created_device();
+		device::reuse_device(reused);
 		let mount = unsafe { laminafs_sys::lfs_create_mount_with_permissions(
-			self.context,
-			device_type,
+			self.context.raw,
+			device_id,
 			mount_point.as_c_str().as_ptr(),
 			device_path.as_c_str().as_ptr(),
 			&mut result_code,
 			permissions.bits()) };
+		device::reuse_device(None);
+		let created_device = device::take_created_device();
 
 		if result_code == laminafs_sys::lfs_error_code_t_LFS_OK {
-			Ok(Mount {
-				mount: mount,
-				context: self.context
-			})
+			Ok((MountPtr { ptr: mount }, created_device))
 		} else {
 			Err(ResultCode::from_lamina(result_code))
 		}
 	}
 
-	pub fn create_mount(&self, device_type: u32, mount_point: &str, device_path: &str) -> Result<Mount, ResultCode> {
+	pub fn create_mount_with_permissions<T: Into<DeviceType>>(self: &Arc<Self>, device_type: T, mount_point: &str, device_path: &str, permissions: MountPermissions) -> Result<Mount, LfsError> {
+		self.create_mount_with_options(device_type.into(), mount_point, device_path, permissions, MountOptions::default())
+	}
+
+	fn create_mount_with_options(self: &Arc<Self>, device_type: DeviceType, mount_point: &str, device_path: &str, permissions: MountPermissions, options: MountOptions) -> Result<Mount, LfsError> {
+		let (handle, created_device) = self.create_lamina_mount(device_type, mount_point, device_path, permissions, None)
+			.map_err(|code| LfsError::new(code, OperationKind::CreateMount, mount_point))?;
+
+		// the built-in Directory device lives in C, so mirror it with a std::fs device
+		let device = match created_device {
+			Some(device) => Some(device),
+			None if device_type == DeviceType::Directory => device::DiskDevice::create(device_path).ok().map(|d| CreatedDevice::new(Arc::new(d))),
+			None => None
+		};
+
+		let case_index = if options.case_insensitive {
+			let built = match device {
+				Some(ref device) => CaseIndex::build(device.device.as_ref()),
+				None => Err(ResultCode::Unsupported)
+			};
+			match built {
+				Ok(case_index) => Some(case_index),
+				Err(code) => {
+					self.context.release_mount(&handle);
+					return Err(LfsError::new(code, OperationKind::CreateMount, mount_point));
+				}
+			}
+		} else {
+			None
+		};
+
+		let quota = match options.max_bytes {
+			Some(max_bytes) => {
+				let built = match device {
+					Some(ref device) => Quota::new(device.device.as_ref(), max_bytes),
+					None => Err(ResultCode::Unsupported)
+				};
+				match built {
+					Ok(quota) => Some(Arc::new(quota)),
+					Err(code) => {
+						self.context.release_mount(&handle);
+						return Err(LfsError::new(code, OperationKind::CreateMount, mount_point));
+					}
+				}
+			},
+			None => None
+		};
+
+		let info = Arc::new(MountEntry::new(mount_point, device_type, device_path, permissions, device, Some(handle)));
+		info.set_priority(options.priority);
+		info.set_case_index(case_index);
+		info.set_quota(quota);
+		info.set_throttle(options.bandwidth_limit.map(|bytes_per_sec| Arc::new(Throttle::new(bytes_per_sec))));
+		info.set_strict_paths(options.strict_paths);
+		info.set_retry_policy(options.retry_policy);
+		info.apply_retry_policy(self.retry_policy());
+		self.mounts.add(&info);
+		self.cache.clear();
+
+		// the context puts the new mount first, which is only right if nothing outranks it
+		if self.mounts.ordered().iter().any(|mount| mount.priority() > 0) {
+			if let Err(error) = self.reorder_mounts() {
+				if let Some(handle) = info.handle.lock().unwrap().take() {
+					self.context.release_mount(&handle);
+				}
+				self.cache.clear();
+				return Err(error);
+			}
+		}
+		self.mount_events.fire(MountEvent::Created(info.info()));
+
+		Ok(Mount {
+			fs: self.clone(),
+			info: info
+		})
+	}
+
+	// Calls `callback` with every mount created or released from now on, until the hook is
+	// removed again.
+	pub fn on_mount_event<F>(&self, callback: F) -> MountEventHook
+		where F: Fn(&MountEvent) + Send + Sync + 'static {
+		self.mount_events.add(Arc::new(callback))
+	}
+
+	pub fn remove_mount_event_hook(&self, hook: MountEventHook) -> bool {
+		self.mount_events.remove(hook)
+	}
+
+	// The active mounts in the order paths are resolved against them.
+	pub fn mounts(&self) -> Vec<MountInfo> {
+		self.mounts.ordered().iter().map(|mount| mount.info()).collect()
+	}
+
+	pub fn resolve(&self, path: &str) -> Result<ResolvedPath, LfsError> {
+		let error = |code| LfsError::new(code, OperationKind::Resolve, path);
+		let target = self.virtual_path(path, false).map_err(|e| error(e.code()))?;
+		self.mounts.resolve_path(&target).map_err(error)
+	}
+
+	// Checks whether `operation` on `path` would be allowed by the permissions of the mounts
+	// covering it, without submitting anything, e.g. to disable a "Delete" button up front.
+	// Fails with NotFound if no mount covers the path; whether the file itself exists isn't
+	// checked.
+	pub fn check_permission(&self, path: &str, operation: OperationKind) -> Result<(), LfsError> {
+		let error = |code| LfsError::new(code, operation, path);
+		let target = self.virtual_path(path, false).map_err(|e| error(e.code()))?;
+		match operation.permission() {
+			Some(permission) => self.mounts.check_permission(&target, permission).map_err(error),
+			None => Ok(())
+		}
+	}
+
+	// Mounts with a higher priority are searched first; within the same priority the most
+	// recently created mount wins. Mounts start out with priority 0. Waits for the work in
+	// flight, so don't call it from a completion callback.
+	pub fn set_mount_priority(&self, mount: &Mount, priority: i32) -> Result<(), LfsError> {
+		mount.info.set_priority(priority);
+		self.reorder_mounts()
+	}
+
+	// Gives the mounts descending priorities so they are searched in the order given,
+	// ahead of any mount left at the default priority.
+	pub fn set_mount_order(&self, mounts: &[&Mount]) -> Result<(), LfsError> {
+		for (index, mount) in mounts.iter().enumerate() {
+			mount.info.set_priority((mounts.len() - index) as i32);
+		}
+		self.reorder_mounts()
+	}
+
+	// The context searches its mounts most recent first and can't reorder them, so a new
+	// order is applied by recreating the mounts lowest priority first, with dispatching
+	// held back and the work in flight out of the way. Each replacement is created before
+	// the mount it replaces is released, so a mount that can't be recreated is kept as it
+	// was. Rust devices keep their instance across the recreation.
+	fn reorder_mounts(&self) -> Result<(), LfsError> {
+		let _lock = self.mounts.reorder_lock.lock().unwrap();
+		self.queue.pause();
+		self.cache.clear();
+		let result = self.recreate_mounts();
+		self.queue.resume();
+		result
+	}
+
+	fn recreate_mounts(&self) -> Result<(), LfsError> {
+		for mount in self.mounts.ordered().iter().rev() {
+			let mut handle = mount.handle.lock().unwrap();
+			if handle.is_none() {
+				continue;
+			}
+
+			let (new, _) = self.create_lamina_mount(mount.device_type, &mount.mount_point, &mount.device_path, mount.permissions, mount.device.as_ref())
+				.map_err(|code| LfsError::new(code, OperationKind::CreateMount, &mount.mount_point))?;
+			if let Some(old) = handle.replace(new) {
+				self.context.release_mount(&old);
+			}
+		}
+		Ok(())
+	}
+
+	// Reads submitted while a pool is set allocate their buffers from it, and the buffers
+	// return to it once freed. Pass None to go back to the context's allocator.
+	pub fn set_buffer_pool(&self, pool: Option<Arc<BufferPool>>) {
+		*self.buffer_pool.lock().unwrap() = pool;
+	}
+
+	// The policy for retrying transient device failures on mounts without a policy of
+	// their own, see Mount::set_retry_policy. None, the default, doesn't retry. Directory
+	// mounts are left out, as they can't retry.
+	pub fn set_retry_policy(&self, policy: Option<RetryPolicy>) {
+		*self.retry_policy.lock().unwrap() = policy;
+		for mount in self.mounts.ordered() {
+			mount.apply_retry_policy(policy);
+		}
+	}
+
+	pub fn retry_policy(&self) -> Option<RetryPolicy> {
+		*self.retry_policy.lock().unwrap()
+	}
+
+	// While read-only, every operation that would modify a mount fails with
+	// PermissionsError, whatever the mount's permissions, without reaching its device.
+	// Reads are unaffected. Work already submitted isn't.
+	pub fn set_read_only(&self, read_only: bool) {
+		self.mounts.set_read_only(read_only);
+	}
+
+	pub fn is_read_only(&self) -> bool {
+		self.mounts.is_read_only()
+	}
+
+	// Work items submitted and not completed yet, whether queued, in flight or waiting on
+	// a quota or throttle. Prefetches count too; operations returning a Task don't.
+	pub fn pending_work_items(&self) -> usize {
+		self.pending.total()
+	}
+
+	// The same count by the mount point of the first mount covering each item's path, ""
+	// for work no mount covers. Mounts with nothing pending are left out.
+	pub fn pending_work_items_by_mount(&self) -> HashMap<String, usize> {
+		self.pending.by_mount()
+	}
+
+	pub fn queue_stats(&self) -> QueueStats {
+		self.queue.stats()
+	}
+
+	// Cancels every work item still queued, e.g. all the pending loads of a level the
+	// player just left. Work in flight runs to completion.
+	pub fn cancel_all(&self) {
+		self.queue.cancel_all();
+	}
+
+	// Only applies to contexts created with a work item pool size, see QueueStats::capacity.
+	// Work that never reaches the context, like cache hits, isn't held back.
+	pub fn set_backpressure(&self, backpressure: Backpressure) {
+		self.queue.set_backpressure(backpressure);
+	}
+
+	pub fn backpressure(&self) -> Backpressure {
+		self.queue.backpressure()
+	}
+
+	// Holds back work that modifies a path (writes, appends, deletes and directory
+	// changes) until the modifying work submitted before it on the same virtual path has
+	// finished, so overlapping writes land in submission order and the last one wins.
+	// Off by default. Operations returning a Task, like write_file_atomic, run outside the
+	// queue and aren't ordered this way.
+	pub fn set_serialize_writes(&self, serialize_writes: bool) {
+		self.queue.set_serialize_writes(serialize_writes);
+	}
+
+	pub fn serialize_writes(&self) -> bool {
+		self.queue.serialize_writes()
+	}
+
+	// What dropping an unfinished WorkHandle does, DropPolicy::Wait by default. Detached
+	// work still queued when the LaminaFS is dropped is cancelled like any other.
+	pub fn set_drop_policy(&self, policy: DropPolicy) {
+		self.detached.set_policy(policy);
+	}
+
+	pub fn drop_policy(&self) -> DropPolicy {
+		self.detached.policy()
+	}
+
+	pub fn execution_mode(&self) -> ExecutionMode {
+		self.mode
+	}
+
+	pub fn buffer_pool(&self) -> Option<Arc<BufferPool>> {
+		self.buffer_pool.lock().unwrap().clone()
+	}
+
+	// The allocator for a new read buffer, and whether it comes from the buffer pool.
+	fn read_allocator(&self) -> (Arc<ContextAllocator>, bool) {
+		match *self.buffer_pool.lock().unwrap() {
+			Some(ref pool) => (pool.allocator().clone(), true),
+			None => (self.context.allocator.clone(), false)
+		}
+	}
+
+	pub fn create_mount<T: Into<DeviceType>>(self: &Arc<Self>, device_type: T, mount_point: &str, device_path: &str) -> Result<Mount, LfsError> {
 		self.create_mount_with_permissions(device_type, mount_point, device_path, MountPermissions::Default)
 	}
 
-	pub fn append_file(&self, path: &str, buffer: Arc<[u8]>) -> Arc<Mutex<WorkItem>> {
-		let path = CString::new(path).unwrap();
-		let work_item = unsafe { laminafs_sys::lfs_append_file(
-			self.context,
-			path.as_c_str().as_ptr(),
+	// The path work on `path` goes to, with its alias applied and its case folded on
+	// case-insensitive mounts. Fails with InvalidPath for invalid paths, and for paths
+	// with "." or ".." components on strict mounts.
+	fn virtual_path(&self, path: &str, created: bool) -> Result<String, LfsError> {
+		let lfs_path = LfsPath::new(path)?;
+
+		// ".." is resolved before the path reaches a mount, so it can't climb out of one
+		let normalized = lfs_path.normalized();
+		if !lfs_path.is_plain() && self.mounts.is_strict(&normalized) {
+			return Err(LfsError::invalid_path(path));
+		}
+
+		Ok(match self.aliases.resolve(&normalized) {
+			Some(target) => self.mounts.fold_case(&target, created),
+			None => self.mounts.fold_case(&normalized, created)
+		})
+	}
+
+	fn submit<F>(&self, operation: OperationKind, path: &str, write_buffer: Option<Arc<[u8]>>, owns_buffer: bool, priority: Priority, callback: Option<CompletionCallback>, local: DeviceOp, submit: F) -> WorkHandle
+		where F: FnOnce(*const std::os::raw::c_char, laminafs_sys::lfs_callback_t, *mut std::ffi::c_void) -> *mut laminafs_sys::lfs_work_item_t + Send + 'static {
+		let created = match operation {
+			OperationKind::WriteFile | OperationKind::WriteFileSegment | OperationKind::AppendFile | OperationKind::CreateDir => true,
+			_ => false
+		};
+		let target = self.virtual_path(path, created);
+
+		#[cfg(feature = "tracing")]
+		let span = spans::work_span(operation, path);
+		#[cfg(feature = "tracing")]
+		let _entered = span.enter();
+		#[cfg(feature = "tracing")]
+		let callback = spans::on_completion(span.clone(), callback);
+
+		let mount_point = target.as_ref().map(|target| self.mounts.mount_point(target)).unwrap_or_default();
+		#[cfg(feature = "metrics")]
+		let callback = instrument::on_completion(operation, mount_point.clone(), &local, callback);
+		let callback = self.pending.track(mount_point, callback);
+		let callback = self.recorder.track(operation, path, &local, callback);
+
+		let callback = match target {
+			Ok(target) => match self.mounts.reserve_op(&target, &local) {
+				Ok(reservation) => Ok((target, quota::release_on_failure(reservation, callback))),
+				// writes that don't fit the mount's quota
+				Err(code) => Err((callback, code))
+			},
+			// invalid paths fail the work item rather than reaching the context
+			Err(error) => Err((callback, error.code()))
+		};
+
+		let work = match callback {
+			Ok((target, callback)) => match self.cache.intercept(&target, &local, callback) {
+				Intercepted::Hit(result, callback) => self.queue.complete_locally(path, callback, ResultCode::Ok, result.bytes, result.buffer),
+				Intercepted::Submit(callback) => match LfsPath::new(&target) {
+					Ok(_) if self.mode == ExecutionMode::SingleThread => match self.mounts.execute(&target, &local) {
+						Ok(result) => self.queue.complete_locally(path, callback, ResultCode::Ok, result.bytes, result.buffer),
+						Err(code) => self.queue.complete_locally(path, callback, code, 0, 0 as *mut u8)
+					},
+					Ok(lfs_path) => {
+						let c_path = lfs_path.to_c_string();
+						let throttle = self.mounts.throttle(&target, write_buffer.is_some() || created);
+						let callback = throttle::charge_on_completion(throttle.clone(), write_buffer.as_ref().map(|buffer| buffer.len()), callback);
+						let dispatch: SubmitFn = Box::new(move |lfs_callback, user_data| submit(c_path.as_ptr(), lfs_callback, user_data));
+						#[cfg(feature = "tracing")]
+						let dispatch = spans::on_dispatch(span.clone(), dispatch);
+						let serial_path = match operation.permission() {
+							Some(permission) if permission != MountPermissions::Read => Some(target.as_str()),
+							_ => None
+						};
+						self.queue.push_throttled(priority, &target, callback, throttle, serial_path, dispatch)
+					},
+					Err(error) => self.queue.fail(path, callback, error.code())
+				}
+			},
+			Err((callback, code)) => self.queue.fail(path, callback, code)
+		};
+
+		WorkHandle {
+			work: work,
+			operation: operation,
+			path: path.to_string(),
+			context: self.context.clone(),
+			write_buffer: write_buffer,
+			owns_buffer: owns_buffer,
+			allocator: self.context.allocator.clone(),
+			pooled: false,
+			buffer_taken: false,
+			hash: None,
+			drop_policy: None,
+			detached: self.detached.clone()
+		}
+	}
+
+	// Buffers can be passed as Arc<[u8]>, Vec<u8>, Box<[u8]> or &[u8], the latter copied.
+	pub fn append_file<B: Into<Arc<[u8]>>>(&self, path: &str, buffer: B) -> WorkHandle {
+		self.submit_append_file(path, buffer.into(), None)
+	}
+
+	pub fn append_file_with_callback<B, C>(&self, path: &str, buffer: B, callback: C) -> WorkHandle
+		where B: Into<Arc<[u8]>>, C: FnOnce(&WorkItemResult) + Send + 'static {
+		self.submit_append_file(path, buffer.into(), Some(Box::new(callback)))
+	}
+
+	fn submit_append_file(&self, path: &str, buffer: Arc<[u8]>, callback: Option<CompletionCallback>) -> WorkHandle {
+		let context = self.context.raw;
+		let local = DeviceOp::WriteFile { mode: WriteMode::Append, offset: 0, buffer: buffer.clone() };
+		self.submit(OperationKind::AppendFile, path, Some(buffer.clone()), false, Priority::Normal, callback, local, move |c_path, lfs_callback, user_data| unsafe { laminafs_sys::lfs_append_file(
+			context,
+			c_path,
 			buffer.as_ptr() as *const std::ffi::c_void,
 			buffer.len() as u64,
-			None,
-			0 as *mut std::ffi::c_void) };
-
-		Arc::new(Mutex::new(WorkItem {
-			work_item: WorkItemPtr::new(work_item),
-			context: self.context,
-			write_buffer: Some(buffer),
-			finished: false,
-			owns_buffer: false
-		}))
-	}
-
-	pub fn read_file(&self, path: &str, null_terminate: bool) -> Arc<Mutex<WorkItem>> {
-		let path = CString::new(path).unwrap();
-		let work_item = unsafe { laminafs_sys::lfs_read_file_ctx_alloc(
-    		self.context,
-    		path.as_c_str().as_ptr(),
-    		null_terminate,
-			None,
-    		0 as *mut std::ffi::c_void) };
-
-		Arc::new(Mutex::new(WorkItem {
-			work_item: WorkItemPtr::new(work_item),
-			context: self.context,
-			write_buffer: None,
-			finished: false,
-			owns_buffer: true
-		}))
-	}
-
-	pub fn read_file_segment(&self, path: &str, offset: u64, max_bytes: u64, null_terminate: bool) -> Arc<Mutex<WorkItem>> {
-		let path = CString::new(path).unwrap();
-		let work_item = unsafe { laminafs_sys::lfs_read_file_segment_ctx_alloc(
-    		self.context,
-    		path.as_c_str().as_ptr(),
+			lfs_callback,
+			user_data) })
+	}
+
+	pub fn read_file(&self, path: &str, null_terminate: bool) -> WorkHandle {
+		self.submit_read_file(path, null_terminate, Priority::Normal, None)
+	}
+
+	pub fn read_file_with_callback<C>(&self, path: &str, null_terminate: bool, callback: C) -> WorkHandle
+		where C: FnOnce(&WorkItemResult) + Send + 'static {
+		self.submit_read_file(path, null_terminate, Priority::Normal, Some(Box::new(callback)))
+	}
+
+	pub fn read_file_with_priority(&self, path: &str, null_terminate: bool, priority: Priority) -> WorkHandle {
+		self.submit_read_file(path, null_terminate, priority, None)
+	}
+
+	fn submit_read_file(&self, path: &str, null_terminate: bool, priority: Priority, callback: Option<CompletionCallback>) -> WorkHandle {
+		let context = self.context.raw;
+		let (allocator, pooled) = self.read_allocator();
+		let read_allocator = allocator.clone();
+		let local = DeviceOp::ReadFile { null_terminate: null_terminate, allocator: allocator.clone() };
+		let mut work_item = self.submit(OperationKind::ReadFile, path, None, true, priority, callback, local, move |c_path, lfs_callback, user_data| unsafe { laminafs_sys::lfs_read_file(
+			context,
+			c_path,
+			null_terminate,
+			read_allocator.as_raw(),
+			lfs_callback,
+			user_data) });
+		work_item.set_read_allocator(allocator, pooled);
+		work_item
+	}
+
+	pub fn read_file_segment(&self, path: &str, offset: u64, max_bytes: u64, null_terminate: bool) -> WorkHandle {
+		self.submit_read_file_segment(path, offset, max_bytes, null_terminate, Priority::Normal, None)
+	}
+
+	pub fn read_file_segment_with_callback<C>(&self, path: &str, offset: u64, max_bytes: u64, null_terminate: bool, callback: C) -> WorkHandle
+		where C: FnOnce(&WorkItemResult) + Send + 'static {
+		self.submit_read_file_segment(path, offset, max_bytes, null_terminate, Priority::Normal, Some(Box::new(callback)))
+	}
+
+	pub fn read_file_segment_with_priority(&self, path: &str, offset: u64, max_bytes: u64, null_terminate: bool, priority: Priority) -> WorkHandle {
+		self.submit_read_file_segment(path, offset, max_bytes, null_terminate, priority, None)
+	}
+
+	fn submit_read_file_segment(&self, path: &str, offset: u64, max_bytes: u64, null_terminate: bool, priority: Priority, callback: Option<CompletionCallback>) -> WorkHandle {
+		let context = self.context.raw;
+		let (allocator, pooled) = self.read_allocator();
+		let read_allocator = allocator.clone();
+		let local = DeviceOp::ReadFileSegment { offset: offset, max_bytes: max_bytes, null_terminate: null_terminate, allocator: allocator.clone() };
+		let mut work_item = self.submit(OperationKind::ReadFileSegment, path, None, true, priority, callback, local, move |c_path, lfs_callback, user_data| unsafe { laminafs_sys::lfs_read_file_segment(
+			context,
+			c_path,
 			offset,
 			max_bytes,
-    		null_terminate,
-			None,
-    		0 as *mut std::ffi::c_void) };
-
-		Arc::new(Mutex::new(WorkItem {
-			work_item: WorkItemPtr::new(work_item),
-			context: self.context,
-			write_buffer: None,
-			finished: false,
-			owns_buffer: true
-		}))
-	}
-
-	pub fn write_file(&self, path: &str, buffer: Arc<[u8]>) -> Arc<Mutex<WorkItem>> {
-		let path = CString::new(path).unwrap();
-		let work_item = unsafe { laminafs_sys::lfs_write_file(
-			self.context,
-			path.as_c_str().as_ptr(),
+			null_terminate,
+			read_allocator.as_raw(),
+			lfs_callback,
+			user_data) });
+		work_item.set_read_allocator(allocator, pooled);
+		work_item
+	}
+
+	pub fn write_file<B: Into<Arc<[u8]>>>(&self, path: &str, buffer: B) -> WorkHandle {
+		self.submit_write_file(path, buffer.into(), None)
+	}
+
+	pub fn write_file_with_callback<B, C>(&self, path: &str, buffer: B, callback: C) -> WorkHandle
+		where B: Into<Arc<[u8]>>, C: FnOnce(&WorkItemResult) + Send + 'static {
+		self.submit_write_file(path, buffer.into(), Some(Box::new(callback)))
+	}
+
+	fn submit_write_file(&self, path: &str, buffer: Arc<[u8]>, callback: Option<CompletionCallback>) -> WorkHandle {
+		let context = self.context.raw;
+		let local = DeviceOp::WriteFile { mode: WriteMode::Overwrite, offset: 0, buffer: buffer.clone() };
+		self.submit(OperationKind::WriteFile, path, Some(buffer.clone()), false, Priority::Normal, callback, local, move |c_path, lfs_callback, user_data| unsafe { laminafs_sys::lfs_write_file(
+			context,
+			c_path,
 			buffer.as_ptr() as *const std::ffi::c_void,
 			buffer.len() as u64,
-			None,
-			0 as *mut std::ffi::c_void) };
-
-		Arc::new(Mutex::new(WorkItem {
-			work_item: WorkItemPtr::new(work_item),
-			context: self.context,
-			write_buffer: Some(buffer),
-			finished: false,
-			owns_buffer: false
-		}))
-	}
-
-	pub fn write_file_segment(&self, path: &str, offset: u64, buffer: Arc<[u8]>) -> Arc<Mutex<WorkItem>> {
-		let path = CString::new(path).unwrap();
-		let work_item = unsafe { laminafs_sys::lfs_write_file_segment(
-			self.context,
-			path.as_c_str().as_ptr(),
+			lfs_callback,
+			user_data) })
+	}
+
+	pub fn write_file_segment<B: Into<Arc<[u8]>>>(&self, path: &str, offset: u64, buffer: B) -> WorkHandle {
+		self.submit_write_file_segment(path, offset, buffer.into(), None)
+	}
+
+	pub fn write_file_segment_with_callback<B, C>(&self, path: &str, offset: u64, buffer: B, callback: C) -> WorkHandle
+		where B: Into<Arc<[u8]>>, C: FnOnce(&WorkItemResult) + Send + 'static {
+		self.submit_write_file_segment(path, offset, buffer.into(), Some(Box::new(callback)))
+	}
+
+	fn submit_write_file_segment(&self, path: &str, offset: u64, buffer: Arc<[u8]>, callback: Option<CompletionCallback>) -> WorkHandle {
+		let context = self.context.raw;
+		let local = DeviceOp::WriteFile { mode: WriteMode::Segment, offset: offset, buffer: buffer.clone() };
+		self.submit(OperationKind::WriteFileSegment, path, Some(buffer.clone()), false, Priority::Normal, callback, local, move |c_path, lfs_callback, user_data| unsafe { laminafs_sys::lfs_write_file_segment(
+			context,
+			c_path,
 			offset,
 			buffer.as_ptr() as *const std::ffi::c_void,
 			buffer.len() as u64,
-			None,
-			0 as *mut std::ffi::c_void) };
-
-		Arc::new(Mutex::new(WorkItem {
-			work_item: WorkItemPtr::new(work_item),
-			context: self.context,
-			write_buffer: Some(buffer),
-			finished: false,
-			owns_buffer: false
-		}))
-	}
-
-	pub fn create_dir(&self, path: &str) -> Arc<Mutex<WorkItem>> {
-		let path = CString::new(path).unwrap();
-		let work_item = unsafe { laminafs_sys::lfs_create_dir(
-			self.context,
-			path.as_c_str().as_ptr(),
-			None,
-			0 as *mut std::ffi::c_void) };
-
-		Arc::new(Mutex::new(WorkItem {
-			work_item: WorkItemPtr::new(work_item),
-			context: self.context,
-			write_buffer: None,
-			finished: false,
-			owns_buffer: false
-		}))
-	}
-
-	pub fn delete_dir(&self, path: &str) -> Arc<Mutex<WorkItem>> {
-		let path = CString::new(path).unwrap();
-		let work_item = unsafe { laminafs_sys::lfs_delete_dir(
-			self.context,
-			path.as_c_str().as_ptr(),
-			None,
-			0 as *mut std::ffi::c_void) };
-
-		Arc::new(Mutex::new(WorkItem {
-			work_item: WorkItemPtr::new(work_item),
-			context: self.context,
-			write_buffer: None,
-			finished: false,
-			owns_buffer: false
-		}))
-	}
-
-	pub fn delete_file(&self, path: &str) -> Arc<Mutex<WorkItem>> {
-		let path = CString::new(path).unwrap();
-		let work_item = unsafe { laminafs_sys::lfs_delete_file(
-			self.context,
-			path.as_c_str().as_ptr(),
-			None,
-			0 as *mut std::ffi::c_void) };
-
-		Arc::new(Mutex::new(WorkItem {
-			work_item: WorkItemPtr::new(work_item),
-			context: self.context,
-			write_buffer: None,
-			finished: false,
-			owns_buffer: false
-		}))
-	}
-
-	pub fn file_exists(&self, path: &str) -> Arc<Mutex<WorkItem>> {
-		let path = CString::new(path).unwrap();
-		let work_item = unsafe { laminafs_sys::lfs_file_exists(
-			self.context,
-			path.as_c_str().as_ptr(),
-			None,
-			0 as *mut std::ffi::c_void) };
-
-		Arc::new(Mutex::new(WorkItem {
-			work_item: WorkItemPtr::new(work_item),
-			context: self.context,
-			write_buffer: None,
-			finished: false,
-			owns_buffer: false
-		}))
+			lfs_callback,
+			user_data) })
+	}
+
+	pub fn create_dir(&self, path: &str) -> WorkHandle {
+		self.submit_create_dir(path, None)
+	}
+
+	pub fn create_dir_with_callback<C>(&self, path: &str, callback: C) -> WorkHandle
+		where C: FnOnce(&WorkItemResult) + Send + 'static {
+		self.submit_create_dir(path, Some(Box::new(callback)))
+	}
+
+	fn submit_create_dir(&self, path: &str, callback: Option<CompletionCallback>) -> WorkHandle {
+		let context = self.context.raw;
+		self.submit(OperationKind::CreateDir, path, None, false, Priority::Normal, callback, DeviceOp::CreateDir, move |c_path, lfs_callback, user_data| unsafe { laminafs_sys::lfs_create_dir(
+			context,
+			c_path,
+			lfs_callback,
+			user_data) })
+	}
+
+	pub fn delete_dir(&self, path: &str) -> WorkHandle {
+		self.submit_delete_dir(path, None)
+	}
+
+	pub fn delete_dir_with_callback<C>(&self, path: &str, callback: C) -> WorkHandle
+		where C: FnOnce(&WorkItemResult) + Send + 'static {
+		self.submit_delete_dir(path, Some(Box::new(callback)))
+	}
+
+	fn submit_delete_dir(&self, path: &str, callback: Option<CompletionCallback>) -> WorkHandle {
+		let context = self.context.raw;
+		self.submit(OperationKind::DeleteDir, path, None, false, Priority::Normal, callback, DeviceOp::DeleteDir, move |c_path, lfs_callback, user_data| unsafe { laminafs_sys::lfs_delete_dir(
+			context,
+			c_path,
+			lfs_callback,
+			user_data) })
+	}
+
+	pub fn delete_file(&self, path: &str) -> WorkHandle {
+		self.submit_delete_file(path, None)
+	}
+
+	pub fn delete_file_with_callback<C>(&self, path: &str, callback: C) -> WorkHandle
+		where C: FnOnce(&WorkItemResult) + Send + 'static {
+		self.submit_delete_file(path, Some(Box::new(callback)))
+	}
+
+	fn submit_delete_file(&self, path: &str, callback: Option<CompletionCallback>) -> WorkHandle {
+		let context = self.context.raw;
+		self.submit(OperationKind::DeleteFile, path, None, false, Priority::Normal, callback, DeviceOp::DeleteFile, move |c_path, lfs_callback, user_data| unsafe { laminafs_sys::lfs_delete_file(
+			context,
+			c_path,
+			lfs_callback,
+			user_data) })
+	}
+
+	pub fn file_exists(&self, path: &str) -> WorkHandle {
+		self.submit_file_exists(path, None)
+	}
+
+	pub fn file_exists_with_callback<C>(&self, path: &str, callback: C) -> WorkHandle
+		where C: FnOnce(&WorkItemResult) + Send + 'static {
+		self.submit_file_exists(path, Some(Box::new(callback)))
+	}
+
+	fn submit_file_exists(&self, path: &str, callback: Option<CompletionCallback>) -> WorkHandle {
+		let context = self.context.raw;
+		self.submit(OperationKind::FileExists, path, None, false, Priority::Normal, callback, DeviceOp::FileExists, move |c_path, lfs_callback, user_data| unsafe { laminafs_sys::lfs_file_exists(
+			context,
+			c_path,
+			lfs_callback,
+			user_data) })
+	}
+}
+
+impl LaminaFS {
+	fn spawn_device_task<T, F>(&self, operation: OperationKind, path: &str, op: F) -> Task<T>
+		where T: Send + 'static, F: Fn(&dyn Device, &str) -> Result<T, ResultCode> + Send + 'static {
+		let mounts = self.mounts.clone();
+		let path_owned = self.virtual_path(path, false);
+		self.tasks.spawn(operation, path, move || {
+			let path_owned = path_owned.map_err(|error| error.code())?;
+			mounts.query(&path_owned, op)
+		})
+	}
+
+	pub fn file_size(&self, path: &str) -> Task<u64> {
+		self.spawn_device_task(OperationKind::FileSize, path, |device, path| device.file_size(path))
+	}
+
+	pub fn stat(&self, path: &str) -> Task<FileStat> {
+		self.spawn_device_task(OperationKind::Stat, path, |device, path| device.stat(path))
+	}
+
+	// Reads several (offset, max_bytes) ranges of one file as a single task, opening the
+	// file once where the device allows, e.g. for the entries of an asset pack. Ranges are
+	// returned in the order given and cut short at the end of the file like
+	// read_file_segment.
+	pub fn read_file_ranges(&self, path: &str, ranges: &[(u64, u64)]) -> Task<Vec<Vec<u8>>> {
+		let ranges = ranges.to_vec();
+		self.spawn_device_task(OperationKind::ReadFileRanges, path, move |device, path| device.read_ranges(path, &ranges))
+	}
+
+	// Shortens or zero-extends a file on the first mount covering it that allows writes,
+	// e.g. to compact a save slot in place after rewriting it with write_file_segment.
+	pub fn truncate_file(&self, path: &str, new_len: u64) -> Task<()> {
+		let mounts = self.mounts.clone();
+		let cache = self.cache.clone();
+		let path_owned = self.virtual_path(path, false);
+		self.tasks.spawn(OperationKind::TruncateFile, path, move || {
+			let path_owned = path_owned.map_err(|error| error.code())?;
+			let (device, relative_path) = mounts.writable_device(&path_owned, MountPermissions::WriteFile)?;
+			let result = mounts.charged(&path_owned, MountPermissions::WriteFile, |_| new_len, || device.set_len(&relative_path, new_len));
+			cache.invalidate(&path_owned);
+			result
+		})
+	}
+
+	// Reserves space for a file of at least size bytes on the first mount covering it that
+	// allows writes, creating it if needed, so large downloads and recordings fail early
+	// with OutOfSpace instead of mid-write.
+	pub fn allocate(&self, path: &str, size: u64) -> Task<()> {
+		let mounts = self.mounts.clone();
+		let cache = self.cache.clone();
+		let path_owned = self.virtual_path(path, true);
+		self.tasks.spawn(OperationKind::AllocateFile, path, move || {
+			let path_owned = path_owned.map_err(|error| error.code())?;
+			let (device, relative_path) = mounts.writable_device(&path_owned, MountPermissions::WriteFile)?;
+			let result = mounts.charged(&path_owned, MountPermissions::WriteFile, |len| std::cmp::max(len, size), || device.allocate(&relative_path, size));
+			cache.invalidate(&path_owned);
+			result
+		})
+	}
+
+	// Replaces the file's contents through a temporary sibling that is renamed over it, so
+	// a crash mid-save can't corrupt the only copy. Unlike write_file, this runs against
+	// the mount's device from the Rust side.
+	pub fn write_file_atomic<B: Into<Arc<[u8]>>>(&self, path: &str, buffer: B) -> Task<u64> {
+		let mounts = self.mounts.clone();
+		let cache = self.cache.clone();
+		let buffer = buffer.into();
+		let path_owned = self.virtual_path(path, true);
+		self.tasks.spawn(OperationKind::WriteFile, path, move || {
+			let path_owned = path_owned.map_err(|error| error.code())?;
+			let (device, relative_path) = mounts.writable_device(&path_owned, MountPermissions::WriteFile)?;
+			let result = mounts.charged(&path_owned, MountPermissions::WriteFile, |_| buffer.len() as u64, || device.write_file(&relative_path, 0, &buffer, WriteMode::Atomic));
+			cache.invalidate(&path_owned);
+			result
+		})
+	}
+
+	// Overwrites the file with the buffers written back to back, e.g. a serializer's
+	// header, payload and footer, without joining them into one buffer first. Like
+	// write_file_atomic, this runs against the mount's device from the Rust side.
+	pub fn write_file_gather(&self, path: &str, buffers: &[Arc<[u8]>]) -> Task<u64> {
+		let mounts = self.mounts.clone();
+		let cache = self.cache.clone();
+		let buffers = buffers.to_vec();
+		let path_owned = self.virtual_path(path, true);
+		self.tasks.spawn(OperationKind::WriteFile, path, move || {
+			let path_owned = path_owned.map_err(|error| error.code())?;
+			let (device, relative_path) = mounts.writable_device(&path_owned, MountPermissions::WriteFile)?;
+			let slices: Vec<&[u8]> = buffers.iter().map(|buffer| &buffer[..]).collect();
+			let len = slices.iter().map(|slice| slice.len() as u64).sum::<u64>();
+			let result = mounts.charged(&path_owned, MountPermissions::WriteFile, |_| len, || device.write_gather(&relative_path, &slices));
+			cache.invalidate(&path_owned);
+			result
+		})
+	}
+
+	pub fn list_dir(&self, path: &str) -> Task<Vec<DirEntry>> {
+		let mounts = self.mounts.clone();
+		let path_owned = self.virtual_path(path, false);
+		self.tasks.spawn(OperationKind::ListDir, path, move || {
+			let path_owned = path_owned.map_err(|error| error.code())?;
+			mounts.list_dir(&path_owned)
+		})
 	}
 }
 
 impl Drop for LaminaFS {
 	fn drop(&mut self) {
-		unsafe {
-			laminafs_sys::lfs_context_destroy(self.context);
-		}
+		// nothing is left to submit queued work once the LaminaFS is gone
+		self.queue.cancel_all();
 	}
 }
 
+// Keeps its LaminaFS alive, so a mount can't outlive the context it was created in.
 pub struct Mount {
-	mount: laminafs_sys::lfs_mount_t,
-	context: laminafs_sys::lfs_context_t
+	fs: Arc<LaminaFS>,
+	info: Arc<MountEntry>
+}
+
+impl Mount {
+	pub fn mount_point(&self) -> &str {
+		&self.info.mount_point
+	}
+
+	pub fn device_path(&self) -> &str {
+		&self.info.device_path
+	}
+
+	pub fn device_type(&self) -> DeviceType {
+		self.info.device_type
+	}
+
+	pub fn permissions(&self) -> MountPermissions {
+		self.info.permissions
+	}
+
+	pub fn priority(&self) -> i32 {
+		self.info.priority()
+	}
+
+	pub fn info(&self) -> MountInfo {
+		self.info.info()
+	}
+
+	// The mount's device as its concrete type, None if it's a device of another type.
+	pub fn device<D: Device>(&self) -> Option<Arc<D>> {
+		self.info.device.as_ref()?.instance::<D>()
+	}
+
+	pub fn is_case_insensitive(&self) -> bool {
+		self.info.is_case_insensitive()
+	}
+
+	// Limits the rate of IO on the mount to `bytes_per_sec`, or lifts the limit for None.
+	// Work of every priority counts against the limit, but only background priority work
+	// is held back to stay within it, so foreground work on the mount always goes first.
+	pub fn set_bandwidth_limit(&self, bytes_per_sec: Option<u64>) {
+		self.info.set_throttle(bytes_per_sec.map(|bytes_per_sec| Arc::new(Throttle::new(bytes_per_sec))));
+	}
+
+	pub fn bandwidth_limit(&self) -> Option<u64> {
+		self.info.throttle().map(|throttle| throttle.bytes_per_sec())
+	}
+
+	// Retries operations on the mount that fail with GenericError according to `policy`,
+	// in place of the context's policy, or goes back to the context's for None. The
+	// retries happen in the mount's device, so Directory mounts, whose IO the context does
+	// without going through Rust, fail with Unsupported.
+	pub fn set_retry_policy(&self, policy: Option<RetryPolicy>) -> Result<(), LfsError> {
+		if policy.is_some() && self.info.device_type == DeviceType::Directory {
+			return Err(LfsError::new(ResultCode::Unsupported, OperationKind::SetRetryPolicy, &self.info.mount_point));
+		}
+		self.info.set_retry_policy(policy);
+		self.info.apply_retry_policy(self.fs.retry_policy());
+		Ok(())
+	}
+
+	// The mount's own policy, None if it goes by the context's.
+	pub fn retry_policy(&self) -> Option<RetryPolicy> {
+		self.info.retry_policy()
+	}
+
+	// Paths are normalized before they reach a mount, so "/mods/../save.dat" is
+	// "/save.dat" and never a file next to the mount's root. With strict paths, paths
+	// covered by the mount that aren't plain in the first place, with "." or ".."
+	// components, repeated slashes, backslashes or colons, fail with InvalidPath instead,
+	// e.g. for mounts serving user-generated content.
+	pub fn set_strict_paths(&self, strict: bool) {
+		self.info.set_strict_paths(strict);
+	}
+
+	pub fn strict_paths(&self) -> bool {
+		self.info.strict_paths()
+	}
+
+	// The quota set with MountBuilder::max_bytes, if any.
+	pub fn max_bytes(&self) -> Option<u64> {
+		self.info.quota().map(|quota| quota.max_bytes())
+	}
+
+	// The bytes counted against the mount's quota, None for mounts without one.
+	pub fn used_bytes(&self) -> Option<u64> {
+		self.info.quota().map(|quota| quota.used())
+	}
+
+	// Rescans the device of a case-insensitive mount, for files added or renamed behind
+	// LaminaFS's back. Files created through LaminaFS are picked up without it.
+	pub fn rebuild_case_index(&self) -> Result<(), LfsError> {
+		let error = |code| LfsError::new(code, OperationKind::ListDir, &self.info.mount_point);
+		if !self.info.is_case_insensitive() {
+			return Err(error(ResultCode::Unsupported));
+		}
+
+		let device = self.info.device.as_ref().ok_or_else(|| error(ResultCode::Unsupported))?;
+		self.info.set_case_index(Some(CaseIndex::build(device.device.as_ref()).map_err(error)?));
+		Ok(())
+	}
+
+	// Releases the mount once the work on paths it covers is out of the way, unlike
+	// dropping it, which releases it straight away. Which mount serves a path is up to the
+	// context, so this also drains work another mount over the same paths may be serving.
+	pub fn unmount(self, mode: UnmountMode) -> Result<(), LfsError> {
+		let info = self.info.clone();
+		self.fs.queue.drain(|path| info.relative_path(path).is_some(), mode == UnmountMode::Cancel);
+		self.fs.cache.clear();
+
+		let handle = self.info.handle.lock().unwrap().take();
+		match handle {
+			Some(handle) if self.fs.context.release_mount(&handle) => {
+				self.fs.mount_events.fire(MountEvent::Released(self.info.info()));
+				Ok(())
+			},
+			Some(_) => Err(LfsError::new(ResultCode::GenericError, OperationKind::Unmount, &self.info.mount_point)),
+			None => Err(LfsError::new(ResultCode::NotFound, OperationKind::Unmount, &self.info.mount_point))
+		}
+	}
 }
 
 impl Drop for Mount {
 	fn drop(&mut self) {
-		unsafe {
-			laminafs_sys::lfs_release_mount(self.context, self.mount);
+		let handle = self.info.handle.lock().unwrap().take();
+		if let Some(handle) = handle {
+			self.fs.context.release_mount(&handle);
+			self.fs.cache.clear();
+			self.fs.mount_events.fire(MountEvent::Released(self.info.info()));
 		}
 	}
 }
@@ -333,99 +1211,307 @@ impl WorkItemPtr {
 	}
 }
 
-pub struct WorkItem {
-	work_item: WorkItemPtr,
-	context: laminafs_sys::lfs_context_t,
-	write_buffer: Option<Arc<[u8]>>,
-	finished: bool,
-	owns_buffer: bool
+// What a completion callback gets to see of the finished work item.
+pub struct WorkItemResult<'a> {
+	result: ResultCode,
+	buffer: &'a [u8],
+	// set when a step run on completion, such as syncing a write, failed
+	failure: Cell<Option<ResultCode>>
 }
 
-impl WorkItem {
-	pub fn wait(&mut self) {
-		if !self.finished {
-			unsafe { laminafs_sys::lfs_wait_for_work_item(self.work_item.ptr.as_ptr()); }
-			self.finished = true;
+impl<'a> WorkItemResult<'a> {
+	pub(crate) fn new(result: ResultCode, buffer: &'a [u8]) -> WorkItemResult<'a> {
+		WorkItemResult {
+			result: result,
+			buffer: buffer,
+			failure: Cell::new(None)
 		}
 	}
 
-	pub fn get_result(&mut self) -> ResultCode {
-		self.wait();
-		ResultCode::from_lamina(unsafe { laminafs_sys::lfs_work_item_get_result(self.work_item.ptr.as_ptr()) })
+	pub fn get_result(&self) -> ResultCode {
+		self.failure.get().unwrap_or(self.result)
 	}
 
-	pub fn get_bytes(&mut self) -> usize {
-		self.wait();
-		(unsafe { laminafs_sys::lfs_work_item_get_bytes(self.work_item.ptr.as_ptr()) }) as usize
+	// Fails work that succeeded as far as the context is concerned.
+	pub(crate) fn fail(&self, code: ResultCode) {
+		self.failure.set(Some(code));
 	}
 
-	pub fn get_buffer(&mut self) -> &[u8] {
-		self.wait();
+	pub fn get_bytes(&self) -> usize {
+		self.buffer.len()
+	}
 
-		let buffer_len = self.get_bytes();
-		let buffer_ptr = (unsafe { laminafs_sys::lfs_work_item_get_buffer(self.work_item.ptr.as_ptr()) }) as *mut u8;
-		if buffer_ptr != 0 as *mut u8 && buffer_len > 0 {
-			unsafe { std::slice::from_raw_parts(buffer_ptr, buffer_len) }
-		} else {
-			unsafe { std::slice::from_raw_parts(std::ptr::NonNull::dangling().as_ptr(), 0) }
-		}
+	pub fn get_buffer(&self) -> &'a [u8] {
+		self.buffer
+	}
+}
+
+// The handle returned for submitted work. Everything but taking the read buffer works
+// through a shared reference, and the handle can be sent to or shared with other threads.
+pub struct WorkHandle {
+	work: Arc<WorkState>,
+	operation: OperationKind,
+	path: String,
+	context: Arc<Context>,
+	write_buffer: Option<Arc<[u8]>>,
+	owns_buffer: bool,
+	// what the read buffer was allocated with
+	allocator: Arc<ContextAllocator>,
+	pooled: bool,
+	buffer_taken: bool,
+	// set for read_file_hashed
+	hash: Option<HashSlot>,
+	// None to follow the LaminaFS's policy
+	drop_policy: Option<DropPolicy>,
+	detached: Arc<Detached>
+}
+
+impl WorkHandle {
+	pub fn wait(&self) {
+		self.work.wait_completed();
+	}
+
+	// Waits at most timeout, returning whether the work finished, so a frame can give IO a
+	// fixed budget.
+	pub fn wait_timeout(&self, timeout: std::time::Duration) -> bool {
+		self.work.wait_completed_timeout(timeout)
+	}
+
+	pub fn get_result(&self) -> ResultCode {
+		self.wait();
+		self.work.result()
+	}
+
+	// Never blocks, for polling outstanding work once per frame.
+	pub fn is_finished(&self) -> bool {
+		self.work.is_completed()
+	}
+
+	pub fn try_get_result(&self) -> Option<ResultCode> {
+		if self.is_finished() {
+			Some(self.work.result())
+		} else {
+			None
+		}
+	}
+
+	pub fn get_bytes(&self) -> usize {
+		self.wait();
+		self.work.bytes()
+	}
+
+	// Empty once the buffer has been taken with take_buffer.
+	pub fn get_buffer(&self) -> &[u8] {
+		self.wait();
+
+		let buffer_len = self.work.bytes();
+		let buffer_ptr = self.work.buffer();
+		if self.buffer_taken || buffer_ptr.is_null() || buffer_len == 0 {
+			&[]
+		} else {
+			unsafe { std::slice::from_raw_parts(buffer_ptr, buffer_len) }
+		}
+	}
+
+	// Cancels the work item if it hasn't been handed to a worker yet, or abandons it if
+	// it's a read that has, in which case the context still does the read but its result
+	// is dropped. A cancelled item reports ResultCode::Cancelled; other work that already
+	// started runs to completion.
+	pub fn cancel(&self) -> bool {
+		self.work.cancel()
+	}
+
+	// Finishes the work with ResultCode::TimedOut if it hasn't completed within `timeout`,
+	// e.g. fs.read_file(path, false).with_timeout(Duration::from_secs(5)). Work that timed
+	// out after reaching the context is still done by it eventually; dropping the handle
+	// detaches it rather than blocking, whatever the drop policy.
+	pub fn with_timeout(self, timeout: std::time::Duration) -> WorkHandle {
+		if !self.is_finished() {
+			self.work.set_timeout(timeout);
+		}
+		self
+	}
+
+	// Drops the handle without waiting for the work, which still runs to completion. The
+	// work item is released once it has, whatever the LaminaFS's drop policy.
+	pub fn detach(mut self) {
+		self.drop_policy = Some(DropPolicy::Detach);
+	}
+
+	// Makes dropping the handle cancel the work instead of waiting for it, for loads owned
+	// by something that may go away first, like a UI screen. Work in flight that cancel
+	// can't stop is detached instead.
+	pub fn cancel_on_drop(mut self) -> WorkHandle {
+		self.drop_policy = Some(DropPolicy::Cancel);
+		self
+	}
+
+	pub fn operation(&self) -> OperationKind {
+		self.operation
+	}
 
+	pub fn path(&self) -> &str {
+		&self.path
+	}
+
+	pub(crate) fn error(&self, code: ResultCode) -> LfsError {
+		LfsError::new(code, self.operation, &self.path)
+	}
+
+	pub(crate) fn work_state(&self) -> &Arc<WorkState> {
+		&self.work
+	}
+
+	// Detaches the read buffer from the work item, so it can outlive the work item without
+	// being copied. The work item no longer frees it and get_buffer returns an empty slice.
+	// Only reads hand out their buffer; for other operations the result is empty.
+	pub fn take_buffer(&mut self) -> ReadBuffer {
+		self.wait();
+
+		let buffer_ptr = self.work.buffer();
+		if self.owns_buffer && !buffer_ptr.is_null() {
+			self.owns_buffer = false;
+			self.buffer_taken = true;
+			ReadBuffer {
+				ptr: NonNull::new(buffer_ptr),
+				len: self.work.bytes(),
+				allocator: self.allocator.clone()
+			}
+		} else {
+			ReadBuffer {
+				ptr: None,
+				len: 0,
+				allocator: self.allocator.clone()
+			}
+		}
+	}
+
+	// Detaches the read buffer like take_buffer, but only if it came from a buffer
+	// pool. Dropping the returned buffer hands it back to the pool.
+	pub fn take_pooled_buffer(&mut self) -> Option<PooledBuffer> {
+		if self.pooled {
+			Some(PooledBuffer::new(self.take_buffer()))
+		} else {
+			None
+		}
+	}
+
+	// The hash of a read submitted with read_file_hashed, None if the read failed or the
+	// work wasn't a hashed read.
+	pub fn get_hash(&self) -> Option<FileHash> {
+		self.wait();
+		self.hash.as_ref().and_then(|hash| *hash.lock().unwrap())
+	}
+
+	fn set_hash_slot(&mut self, hash: HashSlot) {
+		self.hash = Some(hash);
+	}
+
+	fn set_read_allocator(&mut self, allocator: Arc<ContextAllocator>, pooled: bool) {
+		self.allocator = allocator;
+		self.pooled = pooled;
 	}
 }
 
-impl Drop for WorkItem {
+impl Drop for WorkHandle {
 	fn drop(&mut self) {
-		self.wait();
+		let policy = self.drop_policy.unwrap_or_else(|| self.detached.policy());
+		if policy == DropPolicy::Cancel {
+			self.work.cancel();
+		}
 
-		if self.owns_buffer {
-			unsafe { laminafs_sys::lfs_work_item_free_buffer(self.work_item.ptr.as_ptr()); }
+		// work that timed out in flight is detached even with DropPolicy::Wait, as its
+		// handle has already seen it complete
+		if !self.work.is_idle() && (policy != DropPolicy::Wait || self.work.is_completed()) {
+			self.detached.push(DetachedWork {
+				work: self.work.clone(),
+				context: self.context.clone(),
+				write_buffer: self.write_buffer.take(),
+				owns_buffer: self.owns_buffer,
+				allocator: self.allocator.clone()
+			});
+			return;
 		}
-		unsafe { laminafs_sys::lfs_release_work_item(self.context, self.work_item.ptr.as_ptr()); }
+
+		release_handle(&self.work, &self.context, self.write_buffer.take(), self.owns_buffer, &self.allocator);
 	}
 }
 
-/*
-struct lfs_device_interface_t {
-	// required
-	lfs_device_create_func_t _create;
-	lfs_device_destroy_func_t _destroy;
-
-	lfs_device_file_exists_func_t _fileExists;
-	lfs_device_file_size_func_t _fileSize;
-	lfs_device_read_file_func_t _readFile;
-
-	// optional
-	lfs_device_write_file_func_t _writeFile;
-	lfs_device_delete_file_func_t _deleteFile;
-	lfs_device_create_dir_func_t _createDir;
-	lfs_device_delete_dir_func_t _deleteDir;
-};
-*/
-//typedef enum lfs_error_code_t (*lfs_device_create_func_t)(struct lfs_allocator_t *, const char *, void **);
+// Waits for the work and releases its work item, or the buffer of work that ran without
+// the context. The context reads writes straight out of write_buffer, so it's only
+// dropped once the work item has been released.
+fn release_handle(work: &Arc<WorkState>, context: &Arc<Context>, write_buffer: Option<Arc<[u8]>>, owns_buffer: bool, allocator: &ContextAllocator) {
+	work.wait_idle();
+	work.wait();
 
-pub struct DeviceInterface {
-	create_func: Fn(&str) -> ResultCode
+	if work.work_item().is_some() {
+		release_work_item(work, context, owns_buffer);
+	} else if owns_buffer && !work.buffer().is_null() {
+		// read without the context, see ExecutionMode::SingleThread
+		unsafe { allocator.free(work.buffer() as *mut std::ffi::c_void); }
+	}
+	drop(write_buffer);
 }
 
-impl DeviceInterface {
+fn release_work_item(work: &WorkState, context: &Context, owns_buffer: bool) {
+	if let Some(work_item) = work.work_item() {
+		if owns_buffer {
+			unsafe { laminafs_sys::lfs_work_item_free_buffer(work_item); }
+		}
+		unsafe { laminafs_sys::lfs_release_work_item(context.raw, work_item); }
+		work.release();
+	}
+}
 
+// A read buffer allocated by the context, owned independently of its work item.
+pub struct ReadBuffer {
+	ptr: Option<NonNull<u8>>,
+	len: usize,
+	allocator: Arc<ContextAllocator>
 }
 
+unsafe impl Send for ReadBuffer {}
+unsafe impl Sync for ReadBuffer {}
+
+impl std::ops::Deref for ReadBuffer {
+	type Target = [u8];
+
+	fn deref(&self) -> &[u8] {
+		match self.ptr {
+			Some(ptr) => unsafe { std::slice::from_raw_parts(ptr.as_ptr(), self.len) },
+			None => &[]
+		}
+	}
+}
+
+impl From<ReadBuffer> for Vec<u8> {
+	fn from(buffer: ReadBuffer) -> Vec<u8> {
+		buffer.to_vec()
+	}
+}
 
+impl Drop for ReadBuffer {
+	fn drop(&mut self) {
+		if let Some(ptr) = self.ptr {
+			unsafe { self.allocator.free(ptr.as_ptr() as *mut std::ffi::c_void); }
+		}
+	}
+}
 
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use crate::test_dir::TestDir;
 	use std::thread;
 
 	#[test]
 	fn read_test() {
 		let fs = LaminaFS::new();
-		let mount = fs.create_mount(0, "/", "./");
+		let mount = fs.create_mount(DeviceType::Directory, "/", "./");
 		let work = fs.read_file("/Cargo.lock", true);
 
 		let t = thread::spawn(move || {
-			let mut item_inner = work.lock().unwrap();
+			let item_inner = work;
 			assert!(item_inner.get_result() == ResultCode::Ok);
 			assert!(item_inner.get_bytes() > 0);
 			assert!(item_inner.get_bytes() == item_inner.get_buffer().len());
@@ -434,4 +1520,674 @@ mod tests {
 		});
 		t.join();
 	}
+
+	#[test]
+	fn shared_handle_test() {
+		let root = TestDir::new("shared_handle_test");
+		std::fs::write(root.join("level.bin"), b"level data").unwrap();
+		let fs = LaminaFS::new();
+		let _mount = root.mount(&fs);
+
+		// any number of threads can wait on and read from the same handle
+		let work = Arc::new(fs.read_file("/level.bin", false));
+		let readers: Vec<_> = (0..4).map(|_| {
+			let work = work.clone();
+			thread::spawn(move || {
+				assert!(work.get_result() == ResultCode::Ok);
+				assert!(work.get_buffer() == b"level data");
+			})
+		}).collect();
+		for reader in readers {
+			reader.join().unwrap();
+		}
+		assert!(work.operation() == OperationKind::ReadFile && work.path() == "/level.bin");
+		assert!(work.get_bytes() == 10);
+	}
+
+	#[test]
+	fn take_buffer_test() {
+		let fs = LaminaFS::new();
+		let _mount = fs.create_mount(DeviceType::Directory, "/", "./");
+		let mut work = fs.read_file("/Cargo.toml", false);
+
+		let buffer = work.take_buffer();
+		assert!(work.get_buffer().is_empty());
+		drop(work);
+
+		assert!(std::str::from_utf8(&buffer).unwrap().contains("[package]"));
+	}
+
+	#[test]
+	fn read_to_string_test() {
+		let fs = LaminaFS::new();
+		let _mount = fs.create_mount(DeviceType::Directory, "/", "./");
+
+		let contents = fs.read_to_string("/Cargo.toml").unwrap();
+		assert!(contents.starts_with("[package]"));
+		assert!(!contents.ends_with('\0'));
+		assert!(fs.read_to_string("/missing.toml").unwrap_err().code() == ResultCode::NotFound);
+	}
+
+	#[test]
+	fn write_string_test() {
+		let root = TestDir::new("write_string_test");
+
+		let fs = LaminaFS::new();
+		let _mount = root.mount(&fs);
+
+		fs.write_string("/report.txt", "first\n").unwrap();
+		fs.append_string("/report.txt", "second\n").unwrap();
+		assert!(fs.read_to_string("/report.txt").unwrap() == "first\nsecond\n");
+	}
+
+	#[test]
+	fn detach_test() {
+		let root = TestDir::new("detach_test");
+
+		let fs = LaminaFS::new();
+		let _mount = root.mount(&fs);
+
+		fs.write_file("/detached.txt", &b"detached"[..]).detach();
+		fs.set_drop_policy(DropPolicy::Detach);
+		drop(fs.write_file("/dropped.txt", &b"dropped"[..]));
+		while fs.pending_work_items() > 0 {
+			std::thread::sleep(std::time::Duration::from_millis(1));
+		}
+
+		assert!(std::fs::read(root.join("detached.txt")).unwrap() == b"detached");
+		assert!(std::fs::read(root.join("dropped.txt")).unwrap() == b"dropped");
+	}
+
+	#[test]
+	fn detached_release_test() {
+		let root = TestDir::new("detached_release_test");
+
+		// a single work item, which the detached write holds until it's released
+		let fs = LaminaFS::new_with_capacity(1, 1);
+		let _mount = root.mount(&fs);
+		fs.set_backpressure(Backpressure::Block);
+
+		fs.write_file("/first.txt", &b"first"[..]).detach();
+		assert!(fs.write_file("/second.txt", &b"second"[..]).get_result() == ResultCode::Ok);
+		assert!(std::fs::read(root.join("first.txt")).unwrap() == b"first");
+	}
+
+	// Holds reads and writes up until opened, like a device stuck on a disc that spun down.
+	struct StalledDevice {
+		open: Mutex<bool>,
+		opened: std::sync::Condvar,
+		written: Mutex<Option<Vec<u8>>>
+	}
+
+	impl StalledDevice {
+		fn open(&self) {
+			*self.open.lock().unwrap() = true;
+			self.opened.notify_all();
+		}
+
+		fn wait_open(&self) {
+			let mut open = self.open.lock().unwrap();
+			while !*open {
+				open = self.opened.wait(open).unwrap();
+			}
+		}
+	}
+
+	impl device::Device for StalledDevice {
+		fn create(_device_path: &str) -> Result<StalledDevice, ResultCode> {
+			Ok(StalledDevice {
+				open: Mutex::new(false),
+				opened: std::sync::Condvar::new(),
+				written: Mutex::new(None)
+			})
+		}
+
+		fn file_exists(&self, _path: &str) -> bool {
+			false
+		}
+
+		fn file_size(&self, _path: &str) -> Result<u64, ResultCode> {
+			Err(ResultCode::NotFound)
+		}
+
+		fn read_file(&self, _path: &str, _offset: u64, _max_bytes: u64) -> Result<Vec<u8>, ResultCode> {
+			self.wait_open();
+			Err(ResultCode::NotFound)
+		}
+
+		fn write_file(&self, _path: &str, _offset: u64, buffer: &[u8], _mode: device::WriteMode) -> Result<u64, ResultCode> {
+			self.wait_open();
+			*self.written.lock().unwrap() = Some(buffer.to_vec());
+			Ok(buffer.len() as u64)
+		}
+	}
+
+	#[test]
+	fn timed_out_write_test() {
+		let fs = LaminaFS::new();
+		let stalled = fs.register_device::<StalledDevice>();
+		let mount = fs.create_mount_with_permissions(stalled, "/", "", MountPermissions::All).unwrap();
+		let device = mount.device::<StalledDevice>().unwrap();
+
+		let save = vec![0x5a; 64 * 1024];
+		let work = fs.write_file("/save.dat", save.clone()).with_timeout(std::time::Duration::from_millis(10));
+		assert!(work.get_result() == ResultCode::TimedOut);
+		assert!(fs.queue_stats().in_flight == 0);
+		assert!(fs.queue_stats().work_items == 1);
+		// detached rather than waited for, the only reference to the buffer left is the
+		// one the reaper holds
+		drop(work);
+
+		device.open();
+		while device.written.lock().unwrap().is_none() {
+			std::thread::sleep(std::time::Duration::from_millis(1));
+		}
+		assert!(*device.written.lock().unwrap() == Some(save));
+		while fs.queue_stats().work_items != 0 {
+			std::thread::sleep(std::time::Duration::from_millis(1));
+		}
+	}
+
+	#[test]
+	fn wait_timeout_test() {
+		let fs = LaminaFS::new();
+		let stalled = fs.register_device::<StalledDevice>();
+		let mount = fs.create_mount_with_permissions(stalled, "/", "", MountPermissions::All).unwrap();
+		let device = mount.device::<StalledDevice>().unwrap();
+
+		let work = fs.write_file("/save.dat", &b"save"[..]);
+		assert!(!work.wait_timeout(std::time::Duration::from_millis(10)));
+		assert!(!work.is_finished());
+
+		device.open();
+		assert!(work.wait_timeout(std::time::Duration::from_secs(10)));
+		assert!(work.get_result() == ResultCode::Ok);
+		assert!(work.wait_timeout(std::time::Duration::from_millis(0)));
+	}
+
+	#[test]
+	fn cancel_test() {
+		// one work item in flight at a time, held up by the stalled device
+		let fs = LaminaFS::new_with_capacity(1, 8);
+		let stalled = fs.register_device::<StalledDevice>();
+		let mount = fs.create_mount_with_permissions(stalled, "/", "", MountPermissions::All).unwrap();
+		let device = mount.device::<StalledDevice>().unwrap();
+		let wait_for = |in_flight| {
+			while fs.queue_stats().in_flight != in_flight {
+				std::thread::sleep(std::time::Duration::from_millis(1));
+			}
+		};
+
+		let running = fs.read_file("/level.bin", false);
+		wait_for(1);
+		let queued = fs.read_file("/queued.bin", false);
+		let rest: Vec<_> = (0..3).map(|index| fs.read_file(&format!("/rest{}.bin", index), false)).collect();
+
+		assert!(queued.cancel());
+		assert!(queued.get_result() == ResultCode::Cancelled);
+		fs.cancel_all();
+		assert!(rest.iter().all(|work| work.get_result() == ResultCode::Cancelled));
+
+		// the read in flight is abandoned and hands back its slot straight away
+		assert!(running.cancel());
+		assert!(running.get_result() == ResultCode::Cancelled);
+		assert!(!running.cancel());
+		wait_for(0);
+
+		// writes in flight can't be taken back
+		let write = fs.write_file("/save.dat", &b"save"[..]);
+		wait_for(1);
+		assert!(!write.cancel());
+
+		device.open();
+		assert!(write.get_result() == ResultCode::Ok);
+		assert!(*device.written.lock().unwrap() == Some(b"save".to_vec()));
+		drop(write);
+		drop(running);
+		while fs.queue_stats().work_items != 0 {
+			std::thread::sleep(std::time::Duration::from_millis(1));
+		}
+	}
+
+	#[test]
+	fn cancel_on_drop_test() {
+		let root = TestDir::new("cancel_on_drop_test");
+
+		// one work item in flight at a time, held up by its callback
+		let fs = LaminaFS::new_with_capacity(1, 4);
+		let _mount = root.mount(&fs);
+		let (release, released) = std::sync::mpsc::channel::<()>();
+		let running = fs.write_file_with_callback("/running.txt", &b"running"[..], move |_| { let _ = released.recv(); });
+
+		let result = Arc::new(Mutex::new(None));
+		let callback_result = result.clone();
+		drop(fs.write_file_with_callback("/closed.txt", &b"closed"[..], move |result| *callback_result.lock().unwrap() = Some(result.get_result())).cancel_on_drop());
+		assert!(*result.lock().unwrap() == Some(ResultCode::Cancelled));
+
+		release.send(()).unwrap();
+		assert!(running.get_result() == ResultCode::Ok);
+		assert!(!root.join("closed.txt").exists());
+	}
+
+	#[test]
+	fn callback_test() {
+		let root = TestDir::new("callback_test");
+		let fs = LaminaFS::new();
+		let _mount = root.mount(&fs);
+
+		let record = |seen: &Arc<Mutex<Option<(ResultCode, Vec<u8>)>>>| {
+			let seen = seen.clone();
+			move |result: &WorkItemResult| *seen.lock().unwrap() = Some((result.get_result(), result.get_buffer().to_vec()))
+		};
+
+		let written = Arc::new(Mutex::new(None));
+		assert!(fs.write_file_with_callback("/level.bin", &b"level data"[..], record(&written)).get_result() == ResultCode::Ok);
+		assert!(written.lock().unwrap().as_ref().map(|seen| seen.0) == Some(ResultCode::Ok));
+
+		// the callback has run by the time the handle reports its result
+		let read = Arc::new(Mutex::new(None));
+		assert!(fs.read_file_with_callback("/level.bin", false, record(&read)).get_result() == ResultCode::Ok);
+		assert!(*read.lock().unwrap() == Some((ResultCode::Ok, b"level data".to_vec())));
+
+		let segment = Arc::new(Mutex::new(None));
+		assert!(fs.read_file_segment_with_callback("/level.bin", 6, 4, false, record(&segment)).get_result() == ResultCode::Ok);
+		assert!(*segment.lock().unwrap() == Some((ResultCode::Ok, b"data".to_vec())));
+
+		let missing = Arc::new(Mutex::new(None));
+		assert!(fs.delete_file_with_callback("/missing.bin", record(&missing)).get_result() == ResultCode::NotFound);
+		assert!(missing.lock().unwrap().as_ref().map(|seen| seen.0) == Some(ResultCode::NotFound));
+	}
+
+	#[test]
+	fn unmount_resolved_paths_test() {
+		let other = TestDir::new("unmount_resolved_other");
+		let mods = TestDir::new("unmount_resolved_mods");
+
+		// one work item in flight at a time, held up by its callback on another mount
+		let fs = LaminaFS::new_with_capacity(1, 8);
+		let _other_mount = fs.create_mount_with_permissions(DeviceType::Directory, "/other", other.to_str(), MountPermissions::All).unwrap();
+		let mods_mount = fs.create_mount_with_permissions(DeviceType::Directory, "/mods", mods.to_str(), MountPermissions::All).unwrap();
+		let (release, released) = std::sync::mpsc::channel::<()>();
+		let running = fs.write_file_with_callback("/other/running.txt", &b"running"[..], move |_| { let _ = released.recv(); });
+
+		fs.alias("/save.dat", "/mods/save.dat").unwrap();
+		let aliased = fs.write_file("/save.dat", &b"save"[..]);
+		let climbing = fs.write_file("/other/../mods/level.dat", &b"level"[..]);
+		mods_mount.unmount(UnmountMode::Cancel).unwrap();
+		assert!(aliased.get_result() == ResultCode::Cancelled);
+		assert!(climbing.get_result() == ResultCode::Cancelled);
+
+		release.send(()).unwrap();
+		assert!(running.get_result() == ResultCode::Ok);
+		assert!(!mods.join("save.dat").exists());
+		assert!(!mods.join("level.dat").exists());
+	}
+
+	#[test]
+	fn read_lines_test() {
+		use std::io::BufRead;
+
+		let fs = LaminaFS::new();
+		let _mount = fs.create_mount(DeviceType::Directory, "/", "./");
+
+		let expected: Vec<String> = std::fs::read_to_string("Cargo.toml").unwrap().lines().map(|line| line.to_string()).collect();
+		let lines: Vec<String> = fs.read_lines("/Cargo.toml").map(|line| line.unwrap()).collect();
+		assert!(lines == expected);
+
+		// lines spanning chunk boundaries
+		let reader = io::LfsBufReader::new(io::LfsReader::with_chunk_size(&fs, "/Cargo.toml", 7));
+		let lines: Vec<String> = reader.lines().map(|line| line.unwrap()).collect();
+		assert!(lines == expected);
+	}
+
+	#[test]
+	fn as_raw_test() {
+		let fs = LaminaFS::new();
+		let mount = fs.create_mount(DeviceType::Directory, "/", "./").unwrap();
+		assert!(mount.as_raw().is_some());
+
+		let work_item = fs.file_exists("/Cargo.toml");
+		work_item.wait();
+		assert!(work_item.as_raw().is_some());
+		assert!(fs.file_exists("invalid path").as_raw().is_none());
+	}
+
+	#[test]
+	fn from_raw_context_test() {
+		let engine = LaminaFS::new();
+		let fs = unsafe { LaminaFS::from_raw_context(engine.as_raw(), false) };
+		let mount = fs.create_mount(DeviceType::Directory, "/", "./").unwrap();
+		assert!(fs.file_exists_sync("/Cargo.toml").unwrap());
+
+		drop(mount);
+		drop(fs);
+		assert!(!engine.file_exists_sync("/Cargo.toml").unwrap());
+	}
+
+	#[cfg(feature = "testing")]
+	#[test]
+	fn mock_device_test() {
+		let fs = LaminaFS::new();
+		let mock = fs.register_device::<device::MockDevice>();
+		let mount = fs.create_mount_with_permissions(mock, "/", "", MountPermissions::All).unwrap();
+		let device = mount.device::<device::MockDevice>().unwrap();
+
+		device.set_file("/level.bin", b"level");
+		assert!(fs.read_file_sync("/level.bin").unwrap() == b"level");
+
+		device.fail("/save.dat", ResultCode::OutOfSpace);
+		assert!(fs.write_file_sync("/save.dat", b"save").unwrap_err().code() == ResultCode::OutOfSpace);
+	}
+
+	#[test]
+	fn device_type_test() {
+		let fs = LaminaFS::new();
+		let memory = fs.register_device::<device::MemoryDevice>();
+		let stalled = fs.register_device::<StalledDevice>();
+		assert!(memory.id() != stalled.id());
+		assert!(DeviceType::from(memory).id() == Some(memory.id()));
+		assert!(DeviceType::Directory.id() != Some(memory.id()) && DeviceType::Directory.id() != Some(stalled.id()));
+
+		let mount = fs.create_mount_with_permissions(memory, "/", "", MountPermissions::All).unwrap();
+		assert!(mount.device_type() == DeviceType::Registered(memory));
+		assert!(mount.device::<device::MemoryDevice>().is_some());
+		assert!(mount.device::<StalledDevice>().is_none());
+
+		let directory = fs.create_mount(DeviceType::Directory, "/src", "./src").unwrap();
+		assert!(directory.device_type() == DeviceType::Directory);
+	}
+
+	#[test]
+	fn mount_accessors_test() {
+		let fs = LaminaFS::new();
+		let mount = fs.create_mount_with_permissions(DeviceType::Directory, "/src", "./src", MountPermissions::Read).unwrap();
+		assert!(mount.mount_point() == "/src");
+		assert!(mount.device_path() == "./src");
+		assert!(mount.device_type() == DeviceType::Directory);
+		assert!(mount.permissions() == MountPermissions::Read);
+		assert!(mount.priority() == 0);
+
+		fs.set_mount_priority(&mount, 2).unwrap();
+		let info = mount.info();
+		assert!(info.mount_point == "/src" && info.device_path == "./src" && info.priority == 2);
+		assert!(fs.mounts() == vec![info]);
+	}
+
+	#[test]
+	fn empty_file_read_test() {
+		let fs = LaminaFS::new();
+		let memory = fs.register_device::<device::MemoryDevice>();
+		let _mount = fs.create_mount_with_permissions(memory, "/", "", MountPermissions::All).unwrap();
+
+		fs.write_file_sync("/empty.txt", b"").unwrap();
+		assert!(fs.read_file_sync("/empty.txt").unwrap().is_empty());
+		assert!(fs.read_to_string("/empty.txt").unwrap().is_empty());
+	}
+
+	#[test]
+	fn stat_test() {
+		let fs = LaminaFS::new();
+		let memory = fs.register_device::<device::MemoryDevice>();
+		let base = fs.create_mount_with_permissions(memory, "/", "", MountPermissions::All).unwrap();
+		let mods = fs.create_mount_with_permissions(memory, "/", "", MountPermissions::All).unwrap();
+
+		base.device::<device::MemoryDevice>().unwrap().write_file("level.bin", 0, b"base level", device::WriteMode::Overwrite).unwrap();
+		base.device::<device::MemoryDevice>().unwrap().write_file("readme.txt", 0, b"readme", device::WriteMode::Overwrite).unwrap();
+		mods.device::<device::MemoryDevice>().unwrap().write_file("level.bin", 0, b"mod", device::WriteMode::Overwrite).unwrap();
+		fs.create_dir_sync("/saves").unwrap();
+
+		// the most recent mount with the file answers, falling back to older ones
+		assert!(fs.file_size("/level.bin").get_result().unwrap() == 3);
+		assert!(fs.file_size("/readme.txt").get_result().unwrap() == 6);
+		assert!(fs.file_size("/missing.bin").get_result().unwrap_err().code() == ResultCode::NotFound);
+
+		let stat = fs.stat("/readme.txt").get_result().unwrap();
+		assert!(stat.size == 6 && !stat.is_dir);
+		assert!(fs.stat("/saves").get_result().unwrap().is_dir);
+		assert!(fs.stat("/missing.bin").get_result().unwrap_err().code() == ResultCode::NotFound);
+	}
+
+	#[test]
+	fn record_test() {
+		let fs = LaminaFS::new();
+		let _mount = fs.create_mount(DeviceType::Directory, "/", "./").unwrap();
+
+		fs.start_recording(true);
+		let contents = fs.read_file_sync("/Cargo.toml").unwrap();
+		assert!(!fs.file_exists_sync("/missing.toml").unwrap());
+		let recording = fs.stop_recording().unwrap();
+		assert!(fs.stop_recording().is_none());
+
+		assert!(recording.ops.len() == 2);
+		assert!(recording.ops[0].operation == OperationKind::ReadFile && recording.ops[0].data.as_ref() == Some(&contents));
+		assert!(recording.ops[1].result == ResultCode::NotFound);
+
+		let replay = device::ReplayDevice::new(recording);
+		assert!(device::Device::read_file(&replay, "/Cargo.toml", 0, u64::max_value()).unwrap() == contents);
+	}
+
+	#[test]
+	fn check_permission_test() {
+		let fs = LaminaFS::new();
+		let _data = fs.create_mount_with_permissions(DeviceType::Directory, "/data", "./src", MountPermissions::Read).unwrap();
+
+		assert!(fs.check_permission("/data/lib.rs", OperationKind::ReadFile).is_ok());
+		assert!(fs.check_permission("/data/lib.rs", OperationKind::DeleteFile).unwrap_err().code() == ResultCode::PermissionsError);
+		assert!(fs.check_permission("/saves/slot1", OperationKind::WriteFile).unwrap_err().code() == ResultCode::NotFound);
+
+		let _writable = fs.create_mount_with_permissions(DeviceType::Directory, "/data", "./src", MountPermissions::All).unwrap();
+		assert!(fs.check_permission("/data/lib.rs", OperationKind::DeleteFile).is_ok());
+	}
+
+	#[test]
+	fn mount_priority_test() {
+		let base = TestDir::new("mount_priority_base");
+		let patch = TestDir::new("mount_priority_patch");
+		std::fs::write(base.join("config.ini"), b"base").unwrap();
+		std::fs::write(patch.join("config.ini"), b"patch").unwrap();
+
+		let fs = LaminaFS::new();
+		let base_mount = base.mount(&fs);
+		let patch_mount = patch.mount(&fs);
+		assert!(fs.read_file_sync("/config.ini").unwrap() == b"patch");
+
+		fs.set_mount_priority(&base_mount, 1).unwrap();
+		assert!(fs.read_file_sync("/config.ini").unwrap() == b"base");
+		assert!(base_mount.priority() == 1);
+
+		fs.set_mount_order(&[&patch_mount, &base_mount]).unwrap();
+		assert!(fs.read_file_sync("/config.ini").unwrap() == b"patch");
+	}
+
+	#[test]
+	fn mounts_test() {
+		let base = TestDir::new("mounts_base");
+		let saves = TestDir::new("mounts_saves");
+
+		let fs = LaminaFS::new();
+		let base_mount = fs.create_mount_with_permissions(DeviceType::Directory, "/", base.to_str(), MountPermissions::Read).unwrap();
+		let saves_mount = fs.create_mount_with_permissions(DeviceType::Directory, "/saves", saves.to_str(), MountPermissions::All).unwrap();
+
+		// listed in resolution order, most recent first
+		let mounts = fs.mounts();
+		assert!(mounts.len() == 2);
+		assert!(mounts[0].mount_point == "/saves" && mounts[0].device_path == saves.to_str());
+		assert!(mounts[0].device_type == DeviceType::Directory && mounts[0].permissions == MountPermissions::All);
+		assert!(mounts[1].mount_point == "/" && mounts[1].permissions == MountPermissions::Read);
+
+		fs.set_mount_priority(&base_mount, 1).unwrap();
+		let mounts = fs.mounts();
+		assert!(mounts[0].mount_point == "/" && mounts[0].priority == 1);
+
+		drop(saves_mount);
+		assert!(fs.mounts().iter().map(|mount| mount.mount_point.as_str()).collect::<Vec<_>>() == vec!["/"]);
+	}
+
+	#[test]
+	fn resolve_test() {
+		let base = TestDir::new("resolve_base");
+		let patch = TestDir::new("resolve_patch");
+		std::fs::write(base.join("config.ini"), b"base").unwrap();
+		std::fs::write(base.join("level.bin"), b"level").unwrap();
+		std::fs::write(patch.join("config.ini"), b"patch").unwrap();
+
+		let fs = LaminaFS::new();
+		let _base_mount = fs.create_mount(DeviceType::Directory, "/data", base.to_str()).unwrap();
+		let _patch_mount = fs.create_mount(DeviceType::Directory, "/data", patch.to_str()).unwrap();
+
+		let resolved = fs.resolve("/data/config.ini").unwrap();
+		assert!(resolved.mount.device_path == patch.to_str());
+		assert!(resolved.relative_path.trim_start_matches('/') == "config.ini");
+		assert!(resolved.backing_path == Some(patch.join("config.ini")));
+
+		// falls back to the older mount when the newer one doesn't have the file
+		let resolved = fs.resolve("/data/level.bin").unwrap();
+		assert!(resolved.mount.device_path == base.to_str());
+		assert!(resolved.backing_path == Some(base.join("level.bin")));
+
+		let error = fs.resolve("/data/missing.bin").unwrap_err();
+		assert!(error.code() == ResultCode::NotFound && error.operation() == Some(OperationKind::Resolve));
+	}
+
+	#[test]
+	fn read_only_test() {
+		let root = TestDir::new("read_only_test");
+
+		let fs = LaminaFS::new();
+		let _mount = root.mount(&fs);
+		fs.write_file_sync("/save.dat", b"slot").unwrap();
+
+		fs.set_read_only(true);
+		assert!(fs.is_read_only());
+		assert!(fs.write_file_sync("/save.dat", b"spectator").unwrap_err().code() == ResultCode::PermissionsError);
+		assert!(fs.delete_file_sync("/save.dat").unwrap_err().code() == ResultCode::PermissionsError);
+		assert!(fs.create_dir_sync("/saves").unwrap_err().code() == ResultCode::PermissionsError);
+		assert!(fs.check_permission("/save.dat", OperationKind::WriteFile).unwrap_err().code() == ResultCode::PermissionsError);
+		assert!(fs.read_file_sync("/save.dat").unwrap() == b"slot");
+
+		fs.set_read_only(false);
+		fs.delete_file_sync("/save.dat").unwrap();
+	}
+
+	#[test]
+	fn mount_event_test() {
+		let fs = LaminaFS::new();
+		let events = Arc::new(Mutex::new(Vec::new()));
+		let hook_events = events.clone();
+		let hook = fs.on_mount_event(move |event| hook_events.lock().unwrap().push(event.clone()));
+
+		let mount = fs.create_mount(DeviceType::Directory, "/mods", "./src").unwrap();
+		let info = mount.info();
+		mount.unmount(UnmountMode::Wait).unwrap();
+		assert!(fs.remove_mount_event_hook(hook));
+		drop(fs.create_mount(DeviceType::Directory, "/mods", "./src").unwrap());
+
+		assert!(*events.lock().unwrap() == [MountEvent::Created(info.clone()), MountEvent::Released(info)]);
+	}
+
+	#[test]
+	fn strict_paths_test() {
+		let fs = LaminaFS::new();
+		let mount = fs.create_mount(DeviceType::Directory, "/mods", "./src").unwrap();
+
+		assert!(!fs.file_exists_sync("/mods/../Cargo.toml").unwrap());
+		assert!(fs.file_exists_sync("/mods/./lib.rs").unwrap());
+
+		mount.set_strict_paths(true);
+		assert!(mount.strict_paths());
+		assert!(fs.read_file_sync("/mods/./lib.rs").unwrap_err().code() == ResultCode::InvalidPath);
+		assert!(fs.file_exists_sync("/mods/lib.rs").unwrap());
+
+		// the APIs that don't go through the queue refuse them the same way
+		let error = fs.resolve("/mods/./lib.rs").unwrap_err();
+		assert!(error.code() == ResultCode::InvalidPath && error.operation() == Some(OperationKind::Resolve));
+		assert!(fs.check_permission("/mods/./lib.rs", OperationKind::ReadFile).unwrap_err().code() == ResultCode::InvalidPath);
+		assert!(fs.file_size("/mods/./lib.rs").get_result().unwrap_err().code() == ResultCode::InvalidPath);
+		assert!(fs.file_size("/mods/lib.rs").get_result().is_ok());
+	}
+
+	#[test]
+	fn mount_builder_options_test() {
+		let fs = LaminaFS::new();
+		let weak = Arc::downgrade(&fs);
+		let seen = Arc::new(Mutex::new(Vec::new()));
+		let seen_by_hook = seen.clone();
+		let _hook = fs.on_mount_event(move |event| {
+			// the options are in place by the time anyone hears of the mount
+			if let MountEvent::Created(_) = event {
+				let fs = weak.upgrade().unwrap();
+				seen_by_hook.lock().unwrap().push(fs.resolve("/mods/./lib.rs").map(|_| ()).map_err(|error| error.code()));
+			}
+		});
+
+		let policy = RetryPolicy::new(3, std::time::Duration::from_millis(1));
+		let memory = fs.register_device::<device::MemoryDevice>();
+		let mount = fs.mount("/mods").device(memory).strict_paths(true).bandwidth_limit(1 << 20).retry_policy(policy).build().unwrap();
+		assert!(*seen.lock().unwrap() == vec![Err(ResultCode::InvalidPath)]);
+		assert!(mount.strict_paths() && mount.bandwidth_limit() == Some(1 << 20) && mount.retry_policy() == Some(policy));
+	}
+
+	#[test]
+	fn retry_policy_test() {
+		let fs = LaminaFS::new();
+		let policy = RetryPolicy::new(3, std::time::Duration::from_millis(1));
+		let mount = fs.create_mount(DeviceType::Directory, "/", "./").unwrap();
+		let error = mount.set_retry_policy(Some(policy)).unwrap_err();
+		assert!(error.code() == ResultCode::Unsupported && error.operation() == Some(OperationKind::SetRetryPolicy));
+		assert!(error.to_string() == "set_retry_policy \"/\": unsupported");
+		assert!(mount.set_retry_policy(None).is_ok());
+		assert!(fs.mount("/data").path("./src").retry_policy(policy).build().is_err());
+	}
+
+	#[test]
+	fn truncate_file_test() {
+		let root = TestDir::new("truncate_file_test");
+		std::fs::write(root.join("save.bin"), b"level 2, gold 30").unwrap();
+		let fs = LaminaFS::new();
+		let mount = root.mount(&fs);
+
+		fs.truncate_file("/save.bin", 7).get_result().unwrap();
+		assert!(fs.read_file_sync("/save.bin").unwrap() == b"level 2");
+		fs.truncate_file("/save.bin", 9).get_result().unwrap();
+		assert!(fs.read_file_sync("/save.bin").unwrap() == b"level 2\0\0");
+		assert!(fs.truncate_file("/missing.bin", 0).get_result().unwrap_err().code() == ResultCode::NotFound);
+
+		drop(mount);
+		let _mount = fs.create_mount_with_permissions(DeviceType::Directory, "/", root.to_str(), MountPermissions::Read).unwrap();
+		assert!(fs.truncate_file("/save.bin", 0).get_result().is_err());
+		assert!(std::fs::read(root.join("save.bin")).unwrap().len() == 9);
+	}
+
+	#[test]
+	fn context_lifetime_test() {
+		let root = TestDir::new("context_lifetime_test");
+		std::fs::write(root.join("level.bin"), b"level data").unwrap();
+		let fs = LaminaFS::new();
+		let mount = root.mount(&fs);
+		let weak = Arc::downgrade(&fs);
+
+		// the mount keeps the LaminaFS alive
+		drop(fs);
+		let work = weak.upgrade().unwrap().read_file("/level.bin", false);
+		assert!(work.get_result() == ResultCode::Ok);
+
+		// and the work item keeps the context alive after the LaminaFS is gone
+		drop(mount);
+		assert!(weak.upgrade().is_none());
+		assert!(work.get_buffer() == b"level data");
+		let buffer = {
+			let mut work = work;
+			work.take_buffer()
+		};
+		assert!(&buffer[..] == b"level data");
+	}
+
+	#[test]
+	fn send_sync_test() {
+		fn assert_send_sync<T: Send + Sync>() {}
+		assert_send_sync::<LaminaFS>();
+		assert_send_sync::<Mount>();
+		assert_send_sync::<WorkHandle>();
+		assert_send_sync::<ReadBuffer>();
+	}
 }
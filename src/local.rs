@@ -0,0 +1,164 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// Runs work items directly against the Rust side of the mounts instead of handing them
+// to the context. The results are the same as the context's, as far as the devices
+// the Rust side can reach go.
+
+use crate::alloc::ContextAllocator;
+use crate::device::WriteMode;
+use crate::mount::MountTable;
+use crate::{MountPermissions, ResultCode};
+
+use std::sync::Arc;
+
+// How work items execute.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ExecutionMode {
+	// queued and run by the context's worker threads
+	#[default]
+	Threaded,
+	// run on the submitting thread before the submission returns, without the context's
+	// workers or the task threads, e.g. for deterministic tests. The context is still
+	// created, since mounts go through it, and starts its IO thread as usual; that thread
+	// is the only one running in the background. Tasks run inline, watches are polled with
+	// Watcher::poll, and work finishes on submission, so it can't time out and leave a
+	// thread waiting to release it.
+	SingleThread
+}
+
+// A work item's operation, for running it without the context.
+pub(crate) enum DeviceOp {
+	ReadFile { null_terminate: bool, allocator: Arc<ContextAllocator> },
+	ReadFileSegment { offset: u64, max_bytes: u64, null_terminate: bool, allocator: Arc<ContextAllocator> },
+	WriteFile { mode: WriteMode, offset: u64, buffer: Arc<[u8]> },
+	DeleteFile,
+	CreateDir,
+	DeleteDir,
+	FileExists
+}
+
+// The bytes and buffer a locally run work item completes with.
+pub(crate) struct LocalResult {
+	pub(crate) bytes: usize,
+	pub(crate) buffer: *mut u8
+}
+
+impl LocalResult {
+	fn empty() -> LocalResult {
+		LocalResult {
+			bytes: 0,
+			buffer: 0 as *mut u8
+		}
+	}
+
+	// Copies data into a buffer from `allocator`, the way the context hands out reads.
+	pub(crate) fn read(data: &[u8], null_terminate: bool, allocator: &ContextAllocator) -> Result<LocalResult, ResultCode> {
+		let size = data.len() + if null_terminate { 1 } else { 0 };
+		let buffer = unsafe { allocator.alloc(std::cmp::max(size, 1), 1) } as *mut u8;
+		if buffer.is_null() {
+			return Err(ResultCode::OutOfSpace);
+		}
+
+		unsafe {
+			std::ptr::copy_nonoverlapping(data.as_ptr(), buffer, data.len());
+			if null_terminate {
+				*buffer.add(data.len()) = 0;
+			}
+		}
+		Ok(LocalResult {
+			bytes: data.len(),
+			buffer: buffer
+		})
+	}
+}
+
+impl MountTable {
+	pub(crate) fn execute(&self, path: &str, op: &DeviceOp) -> Result<LocalResult, ResultCode> {
+		match *op {
+			DeviceOp::ReadFile { null_terminate, ref allocator } => {
+				let data = self.query(path, |device, path| device.read_file(path, 0, u64::max_value()))?;
+				LocalResult::read(&data, null_terminate, allocator)
+			},
+			DeviceOp::ReadFileSegment { offset, max_bytes, null_terminate, ref allocator } => {
+				let data = self.query(path, |device, path| device.read_file(path, offset, max_bytes))?;
+				LocalResult::read(&data, null_terminate, allocator)
+			},
+			DeviceOp::WriteFile { mode, offset, ref buffer } => {
+				let (device, path) = self.writable_device(path, MountPermissions::WriteFile)?;
+				device.write_file(&path, offset, buffer, mode).map(|_| LocalResult::empty())
+			},
+			DeviceOp::DeleteFile => {
+				let (device, path) = self.writable_device(path, MountPermissions::DeleteFile)?;
+				device.delete_file(&path).map(|_| LocalResult::empty())
+			},
+			DeviceOp::CreateDir => {
+				let (device, path) = self.writable_device(path, MountPermissions::CreateDir)?;
+				device.create_dir(&path).map(|_| LocalResult::empty())
+			},
+			DeviceOp::DeleteDir => {
+				let (device, path) = self.writable_device(path, MountPermissions::DeleteDir)?;
+				device.delete_dir(&path).map(|_| LocalResult::empty())
+			},
+			DeviceOp::FileExists => self.query(path, |device, path| {
+				if device.file_exists(path) { Ok(LocalResult::empty()) } else { Err(ResultCode::NotFound) }
+			})
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::device::{CreatedDevice, Device, MemoryDevice};
+	use crate::mount::MountEntry;
+	use crate::{BufferPool, DeviceType};
+
+	#[test]
+	fn execute_test() {
+		let device = CreatedDevice::new(Arc::new(MemoryDevice::create("").unwrap()));
+		let table = MountTable::new();
+		let saves = Arc::new(MountEntry::new("/saves/", DeviceType::Directory, "", MountPermissions::All, Some(device.clone()), None));
+		let data = Arc::new(MountEntry::new("/data/", DeviceType::Directory, "", MountPermissions::Read, Some(device), None));
+		table.add(&saves);
+		table.add(&data);
+
+		let write = DeviceOp::WriteFile { mode: WriteMode::Overwrite, offset: 0, buffer: Arc::from(&b"hello"[..]) };
+		assert!(table.execute("/saves/slot0", &write).is_ok());
+		assert!(table.execute("/data/slot0", &write).err() == Some(ResultCode::PermissionsError));
+		assert!(table.execute("/other/slot0", &write).err() == Some(ResultCode::NotFound));
+
+		let pool = BufferPool::new();
+		let allocator = pool.allocator().clone();
+		let read = DeviceOp::ReadFile { null_terminate: true, allocator: allocator.clone() };
+		let result = table.execute("/data/slot0", &read).unwrap();
+		assert!(result.bytes == 5);
+		unsafe {
+			assert!(std::slice::from_raw_parts(result.buffer, 6) == b"hello\0");
+			allocator.free(result.buffer as *mut std::ffi::c_void);
+		}
+
+		assert!(table.execute("/saves/slot0", &DeviceOp::FileExists).is_ok());
+		assert!(table.execute("/saves/slot1", &DeviceOp::FileExists).err() == Some(ResultCode::NotFound));
+	}
+}
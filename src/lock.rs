@@ -0,0 +1,197 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// Advisory locks on files kept on disk, so tools sharing a project directory can take
+// turns with its files. Locks only bind other processes that lock the same file; plain
+// reads and writes go through regardless.
+
+use crate::device;
+use crate::{LaminaFS, OperationKind, ResultCode, Task};
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LockMode {
+	// any number of shared locks can be held at once, but not alongside an exclusive one
+	Shared,
+	Exclusive
+}
+
+// A held lock, released when dropped.
+pub struct FileLock {
+	file: File,
+	mode: LockMode
+}
+
+impl FileLock {
+	pub fn mode(&self) -> LockMode {
+		self.mode
+	}
+}
+
+impl Drop for FileLock {
+	fn drop(&mut self) {
+		// closing the file would release the lock too, this just doesn't wait for it
+		let _ = unlock(&self.file);
+	}
+}
+
+#[cfg(unix)]
+fn lock(file: &File, mode: LockMode, blocking: bool) -> io::Result<bool> {
+	use std::os::unix::io::AsRawFd;
+
+	let mut operation = match mode {
+		LockMode::Shared => libc::LOCK_SH,
+		LockMode::Exclusive => libc::LOCK_EX
+	};
+	if !blocking {
+		operation |= libc::LOCK_NB;
+	}
+
+	loop {
+		if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+			return Ok(true);
+		}
+		let error = io::Error::last_os_error();
+		match error.raw_os_error() {
+			Some(libc::EINTR) => continue,
+			Some(libc::EWOULDBLOCK) if !blocking => return Ok(false),
+			_ => return Err(error)
+		}
+	}
+}
+
+#[cfg(unix)]
+fn unlock(file: &File) -> io::Result<()> {
+	use std::os::unix::io::AsRawFd;
+
+	match unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_UN) } {
+		0 => Ok(()),
+		_ => Err(io::Error::last_os_error())
+	}
+}
+
+// The whole file is locked, as a range running to the largest possible offset.
+#[cfg(windows)]
+fn lock(file: &File, mode: LockMode, blocking: bool) -> io::Result<bool> {
+	use std::os::windows::io::AsRawHandle;
+	use winapi::shared::winerror::ERROR_LOCK_VIOLATION;
+	use winapi::um::minwinbase::{LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY, OVERLAPPED};
+
+	let mut flags = match mode {
+		LockMode::Shared => 0,
+		LockMode::Exclusive => LOCKFILE_EXCLUSIVE_LOCK
+	};
+	if !blocking {
+		flags |= LOCKFILE_FAIL_IMMEDIATELY;
+	}
+
+	let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
+	if unsafe { winapi::um::fileapi::LockFileEx(file.as_raw_handle() as _, flags, 0, !0, !0, &mut overlapped) } != 0 {
+		return Ok(true);
+	}
+	let error = io::Error::last_os_error();
+	match error.raw_os_error() {
+		Some(code) if !blocking && code == ERROR_LOCK_VIOLATION as i32 => Ok(false),
+		_ => Err(error)
+	}
+}
+
+#[cfg(windows)]
+fn unlock(file: &File) -> io::Result<()> {
+	use std::os::windows::io::AsRawHandle;
+	use winapi::um::minwinbase::OVERLAPPED;
+
+	let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
+	match unsafe { winapi::um::fileapi::UnlockFileEx(file.as_raw_handle() as _, 0, !0, !0, &mut overlapped) } {
+		0 => Err(io::Error::last_os_error()),
+		_ => Ok(())
+	}
+}
+
+#[cfg(not(any(unix, windows)))]
+fn lock(_file: &File, _mode: LockMode, _blocking: bool) -> io::Result<bool> {
+	Err(io::ErrorKind::Other.into())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn unlock(_file: &File) -> io::Result<()> {
+	Ok(())
+}
+
+fn lock_path(path: &Path, mode: LockMode) -> Result<FileLock, ResultCode> {
+	let file = File::open(path).map_err(device::from_io_error)?;
+	lock(&file, mode, true).map_err(device::from_io_error)?;
+	Ok(FileLock {
+		file: file,
+		mode: mode
+	})
+}
+
+impl LaminaFS {
+	// Waits for an advisory lock on the file, which needs to exist on a mount that keeps
+	// it on disk as is, like a Directory mount; anything else is Unsupported. The lock is
+	// held until the returned FileLock is dropped. Waiting ties up one of the task threads.
+	pub fn lock_file(&self, path: &str, mode: LockMode) -> Task<FileLock> {
+		let mounts = self.mounts.clone();
+		let path_owned = self.virtual_path(path, false);
+		self.tasks.spawn(OperationKind::LockFile, path, move || {
+			let path_owned = path_owned.map_err(|error| error.code())?;
+			let (device, relative_path, _) = mounts.readable_file(&path_owned)?;
+			match device.raw_path(&relative_path) {
+				Some(raw_path) => lock_path(&raw_path, mode),
+				None => Err(ResultCode::Unsupported)
+			}
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn lock_test() {
+		let path = std::env::temp_dir().join("laminafs_lock_test.project");
+		std::fs::write(&path, b"shared project file").unwrap();
+		let other = File::open(&path).unwrap();
+
+		let shared = lock_path(&path, LockMode::Shared).unwrap();
+		assert!(shared.mode() == LockMode::Shared);
+		assert!(lock(&other, LockMode::Shared, false).unwrap());
+		unlock(&other).unwrap();
+		assert!(!lock(&other, LockMode::Exclusive, false).unwrap());
+		drop(shared);
+
+		let exclusive = lock_path(&path, LockMode::Exclusive).unwrap();
+		assert!(!lock(&other, LockMode::Shared, false).unwrap());
+		drop(exclusive);
+		assert!(lock(&other, LockMode::Exclusive, false).unwrap());
+		unlock(&other).unwrap();
+
+		assert!(lock_path(&path.with_extension("missing"), LockMode::Shared).err() == Some(ResultCode::NotFound));
+		std::fs::remove_file(&path).unwrap();
+	}
+}
@@ -0,0 +1,229 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// Asset manifests map logical asset IDs to the paths they're read from, along with a
+// version and optionally the hash the data is expected to have, e.g.
+//
+//   [assets.hero_mesh]
+//   path = "/data/characters/hero.mesh"
+//   version = 3
+//   hash = "crc32:cbf43926"
+//
+// or the same as JSON, {"assets": {"hero_mesh": {"path": ..., ...}}}. Hashes are written
+// as "crc32:" or "sha256:" followed by the hash in hex.
+
+use crate::{FileHash, HashKind, LaminaFS, LfsError, OperationKind, ResultCode, WorkHandle};
+
+use serde::Deserialize;
+
+use std::collections::HashMap;
+
+#[derive(Deserialize)]
+struct ManifestFile {
+	#[serde(default)]
+	assets: HashMap<String, EntryFile>
+}
+
+#[derive(Deserialize)]
+struct EntryFile {
+	path: String,
+	#[serde(default)]
+	version: u32,
+	#[serde(default)]
+	hash: Option<String>
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ManifestEntry {
+	pub path: String,
+	pub version: u32,
+	pub hash: Option<FileHash>
+}
+
+impl ManifestEntry {
+	// Whether a read made through LaminaFS::read_asset produced the expected data. Entries
+	// without a hash match anything that was read successfully.
+	pub fn matches(&self, work_item: &WorkHandle) -> bool {
+		if work_item.get_result() != ResultCode::Ok {
+			return false;
+		}
+		match self.hash {
+			Some(hash) => work_item.get_hash() == Some(hash),
+			None => true
+		}
+	}
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct Manifest {
+	entries: HashMap<String, ManifestEntry>
+}
+
+impl Manifest {
+	pub fn new() -> Manifest {
+		Manifest::default()
+	}
+
+	#[cfg(feature = "json")]
+	pub fn from_json(data: &[u8]) -> Result<Manifest, ResultCode> {
+		Manifest::from_file(serde_json::from_slice(data).map_err(|_| ResultCode::GenericError)?)
+	}
+
+	#[cfg(feature = "toml")]
+	pub fn from_toml(data: &[u8]) -> Result<Manifest, ResultCode> {
+		let data = std::str::from_utf8(data).map_err(|_| ResultCode::GenericError)?;
+		Manifest::from_file(toml_rs::from_str(data).map_err(|_| ResultCode::GenericError)?)
+	}
+
+	fn from_file(file: ManifestFile) -> Result<Manifest, ResultCode> {
+		let mut manifest = Manifest::new();
+		for (id, entry) in file.assets {
+			let hash = match entry.hash {
+				Some(ref hash) => Some(parse_hash(hash).ok_or(ResultCode::GenericError)?),
+				None => None
+			};
+			manifest.insert(&id, ManifestEntry {
+				path: entry.path,
+				version: entry.version,
+				hash: hash
+			});
+		}
+		Ok(manifest)
+	}
+
+	// Replaces any entry already registered under `id`.
+	pub fn insert(&mut self, id: &str, entry: ManifestEntry) {
+		self.entries.insert(id.to_string(), entry);
+	}
+
+	pub fn get(&self, id: &str) -> Option<&ManifestEntry> {
+		self.entries.get(id)
+	}
+
+	pub fn path(&self, id: &str) -> Option<&str> {
+		self.get(id).map(|entry| entry.path.as_str())
+	}
+
+	pub fn ids(&self) -> impl Iterator<Item = &str> {
+		self.entries.keys().map(|id| id.as_str())
+	}
+
+	pub fn len(&self) -> usize {
+		self.entries.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.entries.is_empty()
+	}
+}
+
+fn parse_hex(hex: &str, out: &mut [u8]) -> Option<()> {
+	if hex.len() != out.len() * 2 || !hex.is_ascii() {
+		return None;
+	}
+	for (i, byte) in out.iter_mut().enumerate() {
+		*byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+	}
+	Some(())
+}
+
+fn parse_hash(hash: &str) -> Option<FileHash> {
+	let separator = hash.find(':')?;
+	let (kind, hex) = (&hash[..separator], &hash[separator + 1..]);
+	match kind {
+		"crc32" => {
+			let mut bytes = [0u8; 4];
+			parse_hex(hex, &mut bytes)?;
+			Some(FileHash::Crc32(u32::from_be_bytes(bytes)))
+		},
+		#[cfg(feature = "sha256")]
+		"sha256" => {
+			let mut bytes = [0u8; 32];
+			parse_hex(hex, &mut bytes)?;
+			Some(FileHash::Sha256(bytes))
+		},
+		_ => None
+	}
+}
+
+impl LaminaFS {
+	// Reads and parses a manifest, picking the format from the extension: .json needs the
+	// json feature and .toml the toml feature. Malformed manifests fail with GenericError.
+	pub fn load_manifest(&self, path: &str) -> Result<Manifest, LfsError> {
+		let data = self.read_file_sync(path)?;
+		let manifest = match path.rsplit('.').next() {
+			#[cfg(feature = "json")]
+			Some("json") => Manifest::from_json(&data),
+			#[cfg(feature = "toml")]
+			Some("toml") => Manifest::from_toml(&data),
+			_ => Err(ResultCode::Unsupported)
+		};
+		manifest.map_err(|code| LfsError::new(code, OperationKind::LoadManifest, path))
+	}
+
+	// Reads the asset registered under `id`, hashing it on the way if the manifest has a
+	// hash for it so the data can be checked with ManifestEntry::matches.
+	pub fn read_asset(&self, manifest: &Manifest, id: &str) -> Result<WorkHandle, LfsError> {
+		let entry = manifest.get(id).ok_or_else(|| LfsError::new(ResultCode::NotFound, OperationKind::ReadAsset, id))?;
+		Ok(match entry.hash {
+			Some(FileHash::Crc32(_)) => self.read_file_hashed(&entry.path, HashKind::Crc32),
+			#[cfg(feature = "sha256")]
+			Some(FileHash::Sha256(_)) => self.read_file_hashed(&entry.path, HashKind::Sha256),
+			None => self.read_file(&entry.path, false)
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_hash_test() {
+		assert!(parse_hash("crc32:cbf43926") == Some(FileHash::Crc32(0xCBF4_3926)));
+		assert!(parse_hash("crc32:CBF43926") == Some(FileHash::Crc32(0xCBF4_3926)));
+		assert!(parse_hash("crc32:cbf439") == None);
+		assert!(parse_hash("crc32:cbf4392g") == None);
+		assert!(parse_hash("md5:cbf43926") == None);
+		assert!(parse_hash("cbf43926") == None);
+	}
+
+	#[cfg(feature = "json")]
+	#[test]
+	fn from_json_test() {
+		let manifest = Manifest::from_json(br#"{"assets": {
+			"hero_mesh": {"path": "/data/hero.mesh", "version": 3, "hash": "crc32:cbf43926"},
+			"title_music": {"path": "/audio/title.ogg"}
+		}}"#).unwrap();
+		assert!(manifest.len() == 2);
+		assert!(manifest.get("hero_mesh") == Some(&ManifestEntry {
+			path: "/data/hero.mesh".to_string(),
+			version: 3,
+			hash: Some(FileHash::Crc32(0xCBF4_3926))
+		}));
+		assert!(manifest.path("title_music") == Some("/audio/title.ogg"));
+		assert!(manifest.get("title_music").unwrap().version == 0);
+
+		assert!(Manifest::from_json(br#"{"assets": {"a": {"path": "/a", "hash": "md5:00"}}}"#).is_err());
+	}
+}
@@ -0,0 +1,151 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// Reads that map the file instead of copying it, so big read-mostly packs are shared
+// through the page cache. Only files a device stores as they are can be mapped (see
+// Device::raw_path); anything else is read into memory like any other read.
+
+use crate::device;
+use crate::{LaminaFS, OperationKind, ResultCode, Task};
+
+use memmap2::Mmap;
+
+use std::fs::File;
+use std::ops::Range;
+use std::sync::Arc;
+
+enum MappedData {
+	Mapped(Arc<Mmap>),
+	Owned(Vec<u8>)
+}
+
+// A view into a mapped file, or the file's data if it couldn't be mapped. The mapping is
+// kept alive by every buffer viewing it and unmapped once the last one is dropped.
+pub struct MappedBuffer {
+	data: MappedData,
+	range: Range<usize>
+}
+
+impl MappedBuffer {
+	pub fn is_mapped(&self) -> bool {
+		match self.data {
+			MappedData::Mapped(_) => true,
+			MappedData::Owned(_) => false
+		}
+	}
+
+	// A view into part of this buffer sharing its mapping, clamped to the buffer.
+	pub fn slice(&self, offset: usize, len: usize) -> MappedBuffer {
+		let start = std::cmp::min(self.range.start + offset, self.range.end);
+		let end = std::cmp::min(start.saturating_add(len), self.range.end);
+		MappedBuffer {
+			data: match self.data {
+				MappedData::Mapped(ref map) => MappedData::Mapped(map.clone()),
+				MappedData::Owned(ref data) => MappedData::Owned(data[start..end].to_vec())
+			},
+			range: match self.data {
+				MappedData::Mapped(_) => start..end,
+				MappedData::Owned(_) => 0..end - start
+			}
+		}
+	}
+}
+
+impl std::ops::Deref for MappedBuffer {
+	type Target = [u8];
+
+	fn deref(&self) -> &[u8] {
+		match self.data {
+			MappedData::Mapped(ref map) => &map[self.range.clone()],
+			MappedData::Owned(ref data) => &data[self.range.clone()]
+		}
+	}
+}
+
+fn map(path: &std::path::Path, offset: u64, max_bytes: u64) -> Result<MappedBuffer, ResultCode> {
+	let file = File::open(path).map_err(device::from_io_error)?;
+	let len = file.metadata().map_err(device::from_io_error)?.len();
+	let start = std::cmp::min(offset, len) as usize;
+	let end = std::cmp::min(offset.saturating_add(max_bytes), len) as usize;
+
+	// empty files can't be mapped
+	if len == 0 {
+		return Ok(MappedBuffer {
+			data: MappedData::Owned(Vec::new()),
+			range: 0..0
+		});
+	}
+
+	// the mapping is read-only, but changes to the file made elsewhere show through it
+	let map = unsafe { Mmap::map(&file) }.map_err(device::from_io_error)?;
+	Ok(MappedBuffer {
+		data: MappedData::Mapped(Arc::new(map)),
+		range: start..end
+	})
+}
+
+impl LaminaFS {
+	pub fn map_file(&self, path: &str) -> Task<MappedBuffer> {
+		self.map_file_segment(path, 0, u64::max_value())
+	}
+
+	// Maps up to max_bytes of the file starting at offset. The whole file is mapped, so
+	// slices of the result can reach past the segment without another mapping.
+	pub fn map_file_segment(&self, path: &str, offset: u64, max_bytes: u64) -> Task<MappedBuffer> {
+		let mounts = self.mounts.clone();
+		let path_owned = self.virtual_path(path, false);
+		self.tasks.spawn(OperationKind::MapFile, path, move || {
+			let path_owned = path_owned.map_err(|error| error.code())?;
+			let (device, relative_path, _) = mounts.readable_file(&path_owned)?;
+			match device.raw_path(&relative_path) {
+				Some(raw_path) => map(&raw_path, offset, max_bytes),
+				None => device.read_file(&relative_path, offset, max_bytes).map(|data| MappedBuffer {
+					range: 0..data.len(),
+					data: MappedData::Owned(data)
+				})
+			}
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn mapped_buffer_test() {
+		let buffer = map(std::path::Path::new("Cargo.toml"), 1, 9).unwrap();
+		assert!(buffer.is_mapped());
+		assert!(&*buffer == b"package]\n" || &*buffer == b"package]\r");
+
+		let slice = buffer.slice(0, 7);
+		assert!(&*slice == b"package");
+		assert!(buffer.slice(100, 7).is_empty());
+
+		let owned = MappedBuffer {
+			data: MappedData::Owned(b"0123456789".to_vec()),
+			range: 0..10
+		};
+		assert!(&*owned.slice(8, 7) == b"89");
+	}
+}
@@ -0,0 +1,578 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// Rust-side record of the mounts created through a LaminaFS, used for operations the C
+// interface has no entry point for. The C context remains the authority for the
+// operations it does implement.
+
+use crate::case_index::CaseIndex;
+use crate::device::{self, CreatedDevice, Device, DirEntry};
+use crate::laminafs_sys;
+use crate::quota::Quota;
+use crate::retry::RetryPolicy;
+use crate::throttle::Throttle;
+use crate::{LaminaFS, LfsError, Mount, MountPermissions, OperationKind, ResultCode};
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+
+// The device type of the built-in Directory device, the first one the context registers.
+const DIRECTORY_DEVICE_TYPE: u32 = 0;
+
+// A device type mounts can be created with.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum DeviceType {
+	Directory,
+	// .zip archives, served by device::ZipDevice
+	Zip,
+	Registered(RegisteredDevice)
+}
+
+impl DeviceType {
+	// The context's id for the device type, None for Zip, which each context only
+	// registers the first time a mount asks for it.
+	pub fn id(self) -> Option<u32> {
+		match self {
+			DeviceType::Directory => Some(DIRECTORY_DEVICE_TYPE),
+			DeviceType::Zip => None,
+			DeviceType::Registered(device) => Some(device.id)
+		}
+	}
+}
+
+impl From<RegisteredDevice> for DeviceType {
+	fn from(device: RegisteredDevice) -> DeviceType {
+		DeviceType::Registered(device)
+	}
+}
+
+// A device interface registered with LaminaFS::register_device, only valid for the
+// context it was registered with.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct RegisteredDevice {
+	id: u32
+}
+
+impl RegisteredDevice {
+	pub(crate) fn new(id: u32) -> RegisteredDevice {
+		RegisteredDevice {
+			id: id
+		}
+	}
+
+	pub fn id(self) -> u32 {
+		self.id
+	}
+}
+
+// Internal struct used for assuring Rust that mounts are Send+Sync
+pub(crate) struct MountPtr {
+	pub(crate) ptr: laminafs_sys::lfs_mount_t
+}
+
+unsafe impl Send for MountPtr {}
+unsafe impl Sync for MountPtr {}
+
+#[derive(Clone, Copy)]
+struct MountOrder {
+	priority: i32,
+	sequence: u64
+}
+
+// A snapshot of an active mount, for debugging and diagnostics.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct MountInfo {
+	pub mount_point: String,
+	pub device_type: DeviceType,
+	pub device_path: String,
+	pub permissions: MountPermissions,
+	pub priority: i32
+}
+
+// Which mount serves a path, and where the file is backed on disk if the device knows.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ResolvedPath {
+	pub mount: MountInfo,
+	pub relative_path: String,
+	pub backing_path: Option<PathBuf>
+}
+
+pub(crate) struct MountEntry {
+	pub(crate) mount_point: String,
+	pub(crate) device_type: DeviceType,
+	pub(crate) device_path: String,
+	pub(crate) permissions: MountPermissions,
+	pub(crate) device: Option<CreatedDevice>,
+	// the context's mount, None once released
+	pub(crate) handle: Mutex<Option<MountPtr>>,
+	// Some for mounts resolving paths case-insensitively
+	pub(crate) case_index: RwLock<Option<CaseIndex>>,
+	// Some for mounts with a quota
+	quota: Mutex<Option<Arc<Quota>>>,
+	// Some for mounts with a bandwidth limit
+	throttle: Mutex<Option<Arc<Throttle>>>,
+	// Some for mounts with a retry policy of their own rather than the context's
+	retry: Mutex<Option<RetryPolicy>>,
+	// set to reject paths that aren't plain rather than normalize them
+	strict_paths: AtomicBool,
+	order: Mutex<MountOrder>
+}
+
+impl MountEntry {
+	pub(crate) fn new(mount_point: &str, device_type: DeviceType, device_path: &str, permissions: MountPermissions, device: Option<CreatedDevice>, handle: Option<MountPtr>) -> MountEntry {
+		MountEntry {
+			mount_point: mount_point.to_string(),
+			device_type: device_type,
+			device_path: device_path.to_string(),
+			permissions: permissions,
+			device: device,
+			handle: Mutex::new(handle),
+			case_index: RwLock::new(None),
+			quota: Mutex::new(None),
+			throttle: Mutex::new(None),
+			retry: Mutex::new(None),
+			strict_paths: AtomicBool::new(false),
+			order: Mutex::new(MountOrder {
+				priority: 0,
+				sequence: 0
+			})
+		}
+	}
+
+	pub(crate) fn priority(&self) -> i32 {
+		self.order.lock().unwrap().priority
+	}
+
+	pub(crate) fn set_priority(&self, priority: i32) {
+		self.order.lock().unwrap().priority = priority;
+	}
+
+	pub(crate) fn set_case_index(&self, case_index: Option<CaseIndex>) {
+		*self.case_index.write().unwrap() = case_index;
+	}
+
+	pub(crate) fn is_case_insensitive(&self) -> bool {
+		self.case_index.read().unwrap().is_some()
+	}
+
+	pub(crate) fn set_quota(&self, quota: Option<Arc<Quota>>) {
+		*self.quota.lock().unwrap() = quota;
+	}
+
+	pub(crate) fn quota(&self) -> Option<Arc<Quota>> {
+		self.quota.lock().unwrap().clone()
+	}
+
+	pub(crate) fn set_throttle(&self, throttle: Option<Arc<Throttle>>) {
+		*self.throttle.lock().unwrap() = throttle;
+	}
+
+	pub(crate) fn throttle(&self) -> Option<Arc<Throttle>> {
+		self.throttle.lock().unwrap().clone()
+	}
+
+	pub(crate) fn set_retry_policy(&self, policy: Option<RetryPolicy>) {
+		*self.retry.lock().unwrap() = policy;
+	}
+
+	pub(crate) fn retry_policy(&self) -> Option<RetryPolicy> {
+		*self.retry.lock().unwrap()
+	}
+
+	// Hands the device the policy to retry with, the mount's own or else `default`.
+	// Directory mounts never retry, as the context does their IO without the Rust side.
+	pub(crate) fn apply_retry_policy(&self, default: Option<RetryPolicy>) {
+		if let Some(ref device) = self.device {
+			*device.retry.lock().unwrap() = match self.device_type {
+				DeviceType::Directory => None,
+				DeviceType::Zip | DeviceType::Registered(_) => self.retry_policy().or(default)
+			};
+		}
+	}
+
+	pub(crate) fn set_strict_paths(&self, strict: bool) {
+		self.strict_paths.store(strict, Ordering::Relaxed);
+	}
+
+	pub(crate) fn strict_paths(&self) -> bool {
+		self.strict_paths.load(Ordering::Relaxed)
+	}
+
+	pub(crate) fn info(&self) -> MountInfo {
+		MountInfo {
+			mount_point: self.mount_point.clone(),
+			device_type: self.device_type,
+			device_path: self.device_path.clone(),
+			permissions: self.permissions,
+			priority: self.priority()
+		}
+	}
+
+	// The path relative to this mount, or None if the mount doesn't cover it.
+	pub(crate) fn relative_path<'a>(&self, path: &'a str) -> Option<&'a str> {
+		let path = path.trim_matches('/');
+		let mount_point = self.mount_point.trim_matches('/');
+		if mount_point.is_empty() {
+			return Some(path);
+		}
+
+		if !path.starts_with(mount_point) {
+			return None;
+		}
+		match &path[mount_point.len()..] {
+			"" => Some(""),
+			rest if rest.starts_with('/') => Some(&rest[1..]),
+			_ => None
+		}
+	}
+}
+
+pub(crate) struct MountTable {
+	mounts: Mutex<Vec<Weak<MountEntry>>>,
+	next_sequence: AtomicU64,
+	// see LaminaFS::set_read_only
+	read_only: AtomicBool,
+	pub(crate) reorder_lock: Mutex<()>
+}
+
+impl MountTable {
+	pub(crate) fn new() -> MountTable {
+		MountTable {
+			mounts: Mutex::new(Vec::new()),
+			next_sequence: AtomicU64::new(0),
+			read_only: AtomicBool::new(false),
+			reorder_lock: Mutex::new(())
+		}
+	}
+
+	pub(crate) fn set_read_only(&self, read_only: bool) {
+		self.read_only.store(read_only, Ordering::Relaxed);
+	}
+
+	pub(crate) fn is_read_only(&self) -> bool {
+		self.read_only.load(Ordering::Relaxed)
+	}
+
+	// Fails anything other than reading while the context is read-only, whatever the
+	// mounts allow.
+	pub(crate) fn check_read_only(&self, permission: MountPermissions) -> Result<(), ResultCode> {
+		if self.is_read_only() && permission != MountPermissions::Read {
+			Err(ResultCode::PermissionsError)
+		} else {
+			Ok(())
+		}
+	}
+
+	pub(crate) fn add(&self, mount: &Arc<MountEntry>) {
+		mount.order.lock().unwrap().sequence = self.next_sequence.fetch_add(1, Ordering::Relaxed);
+
+		let mut mounts = self.mounts.lock().unwrap();
+		mounts.retain(|mount| mount.strong_count() > 0);
+		mounts.push(Arc::downgrade(mount));
+	}
+
+	// The live mounts in resolution order: highest priority first, then most recently
+	// created first, which is the order the context searches its mounts in.
+	pub(crate) fn ordered(&self) -> Vec<Arc<MountEntry>> {
+		let mut mounts: Vec<_> = self.mounts.lock().unwrap().iter().filter_map(|mount| mount.upgrade()).collect();
+		mounts.sort_by_key(|mount| {
+			let order = *mount.order.lock().unwrap();
+			(std::cmp::Reverse(order.priority), std::cmp::Reverse(order.sequence))
+		});
+		mounts
+	}
+
+	// The live mounts covering `path` in resolution order, along with the path relative
+	// to each.
+	pub(crate) fn resolve(&self, path: &str) -> Vec<(Arc<MountEntry>, String)> {
+		self.ordered().into_iter()
+			.filter_map(|mount| {
+				let relative = mount.relative_path(path)?.to_string();
+				Some((mount, relative))
+			})
+			.collect()
+	}
+
+	// Whether any mount covering `path` has strict paths.
+	pub(crate) fn is_strict(&self, path: &str) -> bool {
+		self.resolve(path).iter().any(|(mount, _)| mount.strict_paths())
+	}
+
+	// The mount point of the first mount covering `path`, "" if none.
+	pub(crate) fn mount_point(&self, path: &str) -> String {
+		self.resolve(path).into_iter().next().map(|(mount, _)| mount.mount_point.clone()).unwrap_or_default()
+	}
+
+	// The first mount whose device has something at `path`. Mounts on devices the Rust
+	// side can't reach are skipped.
+	pub(crate) fn resolve_path(&self, path: &str) -> Result<ResolvedPath, ResultCode> {
+		for (mount, relative_path) in self.resolve(path) {
+			let device = match mount.device {
+				Some(ref device) => &device.device,
+				None => continue
+			};
+
+			if device.file_exists(&relative_path) {
+				return Ok(ResolvedPath {
+					mount: mount.info(),
+					backing_path: device.backing_path(&relative_path),
+					relative_path: relative_path
+				});
+			}
+		}
+		Err(ResultCode::NotFound)
+	}
+
+	// Devices of the mounts covering `path` that the Rust side can reach and read from,
+	// along with the path relative to each.
+	fn readable_devices(&self, path: &str) -> Vec<(Arc<dyn Device>, String)> {
+		self.resolve(path).into_iter()
+			.filter(|(mount, _)| mount.permissions.contains(MountPermissions::Read))
+			.filter_map(|(mount, relative_path)| Some((mount.device.as_ref()?.device.clone(), relative_path)))
+			.collect()
+	}
+
+	// Runs `op` against the first mount that has something at `path`, the same way the
+	// context resolves paths for the operations it implements.
+	pub(crate) fn query<T, F>(&self, path: &str, op: F) -> Result<T, ResultCode>
+		where F: Fn(&dyn Device, &str) -> Result<T, ResultCode> {
+		let mut error = ResultCode::NotFound;
+		for (device, relative_path) in self.readable_devices(path) {
+			match op(device.as_ref(), &relative_path) {
+				Err(ResultCode::NotFound) => {},
+				Err(ResultCode::Unsupported) => error = ResultCode::Unsupported,
+				result => return result
+			}
+		}
+		Err(error)
+	}
+
+	// The device of the first mount with a file at `path` that the Rust side can read,
+	// along with the path relative to it and the file's size.
+	pub(crate) fn readable_file(&self, path: &str) -> Result<(Arc<dyn Device>, String, u64), ResultCode> {
+		let mut error = ResultCode::NotFound;
+		for (device, relative_path) in self.readable_devices(path) {
+			match device.file_size(&relative_path) {
+				Ok(size) => return Ok((device, relative_path, size)),
+				Err(ResultCode::NotFound) => {},
+				Err(code) => error = code
+			}
+		}
+		Err(error)
+	}
+
+	// The device of the first mount covering `path` that allows `permission`, the mount
+	// the context would modify the path on.
+	pub(crate) fn writable_device(&self, path: &str, permission: MountPermissions) -> Result<(Arc<dyn Device>, String), ResultCode> {
+		self.check_read_only(permission)?;
+		let mut error = ResultCode::NotFound;
+		for (mount, relative_path) in self.resolve(path) {
+			if !mount.permissions.contains(permission) {
+				error = ResultCode::PermissionsError;
+				continue;
+			}
+			if let Some(ref device) = mount.device {
+				return Ok((device.device.clone(), relative_path));
+			}
+		}
+		Err(error)
+	}
+
+	// Whether some mount covering `path` allows `permission`, without touching its device.
+	// Mounts the Rust side can't reach count too, as the context checks them the same way.
+	pub(crate) fn check_permission(&self, path: &str, permission: MountPermissions) -> Result<(), ResultCode> {
+		self.check_read_only(permission)?;
+		let mounts = self.resolve(path);
+		if mounts.is_empty() {
+			Err(ResultCode::NotFound)
+		} else if mounts.iter().any(|(mount, _)| mount.permissions.contains(permission)) {
+			Ok(())
+		} else {
+			Err(ResultCode::PermissionsError)
+		}
+	}
+
+	// Lists a directory across every mount covering it. Mounts whose device can't list
+	// directories are skipped; entries from more recent mounts shadow older ones.
+	pub(crate) fn list_dir(&self, path: &str) -> Result<Vec<DirEntry>, ResultCode> {
+		let mut listings = Vec::new();
+		let mut error = ResultCode::NotFound;
+		for (device, relative_path) in self.readable_devices(path) {
+			match device.list_dir(&relative_path) {
+				Ok(entries) => listings.push(entries),
+				Err(ResultCode::NotFound) => {},
+				Err(ResultCode::Unsupported) => error = ResultCode::Unsupported,
+				Err(code) => return Err(code)
+			}
+		}
+
+		if listings.is_empty() {
+			Err(error)
+		} else {
+			Ok(device::merge_listings(listings))
+		}
+	}
+}
+
+// What Mount::unmount does with work on paths the mount covers.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UnmountMode {
+	// wait for all of it, queued or in flight
+	Wait,
+	// cancel what is still queued and wait for what is in flight
+	Cancel
+}
+
+// What a MountBuilder sets up on a mount besides its device, in place before anyone
+// can see the mount.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct MountOptions {
+	pub(crate) priority: i32,
+	pub(crate) case_insensitive: bool,
+	pub(crate) max_bytes: Option<u64>,
+	pub(crate) bandwidth_limit: Option<u64>,
+	pub(crate) retry_policy: Option<RetryPolicy>,
+	pub(crate) strict_paths: bool
+}
+
+// Fluent construction of a mount, e.g.
+// fs.mount("/assets").path("./data").permissions(MountPermissions::Read).priority(10).build()
+// The device type defaults to Directory and the permissions to MountPermissions::Default.
+pub struct MountBuilder<'a> {
+	fs: &'a Arc<LaminaFS>,
+	mount_point: String,
+	device_type: DeviceType,
+	device_path: String,
+	permissions: MountPermissions,
+	options: MountOptions
+}
+
+impl<'a> MountBuilder<'a> {
+	pub fn device<T: Into<DeviceType>>(mut self, device_type: T) -> MountBuilder<'a> {
+		self.device_type = device_type.into();
+		self
+	}
+
+	pub fn path(mut self, device_path: &str) -> MountBuilder<'a> {
+		self.device_path = device_path.to_string();
+		self
+	}
+
+	pub fn permissions(mut self, permissions: MountPermissions) -> MountBuilder<'a> {
+		self.permissions = permissions;
+		self
+	}
+
+	pub fn priority(mut self, priority: i32) -> MountBuilder<'a> {
+		self.options.priority = priority;
+		self
+	}
+
+	// Resolves paths on the mount case-insensitively, going by an index of the device's
+	// contents built when the mount is created. Needs a device the Rust side can list.
+	pub fn case_insensitive(mut self, case_insensitive: bool) -> MountBuilder<'a> {
+		self.options.case_insensitive = case_insensitive;
+		self
+	}
+
+	// Caps the bytes stored on the mount, failing writes that would go over it with
+	// OutOfSpace. Needs a device the Rust side can list, to count what it already holds.
+	pub fn max_bytes(mut self, max_bytes: u64) -> MountBuilder<'a> {
+		self.options.max_bytes = Some(max_bytes);
+		self
+	}
+
+	// See Mount::set_bandwidth_limit.
+	pub fn bandwidth_limit(mut self, bytes_per_sec: u64) -> MountBuilder<'a> {
+		self.options.bandwidth_limit = Some(bytes_per_sec);
+		self
+	}
+
+	// See Mount::set_retry_policy.
+	pub fn retry_policy(mut self, policy: RetryPolicy) -> MountBuilder<'a> {
+		self.options.retry_policy = Some(policy);
+		self
+	}
+
+	// See Mount::set_strict_paths.
+	pub fn strict_paths(mut self, strict: bool) -> MountBuilder<'a> {
+		self.options.strict_paths = strict;
+		self
+	}
+
+	pub fn build(self) -> Result<Mount, LfsError> {
+		if self.options.retry_policy.is_some() && self.device_type == DeviceType::Directory {
+			return Err(LfsError::new(ResultCode::Unsupported, OperationKind::CreateMount, &self.mount_point));
+		}
+		self.fs.create_mount_with_options(self.device_type, &self.mount_point, &self.device_path, self.permissions, self.options)
+	}
+}
+
+impl LaminaFS {
+	pub fn mount(self: &Arc<Self>, mount_point: &str) -> MountBuilder<'_> {
+		MountBuilder {
+			fs: self,
+			mount_point: mount_point.to_string(),
+			device_type: DeviceType::Directory,
+			device_path: String::new(),
+			permissions: MountPermissions::Default,
+			options: MountOptions::default()
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn relative_path_test() {
+		let mount = MountEntry::new("/data/", DeviceType::Directory, "./data", MountPermissions::Default, None, None);
+
+		assert!(mount.relative_path("/data/levels/1.bin") == Some("levels/1.bin"));
+		assert!(mount.relative_path("/data") == Some(""));
+		assert!(mount.relative_path("/database/x") == None);
+		assert!(mount.relative_path("/other") == None);
+	}
+
+	#[test]
+	fn mount_order_test() {
+		let table = MountTable::new();
+		let base = Arc::new(MountEntry::new("/", DeviceType::Directory, "./base", MountPermissions::Default, None, None));
+		let patch = Arc::new(MountEntry::new("/", DeviceType::Directory, "./patch", MountPermissions::Default, None, None));
+		let mods = Arc::new(MountEntry::new("/", DeviceType::Directory, "./mods", MountPermissions::Default, None, None));
+		table.add(&mods);
+		table.add(&base);
+		table.add(&patch);
+
+		let order = |table: &MountTable| table.ordered().iter().map(|mount| mount.device_path.clone()).collect::<Vec<_>>();
+		assert!(order(&table) == ["./patch", "./base", "./mods"]);
+
+		mods.set_priority(1);
+		assert!(order(&table) == ["./mods", "./patch", "./base"]);
+
+		drop(patch);
+		assert!(order(&table) == ["./mods", "./base"]);
+	}
+}
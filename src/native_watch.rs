@@ -0,0 +1,130 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+// OS change notification for watches over Directory mounts. The OS reports which paths
+// changed (inotify, FSEvents or kqueue, ReadDirectoryChangesW) and only those are looked
+// at again, rather than everything the pattern covers being rescanned.
+
+use crate::device::DiskDevice;
+use crate::mount::{DeviceType, MountTable};
+
+use notify_rs::{Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+pub(crate) struct NativeWatch {
+	// stops watching once dropped
+	_watcher: RecommendedWatcher,
+	events: Receiver<notify_rs::Result<Event>>,
+	// the directory backing the root of each watched mount, along with its mount point
+	roots: Vec<(PathBuf, String)>,
+	base: String
+}
+
+impl NativeWatch {
+	// Watches the directories backing `base` on every mount covering it. None if one of
+	// those mounts isn't a Directory mount or the OS won't watch it, so it has to be polled.
+	pub(crate) fn new(mounts: &MountTable, base: &str) -> Option<NativeWatch> {
+		let (sender, events) = channel();
+		let mut watcher = RecommendedWatcher::new(sender, Config::default()).ok()?;
+		let mut roots = Vec::new();
+		for (mount, relative_path) in mounts.resolve(base) {
+			if mount.device_type != DeviceType::Directory {
+				return None;
+			}
+			let disk = mount.device.as_ref()?.instance::<DiskDevice>()?;
+			let root = disk.resolve("").ok()?;
+			// a mount without the base directory yet is watched from its root, to see it appear
+			let dir = disk.resolve(&relative_path).ok()?;
+			let watched = if dir.is_dir() { dir } else { root.clone() };
+			watcher.watch(&watched, RecursiveMode::Recursive).ok()?;
+			roots.push((root, mount.mount_point.clone()));
+		}
+
+		Some(NativeWatch {
+			_watcher: watcher,
+			events: events,
+			roots: roots,
+			base: base.to_string()
+		})
+	}
+
+	// Waits up to `timeout` for a change, then keeps gathering changes for `window` so a
+	// burst of them is reported once. The paths that changed, empty if nothing did; None
+	// once the OS has stopped reporting changes.
+	pub(crate) fn wait(&self, timeout: Duration, window: Duration) -> Option<HashSet<String>> {
+		let mut changed = HashSet::new();
+		match self.events.recv_timeout(timeout) {
+			Ok(event) => self.add(event, &mut changed),
+			Err(RecvTimeoutError::Timeout) => return Some(changed),
+			Err(RecvTimeoutError::Disconnected) => return None
+		}
+
+		let deadline = Instant::now() + window;
+		loop {
+			let now = Instant::now();
+			if now >= deadline {
+				return Some(changed);
+			}
+			match self.events.recv_timeout(deadline - now) {
+				Ok(event) => self.add(event, &mut changed),
+				Err(RecvTimeoutError::Timeout) => return Some(changed),
+				Err(RecvTimeoutError::Disconnected) => return None
+			}
+		}
+	}
+
+	fn add(&self, event: notify_rs::Result<Event>, changed: &mut HashSet<String>) {
+		match event {
+			Ok(ref event) if event.need_rescan() => {
+				changed.insert(self.base.clone());
+			},
+			Ok(Event { kind: EventKind::Access(_), .. }) => {},
+			Ok(event) => changed.extend(event.paths.iter().filter_map(|path| self.virtual_path(path))),
+			// events were lost, so everything may have changed
+			Err(_) => {
+				changed.insert(self.base.clone());
+			}
+		}
+	}
+
+	// The path in the file system of a path the OS reported.
+	fn virtual_path(&self, path: &Path) -> Option<String> {
+		self.roots.iter().find_map(|(root, mount_point)| {
+			let relative = path.strip_prefix(root).ok()?;
+			let mut virtual_path = mount_point.trim_end_matches('/').to_string();
+			for component in relative.components() {
+				virtual_path.push('/');
+				virtual_path.push_str(component.as_os_str().to_str()?);
+			}
+			if virtual_path.is_empty() {
+				virtual_path.push('/');
+			}
+			Some(virtual_path)
+		})
+	}
+}
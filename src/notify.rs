@@ -0,0 +1,168 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// Completion notification over channels: instead of handing back a work handle, the work
+// is sent to a channel once it completes, so a loader thread can just recv() it.
+
+use crate::queue::CompletionCallback;
+use crate::{LaminaFS, WorkHandle};
+
+use std::sync::{mpsc, Arc, Mutex};
+
+// A finished work item, as delivered to a notification channel.
+pub struct CompletedWork {
+	work: WorkHandle
+}
+
+impl CompletedWork {
+	pub fn into_work_handle(self) -> WorkHandle {
+		self.work
+	}
+}
+
+impl std::ops::Deref for CompletedWork {
+	type Target = WorkHandle;
+
+	fn deref(&self) -> &WorkHandle {
+		&self.work
+	}
+}
+
+impl std::ops::DerefMut for CompletedWork {
+	fn deref_mut(&mut self) -> &mut WorkHandle {
+		&mut self.work
+	}
+}
+
+// Channel senders completed work can be delivered through.
+pub trait NotifySender: Send + 'static {
+	// Gives the work back if the receiving end is gone.
+	fn notify(&self, work: CompletedWork) -> Result<(), CompletedWork>;
+}
+
+impl NotifySender for mpsc::Sender<CompletedWork> {
+	fn notify(&self, work: CompletedWork) -> Result<(), CompletedWork> {
+		self.send(work).map_err(|error| error.0)
+	}
+}
+
+impl NotifySender for mpsc::SyncSender<CompletedWork> {
+	fn notify(&self, work: CompletedWork) -> Result<(), CompletedWork> {
+		self.send(work).map_err(|error| error.0)
+	}
+}
+
+#[cfg(feature = "crossbeam")]
+impl NotifySender for crossbeam_channel::Sender<CompletedWork> {
+	fn notify(&self, work: CompletedWork) -> Result<(), CompletedWork> {
+		self.send(work).map_err(|error| error.0)
+	}
+}
+
+// The completion callback can fire before the submitting thread has the handle, so
+// whichever of the two comes second sends it.
+struct NotifySlot<S> {
+	sender: Option<S>,
+	work: Option<WorkHandle>,
+	completed: bool
+}
+
+fn send<S: NotifySender>(sender: S, work: WorkHandle, on_worker: bool) {
+	if let Err(work) = sender.notify(CompletedWork { work: work }) {
+		if on_worker {
+			// dropping a handle waits for the C side to be done with the item, which it
+			// can't be while its completion callback is still running
+			std::thread::spawn(move || drop(work));
+		}
+	}
+}
+
+impl LaminaFS {
+	pub fn read_file_notify<S: NotifySender>(&self, path: &str, null_terminate: bool, sender: S) {
+		self.submit_notify(sender, |callback| self.submit_read_file(path, null_terminate, Default::default(), Some(callback)));
+	}
+
+	pub fn read_file_segment_notify<S: NotifySender>(&self, path: &str, offset: u64, max_bytes: u64, null_terminate: bool, sender: S) {
+		self.submit_notify(sender, |callback| self.submit_read_file_segment(path, offset, max_bytes, null_terminate, Default::default(), Some(callback)));
+	}
+
+	fn submit_notify<S, F>(&self, sender: S, submit: F)
+		where S: NotifySender, F: FnOnce(CompletionCallback) -> WorkHandle {
+		let slot = Arc::new(Mutex::new(NotifySlot {
+			sender: Some(sender),
+			work: None,
+			completed: false
+		}));
+
+		let callback_slot = slot.clone();
+		let work = submit(Box::new(move |_| {
+			let mut slot = callback_slot.lock().unwrap();
+			slot.completed = true;
+			if let (Some(sender), Some(work)) = (slot.sender.take(), slot.work.take()) {
+				send(sender, work, true);
+			}
+		}));
+
+		let mut slot = slot.lock().unwrap();
+		if slot.completed {
+			if let Some(sender) = slot.sender.take() {
+				send(sender, work, false);
+			}
+		} else {
+			slot.work = Some(work);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_dir::TestDir;
+	use crate::ResultCode;
+
+	#[test]
+	fn notify_test() {
+		let root = TestDir::new("notify_test");
+		std::fs::write(root.join("level.bin"), b"level data").unwrap();
+		let fs = LaminaFS::new();
+		let _mount = root.mount(&fs);
+
+		let (sender, receiver) = mpsc::channel();
+		fs.read_file_notify("/level.bin", false, sender.clone());
+		fs.read_file_segment_notify("/level.bin", 6, 4, false, sender.clone());
+		fs.read_file_notify("/missing.bin", false, sender);
+
+		let mut completed: Vec<_> = receiver.iter().map(|work| (work.get_result(), work.get_buffer().to_vec())).collect();
+		completed.sort_by(|a, b| a.1.cmp(&b.1));
+		assert!(completed == vec![
+			(ResultCode::NotFound, Vec::new()),
+			(ResultCode::Ok, b"data".to_vec()),
+			(ResultCode::Ok, b"level data".to_vec())
+		]);
+
+		// a receiver that's gone doesn't hold the work up
+		let (sender, receiver) = mpsc::sync_channel(1);
+		drop(receiver);
+		fs.read_file_notify("/level.bin", false, sender);
+	}
+}
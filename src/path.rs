@@ -0,0 +1,299 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+use crate::LfsError;
+
+use std::borrow::Borrow;
+use std::convert::TryFrom;
+use std::ffi::CString;
+use std::fmt;
+use std::ops::Deref;
+
+// Backslashes are separators to some devices, so they're treated as such everywhere.
+fn split_components(path: &str) -> impl Iterator<Item = &str> {
+	path.split(|c| c == '/' || c == '\\')
+}
+
+// e.g. "C:", which some devices take for a drive rather than a name
+fn is_drive_prefix(component: &str) -> bool {
+	let bytes = component.as_bytes();
+	bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+}
+
+fn validate(path: &str) -> Result<(), LfsError> {
+	if !path.starts_with('/') || path.contains('\0') {
+		return Err(LfsError::invalid_path(path));
+	}
+
+	// ".." is fine as long as it never climbs above the root
+	let mut depth = 0usize;
+	for component in split_components(path).filter(|component| !component.is_empty() && *component != ".") {
+		if is_drive_prefix(component) {
+			return Err(LfsError::invalid_path(path));
+		}
+		if component == ".." {
+			depth = depth.checked_sub(1).ok_or_else(|| LfsError::invalid_path(path))?;
+		} else {
+			depth += 1;
+		}
+	}
+	Ok(())
+}
+
+// A virtual path that is known to be absolute, free of NUL bytes and unable to escape
+// the root. Derefs to str, so it can be passed anywhere a path is taken.
+#[repr(transparent)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct LfsPath {
+	inner: str
+}
+
+impl LfsPath {
+	pub fn new(path: &str) -> Result<&LfsPath, LfsError> {
+		validate(path)?;
+		Ok(LfsPath::from_str_unchecked(path))
+	}
+
+	fn from_str_unchecked(path: &str) -> &LfsPath {
+		unsafe { &*(path as *const str as *const LfsPath) }
+	}
+
+	pub fn as_str(&self) -> &str {
+		&self.inner
+	}
+
+	pub fn parent(&self) -> Option<&LfsPath> {
+		let trimmed = self.inner.trim_end_matches('/');
+		match trimmed.rfind('/') {
+			Some(0) if trimmed.len() > 1 => Some(LfsPath::from_str_unchecked("/")),
+			Some(index) if index > 0 => Some(LfsPath::from_str_unchecked(&trimmed[..index])),
+			_ => None
+		}
+	}
+
+	pub fn file_name(&self) -> Option<&str> {
+		self.inner.trim_end_matches('/').rsplit('/').next().filter(|name| !name.is_empty())
+	}
+
+	// The path with "." and ".." resolved and repeated slashes collapsed, e.g.
+	// "/mods/../save.dat" becomes "/save.dat". Backslashes become slashes and a trailing
+	// slash is kept.
+	pub fn normalized(&self) -> LfsPathBuf {
+		let mut components: Vec<&str> = Vec::new();
+		for component in split_components(&self.inner) {
+			match component {
+				"" | "." => {},
+				".." => {
+					components.pop();
+				},
+				_ => components.push(component)
+			}
+		}
+
+		let mut normalized = format!("/{}", components.join("/"));
+		if self.inner.ends_with(|c| c == '/' || c == '\\') && !components.is_empty() {
+			normalized.push('/');
+		}
+		LfsPathBuf {
+			inner: normalized
+		}
+	}
+
+	// Whether the path is already normalized and free of backslashes and colons, which
+	// some devices take for separators or drive prefixes.
+	pub fn is_plain(&self) -> bool {
+		self.normalized().as_str() == &self.inner && !self.inner.contains('\\') && !self.inner.contains(':')
+	}
+
+	pub fn join(&self, path: &str) -> Result<LfsPathBuf, LfsError> {
+		let mut joined = self.to_path_buf();
+		joined.push(path)?;
+		Ok(joined)
+	}
+
+	pub fn to_path_buf(&self) -> LfsPathBuf {
+		LfsPathBuf {
+			inner: self.inner.to_string()
+		}
+	}
+
+	pub(crate) fn to_c_string(&self) -> CString {
+		CString::new(&self.inner).unwrap()
+	}
+}
+
+impl Deref for LfsPath {
+	type Target = str;
+
+	fn deref(&self) -> &str {
+		&self.inner
+	}
+}
+
+impl AsRef<str> for LfsPath {
+	fn as_ref(&self) -> &str {
+		&self.inner
+	}
+}
+
+impl ToOwned for LfsPath {
+	type Owned = LfsPathBuf;
+
+	fn to_owned(&self) -> LfsPathBuf {
+		self.to_path_buf()
+	}
+}
+
+impl fmt::Display for LfsPath {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(&self.inner)
+	}
+}
+
+impl<'a> TryFrom<&'a str> for &'a LfsPath {
+	type Error = LfsError;
+
+	fn try_from(path: &'a str) -> Result<&'a LfsPath, LfsError> {
+		LfsPath::new(path)
+	}
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct LfsPathBuf {
+	inner: String
+}
+
+impl LfsPathBuf {
+	pub fn new<S: Into<String>>(path: S) -> Result<LfsPathBuf, LfsError> {
+		let path = path.into();
+		validate(&path)?;
+		Ok(LfsPathBuf {
+			inner: path
+		})
+	}
+
+	pub fn as_path(&self) -> &LfsPath {
+		LfsPath::from_str_unchecked(&self.inner)
+	}
+
+	// Appends a relative path, leaving self untouched if the result would be invalid.
+	pub fn push(&mut self, path: &str) -> Result<(), LfsError> {
+		let joined = format!("{}/{}", self.inner.trim_end_matches('/'), path.trim_start_matches('/'));
+		validate(&joined)?;
+		self.inner = joined;
+		Ok(())
+	}
+
+	pub fn into_string(self) -> String {
+		self.inner
+	}
+}
+
+impl Deref for LfsPathBuf {
+	type Target = LfsPath;
+
+	fn deref(&self) -> &LfsPath {
+		self.as_path()
+	}
+}
+
+impl Borrow<LfsPath> for LfsPathBuf {
+	fn borrow(&self) -> &LfsPath {
+		self.as_path()
+	}
+}
+
+impl AsRef<LfsPath> for LfsPathBuf {
+	fn as_ref(&self) -> &LfsPath {
+		self.as_path()
+	}
+}
+
+impl AsRef<str> for LfsPathBuf {
+	fn as_ref(&self) -> &str {
+		&self.inner
+	}
+}
+
+impl fmt::Display for LfsPathBuf {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(&self.inner)
+	}
+}
+
+impl TryFrom<&str> for LfsPathBuf {
+	type Error = LfsError;
+
+	fn try_from(path: &str) -> Result<LfsPathBuf, LfsError> {
+		LfsPathBuf::new(path)
+	}
+}
+
+impl TryFrom<String> for LfsPathBuf {
+	type Error = LfsError;
+
+	fn try_from(path: String) -> Result<LfsPathBuf, LfsError> {
+		LfsPathBuf::new(path)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn path_test() {
+		assert!(LfsPath::new("/data/levels/1.bin").is_ok());
+		assert!(LfsPath::new("/data/../config.ini").is_ok());
+		assert!(LfsPath::new("data/levels/1.bin").is_err());
+		assert!(LfsPath::new("/data/../../etc/passwd").is_err());
+		assert!(LfsPath::new("/data\0/x").is_err());
+		assert!(LfsPath::new("/mods/..\\..\\secret").is_err());
+		assert!(LfsPath::new("/mods/C:/Windows/x").is_err());
+		assert!(LfsPath::new("/mods/c:config.ini").is_err());
+		assert!(LfsPath::new("/logs/10:30.txt").is_ok());
+
+		let path = LfsPathBuf::new("/data").unwrap().join("levels/1.bin").unwrap();
+		assert!(path.as_str() == "/data/levels/1.bin");
+		assert!(path.parent().unwrap().as_str() == "/data/levels");
+		assert!(path.file_name() == Some("1.bin"));
+		assert!(LfsPath::new("/data").unwrap().parent().unwrap().as_str() == "/");
+		assert!(path.join("../../..").is_ok() && path.join("../../../..").is_err());
+	}
+
+	#[test]
+	fn normalized_test() {
+		let normalized = |path: &str| LfsPath::new(path).unwrap().normalized().as_str().to_string();
+		assert!(normalized("/mods/../save.dat") == "/save.dat");
+		assert!(normalized("/data/./levels//1.bin") == "/data/levels/1.bin");
+		assert!(normalized("/data/levels/") == "/data/levels/");
+		assert!(normalized("/data/..") == "/");
+		assert!(normalized("/mods/levels\\..\\save.dat") == "/mods/save.dat");
+		assert!(normalized("/mods\\levels\\") == "/mods/levels/");
+
+		assert!(LfsPath::new("/data/levels/1.bin").unwrap().is_plain());
+		assert!(!LfsPath::new("/mods/../save.dat").unwrap().is_plain());
+		assert!(!LfsPath::new("/mods/..\\save.dat").unwrap().is_plain());
+		assert!(!LfsPath::new("/mods/10:30.txt").unwrap().is_plain());
+	}
+}
@@ -0,0 +1,105 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+// Counts of the work submitted through LaminaFS that hasn't completed yet, kept per mount
+// so progress and quiescence can be checked without walking the queue.
+
+use crate::queue::CompletionCallback;
+use crate::WorkItemResult;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+pub(crate) struct PendingWork {
+	// by the mount point of the first mount covering the path, "" if none
+	by_mount: Mutex<HashMap<String, usize>>
+}
+
+impl PendingWork {
+	pub(crate) fn new() -> PendingWork {
+		PendingWork {
+			by_mount: Mutex::new(HashMap::new())
+		}
+	}
+
+	// Counts the work as pending until its completion callback has run.
+	pub(crate) fn track(self: &Arc<Self>, mount: String, callback: Option<CompletionCallback>) -> Option<CompletionCallback> {
+		*self.by_mount.lock().unwrap().entry(mount.clone()).or_insert(0) += 1;
+
+		let pending = self.clone();
+		Some(Box::new(move |result: &WorkItemResult| {
+			pending.finished(&mount);
+			if let Some(callback) = callback {
+				callback(result);
+			}
+		}))
+	}
+
+	fn finished(&self, mount: &str) {
+		let mut by_mount = self.by_mount.lock().unwrap();
+		let remove = match by_mount.get_mut(mount) {
+			Some(count) => {
+				*count -= 1;
+				*count == 0
+			},
+			None => false
+		};
+		if remove {
+			by_mount.remove(mount);
+		}
+	}
+
+	pub(crate) fn total(&self) -> usize {
+		self.by_mount.lock().unwrap().values().sum()
+	}
+
+	pub(crate) fn by_mount(&self) -> HashMap<String, usize> {
+		self.by_mount.lock().unwrap().clone()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::ResultCode;
+
+	#[test]
+	fn pending_test() {
+		let pending = Arc::new(PendingWork::new());
+		let first = pending.track("/data/".to_string(), None).unwrap();
+		let second = pending.track("/data/".to_string(), None).unwrap();
+		let third = pending.track("/save/".to_string(), None).unwrap();
+		assert!(pending.total() == 3);
+		assert!(pending.by_mount().get("/data/") == Some(&2));
+
+		first(&WorkItemResult::new(ResultCode::Ok, &[]));
+		third(&WorkItemResult::new(ResultCode::NotFound, &[]));
+		assert!(pending.total() == 1);
+		assert!(pending.by_mount().get("/save/").is_none());
+
+		second(&WorkItemResult::new(ResultCode::Ok, &[]));
+		assert!(pending.total() == 0);
+		assert!(pending.by_mount().is_empty());
+	}
+}
@@ -0,0 +1,231 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+use crate::alloc::{Allocator, ContextAllocator};
+use crate::ReadBuffer;
+
+use std::alloc::Layout;
+use std::collections::HashMap;
+use std::ptr::NonNull;
+use std::sync::{Arc, Mutex};
+
+// Buffers are bucketed by power of two size, so a buffer serves any read of up to twice
+// the size it was first allocated for.
+const MIN_CLASS_SHIFT: u32 = 12;
+const CLASS_COUNT: usize = 20;
+const MIN_ALIGNMENT: usize = 16;
+
+pub const DEFAULT_BUFFERS_PER_CLASS: usize = 8;
+
+fn size_class(size: usize) -> Option<usize> {
+	let shift = std::cmp::max(size.next_power_of_two().trailing_zeros(), MIN_CLASS_SHIFT);
+	let class = (shift - MIN_CLASS_SHIFT) as usize;
+	if class < CLASS_COUNT { Some(class) } else { None }
+}
+
+struct PoolState {
+	free: Vec<Vec<(usize, Layout)>>,
+	// every buffer handed out by the pool, by address
+	live: HashMap<usize, Layout>
+}
+
+struct PoolAllocator {
+	buffers_per_class: usize,
+	state: Mutex<PoolState>
+}
+
+impl Allocator for PoolAllocator {
+	fn alloc(&self, size: usize, alignment: usize) -> *mut u8 {
+		let alignment = std::cmp::max(alignment, MIN_ALIGNMENT);
+		let class = size_class(size);
+		let mut state = self.state.lock().unwrap();
+
+		if let Some(class) = class {
+			let free = &mut state.free[class];
+			if let Some(index) = free.iter().position(|&(_, layout)| layout.align() >= alignment) {
+				let (ptr, layout) = free.swap_remove(index);
+				state.live.insert(ptr, layout);
+				return ptr as *mut u8;
+			}
+		}
+
+		let capacity = match class {
+			Some(class) => 1 << (class as u32 + MIN_CLASS_SHIFT),
+			None => size
+		};
+		let layout = match Layout::from_size_align(std::cmp::max(capacity, 1), alignment) {
+			Ok(layout) => layout,
+			Err(_) => return 0 as *mut u8
+		};
+		let ptr = unsafe { std::alloc::alloc(layout) };
+		if !ptr.is_null() {
+			state.live.insert(ptr as usize, layout);
+		}
+		ptr
+	}
+
+	fn free(&self, ptr: *mut u8) {
+		let mut state = self.state.lock().unwrap();
+		if let Some(layout) = state.live.remove(&(ptr as usize)) {
+			match size_class(layout.size()) {
+				Some(class) if state.free[class].len() < self.buffers_per_class => state.free[class].push((ptr as usize, layout)),
+				_ => unsafe { std::alloc::dealloc(ptr, layout) }
+			}
+		}
+	}
+}
+
+impl Drop for PoolAllocator {
+	fn drop(&mut self) {
+		let state = self.state.get_mut().unwrap();
+		for (ptr, layout) in state.free.drain(..).flatten() {
+			unsafe { std::alloc::dealloc(ptr as *mut u8, layout); }
+		}
+	}
+}
+
+// Recycles read buffers across work items. Reads only draw from the pool once it is set on
+// the context with LaminaFS::set_buffer_pool.
+pub struct BufferPool {
+	allocator: Arc<ContextAllocator>,
+	pool: *const PoolAllocator
+}
+
+unsafe impl Send for BufferPool {}
+unsafe impl Sync for BufferPool {}
+
+impl BufferPool {
+	pub fn new() -> Arc<BufferPool> {
+		BufferPool::with_buffers_per_class(DEFAULT_BUFFERS_PER_CLASS)
+	}
+
+	// Caps how many idle buffers of each size are kept around.
+	pub fn with_buffers_per_class(buffers_per_class: usize) -> Arc<BufferPool> {
+		let allocator = ContextAllocator::new(PoolAllocator {
+			buffers_per_class: buffers_per_class,
+			state: Mutex::new(PoolState {
+				free: vec![Vec::new(); CLASS_COUNT],
+				live: HashMap::new()
+			})
+		});
+		let pool = unsafe { (*allocator.as_raw()).allocator as *const PoolAllocator };
+
+		Arc::new(BufferPool {
+			allocator: Arc::new(allocator),
+			pool: pool
+		})
+	}
+
+	fn pool(&self) -> &PoolAllocator {
+		// owned by the allocator, which lives as long as self
+		unsafe { &*self.pool }
+	}
+
+	// Takes a buffer of len bytes from the pool, e.g. to stage a write.
+	pub fn acquire(&self, len: usize) -> PooledBuffer {
+		let ptr = self.pool().alloc(len, MIN_ALIGNMENT);
+		PooledBuffer {
+			buffer: ReadBuffer {
+				ptr: NonNull::new(ptr),
+				len: if ptr.is_null() { 0 } else { len },
+				allocator: self.allocator.clone()
+			}
+		}
+	}
+
+	// The number of idle buffers waiting to be reused.
+	pub fn idle_buffers(&self) -> usize {
+		self.pool().state.lock().unwrap().free.iter().map(|free| free.len()).sum()
+	}
+
+	// Frees the idle buffers. Buffers still in use return to the pool as usual.
+	pub fn clear(&self) {
+		let mut state = self.pool().state.lock().unwrap();
+		for (ptr, layout) in state.free.iter_mut().flat_map(|free| free.drain(..)) {
+			unsafe { std::alloc::dealloc(ptr as *mut u8, layout); }
+		}
+	}
+
+	pub(crate) fn allocator(&self) -> &Arc<ContextAllocator> {
+		&self.allocator
+	}
+}
+
+// A buffer owned by a BufferPool, handed back to it on drop.
+pub struct PooledBuffer {
+	buffer: ReadBuffer
+}
+
+impl PooledBuffer {
+	pub(crate) fn new(buffer: ReadBuffer) -> PooledBuffer {
+		PooledBuffer {
+			buffer: buffer
+		}
+	}
+}
+
+impl std::ops::Deref for PooledBuffer {
+	type Target = [u8];
+
+	fn deref(&self) -> &[u8] {
+		&self.buffer
+	}
+}
+
+impl std::ops::DerefMut for PooledBuffer {
+	fn deref_mut(&mut self) -> &mut [u8] {
+		match self.buffer.ptr {
+			Some(ptr) => unsafe { std::slice::from_raw_parts_mut(ptr.as_ptr(), self.buffer.len) },
+			None => &mut []
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn buffer_pool_test() {
+		let pool = BufferPool::with_buffers_per_class(1);
+
+		let first = pool.acquire(5000);
+		let first_ptr = first.as_ptr();
+		assert!(first.len() == 5000);
+		drop(first);
+		assert!(pool.idle_buffers() == 1);
+
+		// same size class, so the buffer is reused
+		let mut second = pool.acquire(8000);
+		assert!(second.as_ptr() == first_ptr);
+		second[7999] = 1;
+
+		let third = pool.acquire(8000);
+		drop(second);
+		drop(third);
+		assert!(pool.idle_buffers() == 1);
+
+		pool.clear();
+		assert!(pool.idle_buffers() == 0);
+	}
+}
@@ -0,0 +1,971 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+// Work is held in a Rust-side queue and handed to the C context a few items at a time.
+// Keeping the C queue shallow is what allows queued work to be cancelled, since the C
+// API has no way of pulling an item back out once it has been submitted.
+
+use crate::laminafs_sys;
+use crate::throttle::Throttle;
+use crate::timeout::Deadlines;
+use crate::{ResultCode, WorkItemPtr, WorkItemResult};
+
+use std::collections::{HashMap, VecDeque};
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::Waker;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub(crate) const DEFAULT_MAX_IN_FLIGHT: usize = 8;
+const PRIORITY_LEVELS: usize = 3;
+
+pub(crate) type SubmitFn = Box<dyn FnOnce(laminafs_sys::lfs_callback_t, *mut c_void) -> *mut laminafs_sys::lfs_work_item_t + Send>;
+pub(crate) type CompletionCallback = Box<dyn FnOnce(&WorkItemResult) + Send>;
+
+// Queued work is handed to the context highest priority first. Work that is already in
+// flight isn't preempted, but at most a few items are ever ahead of a high priority one.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Priority {
+	High,
+	#[default]
+	Normal,
+	Background
+}
+
+impl Priority {
+	fn index(self) -> usize {
+		match self {
+			Priority::High => 0,
+			Priority::Normal => 1,
+			Priority::Background => 2
+		}
+	}
+}
+
+// What submitting does once the context's work item pool is used up, see
+// LaminaFS::new_with_capacity.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Backpressure {
+	// the work fails with ResultCode::QueueFull
+	#[default]
+	Fail,
+	// the submitting thread waits until a work item is released, which deadlocks if only
+	// that thread was going to drop the handles holding them
+	Block
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct QueueStats {
+	// waiting to be handed to the context
+	pub queued: usize,
+	// handed to the context and not completed yet
+	pub in_flight: usize,
+	// taken from the context's pool: in flight, or completed with the handle still alive
+	pub work_items: usize,
+	// the size of the context's pool, None if left to the C library
+	pub capacity: Option<usize>
+}
+
+// Shared between a work item, the queue and the completion callback, which fires on a
+// worker thread and may do so before anyone waits on the item.
+pub(crate) struct WorkState {
+	status: Mutex<WorkStatus>,
+	condvar: Condvar,
+	callback: Mutex<Option<CompletionCallback>>,
+	queue: Arc<WorkQueue>,
+	// the virtual path the work goes to, with aliases, case and ".." resolved, which
+	// draining matches mounts against
+	path: String,
+	// only reads, which cancel abandons even once they're in flight
+	read_only: bool,
+	// set by whichever decides the result first, the completion, a timeout or a cancel
+	settled: AtomicBool,
+	// cached on completion so finished work can be inspected without locking
+	completed: AtomicBool,
+	// set once handed to the context, and once the context has called back for it, which
+	// for work that timed out is after it completed
+	submitted: AtomicBool,
+	returned: AtomicBool,
+	result: AtomicU8,
+	bytes: AtomicUsize,
+	buffer: AtomicPtr<u8>
+}
+
+struct WorkStatus {
+	work_item: Option<WorkItemPtr>,
+	finished: bool,
+	waker: Option<Waker>,
+	// woken once the work is idle, see poll_idle
+	idle_waker: Option<Waker>
+}
+
+impl WorkState {
+	fn set_work_item(&self, work_item: *mut laminafs_sys::lfs_work_item_t) {
+		let mut status = self.status.lock().unwrap();
+		if status.work_item.is_none() {
+			status.work_item = Some(WorkItemPtr::new(work_item));
+		}
+	}
+
+	fn store_completion(&self, result: ResultCode, bytes: usize, buffer: *mut u8) {
+		self.result.store(result.to_index(), Ordering::Relaxed);
+		self.bytes.store(bytes, Ordering::Relaxed);
+		self.buffer.store(buffer, Ordering::Relaxed);
+		self.completed.store(true, Ordering::Release);
+	}
+
+	fn complete(&self) {
+		let mut status = self.status.lock().unwrap();
+		status.finished = true;
+		self.condvar.notify_all();
+		if let Some(waker) = status.waker.take() {
+			waker.wake();
+		}
+		self.wake_if_idle(&mut status);
+	}
+
+	// Called once the context is done with the work item, whether or not it decided the
+	// result.
+	fn set_returned(&self) {
+		let mut status = self.status.lock().unwrap();
+		self.returned.store(true, Ordering::Release);
+		self.condvar.notify_all();
+		self.wake_if_idle(&mut status);
+	}
+
+	fn wake_if_idle(&self, status: &mut WorkStatus) {
+		if self.is_idle() {
+			if let Some(waker) = status.idle_waker.take() {
+				waker.wake();
+			}
+		}
+	}
+
+	// Finishes work that never reached the context, such as cancelled work.
+	fn finish_unsubmitted(&self, code: ResultCode) {
+		self.finish_locally(code, 0, 0 as *mut u8);
+	}
+
+	// Finishes work that ran without the context, with the result it produced. Returns
+	// false if the work's result was already decided.
+	fn finish_locally(&self, code: ResultCode, bytes: usize, buffer: *mut u8) -> bool {
+		if !self.settle() {
+			return false;
+		}
+
+		let code = self.run_callback(&WorkItemResult::new(code, if buffer.is_null() || bytes == 0 { &[] } else { unsafe { std::slice::from_raw_parts(buffer, bytes) } }));
+		self.store_completion(code, bytes, buffer);
+		self.complete();
+		true
+	}
+
+	// Returns false if the work's result was already decided.
+	fn settle(&self) -> bool {
+		!self.settled.swap(true, Ordering::AcqRel)
+	}
+
+	// Returns the result the work finishes with, which the callback may have turned into a
+	// failure. Completion is only stored afterwards, so whatever the callback records is in
+	// place before anyone sees the work as finished.
+	fn run_callback(&self, result: &WorkItemResult) -> ResultCode {
+		let callback = self.callback.lock().unwrap().take();
+		if let Some(callback) = callback {
+			// a panicking callback must not unwind into C or leave waiters hanging, so it
+			// fails the work and the work item is released as usual
+			if std::panic::catch_unwind(std::panic::AssertUnwindSafe(move || callback(result))).is_err() {
+				result.fail(ResultCode::GenericError);
+			}
+		}
+		result.get_result()
+	}
+
+	pub(crate) fn wait(&self) {
+		let mut status = self.status.lock().unwrap();
+		while !status.finished {
+			status = self.condvar.wait(status).unwrap();
+		}
+
+		// the callback runs just before the C side is done with the item
+		if let Some(ref work_item) = status.work_item {
+			unsafe { laminafs_sys::lfs_wait_for_work_item(work_item.ptr.as_ptr()); }
+		}
+	}
+
+	// Waits for the completion callback only. Unlike wait, the C side may still be wrapping
+	// up the item afterwards, which is fine for reading its results.
+	pub(crate) fn wait_completed(&self) {
+		if self.completed.load(Ordering::Acquire) {
+			return;
+		}
+
+		let mut status = self.status.lock().unwrap();
+		while !status.finished {
+			status = self.condvar.wait(status).unwrap();
+		}
+	}
+
+	// Returns false if the timeout elapsed first.
+	pub(crate) fn wait_completed_timeout(&self, timeout: Duration) -> bool {
+		if self.completed.load(Ordering::Acquire) {
+			return true;
+		}
+
+		let deadline = Instant::now() + timeout;
+		let mut status = self.status.lock().unwrap();
+		while !status.finished {
+			let now = Instant::now();
+			if now >= deadline {
+				return false;
+			}
+			status = self.condvar.wait_timeout(status, deadline - now).unwrap().0;
+		}
+		true
+	}
+
+	pub(crate) fn is_completed(&self) -> bool {
+		self.completed.load(Ordering::Acquire)
+	}
+
+	// Completed and, if it reached the context, called back for by it. Work that timed out
+	// in flight completes long before it's idle, and its work item and write buffer have to
+	// be kept until then.
+	pub(crate) fn is_idle(&self) -> bool {
+		self.is_completed() && (!self.submitted.load(Ordering::Acquire) || self.returned.load(Ordering::Acquire))
+	}
+
+	pub(crate) fn wait_idle(&self) {
+		let mut status = self.status.lock().unwrap();
+		while !self.is_idle() {
+			status = self.condvar.wait(status).unwrap();
+		}
+	}
+
+	// Only meaningful once completed.
+	pub(crate) fn result(&self) -> ResultCode {
+		ResultCode::from_index(self.result.load(Ordering::Relaxed))
+	}
+
+	pub(crate) fn bytes(&self) -> usize {
+		self.bytes.load(Ordering::Relaxed)
+	}
+
+	pub(crate) fn buffer(&self) -> *mut u8 {
+		self.buffer.load(Ordering::Relaxed)
+	}
+
+	// Returns true if finished, otherwise registers the waker to be woken on completion.
+	pub(crate) fn poll_finished(&self, waker: &Waker) -> bool {
+		let mut status = self.status.lock().unwrap();
+		if !status.finished {
+			status.waker = Some(waker.clone());
+		}
+		status.finished
+	}
+
+	// Returns true if idle, otherwise registers the waker to be woken once it is.
+	pub(crate) fn poll_idle(&self, waker: &Waker) -> bool {
+		let mut status = self.status.lock().unwrap();
+		let idle = self.is_idle();
+		if !idle {
+			status.idle_waker = Some(waker.clone());
+		}
+		idle
+	}
+
+	// The C work item, or None if the work finished before it was ever submitted.
+	pub(crate) fn work_item(&self) -> Option<*mut laminafs_sys::lfs_work_item_t> {
+		self.status.lock().unwrap().work_item.as_ref().map(|work_item| work_item.ptr.as_ptr())
+	}
+
+	pub(crate) fn cancel(self: &Arc<Self>) -> bool {
+		self.queue.cancel(self)
+	}
+
+	pub(crate) fn release(&self) {
+		self.queue.release_work_item();
+	}
+
+	pub(crate) fn set_timeout(self: &Arc<Self>, timeout: Duration) {
+		self.queue.set_timeout(self, timeout)
+	}
+
+	// In-flight work finishes with TimedOut straight away, so a hung device doesn't hold up
+	// everything submitted after it.
+	pub(crate) fn time_out(self: &Arc<Self>) {
+		if !self.queue.remove_pending(self, ResultCode::TimedOut) {
+			self.abandon(ResultCode::TimedOut);
+		}
+	}
+
+	// Finishes in-flight work with `code` and gives up its in-flight slot. The context
+	// still completes its work item eventually, but the result is dropped. Returns false
+	// if the work's result was already decided.
+	fn abandon(self: &Arc<Self>, code: ResultCode) -> bool {
+		let abandoned = self.finish_locally(code, 0, 0 as *mut u8);
+		self.queue.finished(self);
+		abandoned
+	}
+}
+
+unsafe extern "C" fn work_item_completed(work_item: *mut laminafs_sys::lfs_work_item_t, user_data: *mut c_void) {
+	let work = Arc::from_raw(user_data as *const WorkState);
+	work.set_work_item(work_item);
+
+	if work.settle() {
+		let result = ResultCode::from_lamina(laminafs_sys::lfs_work_item_get_result(work_item));
+		let buffer_len = laminafs_sys::lfs_work_item_get_bytes(work_item) as usize;
+		let buffer_ptr = laminafs_sys::lfs_work_item_get_buffer(work_item) as *mut u8;
+		let result = work.run_callback(&WorkItemResult::new(result, if buffer_ptr.is_null() || buffer_len == 0 { &[] } else { std::slice::from_raw_parts(buffer_ptr, buffer_len) }));
+		work.store_completion(result, buffer_len, buffer_ptr);
+		work.complete();
+	}
+	work.queue.finished(&work);
+	work.set_returned();
+}
+
+struct PendingWork {
+	work: Arc<WorkState>,
+	submit: SubmitFn,
+	#[cfg(feature = "metrics")]
+	queued: Instant,
+	// only set for background work, the only work that waits for a bandwidth limit
+	throttle: Option<Arc<Throttle>>,
+	// the virtual path of mutating work while writes are serialized
+	serial_path: Option<String>
+}
+
+impl PendingWork {
+	// How long the work has to wait for its mount's bandwidth limit, None if it can start.
+	fn delay(&self) -> Option<Duration> {
+		self.throttle.as_ref().and_then(|throttle| throttle.delay())
+	}
+}
+
+struct QueueState {
+	pending: [VecDeque<PendingWork>; PRIORITY_LEVELS],
+	in_flight: Vec<Arc<WorkState>>,
+	// work items handed out by the context and not released yet
+	work_items: usize,
+	backpressure: Backpressure,
+	serialize_writes: bool,
+	// mutating work per virtual path in the order it was pushed, each item only dispatched
+	// once it's at the front and removed once it's finished or cancelled
+	serialized: HashMap<String, VecDeque<Arc<WorkState>>>,
+	// nothing is dispatched while set, see pause
+	paused: bool
+}
+
+pub(crate) struct WorkQueue {
+	max_in_flight: usize,
+	// queued work plus work items held never exceeds this, so the pool can't run dry
+	capacity: Option<usize>,
+	state: Mutex<QueueState>,
+	// signalled when a work item is released
+	released: Condvar,
+	// signalled when work in flight finishes
+	landed: Condvar,
+	// set while a thread is waiting to dispatch throttled work
+	retry_scheduled: AtomicBool,
+	pub(crate) deadlines: Arc<Deadlines>
+}
+
+impl WorkQueue {
+	#[cfg(test)]
+	pub(crate) fn new(max_in_flight: usize) -> Arc<WorkQueue> {
+		WorkQueue::with_capacity(max_in_flight, None)
+	}
+
+	pub(crate) fn with_capacity(max_in_flight: usize, capacity: Option<usize>) -> Arc<WorkQueue> {
+		Arc::new(WorkQueue {
+			max_in_flight: std::cmp::max(max_in_flight, 1),
+			capacity: capacity,
+			state: Mutex::new(QueueState {
+				pending: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+				in_flight: Vec::new(),
+				work_items: 0,
+				backpressure: Backpressure::default(),
+				serialize_writes: false,
+				serialized: HashMap::new(),
+				paused: false
+			}),
+			released: Condvar::new(),
+			landed: Condvar::new(),
+			retry_scheduled: AtomicBool::new(false),
+			deadlines: Arc::new(Deadlines::new())
+		})
+	}
+
+	fn new_work(self: &Arc<Self>, path: &str, read_only: bool, callback: Option<CompletionCallback>) -> Arc<WorkState> {
+		Arc::new(WorkState {
+			status: Mutex::new(WorkStatus {
+				work_item: None,
+				finished: false,
+				waker: None,
+				idle_waker: None
+			}),
+			condvar: Condvar::new(),
+			callback: Mutex::new(callback),
+			queue: self.clone(),
+			path: path.to_string(),
+			read_only: read_only,
+			settled: AtomicBool::new(false),
+			completed: AtomicBool::new(false),
+			submitted: AtomicBool::new(false),
+			returned: AtomicBool::new(false),
+			result: AtomicU8::new(0),
+			bytes: AtomicUsize::new(0),
+			buffer: AtomicPtr::new(0 as *mut u8)
+		})
+	}
+
+	#[cfg(test)]
+	pub(crate) fn push(self: &Arc<Self>, priority: Priority, path: &str, callback: Option<CompletionCallback>, submit: SubmitFn) -> Arc<WorkState> {
+		self.push_throttled(priority, path, callback, None, None, submit)
+	}
+
+	// Background work waits for the throttle's budget before it's handed to the context;
+	// the throttle is ignored for other priorities. serial_path is the virtual path of
+	// mutating work, which waits for earlier mutating work on that path while writes are
+	// serialized, whatever its priority.
+	pub(crate) fn push_throttled(self: &Arc<Self>, priority: Priority, path: &str, callback: Option<CompletionCallback>, throttle: Option<Arc<Throttle>>, serial_path: Option<&str>, submit: SubmitFn) -> Arc<WorkState> {
+		let mut state = self.state.lock().unwrap();
+		if let Some(capacity) = self.capacity {
+			while WorkQueue::queued(&state) + state.work_items >= capacity {
+				match state.backpressure {
+					Backpressure::Fail => {
+						drop(state);
+						return self.fail(path, callback, ResultCode::QueueFull);
+					},
+					Backpressure::Block => state = self.released.wait(state).unwrap()
+				}
+			}
+		}
+
+		let work = self.new_work(path, serial_path.is_none(), callback);
+		let serial_path = serial_path.filter(|_| state.serialize_writes).map(|serial_path| serial_path.to_string());
+		if let Some(ref serial_path) = serial_path {
+			state.serialized.entry(serial_path.clone()).or_default().push_back(work.clone());
+		}
+		state.pending[priority.index()].push_back(PendingWork {
+			work: work.clone(),
+			submit: submit,
+			#[cfg(feature = "metrics")]
+			queued: Instant::now(),
+			throttle: if priority == Priority::Background { throttle } else { None },
+			serial_path: serial_path
+		});
+		drop(state);
+		self.dispatch();
+
+		work
+	}
+
+	// The next work to submit, highest priority first, skipping throttled work that has to
+	// wait and serialized work with earlier work on its path unfinished. Also returns the
+	// shortest wait among the throttled work skipped.
+	fn take_next(state: &mut QueueState) -> (Option<PendingWork>, Option<Duration>) {
+		let mut shortest_delay: Option<Duration> = None;
+		let serialized = &state.serialized;
+		for pending in state.pending.iter_mut() {
+			let mut ready = None;
+			for (index, work) in pending.iter().enumerate() {
+				if let Some(ref serial_path) = work.serial_path {
+					if !serialized.get(serial_path).and_then(|serial| serial.front()).map_or(true, |front| Arc::ptr_eq(front, &work.work)) {
+						continue;
+					}
+				}
+				match work.delay() {
+					Some(delay) => shortest_delay = Some(shortest_delay.map_or(delay, |shortest| std::cmp::min(shortest, delay))),
+					None => {
+						ready = Some(index);
+						break;
+					}
+				}
+			}
+			if let Some(index) = ready {
+				return (pending.remove(index), shortest_delay);
+			}
+		}
+		(None, shortest_delay)
+	}
+
+	fn dispatch(self: &Arc<Self>) {
+		loop {
+			let next = {
+				let mut state = self.state.lock().unwrap();
+				if state.paused || state.in_flight.len() >= self.max_in_flight {
+					return;
+				}
+				match WorkQueue::take_next(&mut state) {
+					(Some(next), _) => {
+						next.work.submitted.store(true, Ordering::Release);
+						state.in_flight.push(next.work.clone());
+						state.work_items += 1;
+						next
+					},
+					(None, Some(delay)) => {
+						self.retry_after(delay);
+						return;
+					},
+					(None, None) => return
+				}
+			};
+
+			#[cfg(feature = "metrics")]
+			crate::instrument::queue_wait(next.queued.elapsed());
+
+			// submit outside the lock, the completion callback may run before this returns
+			let user_data = Arc::into_raw(next.work.clone()) as *mut c_void;
+			let work_item = (next.submit)(Some(work_item_completed), user_data);
+			next.work.set_work_item(work_item);
+		}
+	}
+
+	// Dispatches again once throttled work may start, unless nothing else does first.
+	fn retry_after(self: &Arc<Self>, delay: Duration) {
+		if self.retry_scheduled.swap(true, Ordering::AcqRel) {
+			return;
+		}
+
+		let queue = Arc::downgrade(self);
+		thread::Builder::new()
+			.name("laminafs-throttle".to_string())
+			.spawn(move || {
+				thread::sleep(delay);
+				if let Some(queue) = queue.upgrade() {
+					queue.retry_scheduled.store(false, Ordering::Release);
+					queue.dispatch();
+				}
+			})
+			.unwrap();
+	}
+
+	fn finished(self: &Arc<Self>, work: &Arc<WorkState>) {
+		{
+			let mut state = self.state.lock().unwrap();
+			if let Some(index) = state.in_flight.iter().position(|in_flight| Arc::ptr_eq(in_flight, work)) {
+				state.in_flight.swap_remove(index);
+				self.landed.notify_all();
+			}
+			WorkQueue::remove_serialized(&mut state, work);
+		}
+		self.dispatch();
+	}
+
+	// Holds back dispatching and waits for the work in flight to finish, for changes the
+	// context can't make under running work. Work pushed meanwhile stays queued until
+	// resume. Deadlocks if called from a completion callback.
+	pub(crate) fn pause(&self) {
+		let mut state = self.state.lock().unwrap();
+		state.paused = true;
+		while !state.in_flight.is_empty() {
+			state = self.landed.wait(state).unwrap();
+		}
+	}
+
+	pub(crate) fn resume(self: &Arc<Self>) {
+		self.state.lock().unwrap().paused = false;
+		self.dispatch();
+	}
+
+	// Lets the next mutating work on the path go ahead of it.
+	fn remove_serialized(state: &mut QueueState, work: &Arc<WorkState>) {
+		if state.serialized.is_empty() {
+			return;
+		}
+		state.serialized.retain(|_, serial| {
+			serial.retain(|serial_work| !Arc::ptr_eq(serial_work, work));
+			!serial.is_empty()
+		});
+	}
+
+	fn queued(state: &QueueState) -> usize {
+		state.pending.iter().map(|pending| pending.len()).sum()
+	}
+
+	pub(crate) fn stats(&self) -> QueueStats {
+		let state = self.state.lock().unwrap();
+		QueueStats {
+			queued: WorkQueue::queued(&state),
+			in_flight: state.in_flight.len(),
+			work_items: state.work_items,
+			capacity: self.capacity
+		}
+	}
+
+	pub(crate) fn set_backpressure(&self, backpressure: Backpressure) {
+		self.state.lock().unwrap().backpressure = backpressure;
+		self.released.notify_all();
+	}
+
+	pub(crate) fn backpressure(&self) -> Backpressure {
+		self.state.lock().unwrap().backpressure
+	}
+
+	// Work pushed from now on is serialized or not; work already queued keeps its order.
+	pub(crate) fn set_serialize_writes(&self, serialize_writes: bool) {
+		self.state.lock().unwrap().serialize_writes = serialize_writes;
+	}
+
+	pub(crate) fn serialize_writes(&self) -> bool {
+		self.state.lock().unwrap().serialize_writes
+	}
+
+	// Called once a work item from the context's pool has been released.
+	pub(crate) fn release_work_item(&self) {
+		let mut state = self.state.lock().unwrap();
+		state.work_items -= 1;
+		self.released.notify_one();
+	}
+
+	// Queued work is cancelled outright, reads in flight are abandoned; anything else in
+	// flight runs to completion and returns false.
+	fn cancel(self: &Arc<Self>, work: &Arc<WorkState>) -> bool {
+		if self.remove_pending(work, ResultCode::Cancelled) {
+			return true;
+		}
+
+		let in_flight = self.state.lock().unwrap().in_flight.iter().any(|in_flight| Arc::ptr_eq(in_flight, work));
+		in_flight && work.read_only && work.abandon(ResultCode::Cancelled)
+	}
+
+	// Finishes the work with `code` if it's still queued, returning false if it isn't.
+	fn remove_pending(self: &Arc<Self>, work: &Arc<WorkState>, code: ResultCode) -> bool {
+		let removed = {
+			let mut state = self.state.lock().unwrap();
+			let removed = state.pending.iter_mut().find_map(|pending| {
+				let index = pending.iter().position(|pending| Arc::ptr_eq(&pending.work, work))?;
+				pending.remove(index)
+			});
+			if removed.is_some() {
+				WorkQueue::remove_serialized(&mut state, work);
+			}
+			removed
+		};
+
+		match removed {
+			Some(pending) => {
+				self.released.notify_all();
+				pending.work.finish_unsubmitted(code);
+				// work serialized behind it may be able to start now
+				self.dispatch();
+				true
+			},
+			None => false
+		}
+	}
+
+	// Work that fails before it can be submitted, e.g. for an invalid path.
+	pub(crate) fn fail(self: &Arc<Self>, path: &str, callback: Option<CompletionCallback>, code: ResultCode) -> Arc<WorkState> {
+		let work = self.new_work(path, false, callback);
+		work.finish_unsubmitted(code);
+		work
+	}
+
+	// Completes work that ran without the context, see ExecutionMode::SingleThread.
+	pub(crate) fn complete_locally(self: &Arc<Self>, path: &str, callback: Option<CompletionCallback>, code: ResultCode, bytes: usize, buffer: *mut u8) -> Arc<WorkState> {
+		let work = self.new_work(path, false, callback);
+		work.finish_locally(code, bytes, buffer);
+		work
+	}
+
+	// Waits for the work on paths matching `covers`, cancelling what is still queued first
+	// if `cancel` is set. Work that is already in flight always runs to completion.
+	pub(crate) fn drain<F: Fn(&str) -> bool>(self: &Arc<Self>, covers: F, cancel: bool) {
+		let (cancelled, waiting) = {
+			let mut state = self.state.lock().unwrap();
+			let mut cancelled = Vec::new();
+			if cancel {
+				for pending in state.pending.iter_mut() {
+					let (matching, rest): (VecDeque<_>, VecDeque<_>) = pending.drain(..).partition(|pending| covers(&pending.work.path));
+					*pending = rest;
+					cancelled.extend(matching.into_iter().map(|pending| pending.work));
+				}
+				for work in &cancelled {
+					WorkQueue::remove_serialized(&mut state, work);
+				}
+			}
+
+			let waiting: Vec<_> = state.pending.iter()
+				.flat_map(|pending| pending.iter().map(|pending| &pending.work))
+				.chain(state.in_flight.iter())
+				.filter(|work| covers(&work.path))
+				.cloned()
+				.collect();
+			(cancelled, waiting)
+		};
+
+		self.released.notify_all();
+		for work in cancelled {
+			work.finish_unsubmitted(ResultCode::Cancelled);
+		}
+		self.dispatch();
+		for work in waiting {
+			work.wait_completed();
+		}
+	}
+
+	pub(crate) fn cancel_all(&self) {
+		let cancelled: Vec<_> = {
+			let mut state = self.state.lock().unwrap();
+			let cancelled: Vec<_> = state.pending.iter_mut().flat_map(|pending| pending.drain(..)).collect();
+			for pending in &cancelled {
+				WorkQueue::remove_serialized(&mut state, &pending.work);
+			}
+			cancelled
+		};
+		self.released.notify_all();
+		for pending in cancelled {
+			pending.work.finish_unsubmitted(ResultCode::Cancelled);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::ptr::NonNull;
+
+	#[test]
+	fn priority_test() {
+		let queue = WorkQueue::new(1);
+		let order = Arc::new(Mutex::new(Vec::new()));
+
+		let submit = |name: &'static str| -> SubmitFn {
+			let order = order.clone();
+			Box::new(move |_, _| {
+				order.lock().unwrap().push(name);
+				NonNull::dangling().as_ptr()
+			})
+		};
+
+		let first = queue.push(Priority::Normal, "/first", None, submit("first"));
+		let background = queue.push(Priority::Background, "/background", None, submit("background"));
+		let normal = queue.push(Priority::Normal, "/normal", None, submit("normal"));
+		let high = queue.push(Priority::High, "/high", None, submit("high"));
+		for work in &[first, high, normal, background] {
+			queue.finished(work);
+		}
+
+		assert!(*order.lock().unwrap() == ["first", "high", "normal", "background"]);
+	}
+
+	#[test]
+	fn completion_test() {
+		let queue = WorkQueue::new(1);
+		let submit = || -> SubmitFn { Box::new(|_, _| NonNull::dangling().as_ptr()) };
+
+		let running = queue.push(Priority::Normal, "/running", None, submit());
+		let queued = queue.push(Priority::Normal, "/queued", None, submit());
+		assert!(!running.is_completed());
+		assert!(!queued.is_completed());
+		assert!(!queued.wait_completed_timeout(Duration::from_millis(1)));
+
+		assert!(queued.cancel());
+		assert!(queued.wait_completed_timeout(Duration::from_millis(1)));
+		assert!(queued.result() == ResultCode::Cancelled);
+	}
+
+	#[test]
+	fn timeout_test() {
+		let queue = WorkQueue::new(1);
+		let submit = || -> SubmitFn { Box::new(|_, _| NonNull::dangling().as_ptr()) };
+		let timed_out = Arc::new(Mutex::new(None));
+		let callback_timed_out = timed_out.clone();
+		let callback: CompletionCallback = Box::new(move |result| *callback_timed_out.lock().unwrap() = Some(result.get_result()));
+
+		let running = queue.push(Priority::Normal, "/running", Some(callback), submit());
+		let queued = queue.push(Priority::Normal, "/queued", None, submit());
+		let untimed = queue.push(Priority::Normal, "/untimed", None, submit());
+		running.set_timeout(Duration::from_millis(10));
+		queued.set_timeout(Duration::from_millis(20));
+
+		assert!(running.wait_completed_timeout(Duration::from_secs(5)));
+		assert!(running.result() == ResultCode::TimedOut);
+		assert!(*timed_out.lock().unwrap() == Some(ResultCode::TimedOut));
+		// the context still has the work item
+		assert!(!running.is_idle());
+		assert!(queued.wait_completed_timeout(Duration::from_secs(5)));
+		assert!(queued.result() == ResultCode::TimedOut);
+		assert!(!untimed.is_completed());
+
+		// timed out work gives up its in-flight slot
+		while queue.stats().queued != 0 {
+			thread::sleep(Duration::from_millis(1));
+		}
+		assert!(queue.stats().in_flight == 1);
+		assert!(queue.stats().work_items == 3);
+	}
+
+	#[test]
+	fn cancel_test() {
+		let queue = WorkQueue::new(2);
+		let submit = || -> SubmitFn { Box::new(|_, _| NonNull::dangling().as_ptr()) };
+
+		let read = queue.push(Priority::Normal, "/level", None, submit());
+		let write = queue.push_throttled(Priority::Normal, "/save", None, None, Some("/save"), submit());
+		let queued = queue.push(Priority::Normal, "/queued", None, submit());
+		assert!(queue.stats().in_flight == 2 && queue.stats().queued == 1);
+
+		// a write in flight runs to completion, a read is abandoned and hands on its slot
+		assert!(!write.cancel() && !write.is_completed());
+		assert!(read.cancel());
+		assert!(read.result() == ResultCode::Cancelled);
+		assert!(!read.is_idle());
+		assert!(!read.cancel());
+		assert!(queue.stats().in_flight == 2 && queue.stats().queued == 0);
+		assert!(queued.cancel() && queued.result() == ResultCode::Cancelled);
+	}
+
+	#[test]
+	fn backpressure_test() {
+		let queue = WorkQueue::with_capacity(1, Some(2));
+		let submit = || -> SubmitFn { Box::new(|_, _| NonNull::dangling().as_ptr()) };
+
+		let running = queue.push(Priority::Normal, "/running", None, submit());
+		let queued = queue.push(Priority::Normal, "/queued", None, submit());
+		let rejected = queue.push(Priority::Normal, "/rejected", None, submit());
+		assert!(rejected.is_completed());
+		assert!(rejected.result() == ResultCode::QueueFull);
+		assert!(queue.stats() == QueueStats {
+			queued: 1,
+			in_flight: 1,
+			work_items: 1,
+			capacity: Some(2)
+		});
+
+		// completing work doesn't free its work item, releasing it does
+		queue.finished(&running);
+		assert!(queue.push(Priority::Normal, "/rejected", None, submit()).result() == ResultCode::QueueFull);
+		running.release();
+		let _third = queue.push(Priority::Normal, "/third", None, submit());
+
+		queue.set_backpressure(Backpressure::Block);
+		let blocking = queue.clone();
+		let blocked = thread::spawn(move || blocking.push(Priority::Normal, "/blocked", None, Box::new(|_, _| NonNull::dangling().as_ptr())));
+		thread::sleep(Duration::from_millis(10));
+		assert!(queue.stats().queued == 1);
+		queue.finished(&queued);
+		queued.release();
+		assert!(!blocked.join().unwrap().is_completed());
+		assert!(queue.stats().queued == 1);
+		assert!(queue.stats().work_items == 1);
+	}
+
+	#[test]
+	fn drain_test() {
+		let queue = WorkQueue::new(1);
+		let submit = || -> SubmitFn { Box::new(|_, _| NonNull::dangling().as_ptr()) };
+
+		let _running = queue.push(Priority::Normal, "/b/running", None, submit());
+		let covered = queue.push(Priority::Normal, "/a/queued", None, submit());
+		let uncovered = queue.push(Priority::Normal, "/b/queued", None, submit());
+
+		queue.drain(|path| path.starts_with("/a/"), true);
+		assert!(covered.is_completed());
+		assert!(covered.result() == ResultCode::Cancelled);
+		assert!(!uncovered.is_completed());
+	}
+
+	#[test]
+	fn callback_failure_test() {
+		let queue = WorkQueue::new(1);
+		let seen = Arc::new(Mutex::new(None));
+		let callback_seen = seen.clone();
+		let callback: CompletionCallback = Box::new(move |result| {
+			result.fail(ResultCode::OutOfSpace);
+			*callback_seen.lock().unwrap() = Some(result.get_result());
+		});
+
+		let work = queue.complete_locally("/save.dat", Some(callback), ResultCode::Ok, 0, 0 as *mut u8);
+		assert!(*seen.lock().unwrap() == Some(ResultCode::OutOfSpace));
+		assert!(work.result() == ResultCode::OutOfSpace);
+	}
+
+	#[test]
+	fn callback_panic_test() {
+		let queue = WorkQueue::new(1);
+		let callback: CompletionCallback = Box::new(|_| panic!("callback panicked"));
+
+		let work = queue.complete_locally("/save.dat", Some(callback), ResultCode::Ok, 0, 0 as *mut u8);
+		assert!(work.is_completed());
+		assert!(work.result() == ResultCode::GenericError);
+	}
+
+	#[test]
+	fn throttle_test() {
+		let queue = WorkQueue::new(4);
+		let submit = || -> SubmitFn { Box::new(|_, _| NonNull::dangling().as_ptr()) };
+		let throttle = Arc::new(Throttle::new(1000));
+		throttle.consume(1100);
+
+		let background = queue.push_throttled(Priority::Background, "/patch.bin", None, Some(throttle.clone()), None, submit());
+		let normal = queue.push_throttled(Priority::Normal, "/level.bin", None, Some(throttle.clone()), None, submit());
+		assert!(normal.work_item().is_some());
+		assert!(background.work_item().is_none());
+
+		std::thread::sleep(Duration::from_millis(150));
+		assert!(background.work_item().is_some());
+	}
+
+	#[test]
+	fn serialize_writes_test() {
+		let queue = WorkQueue::new(4);
+		let order = Arc::new(Mutex::new(Vec::new()));
+		let submit = |name: &'static str| -> SubmitFn {
+			let order = order.clone();
+			Box::new(move |_, _| {
+				order.lock().unwrap().push(name);
+				NonNull::dangling().as_ptr()
+			})
+		};
+
+		// without serialization overlapping writes are in flight together
+		let first = queue.push_throttled(Priority::Normal, "/save.dat", None, None, Some("/save.dat"), submit("unserialized"));
+		let second = queue.push_throttled(Priority::Normal, "/save.dat", None, None, Some("/save.dat"), submit("unserialized"));
+		assert!(first.work_item().is_some() && second.work_item().is_some());
+		queue.finished(&first);
+		queue.finished(&second);
+		order.lock().unwrap().clear();
+
+		queue.set_serialize_writes(true);
+		let old = queue.push_throttled(Priority::Normal, "/save.dat", None, None, Some("/save.dat"), submit("old"));
+		let read = queue.push_throttled(Priority::Normal, "/save.dat", None, None, None, submit("read"));
+		let other = queue.push_throttled(Priority::Normal, "/other.dat", None, None, Some("/other.dat"), submit("other"));
+		let cancelled = queue.push_throttled(Priority::Normal, "/save.dat", None, None, Some("/save.dat"), submit("cancelled"));
+		let new = queue.push_throttled(Priority::High, "/save.dat", None, None, Some("/save.dat"), submit("new"));
+		assert!(*order.lock().unwrap() == ["old", "read", "other"]);
+
+		// cancelling queued work lets the work behind it go once it's at the front
+		assert!(cancelled.cancel());
+		assert!(new.work_item().is_none());
+		queue.finished(&old);
+		assert!(*order.lock().unwrap() == ["old", "read", "other", "new"]);
+
+		for work in &[read, other, new] {
+			queue.finished(work);
+		}
+		assert!(queue.state.lock().unwrap().serialized.is_empty());
+	}
+}
@@ -0,0 +1,218 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// Per-mount quotas, capping the bytes stored on a mount. Usage is counted once when the
+// mount is created and then kept up to date by the writes and deletes made through
+// LaminaFS, which reserve the growth they cause up front and fail with OutOfSpace if it
+// doesn't fit. Files changed behind LaminaFS's back aren't accounted for.
+
+use crate::device::{Device, WriteMode};
+use crate::local::DeviceOp;
+use crate::mount::MountTable;
+use crate::queue::CompletionCallback;
+use crate::{MountPermissions, ResultCode, WorkItemResult};
+
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+
+pub(crate) struct Quota {
+	max_bytes: u64,
+	used: Mutex<u64>
+}
+
+impl Quota {
+	// Counts what is already stored on the device.
+	pub(crate) fn new(device: &dyn Device, max_bytes: u64) -> Result<Quota, ResultCode> {
+		let mut used = 0;
+		let mut dirs = vec![String::new()];
+		while let Some(dir) = dirs.pop() {
+			for entry in device.list_dir(&dir)? {
+				let path = if dir.is_empty() { entry.name } else { format!("{}/{}", dir, entry.name) };
+				if entry.is_dir {
+					dirs.push(path);
+				} else {
+					used += entry.size;
+				}
+			}
+		}
+
+		Ok(Quota {
+			max_bytes: max_bytes,
+			used: Mutex::new(used)
+		})
+	}
+
+	pub(crate) fn max_bytes(&self) -> u64 {
+		self.max_bytes
+	}
+
+	pub(crate) fn used(&self) -> u64 {
+		*self.used.lock().unwrap()
+	}
+
+	// Shrinking always fits, even on a mount that is already over its quota.
+	fn reserve(&self, change: i64) -> Result<(), ResultCode> {
+		let mut used = self.used.lock().unwrap();
+		if change > 0 && used.saturating_add(change as u64) > self.max_bytes {
+			return Err(ResultCode::OutOfSpace);
+		}
+		*used = (*used as i64 + change).max(0) as u64;
+		Ok(())
+	}
+}
+
+// Growth taken out of a quota for a write that hasn't finished yet.
+pub(crate) struct Reservation {
+	quota: Arc<Quota>,
+	change: i64
+}
+
+impl Reservation {
+	// Gives the reservation back, for writes that failed.
+	pub(crate) fn cancel(self) {
+		let _ = self.quota.reserve(-self.change);
+	}
+}
+
+// Hands the reservation back if the work fails.
+pub(crate) fn release_on_failure(reservation: Option<Reservation>, callback: Option<CompletionCallback>) -> Option<CompletionCallback> {
+	let reservation = match reservation {
+		Some(reservation) => reservation,
+		None => return callback
+	};
+
+	Some(Box::new(move |result: &WorkItemResult| {
+		if result.get_result() != ResultCode::Ok {
+			reservation.cancel();
+		}
+		if let Some(callback) = callback {
+			callback(result);
+		}
+	}))
+}
+
+impl MountTable {
+	// Reserves what changing the size of `path` from its current size to `new_len(size)`
+	// costs the quota of the mount the change would be made on. None if that mount has no
+	// quota.
+	pub(crate) fn reserve<F: FnOnce(u64) -> u64>(&self, path: &str, permission: MountPermissions, new_len: F) -> Result<Option<Reservation>, ResultCode> {
+		self.check_read_only(permission)?;
+		for (mount, relative_path) in self.resolve(path) {
+			if !mount.permissions.contains(permission) {
+				continue;
+			}
+			let device = match mount.device {
+				Some(ref device) => device,
+				None => continue
+			};
+			let quota = match mount.quota() {
+				Some(quota) => quota,
+				None => return Ok(None)
+			};
+
+			let size = device.device.file_size(&relative_path).unwrap_or(0);
+			let new_len = i64::try_from(new_len(size)).map_err(|_| ResultCode::GenericError)?;
+			let change = new_len - size as i64;
+			quota.reserve(change)?;
+			return Ok(Some(Reservation {
+				quota: quota,
+				change: change
+			}));
+		}
+		Ok(None)
+	}
+
+	pub(crate) fn reserve_op(&self, path: &str, op: &DeviceOp) -> Result<Option<Reservation>, ResultCode> {
+		match *op {
+			DeviceOp::WriteFile { mode, offset, ref buffer } => {
+				let end = match mode {
+					WriteMode::Segment => offset.checked_add(buffer.len() as u64).ok_or(ResultCode::GenericError)?,
+					_ => 0
+				};
+				self.reserve(path, MountPermissions::WriteFile, |size| match mode {
+					WriteMode::Overwrite | WriteMode::Atomic => buffer.len() as u64,
+					WriteMode::Append => size.saturating_add(buffer.len() as u64),
+					WriteMode::Segment => std::cmp::max(size, end)
+				})
+			},
+			DeviceOp::DeleteFile => self.reserve(path, MountPermissions::DeleteFile, |_| 0),
+			// nothing to reserve, but these fail up front on a read-only context too
+			DeviceOp::CreateDir => self.check_read_only(MountPermissions::CreateDir).map(|_| None),
+			DeviceOp::DeleteDir => self.check_read_only(MountPermissions::DeleteDir).map(|_| None),
+			_ => Ok(None)
+		}
+	}
+
+	// Runs `f` with the change reserved, handing it back if `f` fails.
+	pub(crate) fn charged<T, L, F>(&self, path: &str, permission: MountPermissions, new_len: L, f: F) -> Result<T, ResultCode>
+		where L: FnOnce(u64) -> u64, F: FnOnce() -> Result<T, ResultCode> {
+		let reservation = self.reserve(path, permission, new_len)?;
+		let result = f();
+		if let (Err(_), Some(reservation)) = (&result, reservation) {
+			reservation.cancel();
+		}
+		result
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::device::{CreatedDevice, MemoryDevice};
+	use crate::mount::{DeviceType, MountEntry};
+
+	#[test]
+	fn quota_test() {
+		let device = Arc::new(MemoryDevice::create("").unwrap());
+		device.create_dir("/saves").unwrap();
+		device.write_file("/saves/slot0", 0, &[0; 60], WriteMode::Overwrite).unwrap();
+
+		let table = MountTable::new();
+		let mount = Arc::new(MountEntry::new("/user", DeviceType::Directory, "", MountPermissions::All, Some(CreatedDevice::new(device.clone())), None));
+		mount.set_quota(Some(Arc::new(Quota::new(device.as_ref(), 100).unwrap())));
+		table.add(&mount);
+		assert!(mount.quota().unwrap().used() == 60);
+
+		let write = |len: usize, mode: WriteMode| DeviceOp::WriteFile { mode: mode, offset: 0, buffer: Arc::from(vec![0; len]) };
+		assert!(table.reserve_op("/user/saves/slot1", &write(50, WriteMode::Overwrite)).err() == Some(ResultCode::OutOfSpace));
+		assert!(table.reserve_op("/user/saves/slot0", &write(90, WriteMode::Overwrite)).unwrap().is_some());
+		assert!(mount.quota().unwrap().used() == 90);
+
+		let reservation = table.reserve_op("/user/saves/slot1", &write(10, WriteMode::Append)).unwrap();
+		assert!(mount.quota().unwrap().used() == 100);
+		reservation.unwrap().cancel();
+		assert!(mount.quota().unwrap().used() == 90);
+
+		assert!(table.charged("/user/saves/slot1", MountPermissions::WriteFile, |_| 10, || Err::<(), _>(ResultCode::GenericError)).is_err());
+		assert!(mount.quota().unwrap().used() == 90);
+		assert!(table.reserve_op("/user/saves/slot0", &DeviceOp::DeleteFile).is_ok());
+		assert!(mount.quota().unwrap().used() == 30);
+		assert!(table.reserve_op("/other/file", &write(500, WriteMode::Overwrite)).unwrap().is_none());
+
+		let segment = DeviceOp::WriteFile { mode: WriteMode::Segment, offset: u64::max_value(), buffer: Arc::from(vec![0; 1]) };
+		assert!(table.reserve_op("/user/saves/slot0", &segment).err() == Some(ResultCode::GenericError));
+		let segment = DeviceOp::WriteFile { mode: WriteMode::Segment, offset: u64::max_value() - 1, buffer: Arc::from(vec![0; 1]) };
+		assert!(table.reserve_op("/user/saves/slot0", &segment).err() == Some(ResultCode::GenericError));
+		assert!(mount.quota().unwrap().used() == 30);
+	}
+}
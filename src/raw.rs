@@ -0,0 +1,75 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+// Escape hatches for C and C++ code sharing a context with the crate. The handles stay
+// owned by the crate: they're only valid while what they came from is alive, and mounts
+// and work items must not be released through them.
+
+use crate::laminafs_sys;
+use crate::{LaminaFS, Mount, WorkHandle};
+
+use std::sync::Arc;
+
+// The C types behind the raw handles.
+pub mod sys {
+	pub use crate::laminafs_sys::{lfs_context_t, lfs_mount_t, lfs_work_item_t};
+}
+
+impl LaminaFS {
+	/// Shares a context created by C or C++ code, e.g. an engine's, instead of creating a
+	/// second one. The context is destroyed along with the LaminaFS only if `owned` is set.
+	/// Mounts created from C serve the work the context runs but are invisible to the
+	/// operations the crate runs itself, which only see the mounts created through it.
+	///
+	/// # Safety
+	///
+	/// `context` must be a live context that outlives the LaminaFS and every handle taken
+	/// from it. If `owned` is set, nothing else may destroy it, as the LaminaFS does. Mounts
+	/// and devices must not be changed from outside while the LaminaFS changes them.
+	pub unsafe fn from_raw_context(context: laminafs_sys::lfs_context_t, owned: bool) -> Arc<LaminaFS> {
+		LaminaFS::builder().raw_context(context, owned).build()
+	}
+
+	// Mounts created or released through the context directly aren't seen by the crate's
+	// path resolution, which only knows about its own mounts.
+	pub fn as_raw(&self) -> laminafs_sys::lfs_context_t {
+		self.context.raw
+	}
+}
+
+impl Mount {
+	// None once the mount has been released.
+	pub fn as_raw(&self) -> Option<laminafs_sys::lfs_mount_t> {
+		self.info.handle.lock().unwrap().as_ref().map(|handle| handle.ptr)
+	}
+}
+
+impl WorkHandle {
+	// None for work that never reached the context: work that is still queued, failed
+	// before it could be submitted, was cancelled or ran without the context. Waiting on
+	// the item through C is fine, releasing it isn't.
+	pub fn as_raw(&self) -> Option<*mut laminafs_sys::lfs_work_item_t> {
+		self.work.work_item()
+	}
+}
@@ -0,0 +1,361 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+
+// Records the work submitted through a LaminaFS (what, where, when, how long and with
+// what result) so load patterns can be benchmarked and player traces reproduced. With
+// the data included, a recording can stand in for the files it read, see ReplayDevice.
+//
+// The log starts with "LFSREC01" followed by the operation count as a u32 and then each
+// operation, all little-endian:
+//
+//   operation name  u32 length, UTF-8
+//   path            u32 length, UTF-8
+//   offset          u64, the segment or write offset, 0 otherwise
+//   length          u64, max_bytes for segment reads, the buffer size for writes
+//   submitted       u64, microseconds since recording started
+//   duration        u64, microseconds from submission to completion
+//   result          u8, the ResultCode's index
+//   bytes           u64, read or written
+//   data            u8 flag, then u64 length and the bytes if set
+
+use crate::local::DeviceOp;
+use crate::queue::CompletionCallback;
+use crate::{LaminaFS, OperationKind, ResultCode, WorkHandle, WorkItemResult};
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const RECORDING_MAGIC: &[u8; 8] = b"LFSREC01";
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RecordedOp {
+	pub operation: OperationKind,
+	pub path: String,
+	pub offset: u64,
+	pub length: u64,
+	// since recording started
+	pub submitted: Duration,
+	pub duration: Duration,
+	pub result: ResultCode,
+	pub bytes: u64,
+	// what a successful read returned, if the recording includes data
+	pub data: Option<Vec<u8>>
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Recording {
+	// in order of completion
+	pub ops: Vec<RecordedOp>
+}
+
+impl Recording {
+	pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+		writer.write_all(RECORDING_MAGIC)?;
+		writer.write_all(&(self.ops.len() as u32).to_le_bytes())?;
+		for op in &self.ops {
+			write_str(writer, op.operation.name())?;
+			write_str(writer, &op.path)?;
+			writer.write_all(&op.offset.to_le_bytes())?;
+			writer.write_all(&op.length.to_le_bytes())?;
+			writer.write_all(&(op.submitted.as_micros() as u64).to_le_bytes())?;
+			writer.write_all(&(op.duration.as_micros() as u64).to_le_bytes())?;
+			writer.write_all(&[op.result.to_index()])?;
+			writer.write_all(&op.bytes.to_le_bytes())?;
+			match op.data {
+				Some(ref data) => {
+					writer.write_all(&[1])?;
+					writer.write_all(&(data.len() as u64).to_le_bytes())?;
+					writer.write_all(data)?;
+				},
+				None => writer.write_all(&[0])?
+			}
+		}
+		Ok(())
+	}
+
+	pub fn write_to_file(&self, path: &str) -> io::Result<()> {
+		let mut file = io::BufWriter::new(File::create(path)?);
+		self.write(&mut file)?;
+		file.flush()
+	}
+
+	// Fails with InvalidData for anything that isn't a recording.
+	pub fn read<R: Read>(reader: &mut R) -> Result<Recording, ResultCode> {
+		let mut data = Vec::new();
+		reader.read_to_end(&mut data).map_err(|_| ResultCode::GenericError)?;
+
+		let mut log = Log {
+			data: &data,
+			position: 0
+		};
+		if log.take(RECORDING_MAGIC.len())? != RECORDING_MAGIC {
+			return Err(ResultCode::InvalidData);
+		}
+
+		let count = log.u32()?;
+		let mut ops = Vec::new();
+		for _ in 0..count {
+			let operation = OperationKind::from_name(&log.string()?).ok_or(ResultCode::InvalidData)?;
+			let path = log.string()?;
+			let offset = log.u64()?;
+			let length = log.u64()?;
+			let submitted = Duration::from_micros(log.u64()?);
+			let duration = Duration::from_micros(log.u64()?);
+			let result = log.take(1)?[0];
+			if result > ResultCode::InvalidData.to_index() {
+				return Err(ResultCode::InvalidData);
+			}
+			let bytes = log.u64()?;
+			let data = match log.take(1)?[0] {
+				0 => None,
+				_ => {
+					let len = log.u64()? as usize;
+					Some(log.take(len)?.to_vec())
+				}
+			};
+
+			ops.push(RecordedOp {
+				operation: operation,
+				path: path,
+				offset: offset,
+				length: length,
+				submitted: submitted,
+				duration: duration,
+				result: ResultCode::from_index(result),
+				bytes: bytes,
+				data: data
+			});
+		}
+
+		Ok(Recording {
+			ops: ops
+		})
+	}
+
+	pub fn read_from_file(path: &str) -> Result<Recording, ResultCode> {
+		let mut file = File::open(path).map_err(|_| ResultCode::NotFound)?;
+		Recording::read(&mut file)
+	}
+
+	// Submits the recorded reads and existence checks to `fs` again, spaced out as they
+	// were recorded, and returns how long it took for all of them to complete. Writes and
+	// other operations that change files are left out.
+	pub fn replay(&self, fs: &LaminaFS) -> Duration {
+		let mut ops: Vec<&RecordedOp> = self.ops.iter().collect();
+		ops.sort_by_key(|op| op.submitted);
+
+		let started = Instant::now();
+		let mut work: Vec<WorkHandle> = Vec::new();
+		for op in ops {
+			let elapsed = started.elapsed();
+			if op.submitted > elapsed {
+				thread::sleep(op.submitted - elapsed);
+			}
+
+			match op.operation {
+				OperationKind::ReadFile => work.push(fs.read_file(&op.path, false)),
+				OperationKind::ReadFileSegment => work.push(fs.read_file_segment(&op.path, op.offset, op.length, false)),
+				OperationKind::FileExists => work.push(fs.file_exists(&op.path)),
+				_ => {}
+			}
+		}
+
+		for work_item in &work {
+			work_item.wait();
+		}
+		started.elapsed()
+	}
+}
+
+fn write_str<W: Write>(writer: &mut W, value: &str) -> io::Result<()> {
+	writer.write_all(&(value.len() as u32).to_le_bytes())?;
+	writer.write_all(value.as_bytes())
+}
+
+struct Log<'a> {
+	data: &'a [u8],
+	position: usize
+}
+
+impl<'a> Log<'a> {
+	fn take(&mut self, len: usize) -> Result<&'a [u8], ResultCode> {
+		if self.data.len() - self.position < len {
+			return Err(ResultCode::InvalidData);
+		}
+		let taken = &self.data[self.position..self.position + len];
+		self.position += len;
+		Ok(taken)
+	}
+
+	fn u32(&mut self) -> Result<u32, ResultCode> {
+		let mut bytes = [0u8; 4];
+		bytes.copy_from_slice(self.take(4)?);
+		Ok(u32::from_le_bytes(bytes))
+	}
+
+	fn u64(&mut self) -> Result<u64, ResultCode> {
+		let mut bytes = [0u8; 8];
+		bytes.copy_from_slice(self.take(8)?);
+		Ok(u64::from_le_bytes(bytes))
+	}
+
+	fn string(&mut self) -> Result<String, ResultCode> {
+		let len = self.u32()? as usize;
+		String::from_utf8(self.take(len)?.to_vec()).map_err(|_| ResultCode::InvalidData)
+	}
+}
+
+struct Session {
+	started: Instant,
+	include_data: bool,
+	ops: Mutex<Vec<RecordedOp>>
+}
+
+// The recording in progress, if any.
+pub(crate) struct Recorder {
+	session: Mutex<Option<Arc<Session>>>
+}
+
+impl Recorder {
+	pub(crate) fn new() -> Recorder {
+		Recorder {
+			session: Mutex::new(None)
+		}
+	}
+
+	fn start(&self, include_data: bool) {
+		*self.session.lock().unwrap() = Some(Arc::new(Session {
+			started: Instant::now(),
+			include_data: include_data,
+			ops: Mutex::new(Vec::new())
+		}));
+	}
+
+	fn stop(&self) -> Option<Recording> {
+		let session = self.session.lock().unwrap().take()?;
+		let ops = std::mem::replace(&mut *session.ops.lock().unwrap(), Vec::new());
+		Some(Recording {
+			ops: ops
+		})
+	}
+
+	// Records the work once it completes, if a recording is in progress.
+	pub(crate) fn track(&self, operation: OperationKind, path: &str, op: &DeviceOp, callback: Option<CompletionCallback>) -> Option<CompletionCallback> {
+		let session = match *self.session.lock().unwrap() {
+			Some(ref session) => session.clone(),
+			None => return callback
+		};
+
+		let (offset, length) = match *op {
+			DeviceOp::ReadFileSegment { offset, max_bytes, .. } => (offset, max_bytes),
+			DeviceOp::WriteFile { offset, ref buffer, .. } => (offset, buffer.len() as u64),
+			_ => (0, 0)
+		};
+		let reads = match *op {
+			DeviceOp::ReadFile { .. } | DeviceOp::ReadFileSegment { .. } => true,
+			_ => false
+		};
+		let path = path.to_string();
+		let submitted = Instant::now();
+
+		Some(Box::new(move |result: &WorkItemResult| {
+			let data = if session.include_data && reads && result.get_result() == ResultCode::Ok {
+				Some(result.get_buffer().to_vec())
+			} else {
+				None
+			};
+
+			session.ops.lock().unwrap().push(RecordedOp {
+				operation: operation,
+				path: path,
+				offset: offset,
+				length: length,
+				submitted: submitted - session.started,
+				duration: submitted.elapsed(),
+				result: result.get_result(),
+				bytes: result.get_bytes() as u64,
+				data: data
+			});
+
+			if let Some(callback) = callback {
+				callback(result);
+			}
+		}))
+	}
+}
+
+impl LaminaFS {
+	// Starts recording the work items submitted from here on, replacing any recording in
+	// progress. With `include_data`, what successful reads return is recorded too, so the
+	// recording can be served by a ReplayDevice.
+	pub fn start_recording(&self, include_data: bool) {
+		self.recorder.start(include_data);
+	}
+
+	// Work still in flight when the recording stops is left out of it.
+	pub fn stop_recording(&self) -> Option<Recording> {
+		self.recorder.stop()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn recording_round_trip_test() {
+		let recording = Recording {
+			ops: vec![RecordedOp {
+				operation: OperationKind::ReadFileSegment,
+				path: "/levels/1.bin".to_string(),
+				offset: 64,
+				length: 16,
+				submitted: Duration::from_micros(1500),
+				duration: Duration::from_micros(250),
+				result: ResultCode::Ok,
+				bytes: 4,
+				data: Some(b"data".to_vec())
+			}, RecordedOp {
+				operation: OperationKind::FileExists,
+				path: "/save.dat".to_string(),
+				offset: 0,
+				length: 0,
+				submitted: Duration::from_micros(2000),
+				duration: Duration::from_micros(10),
+				result: ResultCode::NotFound,
+				bytes: 0,
+				data: None
+			}]
+		};
+
+		let mut log = Vec::new();
+		recording.write(&mut log).unwrap();
+		assert!(Recording::read(&mut &log[..]).unwrap() == recording);
+		assert!(Recording::read(&mut &log[..log.len() - 1]) == Err(ResultCode::InvalidData));
+		assert!(Recording::read(&mut &b"LFSPACK1"[..]) == Err(ResultCode::InvalidData));
+	}
+}
@@ -0,0 +1,232 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// Transient device failures, like a network device losing its connection or a file
+// briefly held open by another process, surface as GenericError. A retry policy retries
+// those on the thread running the operation before the error reaches the caller; every
+// other error is final.
+
+use crate::device::{Device, DirEntry, FileStat, SyncMode, WriteMode};
+use crate::ResultCode;
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RetryPolicy {
+	// tries in total, including the first
+	pub attempts: u32,
+	// the wait before the first retry, doubling for each one after it
+	pub backoff: Duration
+}
+
+impl RetryPolicy {
+	pub fn new(attempts: u32, backoff: Duration) -> RetryPolicy {
+		RetryPolicy {
+			attempts: attempts,
+			backoff: backoff
+		}
+	}
+}
+
+// The policy a device retries with, shared between the mount and the device instance the
+// context calls into so it can be changed after the mount is created.
+pub(crate) type RetrySlot = Arc<Mutex<Option<RetryPolicy>>>;
+
+fn retry<T, F>(slot: &RetrySlot, mut op: F) -> Result<T, ResultCode> where F: FnMut() -> Result<T, ResultCode> {
+	let policy = *slot.lock().unwrap();
+	let (attempts, mut backoff) = policy.map_or((1, Duration::from_secs(0)), |policy| (policy.attempts, policy.backoff));
+	let mut attempt = 1;
+	loop {
+		match op() {
+			Err(ResultCode::GenericError) if attempt < attempts => {
+				thread::sleep(backoff);
+				backoff = backoff.checked_mul(2).unwrap_or(backoff);
+				attempt += 1;
+			},
+			result => return result
+		}
+	}
+}
+
+// Wraps every device created for a mount, so work the context runs on its threads and
+// work run on the Rust side both retry.
+pub(crate) struct RetryingDevice<D: Device> {
+	pub(crate) device: Arc<D>,
+	policy: RetrySlot
+}
+
+impl<D: Device> RetryingDevice<D> {
+	pub(crate) fn new(device: Arc<D>, policy: RetrySlot) -> RetryingDevice<D> {
+		RetryingDevice {
+			device: device,
+			policy: policy
+		}
+	}
+
+	pub(crate) fn policy(&self) -> RetrySlot {
+		self.policy.clone()
+	}
+}
+
+impl<D: Device> Device for RetryingDevice<D> {
+	fn create(_device_path: &str) -> Result<Self, ResultCode> {
+		Err(ResultCode::Unsupported)
+	}
+
+	fn file_exists(&self, path: &str) -> bool {
+		self.device.file_exists(path)
+	}
+
+	fn file_size(&self, path: &str) -> Result<u64, ResultCode> {
+		retry(&self.policy, || self.device.file_size(path))
+	}
+
+	fn read_file(&self, path: &str, offset: u64, max_bytes: u64) -> Result<Vec<u8>, ResultCode> {
+		retry(&self.policy, || self.device.read_file(path, offset, max_bytes))
+	}
+
+	fn read_ranges(&self, path: &str, ranges: &[(u64, u64)]) -> Result<Vec<Vec<u8>>, ResultCode> {
+		retry(&self.policy, || self.device.read_ranges(path, ranges))
+	}
+
+	// a failed append may have written part of the buffer already, so it isn't retried
+	fn write_file(&self, path: &str, offset: u64, buffer: &[u8], mode: WriteMode) -> Result<u64, ResultCode> {
+		match mode {
+			WriteMode::Append => self.device.write_file(path, offset, buffer, mode),
+			_ => retry(&self.policy, || self.device.write_file(path, offset, buffer, mode))
+		}
+	}
+
+	fn write_gather(&self, path: &str, buffers: &[&[u8]]) -> Result<u64, ResultCode> {
+		retry(&self.policy, || self.device.write_gather(path, buffers))
+	}
+
+	fn delete_file(&self, path: &str) -> Result<(), ResultCode> {
+		retry(&self.policy, || self.device.delete_file(path))
+	}
+
+	fn allocate(&self, path: &str, len: u64) -> Result<(), ResultCode> {
+		retry(&self.policy, || self.device.allocate(path, len))
+	}
+
+	fn set_len(&self, path: &str, len: u64) -> Result<(), ResultCode> {
+		retry(&self.policy, || self.device.set_len(path, len))
+	}
+
+	fn sync(&self, path: &str, mode: SyncMode) -> Result<(), ResultCode> {
+		retry(&self.policy, || self.device.sync(path, mode))
+	}
+
+	fn create_dir(&self, path: &str) -> Result<(), ResultCode> {
+		retry(&self.policy, || self.device.create_dir(path))
+	}
+
+	fn delete_dir(&self, path: &str) -> Result<(), ResultCode> {
+		retry(&self.policy, || self.device.delete_dir(path))
+	}
+
+	fn list_dir(&self, path: &str) -> Result<Vec<DirEntry>, ResultCode> {
+		retry(&self.policy, || self.device.list_dir(path))
+	}
+
+	fn stat(&self, path: &str) -> Result<FileStat, ResultCode> {
+		retry(&self.policy, || self.device.stat(path))
+	}
+
+	fn backing_path(&self, path: &str) -> Option<PathBuf> {
+		self.device.backing_path(path)
+	}
+
+	fn raw_path(&self, path: &str) -> Option<PathBuf> {
+		self.device.raw_path(path)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::device::MemoryDevice;
+	use std::sync::atomic::{AtomicU32, Ordering};
+
+	// Fails reads with GenericError until it has been asked `failures` times.
+	struct FlakyDevice {
+		inner: MemoryDevice,
+		failures: u32,
+		reads: AtomicU32
+	}
+
+	impl Device for FlakyDevice {
+		fn create(_device_path: &str) -> Result<Self, ResultCode> {
+			Err(ResultCode::Unsupported)
+		}
+
+		fn file_exists(&self, path: &str) -> bool {
+			self.inner.file_exists(path)
+		}
+
+		fn file_size(&self, path: &str) -> Result<u64, ResultCode> {
+			self.inner.file_size(path)
+		}
+
+		fn read_file(&self, path: &str, offset: u64, max_bytes: u64) -> Result<Vec<u8>, ResultCode> {
+			if self.reads.fetch_add(1, Ordering::SeqCst) < self.failures {
+				return Err(ResultCode::GenericError);
+			}
+			self.inner.read_file(path, offset, max_bytes)
+		}
+	}
+
+	#[test]
+	fn retry_test() {
+		let memory = MemoryDevice::create("").unwrap();
+		memory.write_file("save", 0, b"hello", WriteMode::Overwrite).unwrap();
+		let flaky = Arc::new(FlakyDevice {
+			inner: memory,
+			failures: 2,
+			reads: AtomicU32::new(0)
+		});
+
+		// without a policy the first failure is final
+		let slot: RetrySlot = Arc::new(Mutex::new(None));
+		let device = RetryingDevice::new(flaky.clone(), slot.clone());
+		assert!(device.read_file("save", 0, 5).err() == Some(ResultCode::GenericError));
+
+		flaky.reads.store(0, Ordering::SeqCst);
+		*slot.lock().unwrap() = Some(RetryPolicy::new(2, Duration::from_millis(1)));
+		assert!(device.read_file("save", 0, 5).err() == Some(ResultCode::GenericError));
+		assert!(flaky.reads.load(Ordering::SeqCst) == 2);
+
+		flaky.reads.store(0, Ordering::SeqCst);
+		*slot.lock().unwrap() = Some(RetryPolicy::new(3, Duration::from_millis(1)));
+		assert!(device.read_file("save", 0, 5).unwrap() == b"hello");
+		assert!(flaky.reads.load(Ordering::SeqCst) == 3);
+
+		// only GenericError is retried
+		flaky.reads.store(2, Ordering::SeqCst);
+		assert!(device.read_file("missing", 0, 5).err() == Some(ResultCode::NotFound));
+		assert!(flaky.reads.load(Ordering::SeqCst) == 3);
+	}
+}
@@ -0,0 +1,150 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// Every work item gets a `laminafs` span when it's submitted. The span is entered while
+// the work is handed to the context and again while its completion runs on the worker
+// thread, and closes once the completion callbacks have returned, so subscribers see the
+// IO nested under whatever was active when it was submitted.
+
+use crate::queue::{CompletionCallback, SubmitFn};
+use crate::{OperationKind, WorkItemResult};
+
+use tracing::Span;
+
+pub(crate) fn work_span(operation: OperationKind, path: &str) -> Span {
+	tracing::info_span!("laminafs", operation = operation.name(), path = path, bytes = tracing::field::Empty, result = tracing::field::Empty)
+}
+
+pub(crate) fn on_completion(span: Span, callback: Option<CompletionCallback>) -> Option<CompletionCallback> {
+	Some(Box::new(move |result: &WorkItemResult| {
+		let _entered = span.enter();
+		span.record("bytes", &(result.get_bytes() as u64));
+		span.record("result", &result.get_result().to_string().as_str());
+
+		if let Some(callback) = callback {
+			callback(result);
+		}
+	}))
+}
+
+// Queued work may be handed to the context from another thread's completion.
+pub(crate) fn on_dispatch(span: Span, submit: SubmitFn) -> SubmitFn {
+	Box::new(move |lfs_callback, user_data| {
+		let _entered = span.enter();
+		submit(lfs_callback, user_data)
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::test_dir::TestDir;
+	use crate::{LaminaFS, ResultCode};
+
+	use tracing::field::{Field, Visit};
+	use tracing::span::{Attributes, Id, Record};
+	use tracing::{Event, Metadata, Subscriber};
+
+	use std::collections::HashMap;
+	use std::sync::{Arc, Mutex};
+
+	#[derive(Default)]
+	struct SpanLog {
+		name: String,
+		fields: HashMap<String, String>,
+		enters: usize
+	}
+
+	impl Visit for SpanLog {
+		fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+			self.fields.insert(field.name().to_string(), format!("{:?}", value));
+		}
+
+		fn record_str(&mut self, field: &Field, value: &str) {
+			self.fields.insert(field.name().to_string(), value.to_string());
+		}
+
+		fn record_u64(&mut self, field: &Field, value: u64) {
+			self.fields.insert(field.name().to_string(), value.to_string());
+		}
+	}
+
+	struct TestSubscriber {
+		spans: Arc<Mutex<Vec<SpanLog>>>
+	}
+
+	impl Subscriber for TestSubscriber {
+		fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+			true
+		}
+
+		fn new_span(&self, span: &Attributes<'_>) -> Id {
+			let mut log = SpanLog {
+				name: span.metadata().name().to_string(),
+				..SpanLog::default()
+			};
+			span.record(&mut log);
+
+			let mut spans = self.spans.lock().unwrap();
+			spans.push(log);
+			Id::from_u64(spans.len() as u64)
+		}
+
+		fn record(&self, span: &Id, values: &Record<'_>) {
+			values.record(&mut self.spans.lock().unwrap()[span.into_u64() as usize - 1]);
+		}
+
+		fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+		fn event(&self, _event: &Event<'_>) {}
+
+		fn enter(&self, span: &Id) {
+			self.spans.lock().unwrap()[span.into_u64() as usize - 1].enters += 1;
+		}
+
+		fn exit(&self, _span: &Id) {}
+	}
+
+	#[test]
+	fn work_span_test() {
+		let root = TestDir::new("work_span_test");
+		std::fs::write(root.join("level.bin"), b"level").unwrap();
+		let fs = LaminaFS::new();
+		let _mount = root.mount(&fs);
+
+		let spans = Arc::new(Mutex::new(Vec::new()));
+		tracing::subscriber::with_default(TestSubscriber { spans: spans.clone() }, || {
+			assert!(fs.read_file_sync("/level.bin").unwrap() == b"level");
+			assert!(fs.read_file("/missing.bin", false).get_result() == ResultCode::NotFound);
+		});
+
+		let spans = spans.lock().unwrap();
+		let field = |index: usize, name: &str| spans[index].fields.get(name).cloned().unwrap_or_default();
+		assert!(spans.len() == 2);
+		assert!(spans[0].name == "laminafs" && field(0, "operation") == "read_file" && field(0, "path") == "/level.bin");
+		assert!(field(0, "bytes") == "5" && field(0, "result") == ResultCode::Ok.to_string());
+		assert!(field(1, "path") == "/missing.bin" && field(1, "result") == ResultCode::NotFound.to_string());
+
+		// entered on submission and again for the completion
+		assert!(spans.iter().all(|span| span.enters >= 2));
+	}
+}
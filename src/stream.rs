@@ -0,0 +1,128 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+use crate::task::Task;
+use crate::{DirEntry, LaminaFS, LfsError};
+
+use futures_core::Stream;
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+enum DirStreamState {
+	Listing(Task<Vec<DirEntry>>),
+	Entries(std::vec::IntoIter<DirEntry>),
+	Done
+}
+
+// Directory entries as a Stream. Each mount's listing is gathered off the calling thread,
+// then handed out one entry at a time so large directories can be processed as they go.
+pub struct DirStream {
+	state: DirStreamState
+}
+
+impl Stream for DirStream {
+	type Item = Result<DirEntry, LfsError>;
+
+	fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+		loop {
+			let next_state = match self.state {
+				DirStreamState::Listing(ref task) => match task.poll_result(cx.waker()) {
+					Some(Ok(entries)) => DirStreamState::Entries(entries.into_iter()),
+					Some(Err(error)) => {
+						self.state = DirStreamState::Done;
+						return Poll::Ready(Some(Err(error)));
+					},
+					None => return Poll::Pending
+				},
+				DirStreamState::Entries(ref mut entries) => match entries.next() {
+					Some(entry) => return Poll::Ready(Some(Ok(entry))),
+					None => DirStreamState::Done
+				},
+				DirStreamState::Done => return Poll::Ready(None)
+			};
+			self.state = next_state;
+		}
+	}
+
+	fn size_hint(&self) -> (usize, Option<usize>) {
+		match self.state {
+			DirStreamState::Listing(_) => (0, None),
+			DirStreamState::Entries(ref entries) => entries.size_hint(),
+			DirStreamState::Done => (0, Some(0))
+		}
+	}
+}
+
+impl LaminaFS {
+	pub fn read_dir_stream(&self, path: &str) -> DirStream {
+		DirStream {
+			state: DirStreamState::Listing(self.list_dir(path))
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::future::block_on;
+	use crate::test_dir::TestDir;
+	use crate::ResultCode;
+
+	use std::future::Future;
+
+	struct Next<'a>(&'a mut DirStream);
+
+	impl<'a> Future for Next<'a> {
+		type Output = Option<Result<DirEntry, LfsError>>;
+
+		fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+			Pin::new(&mut *self.0).poll_next(cx)
+		}
+	}
+
+	#[test]
+	fn read_dir_stream_test() {
+		let root = TestDir::new("read_dir_stream_test");
+		std::fs::create_dir(root.join("levels")).unwrap();
+		std::fs::write(root.join("levels/level1.bin"), b"level 1").unwrap();
+		std::fs::create_dir(root.join("levels/bonus")).unwrap();
+		let fs = LaminaFS::new();
+		let _mount = root.mount(&fs);
+
+		let mut stream = fs.read_dir_stream("/levels");
+		let mut entries = Vec::new();
+		while let Some(entry) = block_on(Next(&mut stream)) {
+			let entry = entry.unwrap();
+			entries.push((entry.name, entry.is_dir, entry.size));
+		}
+		entries.sort();
+		assert!(entries == vec![("bonus".to_string(), true, 0), ("level1.bin".to_string(), false, 7)]);
+		assert!(stream.size_hint() == (0, Some(0)));
+
+		// the error ends the stream
+		let mut missing = fs.read_dir_stream("/missing");
+		assert!(block_on(Next(&mut missing)).unwrap().unwrap_err().code() == ResultCode::NotFound);
+		assert!(block_on(Next(&mut missing)).is_none());
+	}
+}
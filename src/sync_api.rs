@@ -0,0 +1,211 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// Blocking wrappers over the work item API, for callers that just want the result.
+
+use crate::alloc::{Allocator, ContextAllocator};
+use crate::laminafs_sys;
+use crate::local::DeviceOp;
+use crate::{LaminaFS, LfsError, OperationKind, Priority, ReadBuffer, ResultCode, WorkHandle};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+fn wait_for(mut work_item: WorkHandle) -> Result<ReadBuffer, LfsError> {
+	match work_item.get_result() {
+		ResultCode::Ok => Ok(work_item.take_buffer()),
+		code => Err(work_item.error(code))
+	}
+}
+
+// Hands the context the caller's buffer instead of allocating one.
+struct IntoBuffer {
+	ptr: usize,
+	len: usize,
+	too_small: Arc<AtomicBool>
+}
+
+impl Allocator for IntoBuffer {
+	fn alloc(&self, size: usize, alignment: usize) -> *mut u8 {
+		if size <= self.len && self.ptr % std::cmp::max(alignment, 1) == 0 {
+			self.ptr as *mut u8
+		} else {
+			self.too_small.store(true, Ordering::SeqCst);
+			0 as *mut u8
+		}
+	}
+
+	fn free(&self, _ptr: *mut u8) {}
+}
+
+impl LaminaFS {
+	// Reads the whole file into buffer, failing with OutOfSpace if it doesn't fit.
+	pub fn read_file_into(&self, path: &str, buffer: &mut [u8]) -> Result<usize, LfsError> {
+		let context = self.context.raw;
+		self.read_into(OperationKind::ReadFile, path, None, buffer, move |c_path, allocator, lfs_callback, user_data| unsafe {
+			laminafs_sys::lfs_read_file(context, c_path, false, allocator, lfs_callback, user_data)
+		})
+	}
+
+	// Reads up to buffer.len() bytes starting at offset.
+	pub fn read_file_segment_into(&self, path: &str, offset: u64, buffer: &mut [u8]) -> Result<usize, LfsError> {
+		let context = self.context.raw;
+		let max_bytes = buffer.len() as u64;
+		self.read_into(OperationKind::ReadFileSegment, path, Some(offset), buffer, move |c_path, allocator, lfs_callback, user_data| unsafe {
+			laminafs_sys::lfs_read_file_segment(context, c_path, offset, max_bytes, false, allocator, lfs_callback, user_data)
+		})
+	}
+
+	fn read_into<F>(&self, operation: OperationKind, path: &str, offset: Option<u64>, buffer: &mut [u8], read: F) -> Result<usize, LfsError>
+		where F: FnOnce(*const std::os::raw::c_char, *mut laminafs_sys::lfs_allocator_t, laminafs_sys::lfs_callback_t, *mut std::ffi::c_void) -> *mut laminafs_sys::lfs_work_item_t + Send + 'static {
+		let too_small = Arc::new(AtomicBool::new(false));
+		let allocator = Arc::new(ContextAllocator::new(IntoBuffer {
+			ptr: buffer.as_mut_ptr() as usize,
+			len: buffer.len(),
+			too_small: too_small.clone()
+		}));
+		let read_allocator = allocator.clone();
+		let local = match offset {
+			Some(offset) => DeviceOp::ReadFileSegment { offset: offset, max_bytes: buffer.len() as u64, null_terminate: false, allocator: allocator.clone() },
+			None => DeviceOp::ReadFile { null_terminate: false, allocator: allocator.clone() }
+		};
+
+		// the buffer belongs to the caller, so the work item must not free it, and the work
+		// is finished before the borrow ends
+		let mut work_item = self.submit(operation, path, None, false, Priority::Normal, None, local, move |c_path, lfs_callback, user_data| {
+			read(c_path, read_allocator.as_raw(), lfs_callback, user_data)
+		});
+
+		work_item.set_read_allocator(allocator, false);
+		match work_item.get_result() {
+			ResultCode::Ok => Ok(work_item.get_bytes()),
+			_ if too_small.load(Ordering::SeqCst) => Err(work_item.error(ResultCode::OutOfSpace)),
+			code => Err(work_item.error(code))
+		}
+	}
+
+	pub fn read_file_sync(&self, path: &str) -> Result<Vec<u8>, LfsError> {
+		wait_for(self.read_file(path, false)).map(|buffer| buffer.to_vec())
+	}
+
+	// Reads the whole file as text, failing with InvalidData if it isn't UTF-8.
+	pub fn read_to_string(&self, path: &str) -> Result<String, LfsError> {
+		let buffer = self.read_file_sync(path)?;
+		String::from_utf8(buffer).map_err(|_| LfsError::new(ResultCode::InvalidData, OperationKind::ReadFile, path))
+	}
+
+	pub fn read_file_segment_sync(&self, path: &str, offset: u64, max_bytes: u64) -> Result<Vec<u8>, LfsError> {
+		wait_for(self.read_file_segment(path, offset, max_bytes, false)).map(|buffer| buffer.to_vec())
+	}
+
+	pub fn write_file_sync(&self, path: &str, buffer: &[u8]) -> Result<(), LfsError> {
+		wait_for(self.write_file(path, buffer)).map(|_| ())
+	}
+
+	// Writes text as UTF-8, copying it into a buffer the work item owns.
+	pub fn write_string(&self, path: &str, contents: &str) -> Result<(), LfsError> {
+		self.write_file_sync(path, contents.as_bytes())
+	}
+
+	pub fn append_string(&self, path: &str, contents: &str) -> Result<(), LfsError> {
+		self.append_file_sync(path, contents.as_bytes())
+	}
+
+	pub fn write_file_segment_sync(&self, path: &str, offset: u64, buffer: &[u8]) -> Result<(), LfsError> {
+		wait_for(self.write_file_segment(path, offset, buffer)).map(|_| ())
+	}
+
+	pub fn append_file_sync(&self, path: &str, buffer: &[u8]) -> Result<(), LfsError> {
+		wait_for(self.append_file(path, buffer)).map(|_| ())
+	}
+
+	pub fn delete_file_sync(&self, path: &str) -> Result<(), LfsError> {
+		wait_for(self.delete_file(path)).map(|_| ())
+	}
+
+	pub fn create_dir_sync(&self, path: &str) -> Result<(), LfsError> {
+		wait_for(self.create_dir(path)).map(|_| ())
+	}
+
+	pub fn delete_dir_sync(&self, path: &str) -> Result<(), LfsError> {
+		wait_for(self.delete_dir(path)).map(|_| ())
+	}
+
+	pub fn file_exists_sync(&self, path: &str) -> Result<bool, LfsError> {
+		match wait_for(self.file_exists(path)) {
+			Ok(_) => Ok(true),
+			Err(ref error) if error.code() == ResultCode::NotFound => Ok(false),
+			Err(error) => Err(error)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_dir::TestDir;
+
+	#[test]
+	fn sync_api_test() {
+		let root = TestDir::new("sync_api_test");
+		let fs = LaminaFS::new();
+		let _mount = root.mount(&fs);
+
+		fs.create_dir_sync("/saves").unwrap();
+		fs.write_file_sync("/saves/slot0.txt", b"level 1").unwrap();
+		fs.append_file_sync("/saves/slot0.txt", b", gold 30").unwrap();
+		fs.write_file_segment_sync("/saves/slot0.txt", 6, b"2").unwrap();
+		assert!(fs.read_file_sync("/saves/slot0.txt").unwrap() == b"level 2, gold 30");
+		assert!(fs.read_file_segment_sync("/saves/slot0.txt", 9, 4).unwrap() == b"gold");
+		assert!(fs.read_to_string("/saves/slot0.txt").unwrap() == "level 2, gold 30");
+		assert!(fs.file_exists_sync("/saves/slot0.txt").unwrap());
+
+		fs.delete_file_sync("/saves/slot0.txt").unwrap();
+		assert!(!fs.file_exists_sync("/saves/slot0.txt").unwrap());
+		let error = fs.read_file_sync("/saves/slot0.txt").unwrap_err();
+		assert!(error.code() == ResultCode::NotFound && error.operation() == Some(OperationKind::ReadFile));
+
+		fs.delete_dir_sync("/saves").unwrap();
+		assert!(!root.join("saves").exists());
+	}
+
+	#[test]
+	fn read_into_test() {
+		let root = TestDir::new("read_into_test");
+		std::fs::write(root.join("level.bin"), b"level data").unwrap();
+		let fs = LaminaFS::new();
+		let _mount = root.mount(&fs);
+
+		let mut buffer = [0u8; 16];
+		assert!(fs.read_file_into("/level.bin", &mut buffer).unwrap() == 10);
+		assert!(&buffer[..10] == b"level data");
+
+		let mut segment = [0u8; 4];
+		assert!(fs.read_file_segment_into("/level.bin", 6, &mut segment).unwrap() == 4);
+		assert!(&segment == b"data");
+
+		let mut small = [0u8; 4];
+		assert!(fs.read_file_into("/level.bin", &mut small).unwrap_err().code() == ResultCode::OutOfSpace);
+		assert!(fs.read_file_into("/missing.bin", &mut buffer).unwrap_err().code() == ResultCode::NotFound);
+	}
+}
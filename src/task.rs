@@ -0,0 +1,227 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// Operations the C context doesn't implement run as tasks on a small pool of Rust
+// threads, started the first time one is needed.
+
+use crate::{LfsError, OperationKind, ResultCode};
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+pub(crate) const DEFAULT_TASK_THREADS: usize = 2;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// Runs a worker loop given its thread name, see LaminaFSBuilder::spawner.
+pub(crate) type Spawner = Arc<dyn Fn(&str, Box<dyn FnOnce() + Send>) + Send + Sync>;
+
+struct TaskState<T> {
+	result: Mutex<Option<Result<T, LfsError>>>,
+	condvar: Condvar,
+	waker: Mutex<Option<Waker>>
+}
+
+// Handle to a task running on the pool. Dropping it doesn't cancel the task.
+pub struct Task<T> {
+	state: Arc<TaskState<T>>
+}
+
+impl<T> Task<T> {
+	pub fn is_finished(&self) -> bool {
+		self.state.result.lock().unwrap().is_some()
+	}
+
+	pub fn wait(&self) {
+		let mut result = self.state.result.lock().unwrap();
+		while result.is_none() {
+			result = self.state.condvar.wait(result).unwrap();
+		}
+	}
+
+	pub fn get_result(self) -> Result<T, LfsError> {
+		self.wait();
+		self.state.result.lock().unwrap().take().unwrap()
+	}
+
+	// Takes the result if the task has finished, otherwise registers the waker to be woken
+	// once it does.
+	pub(crate) fn poll_result(&self, waker: &Waker) -> Option<Result<T, LfsError>> {
+		let result = self.state.result.lock().unwrap().take();
+		if result.is_none() {
+			*self.state.waker.lock().unwrap() = Some(waker.clone());
+			// the task may have finished before the waker was in place
+			if self.is_finished() {
+				return self.state.result.lock().unwrap().take();
+			}
+		}
+		result
+	}
+}
+
+// Awaiting a task takes its result, so it can only be awaited to completion once.
+impl<T> Future for Task<T> {
+	type Output = Result<T, LfsError>;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+		match self.poll_result(cx.waker()) {
+			Some(result) => Poll::Ready(result),
+			None => Poll::Pending
+		}
+	}
+}
+
+pub(crate) struct TaskPool {
+	thread_count: usize,
+	thread_name: String,
+	// None to run the workers on threads of their own
+	spawner: Option<Spawner>,
+	sender: Mutex<Option<Sender<Job>>>
+}
+
+impl TaskPool {
+	pub(crate) fn new(thread_count: usize, thread_name: &str, spawner: Option<Spawner>) -> TaskPool {
+		TaskPool {
+			thread_count: std::cmp::max(thread_count, 1),
+			thread_name: thread_name.to_string(),
+			spawner: spawner,
+			sender: Mutex::new(None)
+		}
+	}
+
+	// A pool without threads, which runs each task on the spawning thread.
+	pub(crate) fn inline() -> TaskPool {
+		TaskPool {
+			thread_count: 0,
+			thread_name: String::new(),
+			spawner: None,
+			sender: Mutex::new(None)
+		}
+	}
+
+	fn start(&self) -> Sender<Job> {
+		let (sender, receiver) = channel::<Job>();
+		let receiver = Arc::new(Mutex::new(receiver));
+		for i in 0..self.thread_count {
+			let receiver = receiver.clone();
+			let name = format!("{}-{}", self.thread_name, i);
+			match self.spawner {
+				Some(ref spawner) => spawner(&name, Box::new(move || run_worker(&receiver))),
+				None => {
+					thread::Builder::new()
+						.name(name)
+						.spawn(move || run_worker(&receiver))
+						.unwrap();
+				}
+			}
+		}
+		sender
+	}
+
+	pub(crate) fn spawn<T, F>(&self, operation: OperationKind, path: &str, f: F) -> Task<T>
+		where T: Send + 'static, F: FnOnce() -> Result<T, ResultCode> + Send + 'static {
+		let path = path.to_string();
+		let state = Arc::new(TaskState {
+			result: Mutex::new(None),
+			condvar: Condvar::new(),
+			waker: Mutex::new(None)
+		});
+
+		#[cfg(feature = "tracing")]
+		let span = crate::spans::work_span(operation, &path);
+		let task_state = state.clone();
+		let job: Job = Box::new(move || {
+			#[cfg(feature = "tracing")]
+			let _entered = span.enter();
+			let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or(Err(ResultCode::GenericError));
+			*task_state.result.lock().unwrap() = Some(result.map_err(|code| LfsError::new(code, operation, &path)));
+			task_state.condvar.notify_all();
+			if let Some(waker) = task_state.waker.lock().unwrap().take() {
+				waker.wake();
+			}
+		});
+
+		if self.thread_count == 0 {
+			job();
+			return Task {
+				state: state
+			};
+		}
+
+		let mut sender = self.sender.lock().unwrap();
+		if sender.is_none() {
+			*sender = Some(self.start());
+		}
+		sender.as_ref().unwrap().send(job).unwrap();
+
+		Task {
+			state: state
+		}
+	}
+}
+
+// Workers exit once the pool is dropped and the queue has drained.
+fn run_worker(receiver: &Mutex<Receiver<Job>>) {
+	loop {
+		let job = match receiver.lock().unwrap().recv() {
+			Ok(job) => job,
+			Err(_) => return
+		};
+		job();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn thread_name_test() {
+		let pool = TaskPool::new(2, "io", None);
+		let name = pool.spawn(OperationKind::ReadFile, "/data", || Ok(thread::current().name().map(|name| name.to_string()))).get_result().unwrap();
+		assert!(name.map_or(false, |name| name.starts_with("io-")));
+
+		let inline = TaskPool::inline();
+		let current = thread::current().name().map(|name| name.to_string());
+		assert!(inline.spawn(OperationKind::ReadFile, "/data", || Ok(thread::current().name().map(|name| name.to_string()))).get_result().unwrap() == current);
+	}
+
+	#[test]
+	fn spawner_test() {
+		let spawned = Arc::new(Mutex::new(Vec::new()));
+		let spawner_spawned = spawned.clone();
+		let spawner: Spawner = Arc::new(move |name: &str, worker: Box<dyn FnOnce() + Send>| {
+			spawner_spawned.lock().unwrap().push(name.to_string());
+			thread::spawn(worker);
+		});
+
+		let pool = TaskPool::new(2, "jobs", Some(spawner));
+		assert!(spawned.lock().unwrap().is_empty());
+		assert!(pool.spawn(OperationKind::ReadFile, "/data", || Ok(7)).get_result().unwrap() == 7);
+		assert!(*spawned.lock().unwrap() == ["jobs-0", "jobs-1"]);
+	}
+}
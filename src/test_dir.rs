@@ -0,0 +1,63 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+// Scratch directories for tests, unique per test and process and removed again when
+// dropped, so tests running in parallel or after a failed run don't see each other's files.
+
+use crate::{DeviceType, LaminaFS, Mount, MountPermissions};
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub(crate) struct TestDir {
+	path: PathBuf
+}
+
+impl TestDir {
+	pub(crate) fn new(name: &str) -> TestDir {
+		let path = std::env::temp_dir().join(format!("laminafs_{}_{}", name, std::process::id()));
+		let _ = std::fs::remove_dir_all(&path);
+		std::fs::create_dir_all(&path).unwrap();
+		TestDir {
+			path: path
+		}
+	}
+
+	pub(crate) fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+		self.path.join(path)
+	}
+
+	pub(crate) fn to_str(&self) -> &str {
+		self.path.to_str().unwrap()
+	}
+
+	// Mounts the directory at "/" with every permission.
+	pub(crate) fn mount(&self, fs: &Arc<LaminaFS>) -> Mount {
+		fs.create_mount_with_permissions(DeviceType::Directory, "/", self.to_str(), MountPermissions::All).unwrap()
+	}
+}
+
+impl Drop for TestDir {
+	fn drop(&mut self) {
+		let _ = std::fs::remove_dir_all(&self.path);
+	}
+}
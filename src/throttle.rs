@@ -0,0 +1,152 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// Per-mount bandwidth limits. All work on a limited mount counts against its budget, but
+// only background priority work waits for the budget to refill, so background downloads
+// and precaching yield to foreground streaming on the same disk instead of competing with
+// it. Reads are only charged once they complete, as their size isn't known up front, so a
+// mount can briefly run over its limit; background work then waits until it has paid off
+// the overdraft.
+
+use crate::mount::MountTable;
+use crate::queue::CompletionCallback;
+use crate::{MountPermissions, WorkItemResult};
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+struct Bucket {
+	// bytes that can be spent right now, negative when overdrawn
+	available: f64,
+	refilled: Instant
+}
+
+pub(crate) struct Throttle {
+	bytes_per_sec: u64,
+	bucket: Mutex<Bucket>
+}
+
+impl Throttle {
+	// Starts out with a second's worth of budget, which is also the most it saves up.
+	pub(crate) fn new(bytes_per_sec: u64) -> Throttle {
+		Throttle {
+			bytes_per_sec: std::cmp::max(bytes_per_sec, 1),
+			bucket: Mutex::new(Bucket {
+				available: bytes_per_sec as f64,
+				refilled: Instant::now()
+			})
+		}
+	}
+
+	pub(crate) fn bytes_per_sec(&self) -> u64 {
+		self.bytes_per_sec
+	}
+
+	fn refill(&self, bucket: &mut Bucket) {
+		let now = Instant::now();
+		let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+		bucket.available = (bucket.available + elapsed * self.bytes_per_sec as f64).min(self.bytes_per_sec as f64);
+		bucket.refilled = now;
+	}
+
+	// How long throttled work has to wait before it may start, None if it can go now.
+	pub(crate) fn delay(&self) -> Option<Duration> {
+		let mut bucket = self.bucket.lock().unwrap();
+		self.refill(&mut bucket);
+		if bucket.available >= 0.0 {
+			None
+		} else {
+			Some(Duration::from_secs_f64(-bucket.available / self.bytes_per_sec as f64))
+		}
+	}
+
+	pub(crate) fn consume(&self, bytes: u64) {
+		let mut bucket = self.bucket.lock().unwrap();
+		self.refill(&mut bucket);
+		bucket.available -= bytes as f64;
+	}
+}
+
+// Charges the bytes the work moved to the throttle once it completes: the bytes read, or
+// `write_len` for writes.
+pub(crate) fn charge_on_completion(throttle: Option<Arc<Throttle>>, write_len: Option<usize>, callback: Option<CompletionCallback>) -> Option<CompletionCallback> {
+	let throttle = match throttle {
+		Some(throttle) => throttle,
+		None => return callback
+	};
+
+	Some(Box::new(move |result: &WorkItemResult| {
+		throttle.consume(write_len.unwrap_or_else(|| result.get_bytes()) as u64);
+		if let Some(callback) = callback {
+			callback(result);
+		}
+	}))
+}
+
+impl MountTable {
+	// The throttle of the mount work on `path` would run against: the first mount with
+	// write permission for writes, the first one that has the file for anything else.
+	pub(crate) fn throttle(&self, path: &str, write: bool) -> Option<Arc<Throttle>> {
+		let mounts = self.resolve(path);
+		if mounts.iter().all(|(mount, _)| mount.throttle().is_none()) {
+			return None;
+		}
+
+		for (mount, relative_path) in mounts {
+			let serves = if write {
+				mount.permissions.contains(MountPermissions::WriteFile)
+			} else {
+				match mount.device {
+					Some(ref device) => mount.permissions.contains(MountPermissions::Read) && device.device.file_exists(&relative_path),
+					// no telling whether the context would find the file there
+					None => continue
+				}
+			};
+			if serves {
+				return mount.throttle();
+			}
+		}
+		None
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn throttle_test() {
+		let throttle = Throttle::new(1000);
+		assert!(throttle.delay().is_none());
+
+		throttle.consume(1000);
+		assert!(throttle.delay().is_none());
+
+		throttle.consume(500);
+		let delay = throttle.delay().unwrap();
+		assert!(delay > Duration::from_millis(400) && delay <= Duration::from_millis(500));
+
+		std::thread::sleep(delay + Duration::from_millis(20));
+		assert!(throttle.delay().is_none());
+	}
+}
@@ -0,0 +1,117 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// Work submitted with a timeout is watched by a thread per queue, started the first time
+// a timeout is set. Work still queued when its deadline passes is taken out of the queue;
+// work already handed to the context is finished with TimedOut straight away and its
+// work item left for the context to complete whenever the device gets around to it.
+
+use crate::queue::{WorkQueue, WorkState};
+
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub(crate) struct Deadlines {
+	state: Mutex<DeadlineState>,
+	condvar: Condvar
+}
+
+struct DeadlineState {
+	pending: Vec<(Instant, Weak<WorkState>)>,
+	started: bool,
+	// set once the queue is gone, for the thread to exit
+	shutdown: bool
+}
+
+impl Deadlines {
+	pub(crate) fn new() -> Deadlines {
+		Deadlines {
+			state: Mutex::new(DeadlineState {
+				pending: Vec::new(),
+				started: false,
+				shutdown: false
+			}),
+			condvar: Condvar::new()
+		}
+	}
+
+	// Waits for the next deadline to pass and returns the work it belongs to, None once
+	// the queue is gone.
+	fn next_expired(&self) -> Option<Vec<Weak<WorkState>>> {
+		let mut state = self.state.lock().unwrap();
+		loop {
+			if state.shutdown {
+				return None;
+			}
+
+			let now = Instant::now();
+			let (expired, pending): (Vec<_>, Vec<_>) = state.pending.drain(..).partition(|&(deadline, _)| deadline <= now);
+			state.pending = pending;
+			if !expired.is_empty() {
+				return Some(expired.into_iter().map(|(_, work)| work).collect());
+			}
+
+			// finished work doesn't need watching anymore
+			state.pending.retain(|(_, work)| work.upgrade().map_or(false, |work| !work.is_completed()));
+			state = match state.pending.iter().map(|&(deadline, _)| deadline).min() {
+				Some(deadline) => self.condvar.wait_timeout(state, deadline - now).unwrap().0,
+				None => self.condvar.wait(state).unwrap()
+			};
+		}
+	}
+}
+
+impl WorkQueue {
+	pub(crate) fn set_timeout(self: &Arc<Self>, work: &Arc<WorkState>, timeout: Duration) {
+		let mut state = self.deadlines.state.lock().unwrap();
+		state.pending.push((Instant::now() + timeout, Arc::downgrade(work)));
+		self.deadlines.condvar.notify_one();
+
+		if !state.started {
+			state.started = true;
+			let deadlines = self.deadlines.clone();
+			thread::Builder::new()
+				.name("laminafs-timeout".to_string())
+				.spawn(move || run_watchdog(&deadlines))
+				.unwrap();
+		}
+	}
+}
+
+impl Drop for WorkQueue {
+	fn drop(&mut self) {
+		self.deadlines.state.lock().unwrap().shutdown = true;
+		self.deadlines.condvar.notify_one();
+	}
+}
+
+fn run_watchdog(deadlines: &Deadlines) {
+	while let Some(expired) = deadlines.next_expired() {
+		for work in expired.iter().filter_map(|work| work.upgrade()) {
+			if !work.is_completed() {
+				work.time_out();
+			}
+		}
+	}
+}
@@ -0,0 +1,173 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// Large reads and writes split into segment work items so progress can be reported as
+// each segment completes. Work items of equal priority reach the context in submission
+// order and the context runs them in order, so the leading write_file that creates the
+// file always lands before the segment writes that follow it.
+
+use crate::{LaminaFS, LfsError, ResultCode, WorkHandle, WorkItemResult};
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+pub const DEFAULT_TRANSFER_CHUNK_SIZE: u64 = 1024 * 1024;
+
+struct TransferProgress {
+	bytes_done: AtomicU64,
+	bytes_total: u64
+}
+
+pub struct Transfer {
+	work_items: Vec<WorkHandle>,
+	progress: Arc<TransferProgress>
+}
+
+impl Transfer {
+	// (bytes_done, bytes_total), updated as each segment completes.
+	pub fn progress(&self) -> (u64, u64) {
+		(self.progress.bytes_done.load(Ordering::Acquire), self.progress.bytes_total)
+	}
+
+	pub fn wait(&self) {
+		for work_item in &self.work_items {
+			work_item.wait();
+		}
+	}
+
+	pub fn get_result(&self) -> Result<(), LfsError> {
+		for work_item in &self.work_items {
+			match work_item.get_result() {
+				ResultCode::Ok => {},
+				code => return Err(work_item.error(code))
+			}
+		}
+		Ok(())
+	}
+
+	// Waits for a read transfer and joins its segments.
+	pub fn into_data(self) -> Result<Vec<u8>, LfsError> {
+		self.get_result()?;
+
+		let mut data = Vec::with_capacity(self.progress.bytes_total as usize);
+		for work_item in &self.work_items {
+			data.extend_from_slice(work_item.get_buffer());
+		}
+		Ok(data)
+	}
+}
+
+fn count_progress(progress: &Arc<TransferProgress>, bytes: u64) -> impl FnOnce(&WorkItemResult) + Send + 'static {
+	let progress = progress.clone();
+	move |result: &WorkItemResult| {
+		if result.get_result() == ResultCode::Ok {
+			progress.bytes_done.fetch_add(bytes, Ordering::AcqRel);
+		}
+	}
+}
+
+impl LaminaFS {
+	// Needs the file size up front, so the file has to be on a mount the Rust side can
+	// query (see stat).
+	pub fn read_file_with_progress(&self, path: &str, chunk_size: u64) -> Result<Transfer, LfsError> {
+		let size = self.file_size(path).get_result()?;
+		let chunk_size = std::cmp::max(chunk_size, 1);
+		let progress = Arc::new(TransferProgress {
+			bytes_done: AtomicU64::new(0),
+			bytes_total: size
+		});
+
+		let mut work_items = Vec::new();
+		let mut offset = 0;
+		while offset < size || work_items.is_empty() {
+			let bytes = std::cmp::min(chunk_size, size - offset);
+			work_items.push(self.read_file_segment_with_callback(path, offset, bytes, false, count_progress(&progress, bytes)));
+			offset += bytes;
+		}
+
+		Ok(Transfer {
+			work_items: work_items,
+			progress: progress
+		})
+	}
+
+	pub fn write_file_with_progress<B: Into<Arc<[u8]>>>(&self, path: &str, buffer: B, chunk_size: u64) -> Transfer {
+		let buffer: Arc<[u8]> = buffer.into();
+		let size = buffer.len() as u64;
+		let chunk_size = std::cmp::max(chunk_size, 1);
+		let progress = Arc::new(TransferProgress {
+			bytes_done: AtomicU64::new(0),
+			bytes_total: size
+		});
+
+		let mut work_items = Vec::new();
+		let mut offset = 0;
+		while offset < size || work_items.is_empty() {
+			let bytes = std::cmp::min(chunk_size, size - offset);
+			let chunk: Arc<[u8]> = Arc::from(&buffer[offset as usize..(offset + bytes) as usize]);
+			let callback = count_progress(&progress, bytes);
+			work_items.push(if offset == 0 {
+				self.write_file_with_callback(path, chunk, callback)
+			} else {
+				self.write_file_segment_with_callback(path, offset, chunk, callback)
+			});
+			offset += bytes;
+		}
+
+		Transfer {
+			work_items: work_items,
+			progress: progress
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{device, MountPermissions};
+
+	#[test]
+	fn transfer_progress_test() {
+		let fs = LaminaFS::new();
+		let memory = fs.register_device::<device::MemoryDevice>();
+		let _mount = fs.create_mount_with_permissions(memory, "/", "", MountPermissions::All).unwrap();
+
+		let write = fs.write_file_with_progress("/level.bin", &b"0123456789"[..], 4);
+		write.get_result().unwrap();
+		assert!(write.progress() == (10, 10));
+		assert!(fs.read_file_sync("/level.bin").unwrap() == b"0123456789");
+
+		let read = fs.read_file_with_progress("/level.bin", 3).unwrap();
+		read.wait();
+		assert!(read.progress() == (10, 10));
+		assert!(read.into_data().unwrap() == b"0123456789");
+
+		// an empty file is still one work item
+		let write = fs.write_file_with_progress("/empty.bin", &b""[..], 4);
+		write.get_result().unwrap();
+		assert!(write.progress() == (0, 0));
+		assert!(fs.read_file_with_progress("/empty.bin", 4).unwrap().into_data().unwrap().is_empty());
+
+		assert!(fs.read_file_with_progress("/missing.bin", 4).err().map(|error| error.code()) == Some(ResultCode::NotFound));
+	}
+}
@@ -0,0 +1,156 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// Recursive listing and glob search of the merged namespace. Each directory is listed
+// across every mount covering it, so files shadowed by a higher priority mount appear once.
+
+use crate::glob::{glob_base, glob_match};
+use crate::mount::MountTable;
+use crate::{LaminaFS, LfsPath, OperationKind, ResultCode, Task};
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct WalkEntry {
+	pub path: String,
+	pub size: u64
+}
+
+impl MountTable {
+	// Every file below `dir`, at most `depth` levels down if set, sorted by path.
+	pub(crate) fn walk(&self, dir: &str, depth: Option<usize>) -> Result<Vec<WalkEntry>, ResultCode> {
+		let mut entries = Vec::new();
+		let mut dirs = vec![(dir.trim_end_matches('/').to_string(), depth)];
+		while let Some((dir, depth)) = dirs.pop() {
+			for entry in self.list_dir(if dir.is_empty() { "/" } else { &dir })? {
+				let path = format!("{}/{}", dir, entry.name);
+				if !entry.is_dir {
+					entries.push(WalkEntry {
+						path: path,
+						size: entry.size
+					});
+				} else if depth.map_or(true, |depth| depth > 1) {
+					dirs.push((path, depth.map(|depth| depth - 1)));
+				}
+			}
+		}
+
+		entries.sort_by(|a, b| a.path.cmp(&b.path));
+		Ok(entries)
+	}
+
+	// Every file matching `pattern`, walking only as far below the pattern's literal
+	// prefix as the pattern can match.
+	pub(crate) fn find(&self, pattern: &str) -> Result<Vec<WalkEntry>, ResultCode> {
+		let (base, depth) = glob_base(pattern);
+		match self.walk(&base, depth) {
+			Ok(entries) => Ok(entries.into_iter().filter(|entry| glob_match(pattern, &entry.path)).collect()),
+			Err(ResultCode::NotFound) => Ok(Vec::new()),
+			Err(code) => Err(code)
+		}
+	}
+}
+
+impl LaminaFS {
+	// Lists every file below a directory across all the mounts the Rust side can reach.
+	pub fn walk(&self, path: &str) -> Task<Vec<WalkEntry>> {
+		let mounts = self.mounts.clone();
+		let path_owned = self.virtual_path(path, false);
+		self.tasks.spawn(OperationKind::Walk, path, move || {
+			let path_owned = path_owned.map_err(|error| error.code())?;
+			mounts.walk(&path_owned, None)
+		})
+	}
+
+	// Finds the files matching a glob pattern, where `*` and `?` match within a path
+	// component and `**` matches any number of components, e.g. "/textures/**/*.ktx2".
+	// Patterns aren't aliased or case folded.
+	pub fn find(&self, pattern: &str) -> Task<Vec<WalkEntry>> {
+		let mounts = self.mounts.clone();
+		let pattern_owned = pattern.to_string();
+		self.tasks.spawn(OperationKind::Find, pattern, move || {
+			LfsPath::new(&pattern_owned).map_err(|error| error.code())?;
+			mounts.find(&pattern_owned)
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::device::{CreatedDevice, Device, MemoryDevice, WriteMode};
+	use crate::mount::MountEntry;
+	use crate::{DeviceType, MountPermissions};
+
+	use std::sync::Arc;
+
+	fn memory_mount(mount_point: &str, files: &[(&str, &[u8])]) -> Arc<MountEntry> {
+		let device = MemoryDevice::create("").unwrap();
+		for &(path, data) in files {
+			for (index, _) in path.match_indices('/').skip(1) {
+				let _ = device.create_dir(&path[..index]);
+			}
+			device.write_file(path, 0, data, WriteMode::Overwrite).unwrap();
+		}
+		Arc::new(MountEntry::new(mount_point, DeviceType::Directory, "", MountPermissions::Read, Some(CreatedDevice::new(Arc::new(device))), None))
+	}
+
+	#[test]
+	fn walk_test() {
+		let table = MountTable::new();
+		let base = memory_mount("/", &[("/textures/stone.ktx2", b"base"), ("/textures/ui/button.ktx2", b"b"), ("/config.ini", b"c")]);
+		let patch = memory_mount("/textures", &[("/stone.ktx2", b"patched"), ("/grass.ktx2", b"g")]);
+		table.add(&base);
+		table.add(&patch);
+
+		let walked = table.walk("/textures", None).unwrap();
+		let paths: Vec<_> = walked.iter().map(|entry| entry.path.as_str()).collect();
+		assert!(paths == ["/textures/grass.ktx2", "/textures/stone.ktx2", "/textures/ui/button.ktx2"]);
+		assert!(walked[1].size == 7);
+
+		assert!(table.walk("/", Some(1)).unwrap().iter().map(|entry| entry.path.as_str()).collect::<Vec<_>>() == ["/config.ini"]);
+		assert!(table.walk("/missing", None) == Err(ResultCode::NotFound));
+
+		let found = |pattern: &str| table.find(pattern).unwrap().into_iter().map(|entry| entry.path).collect::<Vec<_>>();
+		assert!(found("/textures/**/*.ktx2") == ["/textures/grass.ktx2", "/textures/stone.ktx2", "/textures/ui/button.ktx2"]);
+		assert!(found("/textures/*.ktx2") == ["/textures/grass.ktx2", "/textures/stone.ktx2"]);
+		assert!(found("/*/ui/butto?.ktx2") == ["/textures/ui/button.ktx2"]);
+		assert!(found("/config.ini") == ["/config.ini"]);
+		assert!(found("/missing/*").is_empty());
+	}
+
+	#[test]
+	fn find_test() {
+		let root = crate::test_dir::TestDir::new("find_test");
+		std::fs::create_dir_all(root.join("textures/ui")).unwrap();
+		std::fs::write(root.join("textures/stone.ktx2"), b"stone").unwrap();
+		std::fs::write(root.join("textures/ui/button.ktx2"), b"button").unwrap();
+		std::fs::write(root.join("textures/readme.txt"), b"readme").unwrap();
+		let fs = LaminaFS::new();
+		let _mount = root.mount(&fs);
+
+		let found = fs.find("/textures/**/*.ktx2").get_result().unwrap();
+		assert!(found.iter().map(|entry| entry.path.as_str()).collect::<Vec<_>>() == ["/textures/stone.ktx2", "/textures/ui/button.ktx2"]);
+		assert!(found[1].size == 6);
+		assert!(fs.find("/sounds/*.ogg").get_result().unwrap().is_empty());
+		assert!(fs.find("/../*.ktx2").get_result().unwrap_err().code() == ResultCode::InvalidPath);
+	}
+}
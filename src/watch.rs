@@ -0,0 +1,361 @@
+/*
+Copyright (c) 2019 Brett Lajzer
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+// Change notification for hot reloading. Watches poll the mounts covering the pattern
+// on a background thread and diff successive snapshots of size and modification time.
+// In ExecutionMode::SingleThread there is no thread and the caller polls instead.
+//
+// With the notify feature, watches over Directory mounts have the OS report changes
+// instead, and only rescan what it reports. The poll interval is then how long changes
+// are gathered before they're reported, so a burst of writes to a file is one event.
+
+use crate::cache::ReadCache;
+use crate::glob::{glob_base, glob_match};
+use crate::mount::MountTable;
+use crate::{ExecutionMode, LaminaFS};
+
+use std::collections::HashMap;
+#[cfg(any(feature = "notify", test))]
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ChangeKind {
+	Created,
+	Modified,
+	Deleted
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ChangeEvent {
+	pub path: String,
+	pub kind: ChangeKind
+}
+
+// Receives the change events of a watch. Dropping the watcher stops watching.
+pub struct Watcher {
+	events: Receiver<ChangeEvent>,
+	stop: Arc<AtomicBool>,
+	// the state a polling thread would own, for watches polled by the caller
+	manual: Option<Mutex<ManualPoll>>
+}
+
+struct ManualPoll {
+	state: PollState,
+	previous: Snapshot,
+	cache: Arc<ReadCache>,
+	sender: Sender<ChangeEvent>
+}
+
+impl Watcher {
+	pub fn events(&self) -> &Receiver<ChangeEvent> {
+		&self.events
+	}
+
+	pub fn try_recv(&self) -> Option<ChangeEvent> {
+		self.events.try_recv().ok()
+	}
+
+	// Scans for changes on the calling thread, queueing events for try_recv and events.
+	// Only watches in ExecutionMode::SingleThread need it; for the others the background
+	// thread polls and this does nothing.
+	pub fn poll(&self) {
+		if let Some(ref manual) = self.manual {
+			let mut manual = manual.lock().unwrap();
+			let current = manual.state.snapshot();
+			send_changes(&manual.previous, &current, &manual.cache, &manual.sender);
+			manual.previous = current;
+		}
+	}
+}
+
+impl Drop for Watcher {
+	fn drop(&mut self) {
+		self.stop.store(true, Ordering::Release);
+	}
+}
+
+type Snapshot = HashMap<String, (u64, Option<SystemTime>)>;
+
+struct PollState {
+	mounts: Arc<MountTable>,
+	pattern: String,
+	base: String,
+	depth: Option<usize>
+}
+
+impl PollState {
+	fn snapshot(&self) -> Snapshot {
+		let mut snapshot = HashMap::new();
+		self.scan(&self.base, self.depth, &mut snapshot);
+		snapshot
+	}
+
+	fn scan(&self, dir: &str, depth: Option<usize>, snapshot: &mut Snapshot) {
+		let entries = match self.mounts.list_dir(dir) {
+			Ok(entries) => entries,
+			Err(_) => return
+		};
+
+		for entry in entries {
+			let path = format!("{}/{}", dir.trim_end_matches('/'), entry.name);
+			if entry.is_dir {
+				if depth.map_or(true, |depth| depth > 1) {
+					self.scan(&path, depth.map(|depth| depth - 1), snapshot);
+				}
+			} else if glob_match(&self.pattern, &path) {
+				let modified = self.mounts.query(&path, |device, path| device.stat(path)).ok().and_then(|stat| stat.modified);
+				snapshot.insert(path, (entry.size, modified));
+			}
+		}
+	}
+
+	// Rescans just the paths that changed, and whatever is below those that are
+	// directories, sending the differences. Returns false once nobody is listening.
+	#[cfg(any(feature = "notify", test))]
+	fn update(&self, previous: &mut Snapshot, changed: &HashSet<String>, cache: &ReadCache, sender: &Sender<ChangeEvent>) -> bool {
+		let mut before = Snapshot::new();
+		let mut after = Snapshot::new();
+		for path in changed {
+			let prefix = format!("{}/", path.trim_end_matches('/'));
+			before.extend(previous.iter()
+				.filter(|(previous_path, _)| *previous_path == path || previous_path.starts_with(&prefix))
+				.map(|(previous_path, state)| (previous_path.clone(), *state)));
+
+			match self.mounts.query(path, |device, path| device.stat(path)) {
+				Ok(ref stat) if stat.is_dir => self.scan(path, None, &mut after),
+				Ok(stat) if glob_match(&self.pattern, path) => {
+					after.insert(path.clone(), (stat.size, stat.modified));
+				},
+				_ => {}
+			}
+		}
+
+		for path in before.keys() {
+			previous.remove(path);
+		}
+		previous.extend(after.iter().map(|(path, state)| (path.clone(), *state)));
+		send_changes(&before, &after, cache, sender)
+	}
+}
+
+// Sends the differences between two snapshots, returning false once nobody is listening.
+// Changed files are dropped from the read cache first.
+fn send_changes(previous: &Snapshot, current: &Snapshot, cache: &ReadCache, sender: &Sender<ChangeEvent>) -> bool {
+	let changed = current.iter().filter_map(|(path, state)| match previous.get(path) {
+		None => Some((path, ChangeKind::Created)),
+		Some(previous_state) if previous_state != state => Some((path, ChangeKind::Modified)),
+		Some(_) => None
+	});
+	let deleted = previous.keys().filter(|path| !current.contains_key(*path)).map(|path| (path, ChangeKind::Deleted));
+
+	for (path, kind) in changed.chain(deleted) {
+		cache.invalidate(path);
+		let event = ChangeEvent {
+			path: path.clone(),
+			kind: kind
+		};
+		if sender.send(event).is_err() {
+			return false;
+		}
+	}
+	true
+}
+
+impl LaminaFS {
+	pub fn watch(&self, pattern: &str) -> Watcher {
+		self.watch_with_interval(pattern, DEFAULT_POLL_INTERVAL)
+	}
+
+	pub fn watch_with_interval(&self, pattern: &str, interval: Duration) -> Watcher {
+		let (base, depth) = glob_base(pattern);
+		let state = PollState {
+			mounts: self.mounts.clone(),
+			pattern: pattern.to_string(),
+			base: base,
+			depth: depth
+		};
+
+		let cache = self.cache.clone();
+		let (sender, receiver) = channel();
+		let stop = Arc::new(AtomicBool::new(false));
+		if self.execution_mode() == ExecutionMode::SingleThread {
+			return Watcher {
+				events: receiver,
+				stop: stop,
+				manual: Some(Mutex::new(ManualPoll {
+					previous: state.snapshot(),
+					state: state,
+					cache: cache,
+					sender: sender
+				}))
+			};
+		}
+
+		let thread_stop = stop.clone();
+		#[cfg(feature = "notify")]
+		let state = match crate::native_watch::NativeWatch::new(&self.mounts, &state.base) {
+			Some(native) => {
+				thread::Builder::new()
+					.name("laminafs-watch".to_string())
+					.spawn(move || {
+						let mut previous = state.snapshot();
+						loop {
+							let changed = native.wait(interval, interval);
+							if thread_stop.load(Ordering::Acquire) {
+								return;
+							}
+
+							match changed {
+								Some(ref changed) if !state.update(&mut previous, changed, &cache, &sender) => return,
+								Some(_) => {},
+								None => return
+							}
+						}
+					})
+					.unwrap();
+
+				return Watcher {
+					events: receiver,
+					stop: stop,
+					manual: None
+				};
+			},
+			None => state
+		};
+
+		thread::Builder::new()
+			.name("laminafs-watch".to_string())
+			.spawn(move || {
+				let mut previous = state.snapshot();
+				loop {
+					thread::sleep(interval);
+					if thread_stop.load(Ordering::Acquire) {
+						return;
+					}
+
+					let current = state.snapshot();
+					if !send_changes(&previous, &current, &cache, &sender) {
+						return;
+					}
+					previous = current;
+				}
+			})
+			.unwrap();
+
+		Watcher {
+			events: receiver,
+			stop: stop,
+			manual: None
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::device::{CreatedDevice, Device, MemoryDevice, WriteMode};
+	use crate::mount::MountEntry;
+	use crate::{DeviceType, MountPermissions};
+
+	#[test]
+	fn manual_poll_test() {
+		let memory = Arc::new(MemoryDevice::create("").unwrap());
+		let mounts = Arc::new(MountTable::new());
+		let data = Arc::new(MountEntry::new("/data/", DeviceType::Directory, "", MountPermissions::All, Some(CreatedDevice::new(memory.clone())), None));
+		mounts.add(&data);
+		memory.write_file("a.json", 0, b"{}", WriteMode::Overwrite).unwrap();
+
+		let (base, depth) = glob_base("/data/*.json");
+		let state = PollState {
+			mounts: mounts,
+			pattern: "/data/*.json".to_string(),
+			base: base,
+			depth: depth
+		};
+		let (sender, receiver) = channel();
+		let watcher = Watcher {
+			events: receiver,
+			stop: Arc::new(AtomicBool::new(false)),
+			manual: Some(Mutex::new(ManualPoll {
+				previous: state.snapshot(),
+				state: state,
+				cache: Arc::new(ReadCache::new()),
+				sender: sender
+			}))
+		};
+
+		memory.write_file("b.json", 0, b"{}", WriteMode::Overwrite).unwrap();
+		memory.write_file("c.txt", 0, b"", WriteMode::Overwrite).unwrap();
+		assert!(watcher.try_recv().is_none());
+		watcher.poll();
+		assert!(watcher.try_recv() == Some(ChangeEvent { path: "/data/b.json".to_string(), kind: ChangeKind::Created }));
+		assert!(watcher.try_recv().is_none());
+
+		memory.delete_file("a.json").unwrap();
+		watcher.poll();
+		assert!(watcher.try_recv() == Some(ChangeEvent { path: "/data/a.json".to_string(), kind: ChangeKind::Deleted }));
+	}
+	#[test]
+	fn update_test() {
+		let memory = Arc::new(MemoryDevice::create("").unwrap());
+		let mounts = Arc::new(MountTable::new());
+		let data = Arc::new(MountEntry::new("/data/", DeviceType::Directory, "", MountPermissions::All, Some(CreatedDevice::new(memory.clone())), None));
+		mounts.add(&data);
+		memory.create_dir("levels").unwrap();
+		memory.write_file("levels/a.json", 0, b"{}", WriteMode::Overwrite).unwrap();
+		memory.write_file("b.json", 0, b"{}", WriteMode::Overwrite).unwrap();
+
+		let (base, depth) = glob_base("/data/**/*.json");
+		let state = PollState {
+			mounts: mounts,
+			pattern: "/data/**/*.json".to_string(),
+			base: base,
+			depth: depth
+		};
+		let mut previous = state.snapshot();
+		let (sender, receiver) = channel();
+		let cache = ReadCache::new();
+
+		memory.write_file("levels/a.json", 0, b"{\"x\": 1}", WriteMode::Overwrite).unwrap();
+		memory.write_file("levels/c.json", 0, b"{}", WriteMode::Overwrite).unwrap();
+		memory.delete_file("b.json").unwrap();
+		let changed: HashSet<String> = ["/data/levels".to_string(), "/data/b.json".to_string()].iter().cloned().collect();
+		assert!(state.update(&mut previous, &changed, &cache, &sender));
+
+		let mut events: Vec<ChangeEvent> = receiver.try_iter().collect();
+		events.sort_by(|a, b| a.path.cmp(&b.path));
+		assert!(events == [
+			ChangeEvent { path: "/data/b.json".to_string(), kind: ChangeKind::Deleted },
+			ChangeEvent { path: "/data/levels/a.json".to_string(), kind: ChangeKind::Modified },
+			ChangeEvent { path: "/data/levels/c.json".to_string(), kind: ChangeKind::Created }
+		]);
+		assert!(previous == state.snapshot());
+	}
+}